@@ -0,0 +1,371 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use soulflame::color_conversion::ColourEncoding;
+use soulflame::vxm::{VxmAsset, VxmVoxel};
+use soulflame::vxm_mesh::{
+    generate_instance_data, MeshedVoxelsFace, VoxelLayer, VoxelNeighbourhood,
+};
+use soulflame::vxm_rng::Rng;
+use std::hint::black_box;
+
+const ALL_FACES: [MeshedVoxelsFace; 6] = [
+    MeshedVoxelsFace::Back,
+    MeshedVoxelsFace::Front,
+    MeshedVoxelsFace::Left,
+    MeshedVoxelsFace::Right,
+    MeshedVoxelsFace::Bottom,
+    MeshedVoxelsFace::Top,
+];
+
+// As large as a vxm model can be across, like street-scene.vxm
+const STREET_SCENE_SIZE: [usize; 3] = [255, 128, 255];
+
+// Any index below `n`
+fn below(rng: &mut Rng, n: usize) -> usize {
+    rng.range_inclusive(0, n as i32 - 1) as usize
+}
+
+fn voxel(hsl: u16) -> VxmVoxel {
+    VxmVoxel {
+        hsl,
+        emissive: false,
+        opacity: u8::MAX,
+    }
+}
+
+/// A street of buildings either side of a cobbled road, with windows, roofs and scattered
+/// clutter, so the meshers see both large flat runs and broken up surfaces
+fn street_scene() -> VxmAsset {
+    let [size_x, size_y, size_z] = STREET_SCENE_SIZE;
+    let mut rng = Rng::new(0x5_7ee7);
+    let mut voxels = vec![vec![vec![VxmVoxel::default(); size_z]; size_y]; size_x];
+
+    let cobbles = [0x8421, 0x8423, 0x8425];
+    for column in voxels.iter_mut() {
+        for row in column.iter_mut().take(4) {
+            for cell in row.iter_mut() {
+                *cell = voxel(cobbles[below(&mut rng, cobbles.len())]);
+            }
+        }
+    }
+
+    let road = size_z / 2 - 16..size_z / 2 + 16;
+    let mut x = 2;
+    while x + 20 < size_x {
+        let width = 14 + below(&mut rng, 8);
+        let height = 30 + below(&mut rng, size_y - 50);
+        let wall = 0x9000 + below(&mut rng, 0x400) as u16 * 4 + 1;
+        for (depth_start, depth_end) in [(4, road.start), (road.end, size_z - 4)] {
+            for bx in x..x + width {
+                for by in 4..4 + height {
+                    for bz in depth_start..depth_end {
+                        let is_shell = bx == x
+                            || bx == x + width - 1
+                            || bz == depth_start
+                            || bz == depth_end - 1;
+                        let is_window = (3..6).contains(&(by % 8)) && (1..4).contains(&(bx % 5));
+                        if is_shell && !is_window {
+                            voxels[bx][by][bz] = voxel(wall);
+                        }
+                    }
+                }
+                // Pitched roof
+                for ridge in 0..(depth_end - depth_start) / 2 {
+                    let by = 4 + height + ridge.min(12);
+                    if by < size_y {
+                        voxels[bx][by][depth_start + ridge] = voxel(0x0c11);
+                        voxels[bx][by][depth_end - 1 - ridge] = voxel(0x0c11);
+                    }
+                }
+            }
+        }
+        x += width + 2 + below(&mut rng, 4);
+    }
+
+    for _ in 0..2000 {
+        let (cx, cz) = (
+            below(&mut rng, size_x),
+            road.start + below(&mut rng, road.len()),
+        );
+        for cy in 4..4 + below(&mut rng, 4) {
+            voxels[cx][cy][cz] = voxel(0x4c41);
+        }
+    }
+
+    VxmAsset {
+        size: STREET_SCENE_SIZE.map(|size| size as u8),
+        voxel_array: voxels,
+        lights: Vec::new(),
+        colour_encoding: ColourEncoding::Hsl,
+        palette: Vec::new(),
+    }
+}
+
+/// The per-axis greedy mesher the bitmask mesher replaced, kept here to measure against
+mod per_axis {
+    use soulflame::render::main::InstanceData;
+    use soulflame::vxm::{VxmAsset, VxmVoxel};
+    use soulflame::vxm_mesh::MeshedVoxelsFace;
+
+    // Instance fields aren't public outside the crate, so it's assembled from its bytes, lit by
+    // the open sky like every face of an unlit model
+    fn instance(position: [u8; 3], width: u8, height: u8, hsl: u16, ao: u8) -> InstanceData {
+        let [hsl_low, hsl_high] = hsl.to_ne_bytes();
+        bytemuck::cast([
+            position[0],
+            position[1],
+            position[2],
+            width,
+            hsl_low,
+            hsl_high,
+            ao,
+            height,
+            15 << 4,
+            0,
+            0,
+            0,
+        ])
+    }
+
+    fn is_solid_voxel(voxel: &VxmVoxel) -> bool {
+        voxel.hsl != 0
+    }
+
+    const FACE_VERTEX_OFFSETS: [[[i32; 3]; 4]; 6] = [
+        [[1, 0, 0], [0, 0, 0], [1, 1, 0], [0, 1, 0]],
+        [[0, 0, 1], [1, 0, 1], [0, 1, 1], [1, 1, 1]],
+        [[0, 0, 0], [0, 0, 1], [0, 1, 0], [0, 1, 1]],
+        [[1, 0, 1], [1, 0, 0], [1, 1, 1], [1, 1, 0]],
+        [[0, 0, 0], [1, 0, 0], [0, 0, 1], [1, 0, 1]],
+        [[1, 1, 0], [0, 1, 0], [1, 1, 1], [0, 1, 1]],
+    ];
+
+    const FACE_NORMALS: [[i32; 3]; 6] = [
+        [0, 0, -1],
+        [0, 0, 1],
+        [-1, 0, 0],
+        [1, 0, 0],
+        [0, -1, 0],
+        [0, 1, 0],
+    ];
+
+    fn is_solid_at(vxm: &VxmAsset, x: i32, y: i32, z: i32) -> bool {
+        let in_bounds = x >= 0
+            && y >= 0
+            && z >= 0
+            && x < vxm.size[0] as i32
+            && y < vxm.size[1] as i32
+            && z < vxm.size[2] as i32;
+        in_bounds && is_solid_voxel(&vxm.voxel_array[x as usize][y as usize][z as usize])
+    }
+
+    fn vertex_ambient_occlusion(side_1: bool, side_2: bool, corner: bool) -> u8 {
+        if side_1 && side_2 {
+            return 0;
+        }
+        3 - side_1 as u8 - side_2 as u8 - corner as u8
+    }
+
+    fn face_ambient_occlusion(
+        vxm: &VxmAsset,
+        x: usize,
+        y: usize,
+        z: usize,
+        face: MeshedVoxelsFace,
+    ) -> u8 {
+        let face = face as usize;
+        let normal = FACE_NORMALS[face];
+        let front = [
+            x as i32 + normal[0],
+            y as i32 + normal[1],
+            z as i32 + normal[2],
+        ];
+
+        let mut ao = 0u8;
+        for (vertex, offset) in FACE_VERTEX_OFFSETS[face].iter().enumerate() {
+            let mut steps = [[0i32; 3]; 2];
+            let mut step_count = 0;
+            for axis in 0..3 {
+                if normal[axis] == 0 {
+                    steps[step_count][axis] = if offset[axis] == 0 { -1 } else { 1 };
+                    step_count += 1;
+                }
+            }
+            let [side_1, side_2] = steps;
+            let at = |step: [i32; 3]| {
+                is_solid_at(
+                    vxm,
+                    front[0] + step[0],
+                    front[1] + step[1],
+                    front[2] + step[2],
+                )
+            };
+
+            let vertex_ao = vertex_ambient_occlusion(
+                at(side_1),
+                at(side_2),
+                at([
+                    side_1[0] + side_2[0],
+                    side_1[1] + side_2[1],
+                    side_1[2] + side_2[2],
+                ]),
+            );
+            ao |= vertex_ao << (6 - vertex * 2);
+        }
+        ao
+    }
+
+    /// Greedy meshes one face direction, walking slices along `axis_d` and growing quads along
+    /// `axis_u` and `axis_v` a voxel at a time
+    pub fn generate_instance_data(vxm: &VxmAsset, face: MeshedVoxelsFace) -> Vec<InstanceData> {
+        let (axis_d, axis_u, axis_v) = match face {
+            MeshedVoxelsFace::Back | MeshedVoxelsFace::Front => (2, 0, 1),
+            MeshedVoxelsFace::Left | MeshedVoxelsFace::Right => (0, 2, 1),
+            MeshedVoxelsFace::Bottom | MeshedVoxelsFace::Top => (1, 0, 2),
+        };
+        let step = FACE_NORMALS[face as usize][axis_d];
+        let size = vxm.size.map(|size| size as usize);
+
+        let idx = |[x, y, z]: [usize; 3]| x * size[1] * size[2] + y * size[2] + z;
+        let to_xyz = |d: usize, u: usize, v: usize| {
+            let mut position = [0; 3];
+            position[axis_d] = d;
+            position[axis_u] = u;
+            position[axis_v] = v;
+            position
+        };
+        let voxel_at = |[x, y, z]: [usize; 3]| &vxm.voxel_array[x][y][z];
+
+        let mut visited_voxels = vec![false; size[0] * size[1] * size[2]];
+
+        let check_voxel = |visited_voxels: &Vec<bool>, position: [usize; 3], hsl: u16, ao: u8| {
+            let front = position[axis_d] as i32 + step;
+            let is_face_hidden = front >= 0 && (front as usize) < size[axis_d] && {
+                let mut front_position = position;
+                front_position[axis_d] = front as usize;
+                is_solid_voxel(voxel_at(front_position))
+            };
+            let [x, y, z] = position;
+
+            !is_solid_voxel(voxel_at(position))
+                || voxel_at(position).hsl != hsl
+                || visited_voxels[idx(position)]
+                || is_face_hidden
+                || face_ambient_occlusion(vxm, x, y, z, face) != ao
+        };
+
+        let mut instance_data = Vec::with_capacity(size[0] * size[1] * size[2] / 4);
+
+        for d in 0..size[axis_d] {
+            for u in 0..size[axis_u] {
+                for v in 0..size[axis_v] {
+                    let position = to_xyz(d, u, v);
+                    let voxel = voxel_at(position);
+                    if !is_solid_voxel(voxel) {
+                        continue;
+                    }
+
+                    let [x, y, z] = position;
+                    let ao = face_ambient_occlusion(vxm, x, y, z, face);
+                    if check_voxel(&visited_voxels, position, voxel.hsl, ao) {
+                        continue;
+                    }
+
+                    let mut u_extent = 1;
+                    let mut v_extent = 1;
+                    let max_extent_u = size[axis_u] - u;
+                    let max_extent_v = size[axis_v] - v;
+
+                    let mut is_u_extendable = true;
+                    let mut is_v_extendable = true;
+
+                    while (is_u_extendable || is_v_extendable)
+                        && u_extent < max_extent_u
+                        && v_extent < max_extent_v
+                    {
+                        is_u_extendable = !(0..v_extent).any(|dv| {
+                            check_voxel(
+                                &visited_voxels,
+                                to_xyz(d, u + u_extent, v + dv),
+                                voxel.hsl,
+                                ao,
+                            )
+                        });
+                        if is_u_extendable {
+                            u_extent += 1;
+                        }
+
+                        is_v_extendable = !(0..u_extent).any(|du| {
+                            check_voxel(
+                                &visited_voxels,
+                                to_xyz(d, u + du, v + v_extent),
+                                voxel.hsl,
+                                ao,
+                            )
+                        });
+                        if is_v_extendable {
+                            v_extent += 1;
+                        }
+                    }
+
+                    for du in 0..u_extent {
+                        for dv in 0..v_extent {
+                            visited_voxels[idx(to_xyz(d, u + du, v + dv))] = true;
+                        }
+                    }
+
+                    instance_data.push(instance(
+                        [x as u8, y as u8, z as u8],
+                        u_extent as u8,
+                        v_extent as u8,
+                        voxel.hsl,
+                        ao,
+                    ));
+                }
+            }
+        }
+        instance_data
+    }
+}
+
+fn mesh_per_axis(vxm: &VxmAsset) -> usize {
+    ALL_FACES
+        .into_iter()
+        .map(|face| per_axis::generate_instance_data(vxm, face).len())
+        .sum()
+}
+
+fn mesh_bitmask(vxm: &VxmAsset) -> usize {
+    let neighbourhood = VoxelNeighbourhood::isolated(vxm);
+    ALL_FACES
+        .into_iter()
+        // Slices past the end of the model are skipped, so this meshes every one
+        .map(|face| {
+            generate_instance_data(
+                &neighbourhood,
+                face,
+                VoxelLayer::Opaque,
+                true,
+                0..usize::MAX,
+            )
+            .len()
+        })
+        .sum()
+}
+
+fn bench_meshers(c: &mut Criterion) {
+    let vxm = street_scene();
+    let (per_axis_quads, bitmask_quads) = (mesh_per_axis(&vxm), mesh_bitmask(&vxm));
+    assert!(
+        bitmask_quads <= per_axis_quads,
+        "bitmask mesher made {bitmask_quads} quads, more than the {per_axis_quads} of the per-axis mesher"
+    );
+
+    let mut group = c.benchmark_group("street scene greedy meshing");
+    group.sample_size(10);
+    group.bench_function("per-axis", |b| b.iter(|| mesh_per_axis(black_box(&vxm))));
+    group.bench_function("bitmask", |b| b.iter(|| mesh_bitmask(black_box(&vxm))));
+    group.finish();
+}
+
+criterion_group!(benches, bench_meshers);
+criterion_main!(benches);
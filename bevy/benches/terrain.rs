@@ -0,0 +1,151 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use fastnoise2::{generator::prelude::*, SafeNode};
+use soulflame::vxm_erosion::ErosionCache;
+use soulflame::vxm_terrain::create_vxm_from_noise;
+use soulflame::vxm_terrain_settings::TerrainSettings;
+use std::hint::black_box;
+
+// The same as the density grid of create_vxm_from_noise
+const DENSITY_STEP: i32 = 4;
+const SURFACE_LOOKAHEAD: i32 = 16;
+const CAVE_SCALE: f32 = 48.0;
+
+// The shipped world, without the prefabs that need loading as assets
+fn world_settings() -> TerrainSettings {
+    let mut settings: TerrainSettings =
+        ron::de::from_str(include_str!("../assets/world.terrain.ron")).unwrap();
+    settings.prefabs.clear();
+    settings
+}
+
+// Built the same as the height noise of create_vxm_from_noise
+fn height_node(settings: &TerrainSettings) -> GeneratorWrapper<SafeNode> {
+    let height_noise = &settings.height_noise;
+    opensimplex2()
+        .fbm(
+            height_noise.gain,
+            height_noise.weighted_strength,
+            height_noise.octaves,
+            height_noise.lacunarity,
+        )
+        .build()
+}
+
+// Built the same as the cave noise of create_vxm_from_noise
+fn cave_node() -> GeneratorWrapper<SafeNode> {
+    opensimplex2().fbm(0.5, 0.0, 2, 2.0).build()
+}
+
+// Samples of a chunk's density grid along each axis, covering the chunk and the voxels scanned
+// above it
+fn density_size(chunk_size: i32) -> [i32; 3] {
+    [
+        chunk_size / DENSITY_STEP + 1,
+        (chunk_size + SURFACE_LOOKAHEAD) / DENSITY_STEP + 1,
+        chunk_size / DENSITY_STEP + 1,
+    ]
+}
+
+fn sample_chunk_noise_on_grids(
+    height_node: &GeneratorWrapper<SafeNode>,
+    cave_node: &GeneratorWrapper<SafeNode>,
+    settings: &TerrainSettings,
+) -> f32 {
+    let chunk_size = settings.chunk_size;
+    let mut heights = vec![0.0; (chunk_size * chunk_size) as usize];
+    height_node.gen_uniform_grid_2d(
+        &mut heights,
+        0,
+        0,
+        chunk_size,
+        chunk_size,
+        1.0 / settings.scale_factor,
+        settings.seed,
+    );
+    let [x_size, y_size, z_size] = density_size(chunk_size);
+    let mut density = vec![0.0; (x_size * y_size * z_size) as usize];
+    cave_node.gen_uniform_grid_3d(
+        &mut density,
+        0,
+        0,
+        0,
+        x_size,
+        y_size,
+        z_size,
+        DENSITY_STEP as f32 / CAVE_SCALE,
+        settings.seed,
+    );
+    heights.iter().chain(&density).sum()
+}
+
+fn sample_chunk_noise_singly(
+    height_node: &GeneratorWrapper<SafeNode>,
+    cave_node: &GeneratorWrapper<SafeNode>,
+    settings: &TerrainSettings,
+) -> f32 {
+    let chunk_size = settings.chunk_size;
+    let mut total = 0.0;
+    for x in 0..chunk_size {
+        for z in 0..chunk_size {
+            total += height_node.gen_single_2d(
+                x as f32 / settings.scale_factor,
+                z as f32 / settings.scale_factor,
+                settings.seed,
+            );
+        }
+    }
+    let [x_size, y_size, z_size] = density_size(chunk_size);
+    for x in 0..x_size {
+        for y in 0..y_size {
+            for z in 0..z_size {
+                total += cave_node.gen_single_3d(
+                    (x * DENSITY_STEP) as f32 / CAVE_SCALE,
+                    (y * DENSITY_STEP) as f32 / CAVE_SCALE,
+                    (z * DENSITY_STEP) as f32 / CAVE_SCALE,
+                    settings.seed,
+                );
+            }
+        }
+    }
+    total
+}
+
+fn bench_chunk_noise(c: &mut Criterion) {
+    let settings = world_settings();
+    let height_node = height_node(&settings);
+    let cave_node = cave_node();
+
+    let mut group = c.benchmark_group("chunk noise");
+    group.bench_function("uniform grids", |b| {
+        b.iter(|| sample_chunk_noise_on_grids(&height_node, &cave_node, black_box(&settings)))
+    });
+    group.bench_function("single samples", |b| {
+        b.iter(|| sample_chunk_noise_singly(&height_node, &cave_node, black_box(&settings)))
+    });
+    group.finish();
+}
+
+fn bench_chunk_generation(c: &mut Criterion) {
+    let settings = world_settings();
+
+    let mut group = c.benchmark_group("chunk generation");
+    group.sample_size(10);
+    group.bench_function("one chunk", |b| {
+        b.iter(|| create_vxm_from_noise(0, 1, 0, black_box(&settings), &ErosionCache::default()))
+    });
+    // Eroded once for every chunk of the column
+    group.bench_function("column of chunks", |b| {
+        b.iter(|| {
+            let erosion_cache = ErosionCache::default();
+            (settings.min_chunk_y..=settings.max_chunk_y)
+                .filter_map(|y| {
+                    create_vxm_from_noise(0, y, 0, black_box(&settings), &erosion_cache)
+                })
+                .count()
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_chunk_noise, bench_chunk_generation);
+criterion_main!(benches);
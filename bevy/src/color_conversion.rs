@@ -1,4 +1,19 @@
 use crate::vxm::VxmVoxel;
+use serde::{Deserialize, Serialize};
+
+/// How the 16 bit colour of a voxel is packed, decoded to match in `shader.wgsl`.
+///
+/// A packed colour of 0 is reserved for air in every encoding.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ColourEncoding {
+    /// Solid bit, 6 bit hue, 3 bit saturation and 6 bit lightness
+    #[default]
+    Hsl = 0,
+    /// 5 bit red, 6 bit green and 5 bit blue
+    Rgb565 = 1,
+    /// Solid bit and an index into the palette of the model
+    Palette = 2,
+}
 
 pub fn convert_rgb_to_hsl(r: f32, g: f32, b: f32) -> (f32, f32, f32) {
     // Find max and min RGB values
@@ -72,6 +87,102 @@ pub fn create_hsl_voxel(r: f32, g: f32, b: f32) -> u16 {
     (1 << 15) | (h5 << 9) | (s5 << 6) | (l5)
 }
 
+// creates a 16 bit rgb565 colour, nudging black so it is not mistaken for air
+pub fn create_rgb565_voxel(r: f32, g: f32, b: f32) -> u16 {
+    let r5 = convert_8bit_to_n_bits((r * 255.0).round() as u8, 5);
+    let g6 = convert_8bit_to_n_bits((g * 255.0).round() as u8, 6);
+    let b5 = convert_8bit_to_n_bits((b * 255.0).round() as u8, 5);
+
+    match (r5 << 11) | (g6 << 5) | b5 {
+        0 => 1 << 5,
+        colour => colour,
+    }
+}
+
+// creates a palette index voxel, with the first bit being 1 to indicate solid voxel
+pub fn create_palette_voxel(index: u8) -> u16 {
+    (1 << 15) | index as u16
+}
+
+fn hue_to_rgb(p: f32, q: f32, t: f32) -> f32 {
+    let t = if t < 0.0 {
+        t + 1.0
+    } else if t > 1.0 {
+        t - 1.0
+    } else {
+        t
+    };
+    if t < 1.0 / 6.0 {
+        return p + (q - p) * 6.0 * t;
+    }
+    if t < 1.0 / 2.0 {
+        return q;
+    }
+    if t < 2.0 / 3.0 {
+        return p + (q - p) * (2.0 / 3.0 - t) * 6.0;
+    }
+    p
+}
+
+// Mirrors convert_hsl_to_rgb in shader.wgsl
+pub fn convert_hsl_to_rgb(h: f32, s: f32, l: f32) -> (f32, f32, f32) {
+    if s == 0.0 {
+        return (l, l, l);
+    }
+
+    let q = if l < 0.5 {
+        l * (1.0 + s)
+    } else {
+        l + s - l * s
+    };
+    let p = 2.0 * l - q;
+
+    (
+        hue_to_rgb(p, q, h + 1.0 / 3.0),
+        hue_to_rgb(p, q, h),
+        hue_to_rgb(p, q, h - 1.0 / 3.0),
+    )
+}
+
+/// Decodes a packed colour to rgb the same way the vertex shader does
+pub fn decode_voxel_colour(
+    colour: u16,
+    encoding: ColourEncoding,
+    palette: &[[u8; 3]],
+) -> (f32, f32, f32) {
+    match encoding {
+        ColourEncoding::Hsl => {
+            let h = ((colour & 0b0111_1110_0000_0000) >> 9) as f32 / 63.0;
+            let s = ((colour & 0b0000_0001_1100_0000) >> 6) as f32 / 7.0;
+            let l = (colour & 0b0000_0000_0011_1111) as f32 / 63.0;
+            convert_hsl_to_rgb(h, s, l)
+        }
+        ColourEncoding::Rgb565 => (
+            (colour >> 11) as f32 / 31.0,
+            ((colour >> 5) & 0b11_1111) as f32 / 63.0,
+            (colour & 0b1_1111) as f32 / 31.0,
+        ),
+        ColourEncoding::Palette => {
+            let [r, g, b] = palette
+                .get((colour & 0xFF) as usize)
+                .copied()
+                .unwrap_or_default();
+            (r as f32 / 255.0, g as f32 / 255.0, b as f32 / 255.0)
+        }
+    }
+}
+
+/// Packs a palette colour with the given encoding
+pub fn encode_palette_colour(index: u8, palette: &[[u8; 3]], encoding: ColourEncoding) -> u16 {
+    let [r, g, b] = palette[index as usize];
+    let (r, g, b) = (r as f32 / 255.0, g as f32 / 255.0, b as f32 / 255.0);
+    match encoding {
+        ColourEncoding::Hsl => create_hsl_voxel(r, g, b),
+        ColourEncoding::Rgb565 => create_rgb565_voxel(r, g, b),
+        ColourEncoding::Palette => create_palette_voxel(index),
+    }
+}
+
 pub fn get_hsl_voxel(voxel: &VxmVoxel) -> (u16, u16, u16) {
     let h = (voxel.hsl & 0b0111_1110_0000_0000) >> 9;
     let s = (voxel.hsl & 0b0000_0001_1100_0000) >> 6;
@@ -91,3 +202,126 @@ pub fn convert_n_bits_to_8bit(value: u16, n: u8) -> u8 {
     let scaled_value = ((value as u32 * 255) + (max_value / 2)) / max_value;
     scaled_value as u8
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every channel at 0, 51, .., 255, 216 colours in all
+    fn test_palette() -> Vec<[u8; 3]> {
+        let levels = [0, 51, 102, 153, 204, 255];
+        let mut palette = Vec::new();
+        for r in levels {
+            for g in levels {
+                for b in levels {
+                    palette.push([r, g, b]);
+                }
+            }
+        }
+        palette
+    }
+
+    // Distance between a source colour and a decoded one, in 8 bit rgb
+    fn colour_distance(
+        [r, g, b]: [u8; 3],
+        (decoded_r, decoded_g, decoded_b): (f32, f32, f32),
+    ) -> f32 {
+        ((decoded_r * 255.0 - r as f32).powi(2)
+            + (decoded_g * 255.0 - g as f32).powi(2)
+            + (decoded_b * 255.0 - b as f32).powi(2))
+        .sqrt()
+    }
+
+    // Mean and max round trip error of every colour of a palette
+    fn measure_colour_error(palette: &[[u8; 3]], encoding: ColourEncoding) -> (f32, f32) {
+        let mut mean = 0.0;
+        let mut max = 0.0f32;
+        for (index, &source) in palette.iter().enumerate() {
+            let colour = encode_palette_colour(index as u8, palette, encoding);
+            let distance = colour_distance(source, decode_voxel_colour(colour, encoding, palette));
+            mean += distance;
+            max = max.max(distance);
+        }
+        (mean / palette.len() as f32, max)
+    }
+
+    #[test]
+    fn colour_error_is_bounded_for_each_encoding() {
+        let palette = test_palette();
+        for (encoding, max_mean, max_max) in [
+            (ColourEncoding::Hsl, 8.0, 18.0),
+            (ColourEncoding::Rgb565, 4.0, 6.5),
+            (ColourEncoding::Palette, 0.0, 0.0),
+        ] {
+            let (mean, max) = measure_colour_error(&palette, encoding);
+            assert!(
+                mean <= max_mean && max <= max_max,
+                "{encoding:?} colour error: mean {mean:.2}, max {max:.2}"
+            );
+        }
+    }
+
+    #[test]
+    fn rgb565_round_trips_within_a_step() {
+        for [r, g, b] in test_palette() {
+            let colour = create_rgb565_voxel(r as f32 / 255.0, g as f32 / 255.0, b as f32 / 255.0);
+            let (decoded_r, decoded_g, decoded_b) =
+                decode_voxel_colour(colour, ColourEncoding::Rgb565, &[]);
+            // Half a 5 bit step, and black nudged up a 6 bit green step so it isn't air
+            assert!((decoded_r * 255.0 - r as f32).abs() <= 4.2);
+            assert!((decoded_g * 255.0 - g as f32).abs() <= 4.1);
+            assert!((decoded_b * 255.0 - b as f32).abs() <= 4.2);
+        }
+    }
+
+    #[test]
+    fn rgb565_black_is_not_air() {
+        assert_ne!(create_rgb565_voxel(0.0, 0.0, 0.0), 0);
+    }
+
+    #[test]
+    fn palette_voxels_keep_their_index() {
+        for index in 0..=255u8 {
+            let colour = create_palette_voxel(index);
+            assert_ne!(colour & 0x8000, 0);
+            assert_eq!(colour & 0xFF, index as u16);
+        }
+    }
+
+    #[test]
+    fn palette_voxels_decode_to_their_exact_colour() {
+        let palette = test_palette();
+        for (index, &[r, g, b]) in palette.iter().enumerate() {
+            let colour = create_palette_voxel(index as u8);
+            assert_eq!(
+                decode_voxel_colour(colour, ColourEncoding::Palette, &palette),
+                (r as f32 / 255.0, g as f32 / 255.0, b as f32 / 255.0)
+            );
+        }
+    }
+
+    #[test]
+    fn encoded_palette_colours_are_never_air() {
+        let palette = test_palette();
+        for encoding in [
+            ColourEncoding::Hsl,
+            ColourEncoding::Rgb565,
+            ColourEncoding::Palette,
+        ] {
+            for index in 0..palette.len() {
+                assert_ne!(encode_palette_colour(index as u8, &palette, encoding), 0);
+            }
+        }
+    }
+
+    #[test]
+    fn hsl_greys_round_trip_within_a_lightness_step() {
+        for level in [0u8, 51, 102, 153, 204, 255] {
+            let value = level as f32 / 255.0;
+            let colour = create_hsl_voxel(value, value, value);
+            let decoded = decode_voxel_colour(colour, ColourEncoding::Hsl, &[]);
+            // Half a 6 bit lightness step
+            assert!(colour_distance([level; 3], decoded) <= 2.1 * 3f32.sqrt());
+        }
+    }
+}
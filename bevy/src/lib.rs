@@ -0,0 +1,24 @@
+pub mod camera;
+pub mod color_conversion;
+pub mod dnd;
+pub mod keyboard_events;
+pub mod render;
+pub mod replace_body_part_meshes;
+pub mod set_animation_clip_keyboard;
+pub mod spawn_player;
+pub mod vxm;
+pub mod vxm_biome;
+pub mod vxm_building;
+pub mod vxm_erosion;
+pub mod vxm_light;
+pub mod vxm_lod;
+pub mod vxm_mesh;
+pub mod vxm_path;
+pub mod vxm_prefab;
+pub mod vxm_region;
+pub mod vxm_rng;
+pub mod vxm_surface_nets;
+pub mod vxm_terrain;
+pub mod vxm_terrain_generator;
+pub mod vxm_terrain_settings;
+pub mod vxm_vegetation;
@@ -1,21 +1,3 @@
-mod camera;
-mod color_conversion;
-mod dnd;
-mod keyboard_events;
-mod render;
-mod replace_body_part_meshes;
-mod set_animation_clip_keyboard;
-mod spawn_player;
-mod vxm;
-mod vxm_mesh;
-mod vxm_terrain;
-
-use crate::camera::{CameraTarget, ThirdPersonCameraPlugin};
-use crate::keyboard_events::{KeyboardEventsPlugin, KeyboardInput};
-use crate::render::main::VoxelRenderPlugin;
-use crate::vxm::{PendingVxm, VxmAsset, VxmAssetLoader};
-use crate::vxm_mesh::{create_mesh_on_vxm_import_system, MeshedVoxels};
-use crate::vxm_terrain::VoxelTerrainPlugin;
 use bevy::color::palettes::css::WHITE;
 use bevy::diagnostic::FrameCountPlugin;
 use bevy::ecs::error::info;
@@ -27,6 +9,19 @@ use bevy::render::primitives::Frustum;
 use bevy::render::view::{VisibilityPlugin, VisibilitySystems, VisibleEntities};
 use bevy::state::app::StatesPlugin;
 use bevy::time::TimePlugin;
+use soulflame::camera::{CameraTarget, ThirdPersonCameraPlugin};
+use soulflame::keyboard_events::{KeyboardEventsPlugin, KeyboardInput};
+use soulflame::render::main::VoxelRenderPlugin;
+use soulflame::vxm::{PendingVxm, VxmAsset, VxmAssetLoader};
+use soulflame::vxm_building::{spawn_building, BuildingSettings};
+use soulflame::vxm_light::VoxelLightStore;
+use soulflame::vxm_lod::{VoxelLod, VoxelLodPlugin};
+use soulflame::vxm_mesh::{
+    create_mesh_on_vxm_import_system, grow_gpu_meshes_system, remesh_edited_chunks_system,
+    MeshedVoxels, MeshingBackend, MeshingBudget, NeighbourRemeshQueue,
+};
+use soulflame::vxm_terrain::VoxelTerrainPlugin;
+use soulflame::vxm_vegetation::{generate_rock, generate_tree, RockSettings, TreeSettings};
 use winit::keyboard::{Key, NamedKey};
 
 fn exit_on_esc_system(keyboard_input: Res<ButtonInput<KeyCode>>, mut exit: EventWriter<AppExit>) {
@@ -159,18 +154,25 @@ fn main() {
             AssetPlugin::default(),
             VoxelRenderPlugin,
             VoxelTerrainPlugin,
+            VoxelLodPlugin,
             VisibilityPlugin,
             KeyboardEventsPlugin,
         ))
         .init_asset::<VxmAsset>()
         .init_asset_loader::<VxmAssetLoader>()
         .init_resource::<Assets<Mesh>>() // Used to allow frustum culling
+        .init_resource::<MeshingBackend>()
+        .init_resource::<MeshingBudget>()
+        .init_resource::<NeighbourRemeshQueue>()
+        .init_resource::<VoxelLightStore>()
         .add_systems(Startup, setup) // Add your setup function
         .add_systems(
             Update,
             (
                 log_fps_every_second,
                 create_mesh_on_vxm_import_system,
+                remesh_edited_chunks_system,
+                grow_gpu_meshes_system,
                 position_sun_to_camera,
                 squish_stretch_and_rotate_object_over_time,
                 no_clip_camera,
@@ -204,7 +206,11 @@ fn squish_stretch_and_rotate_object_over_time(
     }
 }
 
-fn setup(mut commands: Commands, asset_server: Res<AssetServer>) {
+fn setup(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    mut vxm_assets: ResMut<Assets<VxmAsset>>,
+) {
     // ambient light
     commands.insert_resource(AmbientLight {
         color: WHITE.into(),
@@ -242,6 +248,7 @@ fn setup(mut commands: Commands, asset_server: Res<AssetServer>) {
         Name::new("Street 0,0"),
         // CameraTarget(Vec3::new(50.0, 30.0, 50.0)),
         PendingVxm(asset_server.load("street-scene.vxm")),
+        VoxelLod::new(2),
         Transform::default().with_translation(Vec3::new(0.0, 160.0, 0.0)),
     ));
 
@@ -249,8 +256,53 @@ fn setup(mut commands: Commands, asset_server: Res<AssetServer>) {
         Name::new("Dragon 0,0"),
         SquishStretchAndRotateObjectOverTime { time: 20.0 },
         PendingVxm(asset_server.load("dragon.vxm")),
+        VoxelLod::new(2),
         Transform::default()
             .with_scale(Vec3::new(1.0, 10.0, 1.0))
             .with_translation(Vec3::new(128.0, 160.0, 0.0)),
     ));
+
+    for (index, tree) in [
+        TreeSettings::oak(1),
+        TreeSettings::oak(2),
+        TreeSettings::pine(3),
+        TreeSettings::bush(4),
+    ]
+    .iter()
+    .enumerate()
+    {
+        commands.spawn((
+            Name::new(format!("Tree {index}")),
+            PendingVxm(vxm_assets.add(generate_tree(tree))),
+            VoxelLod::new(2),
+            Transform::default().with_translation(Vec3::new(
+                -60.0 - index as f32 * 40.0,
+                160.0,
+                0.0,
+            )),
+        ));
+    }
+
+    for (index, building) in [BuildingSettings::cottage(6), BuildingSettings::tavern(7)]
+        .iter()
+        .enumerate()
+    {
+        spawn_building(
+            &mut commands,
+            &asset_server,
+            &mut vxm_assets,
+            building,
+            Transform::default().with_translation(Vec3::new(
+                -60.0 - index as f32 * 40.0,
+                160.0,
+                -60.0,
+            )),
+        );
+    }
+
+    commands.spawn((
+        Name::new("Rock 0"),
+        PendingVxm(vxm_assets.add(generate_rock(&RockSettings::boulder(5)))),
+        Transform::default().with_translation(Vec3::new(-60.0, 160.0, 40.0)),
+    ));
 }
@@ -0,0 +1,415 @@
+use crate::render::main::InstanceData;
+use crate::vxm_light::OPEN_SKY_LIGHT;
+use crate::vxm_mesh::{VoxelLayer, VoxelNeighbourhood};
+use bevy::prelude::Resource;
+use bytemuck::{Pod, Zeroable};
+use std::sync::{Arc, Mutex, OnceLock};
+use wgpu::util::DeviceExt;
+use wgpu::{BindGroupLayout, Buffer, ComputePipeline, Device, Queue};
+
+// Matches MAX_SLICES in mesher.wgsl, models are at most 255 voxels along an axis
+const MAX_SLICES: usize = 256;
+
+#[derive(Pod, Zeroable, Clone, Copy)]
+#[repr(C)]
+struct MesherParams {
+    size: [u32; 3],
+    layer: u32,
+    match_colours: u32,
+    instance_capacity: u32,
+    _padding: [u32; 2],
+}
+
+// What a model was meshed from, kept until its quads are known to fit so it can be meshed again
+struct MesherInputs {
+    voxel_buffer: Buffer,
+    params: MesherParams,
+}
+
+/// Face instances of a model meshed on the GPU, left in GPU buffers to be drawn from.
+pub struct GpuMeshedFaces {
+    /// Instances of every face direction back to back, in [`MeshedVoxelsFace`] order
+    ///
+    /// [`MeshedVoxelsFace`]: crate::vxm_mesh::MeshedVoxelsFace
+    pub instance_buffer: Buffer,
+    /// A draw per face direction, with the first vertex of the face in a model's 24 vertices
+    pub indirect_buffer: Buffer,
+    pub instance_capacity: u32,
+    /// Number of quads meshed, copied back once the GPU has counted them. It's more than the
+    /// instance buffer holds if it overflowed.
+    instance_total: Arc<OnceLock<u32>>,
+    inputs: Mutex<Option<MesherInputs>>,
+}
+
+impl GpuMeshedFaces {
+    /// Number of quads meshed, once the GPU has finished meshing and been polled since
+    pub fn instance_total(&self) -> Option<u32> {
+        self.instance_total.get().copied()
+    }
+}
+
+/// Greedy meshes voxel grids with compute shaders, producing the same instances as the CPU
+/// mesher in the same order.
+///
+/// A first pass finds the visible faces of every voxel with their colour and ambient occlusion.
+/// Every slice of each face direction is then merged into quads twice, once to count them so
+/// the instances can be laid out face by face, and again to write them out. Slices are merged
+/// by a workgroup each, with a thread per column.
+///
+/// Nothing waits on the GPU. Meshed models are drawn straight from their buffers, and
+/// [`GpuMesher::remesh_if_overflowed`] meshes them again once their quad count is back if it
+/// didn't fit.
+#[derive(Resource)]
+pub struct GpuMesher {
+    device: Arc<Device>,
+    queue: Queue,
+    bind_group_layout: BindGroupLayout,
+    find_faces_pipeline: ComputePipeline,
+    count_quads_pipeline: ComputePipeline,
+    allocate_instances_pipeline: ComputePipeline,
+    emit_quads_pipeline: ComputePipeline,
+    /// Face keys of the last meshed model, kept as they are needed for every model
+    face_key_buffer: Mutex<Option<Buffer>>,
+}
+
+impl GpuMesher {
+    pub fn new(device: Arc<Device>, queue: Queue) -> Self {
+        let storage_entry = |binding, read_only| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Storage { read_only },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Mesher Bind Group Layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                storage_entry(1, true),
+                storage_entry(2, false),
+                storage_entry(3, false),
+                storage_entry(4, false),
+                storage_entry(5, false),
+                storage_entry(6, false),
+            ],
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Mesher Pipeline Layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Mesher Shader"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("shaders/mesher.wgsl").into()),
+        });
+        let create_pipeline = |entry_point| {
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some(entry_point),
+                layout: Some(&pipeline_layout),
+                module: &shader,
+                entry_point: Some(entry_point),
+                compilation_options: Default::default(),
+                cache: None,
+            })
+        };
+
+        Self {
+            find_faces_pipeline: create_pipeline("find_faces"),
+            count_quads_pipeline: create_pipeline("count_quads"),
+            allocate_instances_pipeline: create_pipeline("allocate_instances"),
+            emit_quads_pipeline: create_pipeline("emit_quads"),
+            bind_group_layout,
+            face_key_buffer: Mutex::new(None),
+            device,
+            queue,
+        }
+    }
+
+    /// Meshes one layer of a model into GPU buffers, without waiting for the GPU.
+    ///
+    /// There's room for as many quads as the surface of the model's bounds has faces. If the
+    /// model has more the draws are left empty, until [`GpuMesher::remesh_if_overflowed`] meshes
+    /// it again with enough.
+    pub fn mesh(
+        &self,
+        neighbourhood: &VoxelNeighbourhood,
+        layer: VoxelLayer,
+        match_colours: bool,
+    ) -> GpuMeshedFaces {
+        let size = neighbourhood.centre.size.map(u32::from);
+
+        // The model padded by a voxel of its neighbours on every side, with the light faces in
+        // front of each voxel take
+        let mut voxels =
+            Vec::with_capacity(((size[0] + 2) * (size[1] + 2) * (size[2] + 2)) as usize);
+        for x in -1..=size[0] as i32 {
+            for y in -1..=size[1] as i32 {
+                for z in -1..=size[2] as i32 {
+                    let voxel = neighbourhood
+                        .voxel_at(x, y, z)
+                        .map_or(0, |voxel| voxel.hsl as u32 | (voxel.opacity as u32) << 16);
+                    let light = neighbourhood.light_at(x, y, z).unwrap_or(OPEN_SKY_LIGHT);
+                    voxels.push(voxel | (light as u32) << 24);
+                }
+            }
+        }
+        let voxel_buffer = self
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("Mesher Voxel Buffer"),
+                contents: bytemuck::cast_slice(&voxels),
+                usage: wgpu::BufferUsages::STORAGE,
+            });
+
+        let [x, y, z] = size;
+        let params = MesherParams {
+            size,
+            layer: match layer {
+                VoxelLayer::Opaque => 0,
+                VoxelLayer::Transparent => 1,
+            },
+            match_colours: match_colours as u32,
+            instance_capacity: 2 * (x * y + y * z + x * z),
+            _padding: [0; 2],
+        };
+        self.dispatch(voxel_buffer, params)
+    }
+
+    /// Meshes a model again with room for all of its quads if they didn't fit, once the GPU has
+    /// counted them.
+    ///
+    /// Returns `None` while the count isn't back yet or after the quads fitted. The model's
+    /// voxels are let go of once the count is back, so each model is only meshed again once.
+    pub fn remesh_if_overflowed(&self, meshed: &GpuMeshedFaces) -> Option<GpuMeshedFaces> {
+        let instance_total = meshed.instance_total()?;
+        let inputs = meshed.inputs.lock().unwrap().take()?;
+        (instance_total > meshed.instance_capacity).then(|| {
+            let params = MesherParams {
+                instance_capacity: instance_total,
+                ..inputs.params
+            };
+            self.dispatch(inputs.voxel_buffer, params)
+        })
+    }
+
+    /// Runs any callbacks for meshing the GPU has finished, without waiting for the rest
+    pub fn poll(&self) {
+        self.device
+            .poll(wgpu::PollType::Poll)
+            .expect("Error polling the mesher");
+    }
+
+    fn dispatch(&self, voxel_buffer: Buffer, params: MesherParams) -> GpuMeshedFaces {
+        let [size_x, size_y, size_z] = params.size;
+        let voxel_count = (size_x * size_y * size_z) as u64;
+
+        let params_buffer = self
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("Mesher Params Buffer"),
+                contents: bytemuck::bytes_of(&params),
+                usage: wgpu::BufferUsages::UNIFORM,
+            });
+        let slice_count_buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Mesher Slice Count Buffer"),
+            size: (6 * MAX_SLICES * size_of::<u32>()) as u64,
+            usage: wgpu::BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+        let instance_total_buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Meshed Instance Total Buffer"),
+            size: size_of::<u32>() as u64,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        let instance_total_staging_buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Meshed Instance Total Staging Buffer"),
+            size: size_of::<u32>() as u64,
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let instance_buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Meshed Instance Buffer"),
+            size: (params.instance_capacity.max(1) as usize * size_of::<InstanceData>()) as u64,
+            usage: wgpu::BufferUsages::STORAGE
+                | wgpu::BufferUsages::VERTEX
+                | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        let indirect_buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Meshed Indirect Draw Buffer"),
+            size: (6 * size_of::<wgpu::util::DrawIndirectArgs>()) as u64,
+            usage: wgpu::BufferUsages::STORAGE
+                | wgpu::BufferUsages::INDIRECT
+                | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+
+        // Face keys are only needed while meshing, so one buffer is shared by every model
+        let mut face_key_buffer = self.face_key_buffer.lock().unwrap();
+        let face_key_buffer_size = 6 * voxel_count * size_of::<[u32; 2]>() as u64;
+        if face_key_buffer
+            .as_ref()
+            .is_none_or(|buffer| buffer.size() < face_key_buffer_size)
+        {
+            *face_key_buffer = Some(self.device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("Mesher Face Key Buffer"),
+                size: face_key_buffer_size,
+                usage: wgpu::BufferUsages::STORAGE,
+                mapped_at_creation: false,
+            }));
+        }
+        let face_key_buffer = face_key_buffer.as_ref().unwrap();
+
+        let bind_group = self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Mesher Bind Group"),
+            layout: &self.bind_group_layout,
+            entries: &[
+                params_buffer.as_entire_binding(),
+                voxel_buffer.as_entire_binding(),
+                face_key_buffer.as_entire_binding(),
+                slice_count_buffer.as_entire_binding(),
+                indirect_buffer.as_entire_binding(),
+                instance_total_buffer.as_entire_binding(),
+                instance_buffer.as_entire_binding(),
+            ]
+            .into_iter()
+            .enumerate()
+            .map(|(binding, resource)| wgpu::BindGroupEntry {
+                binding: binding as u32,
+                resource,
+            })
+            .collect::<Vec<_>>(),
+        });
+
+        // A workgroup for every slice along the longest axis, those past the end of shorter
+        // axes return straight away
+        let slice_count = size_x.max(size_y).max(size_z);
+        let mut encoder = self.device.create_command_encoder(&Default::default());
+        let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("Mesher Compute Pass"),
+            timestamp_writes: None,
+        });
+        compute_pass.set_bind_group(0, &bind_group, &[]);
+        compute_pass.set_pipeline(&self.find_faces_pipeline);
+        compute_pass.dispatch_workgroups(
+            size_x.div_ceil(4),
+            size_y.div_ceil(4),
+            size_z.div_ceil(4),
+        );
+        compute_pass.set_pipeline(&self.count_quads_pipeline);
+        compute_pass.dispatch_workgroups(slice_count, 6, 1);
+        compute_pass.set_pipeline(&self.allocate_instances_pipeline);
+        compute_pass.dispatch_workgroups(1, 1, 1);
+        compute_pass.set_pipeline(&self.emit_quads_pipeline);
+        compute_pass.dispatch_workgroups(slice_count, 6, 1);
+        drop(compute_pass);
+        encoder.copy_buffer_to_buffer(
+            &instance_total_buffer,
+            0,
+            &instance_total_staging_buffer,
+            0,
+            size_of::<u32>() as u64,
+        );
+        self.queue.submit([encoder.finish()]);
+
+        // Picked up by whichever poll of the device finds the meshing finished
+        let instance_total = Arc::new(OnceLock::new());
+        let (total, staging_buffer) = (
+            instance_total.clone(),
+            instance_total_staging_buffer.clone(),
+        );
+        instance_total_staging_buffer.map_async(wgpu::MapMode::Read, .., move |result| {
+            result.expect("Error mapping mesher staging buffer");
+            let _ = total.set(bytemuck::pod_read_unaligned(
+                &staging_buffer.slice(..).get_mapped_range(),
+            ));
+            staging_buffer.unmap();
+        });
+
+        GpuMeshedFaces {
+            instance_buffer,
+            indirect_buffer,
+            instance_capacity: params.instance_capacity,
+            instance_total,
+            inputs: Mutex::new(Some(MesherInputs {
+                voxel_buffer,
+                params,
+            })),
+        }
+    }
+
+    /// Meshes one layer of a model and waits for the instances of each face direction, in
+    /// [`MeshedVoxelsFace`] order. Blocks until the GPU is done, so it's kept out of the frame
+    /// and used to check the GPU mesher against the CPU one.
+    ///
+    /// [`MeshedVoxelsFace`]: crate::vxm_mesh::MeshedVoxelsFace
+    pub fn mesh_and_read_back(
+        &self,
+        neighbourhood: &VoxelNeighbourhood,
+        layer: VoxelLayer,
+        match_colours: bool,
+    ) -> [Vec<InstanceData>; 6] {
+        let mut meshed = self.mesh(neighbourhood, layer, match_colours);
+        self.wait();
+        while let Some(remeshed) = self.remesh_if_overflowed(&meshed) {
+            meshed = remeshed;
+            self.wait();
+        }
+
+        let instance_total = meshed.instance_total().unwrap();
+        let draws = self.read_buffer::<wgpu::util::DrawIndirectArgs>(&meshed.indirect_buffer, 6);
+        let instances =
+            self.read_buffer::<InstanceData>(&meshed.instance_buffer, instance_total as usize);
+        std::array::from_fn(|face| {
+            let first = draws[face].first_instance as usize;
+            instances[first..first + draws[face].instance_count as usize].to_vec()
+        })
+    }
+
+    fn wait(&self) {
+        self.device
+            .poll(wgpu::PollType::Wait)
+            .expect("Error waiting for the mesher");
+    }
+
+    // Copies the start of a buffer to the CPU, blocking until the GPU has written it
+    fn read_buffer<T: Pod>(&self, buffer: &Buffer, count: usize) -> Vec<T> {
+        let size = (count * size_of::<T>()) as u64;
+        if size == 0 {
+            return Vec::new();
+        }
+        let staging_buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Mesher Staging Buffer"),
+            size,
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let mut encoder = self.device.create_command_encoder(&Default::default());
+        encoder.copy_buffer_to_buffer(buffer, 0, &staging_buffer, 0, size);
+        self.queue.submit([encoder.finish()]);
+
+        let slice = staging_buffer.slice(..);
+        slice.map_async(wgpu::MapMode::Read, |result| {
+            result.expect("Error mapping mesher staging buffer")
+        });
+        self.wait();
+        // Mapped ranges are aligned to at least 8 bytes, enough for anything read back here
+        let values = bytemuck::cast_slice(&slice.get_mapped_range()).to_vec();
+        staging_buffer.unmap();
+        values
+    }
+}
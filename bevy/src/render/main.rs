@@ -1,7 +1,11 @@
+use crate::color_conversion::ColourEncoding;
 use crate::keyboard_events::{KeyPressedEvent, KeyReleasedEvent};
+use crate::render::compute_mesher::{GpuMeshedFaces, GpuMesher};
 use crate::render::passes::main::MainRenderPass;
 use crate::render::passes::shadow::{ShadowRenderPass, SHADOW_BIND_GROUP_LAYOUT_DESCRIPTOR};
+use crate::render::passes::smooth::SmoothRenderPass;
 use crate::render::passes::tonemap_resolve::TonemapResolvePass;
+use crate::render::passes::transparent::TransparentRenderPass;
 use crate::render::util::get_view_projection_matrix;
 use crate::vxm_mesh::MeshedVoxelsFace;
 use bevy::app::PluginsState;
@@ -9,6 +13,7 @@ use bevy::ecs::schedule::MainThreadExecutor;
 use bevy::math::primitives::Cuboid;
 use bevy::prelude::*;
 use bevy::render::camera::CameraProjection;
+use bevy::render::primitives::Aabb;
 use bytemuck::{Pod, Zeroable};
 use std::mem::size_of;
 use std::sync::mpsc::{Receiver, Sender};
@@ -40,6 +45,9 @@ pub struct InstanceData {
     pub(crate) hsl: u16,
     pub(crate) ambient_occlusion: u8,
     pub(crate) height: u8,
+    /// Block light in the low 4 bits and sky light in the high 4, of the voxel in front of the face
+    pub(crate) light: u8,
+    pub(crate) _padding: [u8; 3],
 }
 
 #[derive(Pod, Zeroable, Clone, Copy)]
@@ -52,6 +60,54 @@ struct WireframeVertex {
 #[derive(Component, Deref, Clone)]
 pub struct InstanceMaterialData(pub Arc<Vec<InstanceData>>);
 
+/// Instances of transparent voxels, drawn after the opaque pass with their opacity in place of
+/// ambient occlusion
+#[derive(Component, Deref, Clone)]
+pub struct TransparentInstanceData(pub Arc<Vec<InstanceData>>);
+
+/// Layers of a model meshed on the GPU, drawn straight from the mesher's buffers in place of its
+/// faces' instance data
+#[derive(Component, Clone)]
+pub struct GpuMeshedVoxels {
+    /// `None` for models whose opaque voxels are meshed into a smooth surface instead
+    pub opaque: Option<Arc<GpuMeshedFaces>>,
+    pub transparent: Arc<GpuMeshedFaces>,
+}
+
+/// Vertex of a smooth voxel surface, in model space
+#[derive(Pod, Zeroable, Clone, Copy)]
+#[repr(C)]
+pub struct SmoothVertex {
+    pub(crate) position: [f32; 3],
+    pub(crate) normal: [f32; 3],
+    /// rgba8
+    pub(crate) colour: u32,
+}
+
+/// Indexed triangles of a smooth voxel surface, drawn by the smooth pass next to the voxel faces
+#[derive(Component, Clone)]
+pub struct SmoothMeshData {
+    pub vertices: Arc<Vec<SmoothVertex>>,
+    pub indices: Arc<Vec<u32>>,
+}
+
+/// How the colours of a voxel model's instances are decoded on the GPU
+#[derive(Component, Clone)]
+pub struct VoxelColourData {
+    pub encoding: ColourEncoding,
+    pub palette: Arc<Vec<[u8; 3]>>,
+    /// Colours and light looked up per fragment by models whose quads merge across them
+    pub volume: Option<ColourVolume>,
+}
+
+/// 16 bit colour of every opaque voxel of a model and of the voxels around it, with the light at
+/// each voxel in the next 8 bits. `size` is the model's, without the voxel of padding either side.
+#[derive(Clone)]
+pub struct ColourVolume {
+    pub size: [u8; 3],
+    pub colours: Arc<Vec<u32>>,
+}
+
 #[derive(Debug)]
 struct WindowCreationData {
     surface: Surface<'static>,
@@ -72,6 +128,8 @@ struct RenderApp {
     pub(crate) wireframe_pipeline: RenderPipeline,
     pub(crate) main_pass: MainRenderPass,
     pub(crate) shadow_pass: ShadowRenderPass,
+    pub(crate) transparent_pass: TransparentRenderPass,
+    pub(crate) smooth_pass: SmoothRenderPass,
     pub(crate) tonemap_resolve_pass: TonemapResolvePass,
     pub(crate) window_creation_receiver: Receiver<WindowCreationData>,
     pub(crate) window_resize_receiver: Receiver<(u32, u32)>,
@@ -147,6 +205,22 @@ pub struct DrawBuffers<'a> {
     pub uniform_buffer: &'a Buffer,
     pub vertex_buffer: &'a Buffer,
     pub lights_uniform_buffer: &'a Buffer,
+    pub gpu_meshed_models: &'a [(u32, Arc<GpuMeshedFaces>)],
+}
+
+/// Draws models meshed on the GPU from their own buffers, one draw per face direction. The vertex
+/// buffer is offset to each model's 24 vertices, as the GPU mesher's draws start at its face.
+pub fn draw_gpu_meshed_models(
+    renderpass: &mut wgpu::RenderPass,
+    vertex_buffer: &Buffer,
+    models: &[(u32, Arc<GpuMeshedFaces>)],
+) {
+    for (model_index, faces) in models {
+        let model_vertices = (*model_index as usize * 24 * size_of::<u32>()) as u64;
+        renderpass.set_vertex_buffer(1, vertex_buffer.slice(model_vertices..));
+        renderpass.set_vertex_buffer(0, faces.instance_buffer.slice(..));
+        renderpass.multi_draw_indirect(&faces.indirect_buffer, 0, 6);
+    }
 }
 
 impl RenderApp {
@@ -282,6 +356,8 @@ impl RenderApp {
         let main_pass = MainRenderPass::new(&device, &shadow_bind_group_layout, initial_size);
         let bind_group_layout = MainRenderPass::get_bind_group_layout(&device);
         let render_pipeline = MainRenderPass::get_pipeline(&device, &shadow_bind_group_layout);
+        let transparent_pass = TransparentRenderPass::new(&device, &shadow_bind_group_layout);
+        let smooth_pass = SmoothRenderPass::new(&device);
 
         let debug_quad_bind_group_layout =
             device.create_bind_group_layout(DEBUG_DEPTH_BIND_GROUP_LAYOUT_DESCRIPTOR);
@@ -335,6 +411,8 @@ impl RenderApp {
             wireframe_pipeline,
             main_pass,
             shadow_pass,
+            transparent_pass,
+            smooth_pass,
             window_creation_receiver,
             window_resize_receiver,
             tonemap_resolve_pass,
@@ -495,6 +573,7 @@ impl RenderApp {
         view_proj: Mat4,
         shadow_view: Mat4,
         voxel_planes: VoxelPlanesData,
+        smooth_meshes: SmoothMeshesData,
         camera_position: Vec3,
         lights_data: LightsData,
         surface_texture: SurfaceTexture,
@@ -508,10 +587,21 @@ impl RenderApp {
         let surface_texture_view = self.get_texture_view(&surface_texture);
         let draw_count = voxel_planes.len() as u32;
 
+        // Transparent draws are sorted before the main pass takes ownership of the voxel planes
+        self.transparent_pass.prepare_buffers(
+            &self.device,
+            &self.queue,
+            &voxel_planes,
+            camera_position,
+        );
+
         // Prepare buffers for the main pass
         self.main_pass
             .prepare_buffers(&self.device, &self.queue, voxel_planes);
 
+        self.smooth_pass
+            .prepare_buffers(&self.device, &self.queue, smooth_meshes);
+
         let uniform_buffer = &self.main_pass.uniform_buffer;
         let vertex_buffer = &self.main_pass.vertex_buffer;
         let instance_buffer = &self.main_pass.instance_buffer;
@@ -519,6 +609,7 @@ impl RenderApp {
         let indirect_buffer = &self.main_pass.indirect_buffer;
         let lights_uniform_buffer = &self.main_pass.lights_uniform_buffer;
         let mvp_buffer = &self.main_pass.mvp_buffer;
+        let gpu_meshed_models = &self.main_pass.gpu_meshed_models;
 
         // Shadow
         let draw_buffers = DrawBuffers {
@@ -528,6 +619,7 @@ impl RenderApp {
             indirect_buffer,
             mvp_buffer,
             lights_uniform_buffer,
+            gpu_meshed_models,
         };
 
         self.shadow_pass.enqueue(
@@ -551,6 +643,22 @@ impl RenderApp {
             view_proj,
         );
 
+        // Smooth surfaces are opaque, so they go in before anything is blended over them
+        self.smooth_pass.enqueue(
+            &self.device,
+            &self.queue,
+            &self.main_pass_texture_view,
+            &self.main_pass,
+        );
+
+        self.transparent_pass.enqueue(
+            &self.device,
+            &self.queue,
+            &self.main_pass_texture_view,
+            &self.main_pass,
+            &self.shadow_pass.shadow_bind_group,
+        );
+
         self.tonemap_resolve_pass.enqueue(
             &self.device,
             &self.queue,
@@ -710,24 +818,65 @@ impl ApplicationHandler for VoxelExtractApp {
 
                         let camera_position = global_transform.translation();
 
-                        // Get each voxel entity, cloning to avoid borrowing issues
-                        let voxel_entities = world
+                        // Get each visible voxel entity, cloning to avoid borrowing issues. Hidden
+                        // models hide all six of their faces, so faces stay grouped by model.
+                        let mut voxel_query = world.query::<(
+                            &MeshedVoxelsFace,
+                            &InstanceMaterialData,
+                            &GlobalTransform,
+                            &ViewVisibility,
+                            &VoxelColourData,
+                            &TransparentInstanceData,
+                            &InheritedVisibility,
+                            &ChildOf,
+                        )>();
+                        let voxel_entities = voxel_query
+                            .iter(world)
+                            .filter(|(.., inherited_visibility, _)| inherited_visibility.get())
+                            .map(
+                                |(
+                                    face,
+                                    instance_data,
+                                    transform,
+                                    visibility,
+                                    colours,
+                                    transparent_data,
+                                    _,
+                                    child_of,
+                                )| {
+                                    // Faces are children of their model, which holds its bounds
+                                    let bounds = world
+                                        .get::<Aabb>(child_of.parent())
+                                        .copied()
+                                        .unwrap_or_default();
+                                    let gpu_meshed =
+                                        world.get::<GpuMeshedVoxels>(child_of.parent()).cloned();
+                                    let cloned_components = (
+                                        face.clone(),
+                                        instance_data.clone(),
+                                        transform.clone(),
+                                        visibility.clone(),
+                                        colours.clone(),
+                                        transparent_data.clone(),
+                                        bounds,
+                                        gpu_meshed,
+                                    );
+                                    cloned_components
+                                },
+                            )
+                            .collect::<Vec<_>>();
+
+                        let smooth_meshes = world
                             .query::<(
-                                &MeshedVoxelsFace,
-                                &InstanceMaterialData,
+                                &SmoothMeshData,
                                 &GlobalTransform,
-                                &ViewVisibility,
+                                Option<&InheritedVisibility>,
                             )>()
-                            .iter_mut(world)
-                            .map(|(face, instance_data, transform, visibility)| {
-                                let cloned_components = (
-                                    face.clone(),
-                                    instance_data.clone(),
-                                    transform.clone(),
-                                    visibility.clone(),
-                                );
-                                cloned_components
+                            .iter(world)
+                            .filter(|(_, _, inherited_visibility)| {
+                                inherited_visibility.is_none_or(|visibility| visibility.get())
                             })
+                            .map(|(mesh, transform, _)| (mesh.clone(), transform.clone()))
                             .collect::<Vec<_>>();
 
                         // Get directional light data (sun)
@@ -751,6 +900,7 @@ impl ApplicationHandler for VoxelExtractApp {
                             .send((
                                 view_proj,
                                 voxel_entities,
+                                smooth_meshes,
                                 sun_data,
                                 camera_position,
                                 lights_data,
@@ -832,13 +982,26 @@ pub type VoxelPlanesData = Vec<(
     InstanceMaterialData,
     GlobalTransform,
     ViewVisibility,
+    VoxelColourData,
+    TransparentInstanceData,
+    Aabb,
+    Option<GpuMeshedVoxels>,
 )>;
 
+pub type SmoothMeshesData = Vec<(SmoothMeshData, GlobalTransform)>;
+
 pub type SunData = (GlobalTransform, DirectionalLight);
 
 pub type LightsData = Vec<(GlobalTransform, PointLight)>;
 
-pub type WorldMessage = (Mat4, VoxelPlanesData, SunData, Vec3, LightsData);
+pub type WorldMessage = (
+    Mat4,
+    VoxelPlanesData,
+    SmoothMeshesData,
+    SunData,
+    Vec3,
+    LightsData,
+);
 
 // TODO: add messaging for window creation and resize
 impl Plugin for VoxelRenderPlugin {
@@ -865,11 +1028,18 @@ impl Plugin for VoxelRenderPlugin {
             required_features: wgpu::Features::INDIRECT_FIRST_INSTANCE
                 | wgpu::Features::MULTI_DRAW_INDIRECT
                 | wgpu::Features::ADDRESS_MODE_CLAMP_TO_BORDER,
+            // Colour volumes of loaded terrain can outgrow the default storage buffer limit
+            required_limits: wgpu::Limits {
+                max_storage_buffer_binding_size: adapter.limits().max_storage_buffer_binding_size,
+                max_buffer_size: adapter.limits().max_buffer_size,
+                ..default()
+            },
             ..default()
         }))
         .unwrap();
 
         let device_arc = Arc::new(device);
+        app.insert_resource(GpuMesher::new(device_arc.clone(), queue.clone()));
         let runner_device_arc = device_arc.clone();
         let render_instance = instance.clone();
         let render_device_arc = device_arc.clone();
@@ -906,8 +1076,14 @@ impl Plugin for VoxelRenderPlugin {
                     loop {
                         match world_message_receiver.recv() {
                             Ok(world_messsage) => {
-                                let (view_proj, voxel_planes, sun_data, camera_position, lights) =
-                                    world_messsage;
+                                let (
+                                    view_proj,
+                                    voxel_planes,
+                                    smooth_meshes,
+                                    sun_data,
+                                    camera_position,
+                                    lights,
+                                ) = world_messsage;
                                 let (shadow_transform, _) = sun_data;
                                 let shadow_view = shadow_transform.compute_matrix().inverse();
 
@@ -918,6 +1094,7 @@ impl Plugin for VoxelRenderPlugin {
                                             view_proj,
                                             shadow_view,
                                             voxel_planes,
+                                            smooth_meshes,
                                             camera_position,
                                             lights,
                                             surface_texture,
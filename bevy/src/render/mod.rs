@@ -1,3 +1,4 @@
-pub(crate) mod main;
+pub(crate) mod compute_mesher;
+pub mod main;
 mod util;
 mod passes;
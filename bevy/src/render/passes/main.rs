@@ -1,8 +1,13 @@
-use crate::render::main::{InstanceData, LightsData, Uniforms, VoxelPlanesData, SURFACE_FORMAT};
+use crate::render::compute_mesher::GpuMeshedFaces;
+use crate::render::main::{
+    draw_gpu_meshed_models, ColourVolume, InstanceData, LightsData, Uniforms, VoxelPlanesData,
+    SURFACE_FORMAT,
+};
 use bevy::math::Vec3;
 use bevy::pbr::PointLight;
 use bevy::prelude::*;
 use bytemuck::{Pod, Zeroable};
+use std::sync::Arc;
 use wgpu::{
     BindGroup, BindGroupLayout, Device, Face, Queue, RenderPipeline, TextureFormat, TextureView,
     VertexAttribute, VertexStepMode,
@@ -55,6 +60,14 @@ pub(crate) struct MainRenderPass {
     pub(crate) mvp_buffer: wgpu::Buffer,
     pub(crate) uniform_buffer: wgpu::Buffer,
     pub(crate) lights_uniform_buffer: wgpu::Buffer,
+    pub(crate) model_colour_buffer: wgpu::Buffer,
+    pub(crate) palette_buffer: wgpu::Buffer,
+    pub(crate) colour_volume_buffer: wgpu::Buffer,
+    /// Colour volumes in the colour volume buffer and their offsets in it, in u32s
+    uploaded_colour_volumes: Vec<(Arc<Vec<u32>>, u32)>,
+    /// Opaque faces of models meshed on the GPU and the index of each model, drawn from their
+    /// own buffers after everything else
+    pub(crate) gpu_meshed_models: Vec<(u32, Arc<GpuMeshedFaces>)>,
     pub(crate) vertex_buffer: wgpu::Buffer,
     pub(crate) bind_group: wgpu::BindGroup,
     pub(crate) depth_texture_view: wgpu::TextureView,
@@ -102,6 +115,30 @@ impl MainRenderPass {
             mapped_at_creation: false,
         });
 
+        // Colour encoding, palette offset, colour volume offset and size for each model
+        let model_colour_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Model Colour Buffer"),
+            size: size_of::<[u32; 4]>() as u64,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        // Palettes of every model packed as rgba8
+        let palette_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Palette Buffer"),
+            size: size_of::<u32>() as u64,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        // Colours of every voxel of the models meshed across colours, two per u32
+        let colour_volume_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Colour Volume Buffer"),
+            size: size_of::<u32>() as u64,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
         Self {
             render_pipeline: Self::get_pipeline(device, shadow_bind_group_layout),
             bind_group: device.create_bind_group(&wgpu::BindGroupDescriptor {
@@ -120,6 +157,18 @@ impl MainRenderPass {
                         binding: 2,
                         resource: lights_uniform_buffer.as_entire_binding(),
                     },
+                    wgpu::BindGroupEntry {
+                        binding: 3,
+                        resource: model_colour_buffer.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 4,
+                        resource: palette_buffer.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 5,
+                        resource: colour_volume_buffer.as_entire_binding(),
+                    },
                 ],
             }),
             instance_buffer: device.create_buffer(&wgpu::BufferDescriptor {
@@ -137,6 +186,11 @@ impl MainRenderPass {
             mvp_buffer,
             uniform_buffer,
             lights_uniform_buffer,
+            model_colour_buffer,
+            palette_buffer,
+            colour_volume_buffer,
+            uploaded_colour_volumes: Vec::new(),
+            gpu_meshed_models: Vec::new(),
             depth_texture_view: depth_texture.create_view(&wgpu::TextureViewDescriptor {
                 label: Some("Depth Texture View"),
                 format: Some(TextureFormat::Depth24Plus),
@@ -188,6 +242,36 @@ impl MainRenderPass {
                     },
                     count: None,
                 },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 4,
+                    visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 5,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
             ],
         })
     }
@@ -209,6 +293,18 @@ impl MainRenderPass {
                     binding: 2,
                     resource: self.lights_uniform_buffer.as_entire_binding(),
                 },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: self.model_colour_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: self.palette_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 5,
+                    resource: self.colour_volume_buffer.as_entire_binding(),
+                },
             ],
         });
 
@@ -254,6 +350,11 @@ impl MainRenderPass {
                                 offset: wgpu::VertexFormat::Uint32.size(),
                                 shader_location: 1,
                             },
+                            VertexAttribute {
+                                format: wgpu::VertexFormat::Uint32,
+                                offset: 2 * wgpu::VertexFormat::Uint32.size(),
+                                shader_location: 3,
+                            },
                         ],
                     },
                     wgpu::VertexBufferLayout {
@@ -312,6 +413,8 @@ impl MainRenderPass {
         let mut all_indirect_data: Vec<wgpu::util::DrawIndirectArgs> =
             Vec::with_capacity(total_instances as usize);
         let mut all_instance_data: Vec<InstanceData> = Vec::new();
+        let mut all_model_colour_data: Vec<[u32; 4]> = Vec::with_capacity(voxel_object_count);
+        let mut all_palette_data: Vec<u32> = Vec::new();
 
         // Pre-allocate memory based on input size
         let face_count = voxel_planes.len();
@@ -325,22 +428,53 @@ impl MainRenderPass {
         // Estimate total instance count to avoid reallocations
         let est_total_instances = voxel_planes
             .iter()
-            .map(|(_, data, _, _)| data.len())
+            .map(|(_, data, ..)| data.len())
             .sum::<usize>();
         all_instance_data.reserve(est_total_instances);
 
+        let colour_volumes = voxel_planes
+            .iter()
+            .step_by(6)
+            .map(|(_, _, _, _, colours, ..)| colours.volume.as_ref())
+            .collect::<Vec<_>>();
+        let colour_volume_offsets = self.upload_colour_volumes(device, queue, &colour_volumes);
+
+        self.gpu_meshed_models = voxel_planes
+            .iter()
+            .step_by(6)
+            .enumerate()
+            .filter_map(|(model_index, (.., gpu_meshed))| {
+                let opaque = gpu_meshed.as_ref()?.opaque.clone()?;
+                Some((model_index as u32, opaque))
+            })
+            .collect();
+
         // Populate MVP matrices and vertex data for each voxel entity, and count total instances
         let mut first_vertex = 0;
 
         let populate_buffers_span = info_span!("Populate buffers").entered();
 
         {
-            for (index, (face, instance_data, transform, _)) in voxel_planes.into_iter().enumerate()
+            for (index, (face, instance_data, transform, _, colours, ..)) in
+                voxel_planes.into_iter().enumerate()
             {
                 // Each voxel entity has 6 faces, so we store one transform for each 6
                 if (index % 6) == 0 {
                     let mvp_index = index / 6;
                     all_mvp_data.push(transform.compute_matrix());
+                    let volume_size = colours.volume.as_ref().map_or(0, |volume| {
+                        let [x, y, z] = volume.size.map(u32::from);
+                        x | y << 8 | z << 16
+                    });
+                    all_model_colour_data.push([
+                        colours.encoding as u32,
+                        all_palette_data.len() as u32,
+                        colour_volume_offsets[mvp_index],
+                        volume_size,
+                    ]);
+                    all_palette_data.extend(colours.palette.iter().map(|&[r, g, b]| {
+                        r as u32 | (g as u32) << 8 | (b as u32) << 16 | 0xFF << 24
+                    }));
                     for _ in 0..24 {
                         all_vertex_data.push(mvp_index as u32);
                     }
@@ -418,58 +552,43 @@ impl MainRenderPass {
                 });
             }
             queue.write_buffer(&self.mvp_buffer, 0, bytemuck::cast_slice(&all_mvp_data));
-            self.bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-                label: Some("Bind Group"),
-                layout: &device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                    label: Some("Bind Group Layout"),
-                    entries: &[
-                        wgpu::BindGroupLayoutEntry {
-                            binding: 0,
-                            visibility: wgpu::ShaderStages::VERTEX,
-                            ty: wgpu::BindingType::Buffer {
-                                ty: wgpu::BufferBindingType::Storage { read_only: true },
-                                has_dynamic_offset: false,
-                                min_binding_size: None,
-                            },
-                            count: None,
-                        },
-                        wgpu::BindGroupLayoutEntry {
-                            binding: 1,
-                            visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
-                            ty: wgpu::BindingType::Buffer {
-                                ty: wgpu::BufferBindingType::Uniform,
-                                has_dynamic_offset: false,
-                                min_binding_size: None,
-                            },
-                            count: None,
-                        },
-                        wgpu::BindGroupLayoutEntry {
-                            binding: 2,
-                            visibility: wgpu::ShaderStages::FRAGMENT,
-                            ty: wgpu::BindingType::Buffer {
-                                ty: wgpu::BufferBindingType::Uniform,
-                                has_dynamic_offset: false,
-                                min_binding_size: None,
-                            },
-                            count: None,
-                        },
-                    ],
-                }),
-                entries: &[
-                    wgpu::BindGroupEntry {
-                        binding: 0,
-                        resource: self.mvp_buffer.as_entire_binding(),
-                    },
-                    wgpu::BindGroupEntry {
-                        binding: 1,
-                        resource: self.uniform_buffer.as_entire_binding(),
-                    },
-                    wgpu::BindGroupEntry {
-                        binding: 2,
-                        resource: self.lights_uniform_buffer.as_entire_binding(),
-                    },
-                ],
-            });
+        }
+
+        // Write colour encodings and palettes for each model to the GPU buffers
+        {
+            let model_colour_buffer_size = (all_model_colour_data.len() * size_of::<[u32; 4]>())
+                .max(size_of::<[u32; 4]>()) as u64;
+            if self.model_colour_buffer.size() < model_colour_buffer_size {
+                self.model_colour_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+                    label: Some("Model Colour Buffer"),
+                    size: model_colour_buffer_size,
+                    usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+                    mapped_at_creation: false,
+                });
+            }
+            queue.write_buffer(
+                &self.model_colour_buffer,
+                0,
+                bytemuck::cast_slice(&all_model_colour_data),
+            );
+
+            let palette_buffer_size =
+                (all_palette_data.len() * size_of::<u32>()).max(size_of::<u32>()) as u64;
+            if self.palette_buffer.size() < palette_buffer_size {
+                self.palette_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+                    label: Some("Palette Buffer"),
+                    size: palette_buffer_size,
+                    usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+                    mapped_at_creation: false,
+                });
+            }
+            queue.write_buffer(
+                &self.palette_buffer,
+                0,
+                bytemuck::cast_slice(&all_palette_data),
+            );
+
+            self.bind_group = self.get_bind_group(device);
         }
 
         // Collect all instance data from the children of each voxel entity
@@ -500,6 +619,102 @@ impl MainRenderPass {
         gpu_upload_span.exit();
     }
 
+    /// Returns the offset of each model's colour volume in the colour volume buffer, or u32::MAX
+    /// for models without one.
+    ///
+    /// Volumes stay in the buffer while their model is drawn, so only newly meshed models are
+    /// uploaded. Volumes no longer drawn leave gaps that are reclaimed when the buffer fills up
+    /// and everything is packed into a larger one.
+    fn upload_colour_volumes(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        colour_volumes: &[Option<&ColourVolume>],
+    ) -> Vec<u32> {
+        let is_drawn = |colours: &Arc<Vec<u32>>| {
+            colour_volumes
+                .iter()
+                .flatten()
+                .any(|volume| Arc::ptr_eq(&volume.colours, colours))
+        };
+        self.uploaded_colour_volumes
+            .retain(|(colours, _)| is_drawn(colours));
+
+        let mut new_volumes: Vec<&Arc<Vec<u32>>> = Vec::new();
+        for volume in colour_volumes.iter().flatten() {
+            let is_uploaded = self
+                .uploaded_colour_volumes
+                .iter()
+                .any(|(colours, _)| Arc::ptr_eq(colours, &volume.colours))
+                || new_volumes
+                    .iter()
+                    .any(|colours| Arc::ptr_eq(colours, &volume.colours));
+            if !is_uploaded {
+                new_volumes.push(&volume.colours);
+            }
+        }
+
+        let mut end = self
+            .uploaded_colour_volumes
+            .iter()
+            .map(|(colours, offset)| offset + colours.len() as u32)
+            .max()
+            .unwrap_or(0);
+        let new_len: u32 = new_volumes.iter().map(|colours| colours.len() as u32).sum();
+        if ((end + new_len) as usize * size_of::<u32>()) as u64 > self.colour_volume_buffer.size() {
+            let kept_len: u32 = self
+                .uploaded_colour_volumes
+                .iter()
+                .map(|(colours, _)| colours.len() as u32)
+                .sum();
+            let buffer_size = ((kept_len + new_len) as usize * size_of::<u32>() * 2) as u64;
+            info!(
+                "Resizing colour volume buffer from {} to {}",
+                self.colour_volume_buffer.size(),
+                buffer_size
+            );
+            self.colour_volume_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("Colour Volume Buffer"),
+                size: buffer_size,
+                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            });
+            end = 0;
+            for (colours, offset) in &mut self.uploaded_colour_volumes {
+                *offset = end;
+                queue.write_buffer(
+                    &self.colour_volume_buffer,
+                    (end as usize * size_of::<u32>()) as u64,
+                    bytemuck::cast_slice(colours),
+                );
+                end += colours.len() as u32;
+            }
+        }
+
+        for colours in new_volumes {
+            queue.write_buffer(
+                &self.colour_volume_buffer,
+                (end as usize * size_of::<u32>()) as u64,
+                bytemuck::cast_slice(colours),
+            );
+            self.uploaded_colour_volumes.push((colours.clone(), end));
+            end += colours.len() as u32;
+        }
+
+        colour_volumes
+            .iter()
+            .map(|volume| {
+                volume
+                    .and_then(|volume| {
+                        self.uploaded_colour_volumes
+                            .iter()
+                            .find(|(colours, _)| Arc::ptr_eq(colours, &volume.colours))
+                    })
+                    .map_or(u32::MAX, |(_, offset)| *offset)
+            })
+            .collect()
+    }
+
     pub(crate) fn enqueue(
         &mut self,
         device: &Device,
@@ -567,6 +782,11 @@ impl MainRenderPass {
             0, // Start at beginning of buffer
             draw_count,
         );
+        draw_gpu_meshed_models(
+            &mut renderpass,
+            &self.vertex_buffer,
+            &self.gpu_meshed_models,
+        );
         drop(renderpass);
 
         queue.submit([encoder.finish()]);
@@ -1,3 +1,5 @@
 pub mod shadow;
 pub mod main;
-pub mod tonemap_resolve;
\ No newline at end of file
+pub mod tonemap_resolve;
+pub mod transparent;
+pub mod smooth;
\ No newline at end of file
@@ -1,4 +1,4 @@
-use crate::render::main::InstanceData;
+use crate::render::main::{draw_gpu_meshed_models, InstanceData};
 use crate::render::util::get_view_projection_matrix;
 use bevy::math::{Mat4, Rect, Vec2, Vec3, Vec4};
 use bevy::prelude::{default, OrthographicProjection, Projection};
@@ -150,7 +150,8 @@ impl ShadowRenderPass {
             ],
         });
 
-        let bind_group_layout = crate::render::passes::main::MainRenderPass::get_bind_group_layout(&device);
+        let bind_group_layout =
+            crate::render::passes::main::MainRenderPass::get_bind_group_layout(&device);
 
         let shadow_render_pipeline = Self::get_shadow_render_pipeline(&device, &bind_group_layout);
 
@@ -247,6 +248,11 @@ impl ShadowRenderPass {
                         0, // Start at the beginning of buffer
                         draw_count as u32,
                     );
+                    draw_gpu_meshed_models(
+                        &mut renderpass,
+                        draw_buffers.vertex_buffer,
+                        draw_buffers.gpu_meshed_models,
+                    );
                 }
 
                 let shadow_pass_command_buffer = encoder.finish();
@@ -340,6 +346,11 @@ impl ShadowRenderPass {
                                 offset: wgpu::VertexFormat::Uint32.size(),
                                 shader_location: 1,
                             },
+                            VertexAttribute {
+                                format: wgpu::VertexFormat::Uint32,
+                                offset: 2 * wgpu::VertexFormat::Uint32.size(),
+                                shader_location: 3,
+                            },
                         ],
                     },
                     wgpu::VertexBufferLayout {
@@ -0,0 +1,292 @@
+use crate::render::main::{SmoothMeshData, SmoothMeshesData, SmoothVertex, SURFACE_FORMAT};
+use crate::render::passes::main::MainRenderPass;
+use bevy::prelude::*;
+use std::ops::Range;
+use std::sync::Arc;
+use wgpu::{
+    BindGroupLayout, Buffer, Device, Face, Queue, RenderPipeline, TextureFormat, TextureView,
+    VertexAttribute, VertexStepMode,
+};
+
+/// Draws smooth voxel surfaces as indexed triangles into the main pass targets, after the voxel
+/// faces and before the transparent pass.
+pub(crate) struct SmoothRenderPass {
+    pub(crate) render_pipeline: RenderPipeline,
+    pub(crate) bind_group_layout: BindGroupLayout,
+    pub(crate) vertex_buffer: Buffer,
+    pub(crate) index_buffer: Buffer,
+    pub(crate) model_buffer: Buffer,
+    /// Index range and base vertex of each mesh, in the same order as the model matrices
+    draws: Vec<(Range<u32>, i32)>,
+    /// Meshes currently in the vertex and index buffers
+    uploaded_meshes: Vec<SmoothMeshData>,
+}
+
+impl SmoothRenderPass {
+    pub(crate) fn new(device: &Device) -> Self {
+        let bind_group_layout = Self::get_bind_group_layout(device);
+        Self {
+            render_pipeline: Self::get_pipeline(device, &bind_group_layout),
+            bind_group_layout,
+            vertex_buffer: device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("Smooth Vertex Buffer"),
+                size: size_of::<SmoothVertex>() as u64,
+                usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            }),
+            index_buffer: device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("Smooth Index Buffer"),
+                size: size_of::<u32>() as u64,
+                usage: wgpu::BufferUsages::INDEX | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            }),
+            model_buffer: device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("Smooth Model Buffer"),
+                size: size_of::<Mat4>() as u64,
+                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            }),
+            draws: Vec::new(),
+            uploaded_meshes: Vec::new(),
+        }
+    }
+
+    fn get_bind_group_layout(device: &Device) -> BindGroupLayout {
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Smooth Bind Group Layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::VERTEX,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::VERTEX,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+        })
+    }
+
+    fn get_pipeline(device: &Device, bind_group_layout: &BindGroupLayout) -> RenderPipeline {
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Smooth Pipeline Layout"),
+            bind_group_layouts: &[bind_group_layout],
+            push_constant_ranges: &[],
+        });
+
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Smooth Shader"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("../shaders/smooth.wgsl").into()),
+        });
+
+        device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Smooth Render Pipeline"),
+            layout: Some(&pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: Some("vs_main"),
+                compilation_options: Default::default(),
+                buffers: &[wgpu::VertexBufferLayout {
+                    array_stride: size_of::<SmoothVertex>() as u64,
+                    step_mode: VertexStepMode::Vertex,
+                    attributes: &[
+                        VertexAttribute {
+                            format: wgpu::VertexFormat::Float32x3,
+                            offset: 0,
+                            shader_location: 0,
+                        },
+                        VertexAttribute {
+                            format: wgpu::VertexFormat::Float32x3,
+                            offset: wgpu::VertexFormat::Float32x3.size(),
+                            shader_location: 1,
+                        },
+                        VertexAttribute {
+                            format: wgpu::VertexFormat::Unorm8x4,
+                            offset: wgpu::VertexFormat::Float32x3.size() * 2,
+                            shader_location: 2,
+                        },
+                    ],
+                }],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: Some("fs_main"),
+                compilation_options: Default::default(),
+                targets: &[Some(SURFACE_FORMAT.into())],
+            }),
+            primitive: wgpu::PrimitiveState {
+                cull_mode: Some(Face::Back),
+                topology: wgpu::PrimitiveTopology::TriangleList,
+                front_face: wgpu::FrontFace::Ccw,
+                ..default()
+            },
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: TextureFormat::Depth24Plus,
+                depth_write_enabled: true,
+                depth_compare: wgpu::CompareFunction::Greater,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState::default(),
+            }),
+            multisample: wgpu::MultisampleState {
+                count: 4,
+                mask: !0,
+                alpha_to_coverage_enabled: false,
+            },
+            multiview: None,
+            cache: None,
+        })
+    }
+
+    /// Writes the model matrices every frame, but only re-uploads the triangles when a mesh has
+    /// been added, removed or re-meshed
+    pub(crate) fn prepare_buffers(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        smooth_meshes: SmoothMeshesData,
+    ) {
+        let model_data = smooth_meshes
+            .iter()
+            .map(|(_, transform)| transform.compute_matrix())
+            .collect::<Vec<_>>();
+        let model_buffer_size = (model_data.len() * size_of::<Mat4>()) as u64;
+        if self.model_buffer.size() < model_buffer_size {
+            self.model_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("Smooth Model Buffer"),
+                size: model_buffer_size,
+                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            });
+        }
+        queue.write_buffer(&self.model_buffer, 0, bytemuck::cast_slice(&model_data));
+
+        let is_uploaded = smooth_meshes.len() == self.uploaded_meshes.len()
+            && smooth_meshes
+                .iter()
+                .zip(&self.uploaded_meshes)
+                .all(|((mesh, _), uploaded)| {
+                    Arc::ptr_eq(&mesh.vertices, &uploaded.vertices)
+                        && Arc::ptr_eq(&mesh.indices, &uploaded.indices)
+                });
+        if is_uploaded {
+            return;
+        }
+
+        let mut all_vertex_data: Vec<SmoothVertex> = Vec::new();
+        let mut all_index_data: Vec<u32> = Vec::new();
+        self.draws.clear();
+        for (mesh, _) in &smooth_meshes {
+            let first_index = all_index_data.len() as u32;
+            self.draws.push((
+                first_index..first_index + mesh.indices.len() as u32,
+                all_vertex_data.len() as i32,
+            ));
+            all_vertex_data.extend_from_slice(&mesh.vertices);
+            all_index_data.extend_from_slice(&mesh.indices);
+        }
+
+        let vertex_buffer_size = (all_vertex_data.len() * size_of::<SmoothVertex>()) as u64;
+        if self.vertex_buffer.size() < vertex_buffer_size {
+            self.vertex_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("Smooth Vertex Buffer"),
+                size: vertex_buffer_size,
+                usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            });
+        }
+        queue.write_buffer(
+            &self.vertex_buffer,
+            0,
+            bytemuck::cast_slice(&all_vertex_data),
+        );
+
+        let index_buffer_size = (all_index_data.len() * size_of::<u32>()) as u64;
+        if self.index_buffer.size() < index_buffer_size {
+            self.index_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("Smooth Index Buffer"),
+                size: index_buffer_size,
+                usage: wgpu::BufferUsages::INDEX | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            });
+        }
+        queue.write_buffer(&self.index_buffer, 0, bytemuck::cast_slice(&all_index_data));
+
+        self.uploaded_meshes = smooth_meshes.into_iter().map(|(mesh, _)| mesh).collect();
+    }
+
+    pub(crate) fn enqueue(
+        &self,
+        device: &Device,
+        queue: &Queue,
+        msaa_texture_view: &TextureView,
+        main_pass: &MainRenderPass,
+    ) {
+        if self.draws.is_empty() {
+            return;
+        }
+
+        // Shares the camera uniforms the main pass has just written
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Smooth Bind Group"),
+            layout: &self.bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: self.model_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: main_pass.uniform_buffer.as_entire_binding(),
+                },
+            ],
+        });
+
+        let mut encoder = device.create_command_encoder(&Default::default());
+        let mut renderpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Smooth Render Pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: msaa_texture_view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: wgpu::StoreOp::Store,
+                },
+            })],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: &main_pass.depth_texture_view,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: wgpu::StoreOp::Store,
+                }),
+                stencil_ops: None,
+            }),
+            timestamp_writes: None,
+            occlusion_query_set: None,
+        });
+
+        renderpass.set_pipeline(&self.render_pipeline);
+        renderpass.set_bind_group(0, &bind_group, &[]);
+        renderpass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
+        renderpass.set_index_buffer(self.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+
+        for (model_index, (indices, base_vertex)) in self.draws.iter().enumerate() {
+            let model_index = model_index as u32;
+            renderpass.draw_indexed(indices.clone(), *base_vertex, model_index..model_index + 1);
+        }
+        drop(renderpass);
+
+        queue.submit([encoder.finish()]);
+    }
+}
@@ -0,0 +1,305 @@
+use crate::render::compute_mesher::GpuMeshedFaces;
+use crate::render::main::{draw_gpu_meshed_models, InstanceData, VoxelPlanesData, SURFACE_FORMAT};
+use crate::render::passes::main::MainRenderPass;
+use bevy::math::Vec3;
+use bevy::prelude::*;
+use std::ops::Range;
+use std::sync::Arc;
+use wgpu::{
+    BindGroup, BindGroupLayout, Buffer, Device, Queue, RenderPipeline, TextureFormat, TextureView,
+    VertexAttribute, VertexStepMode,
+};
+
+/// Draws transparent voxel faces over the opaque scene, blended back to front.
+///
+/// Shares the model indices, transforms and colour buffers of the main pass, and tests against
+/// its depth buffer without writing to it.
+pub(crate) struct TransparentRenderPass {
+    pub(crate) render_pipeline: RenderPipeline,
+    pub(crate) instance_buffer: Buffer,
+    pub(crate) indirect_buffer: Buffer,
+    /// Draws back to front, models meshed on the GPU breaking up the runs drawn from this pass's
+    /// own buffers
+    draws: Vec<TransparentDraw>,
+}
+
+enum TransparentDraw {
+    /// A range of the indirect buffer
+    Batched(Range<u32>),
+    /// A model meshed on the GPU and its index
+    GpuMeshed(u32, Arc<GpuMeshedFaces>),
+}
+
+impl TransparentRenderPass {
+    pub(crate) fn new(device: &Device, shadow_bind_group_layout: &BindGroupLayout) -> Self {
+        Self {
+            render_pipeline: Self::get_pipeline(device, shadow_bind_group_layout),
+            instance_buffer: device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("Transparent instance data buffer"),
+                size: size_of::<InstanceData>() as u64,
+                usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            }),
+            indirect_buffer: device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("Transparent Indirect Draw Buffer"),
+                size: (size_of::<wgpu::util::DrawIndirectArgs>() * 6) as u64, // 6 faces per voxel
+                usage: wgpu::BufferUsages::INDIRECT | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            }),
+            draws: Vec::new(),
+        }
+    }
+
+    fn get_pipeline(
+        device: &Device,
+        shadow_bind_group_layout: &wgpu::BindGroupLayout,
+    ) -> RenderPipeline {
+        let bind_group_layout = MainRenderPass::get_bind_group_layout(device);
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Transparent Pipeline Layout"),
+            bind_group_layouts: &[&bind_group_layout, shadow_bind_group_layout],
+            push_constant_ranges: &[],
+        });
+
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Transparent Shader"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("../shaders/shader.wgsl").into()),
+        });
+
+        device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Transparent Render Pipeline"),
+            layout: Some(&pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: Some("vs_transparent"),
+                compilation_options: Default::default(),
+                buffers: &[
+                    wgpu::VertexBufferLayout {
+                        array_stride: size_of::<InstanceData>() as u64,
+                        step_mode: VertexStepMode::Instance,
+                        attributes: &[
+                            VertexAttribute {
+                                format: wgpu::VertexFormat::Uint32,
+                                offset: 0,
+                                shader_location: 0,
+                            },
+                            VertexAttribute {
+                                format: wgpu::VertexFormat::Uint32,
+                                offset: wgpu::VertexFormat::Uint32.size(),
+                                shader_location: 1,
+                            },
+                            VertexAttribute {
+                                format: wgpu::VertexFormat::Uint32,
+                                offset: 2 * wgpu::VertexFormat::Uint32.size(),
+                                shader_location: 3,
+                            },
+                        ],
+                    },
+                    wgpu::VertexBufferLayout {
+                        array_stride: size_of::<u32>() as u64,
+                        step_mode: wgpu::VertexStepMode::Vertex,
+                        attributes: &[wgpu::VertexAttribute {
+                            format: wgpu::VertexFormat::Uint32,
+                            offset: 0,
+                            shader_location: 2,
+                        }],
+                    },
+                ],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: Some("fs_transparent"),
+                compilation_options: Default::default(),
+                targets: &[Some(wgpu::ColorTargetState {
+                    format: SURFACE_FORMAT,
+                    blend: Some(wgpu::BlendState::ALPHA_BLENDING),
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+            primitive: wgpu::PrimitiveState {
+                // Water surfaces are seen from below as well as above
+                cull_mode: None,
+                topology: wgpu::PrimitiveTopology::TriangleStrip,
+                front_face: wgpu::FrontFace::Ccw,
+                ..default()
+            },
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: TextureFormat::Depth24Plus,
+                depth_write_enabled: false,
+                depth_compare: wgpu::CompareFunction::Greater,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState::default(),
+            }),
+            multisample: wgpu::MultisampleState {
+                count: 4,
+                mask: !0,
+                alpha_to_coverage_enabled: false,
+            },
+            multiview: None,
+            cache: None,
+        })
+    }
+
+    /// Builds the draws for every model with transparent faces, furthest from the camera first
+    /// so each model blends over the ones behind it.
+    ///
+    /// Must be called before the main pass consumes the voxel planes, as the draws index into
+    /// its per-model vertex buffer.
+    pub(crate) fn prepare_buffers(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        voxel_planes: &VoxelPlanesData,
+        camera_position: Vec3,
+    ) {
+        // Models are sorted by the centre of their bounds, which is only a transform away
+        let mut models = voxel_planes
+            .chunks(6)
+            .enumerate()
+            .filter(|(_, faces)| {
+                faces[0].7.is_some()
+                    || faces
+                        .iter()
+                        .any(|(.., transparent_data, _, _)| !transparent_data.is_empty())
+            })
+            .map(|(model_index, faces)| {
+                let (_, _, transform, _, _, _, bounds, _) = &faces[0];
+                let centre = transform.transform_point(bounds.center.into());
+                (model_index, centre.distance_squared(camera_position))
+            })
+            .collect::<Vec<_>>();
+        models.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+        let mut all_indirect_data: Vec<wgpu::util::DrawIndirectArgs> =
+            Vec::with_capacity(models.len() * 6);
+        let mut all_instance_data: Vec<InstanceData> = Vec::new();
+
+        self.draws.clear();
+        for (model_index, _) in models {
+            let model_planes = &voxel_planes[model_index * 6..][..6];
+            if let Some(gpu_meshed) = &model_planes[0].7 {
+                self.draws.push(TransparentDraw::GpuMeshed(
+                    model_index as u32,
+                    gpu_meshed.transparent.clone(),
+                ));
+                continue;
+            }
+            for (face, _, _, _, _, transparent_data, ..) in model_planes {
+                if transparent_data.is_empty() {
+                    continue;
+                }
+                let draw_index = all_indirect_data.len() as u32;
+                match self.draws.last_mut() {
+                    Some(TransparentDraw::Batched(range)) => range.end = draw_index + 1,
+                    _ => self
+                        .draws
+                        .push(TransparentDraw::Batched(draw_index..draw_index + 1)),
+                }
+                all_indirect_data.push(wgpu::util::DrawIndirectArgs {
+                    vertex_count: 4,
+                    instance_count: transparent_data.len() as u32,
+                    first_vertex: model_index as u32 * 24 + *face as u32 * 4,
+                    first_instance: all_instance_data.len() as u32,
+                });
+                all_instance_data.extend_from_slice(transparent_data);
+            }
+        }
+
+        let indirect_buffer_size =
+            (all_indirect_data.len() * size_of::<wgpu::util::DrawIndirectArgs>()) as u64;
+        if self.indirect_buffer.size() < indirect_buffer_size {
+            self.indirect_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("Transparent Indirect Draw Buffer"),
+                size: indirect_buffer_size,
+                usage: wgpu::BufferUsages::INDIRECT | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            });
+        }
+        queue.write_buffer(
+            &self.indirect_buffer,
+            0,
+            bytemuck::cast_slice(&all_indirect_data),
+        );
+
+        // The draw order changes as the camera moves, so the instances are rewritten every frame
+        let instance_buffer_size = (all_instance_data.len() * size_of::<InstanceData>()) as u64;
+        if self.instance_buffer.size() < instance_buffer_size {
+            self.instance_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("Transparent instance data buffer"),
+                size: instance_buffer_size,
+                usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            });
+        }
+        queue.write_buffer(
+            &self.instance_buffer,
+            0,
+            bytemuck::cast_slice(&all_instance_data),
+        );
+    }
+
+    pub(crate) fn enqueue(
+        &self,
+        device: &Device,
+        queue: &Queue,
+        msaa_texture_view: &TextureView,
+        main_pass: &MainRenderPass,
+        shadow_bind_group: &BindGroup,
+    ) {
+        if self.draws.is_empty() {
+            return;
+        }
+
+        let mut encoder = device.create_command_encoder(&Default::default());
+        let mut renderpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Transparent Render Pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: msaa_texture_view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: wgpu::StoreOp::Store,
+                },
+            })],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: &main_pass.depth_texture_view,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: wgpu::StoreOp::Store,
+                }),
+                stencil_ops: None,
+            }),
+            timestamp_writes: None,
+            occlusion_query_set: None,
+        });
+
+        renderpass.set_pipeline(&self.render_pipeline);
+        renderpass.set_bind_group(0, &main_pass.bind_group, &[]);
+        renderpass.set_bind_group(1, shadow_bind_group, &[]);
+
+        for draw in &self.draws {
+            match draw {
+                TransparentDraw::Batched(range) => {
+                    renderpass.set_vertex_buffer(1, main_pass.vertex_buffer.slice(..));
+                    renderpass.set_vertex_buffer(0, self.instance_buffer.slice(..));
+                    renderpass.multi_draw_indirect(
+                        &self.indirect_buffer,
+                        (range.start as usize * size_of::<wgpu::util::DrawIndirectArgs>()) as u64,
+                        range.len() as u32,
+                    );
+                }
+                TransparentDraw::GpuMeshed(model_index, faces) => {
+                    draw_gpu_meshed_models(
+                        &mut renderpass,
+                        &main_pass.vertex_buffer,
+                        &[(*model_index, faces.clone())],
+                    );
+                }
+            }
+        }
+        drop(renderpass);
+
+        queue.submit([encoder.finish()]);
+    }
+}
@@ -0,0 +1,355 @@
+// Greedy meshes a voxel grid into the same face instances as generate_instance_data in
+// vxm_mesh.rs, in the same order, so the results can be compared one to one.
+
+struct Params {
+    size_x: u32,
+    size_y: u32,
+    size_z: u32,
+    layer: u32, // 0 for opaque, 1 for transparent, matching VoxelLayer
+    match_colours: u32,
+    instance_capacity: u32,
+    _padding: vec2<u32>,
+}
+
+struct DrawIndirectArgs {
+    vertex_count: u32,
+    instance_count: u32,
+    first_vertex: u32,
+    first_instance: u32,
+}
+
+@group(0) @binding(0) var<uniform> params: Params;
+// Colour, opacity << 16 and light << 24 of the model and a voxel of its neighbours on every side
+@group(0) @binding(1) var<storage, read> voxels: array<u32>;
+// Key of every voxel for each face direction, and whether the face is VISIBLE
+@group(0) @binding(2) var<storage, read_write> face_keys: array<vec2<u32>>;
+// Quads in each slice, turned into the offset of the slice's first quad by allocate_instances
+@group(0) @binding(3) var<storage, read_write> slice_counts: array<u32>;
+@group(0) @binding(4) var<storage, read_write> indirect_args: array<DrawIndirectArgs, 6>;
+// Total quads, which may be more than the instance buffer holds
+@group(0) @binding(5) var<storage, read_write> instance_total: u32;
+// Laid out as InstanceData
+struct Instance {
+    position_width: u32,
+    colour_height: u32, // Colour, ambient occlusion and height
+    light: u32, // Light, then padding
+}
+
+@group(0) @binding(6) var<storage, read_write> instances: array<Instance>;
+
+const MAX_SLICES = 256u;
+
+const LAYER_OPAQUE = 0u;
+
+const VISIBLE = 1u;
+
+const FACE_NORMALS = array<vec3<i32>, 6>(
+    vec3(0, 0, -1),
+    vec3(0, 0, 1),
+    vec3(-1, 0, 0),
+    vec3(1, 0, 0),
+    vec3(0, -1, 0),
+    vec3(0, 1, 0),
+);
+
+// Unit cube corners of each face, in the same order as the positions array in shader.wgsl
+const FACE_VERTEX_OFFSETS = array<array<vec3<i32>, 4>, 6>(
+    array(vec3(1, 0, 0), vec3(0, 0, 0), vec3(1, 1, 0), vec3(0, 1, 0)),
+    array(vec3(0, 0, 1), vec3(1, 0, 1), vec3(0, 1, 1), vec3(1, 1, 1)),
+    array(vec3(0, 0, 0), vec3(0, 0, 1), vec3(0, 1, 0), vec3(0, 1, 1)),
+    array(vec3(1, 0, 1), vec3(1, 0, 0), vec3(1, 1, 1), vec3(1, 1, 0)),
+    array(vec3(0, 0, 0), vec3(1, 0, 0), vec3(0, 0, 1), vec3(1, 0, 1)),
+    array(vec3(1, 1, 0), vec3(0, 1, 0), vec3(1, 1, 1), vec3(0, 1, 1)),
+);
+
+// Axis faces point along, and the two axes its quads extend over as width and height
+const FACE_AXES = array<vec3<u32>, 6>(
+    vec3(2u, 0u, 1u),
+    vec3(2u, 0u, 1u),
+    vec3(0u, 1u, 2u),
+    vec3(0u, 1u, 2u),
+    vec3(1u, 0u, 2u),
+    vec3(1u, 0u, 2u),
+);
+
+fn model_size() -> vec3<u32> {
+    return vec3(params.size_x, params.size_y, params.size_z);
+}
+
+fn voxel_count() -> u32 {
+    return params.size_x * params.size_y * params.size_z;
+}
+
+// Positions may be a voxel outside the model, where the neighbouring chunks are
+fn voxel_at(position: vec3<i32>) -> u32 {
+    let padded_size = vec3<i32>(model_size()) + 2;
+    let padded = position + 1;
+    return voxels[(padded.x * padded_size.y + padded.y) * padded_size.z + padded.z];
+}
+
+// Every colour encoding reserves 0 for air
+fn is_solid(voxel: u32) -> bool {
+    return (voxel & 0xFFFFu) != 0u;
+}
+
+fn is_opaque(voxel: u32) -> bool {
+    return is_solid(voxel) && ((voxel >> 16u) & 0xFFu) == 255u;
+}
+
+fn layer_contains(voxel: u32) -> bool {
+    if (params.layer == LAYER_OPAQUE) {
+        return is_opaque(voxel);
+    }
+    return is_solid(voxel) && !is_opaque(voxel);
+}
+
+// Transparent faces are hidden by whatever they touch so water has no internal faces
+fn is_hidden_by(voxel: u32) -> bool {
+    if (params.layer == LAYER_OPAQUE) {
+        return is_opaque(voxel);
+    }
+    return is_solid(voxel);
+}
+
+fn vertex_ambient_occlusion(side_1: bool, side_2: bool, corner: bool) -> u32 {
+    if (side_1 && side_2) {
+        return 0u;
+    }
+    return 3u - u32(side_1) - u32(side_2) - u32(corner);
+}
+
+fn face_ambient_occlusion(position: vec3<i32>, face: u32) -> u32 {
+    let normal = FACE_NORMALS[face];
+    let front = position + normal;
+    let corners = FACE_VERTEX_OFFSETS[face];
+
+    var ao = 0u;
+    for (var vertex = 0u; vertex < 4u; vertex++) {
+        let offset = corners[vertex];
+        // Step towards the corner along the two axes the face lies on
+        var steps = array<vec3<i32>, 2>(vec3(0), vec3(0));
+        var step_count = 0u;
+        for (var axis = 0u; axis < 3u; axis++) {
+            if (normal[axis] == 0) {
+                steps[step_count][axis] = select(1, -1, offset[axis] == 0);
+                step_count++;
+            }
+        }
+
+        let vertex_ao = vertex_ambient_occlusion(
+            is_opaque(voxel_at(front + steps[0])),
+            is_opaque(voxel_at(front + steps[1])),
+            is_opaque(voxel_at(front + steps[0] + steps[1]))
+        );
+        ao |= vertex_ao << (6u - vertex * 2u);
+    }
+    return ao;
+}
+
+fn voxel_index(position: vec3<u32>) -> u32 {
+    return (position.x * params.size_y + position.y) * params.size_z + position.z;
+}
+
+fn face_key_index(face: u32, position: vec3<u32>) -> u32 {
+    return face * voxel_count() + voxel_index(position);
+}
+
+// Finds the visible faces of every voxel along with the colour, shading and light they merge on
+@compute @workgroup_size(4, 4, 4)
+fn find_faces(@builtin(global_invocation_id) id: vec3<u32>) {
+    if (any(id >= model_size())) {
+        return;
+    }
+    let position = vec3<i32>(id);
+    let voxel = voxel_at(position);
+
+    for (var face = 0u; face < 6u; face++) {
+        var key = vec2(0u);
+        let front = voxel_at(position + FACE_NORMALS[face]);
+        if (layer_contains(voxel) && !is_hidden_by(front)) {
+            key.y = VISIBLE;
+        }
+        // Without matching colours faces merge on occupancy alone, and are shaded per fragment
+        if (key.y == VISIBLE && params.match_colours != 0u) {
+            // Transparent faces have no ambient occlusion, so carry their opacity instead
+            var shading = (voxel >> 16u) & 0xFFu;
+            if (params.layer == LAYER_OPAQUE) {
+                shading = face_ambient_occlusion(position, face);
+            }
+            // Faces are lit by the voxel in front of them
+            let light = front >> 24u;
+            key.x = (voxel & 0xFFFFu) | shading << 16u | light << 24u;
+        }
+        face_keys[face_key_index(face, id)] = key;
+    }
+}
+
+fn slice_position(face: u32, d: u32, u: u32, v: u32) -> vec3<u32> {
+    let axes = FACE_AXES[face];
+    var position = vec3(0u);
+    position[axes.x] = d;
+    position[axes.y] = u;
+    position[axes.z] = v;
+    return position;
+}
+
+// Whether a face is visible, whether or not it's already part of a quad
+fn is_visible(face: u32, position: vec3<u32>) -> bool {
+    return face_keys[face_key_index(face, position)].y == VISIBLE;
+}
+
+fn face_key(face: u32, position: vec3<u32>) -> u32 {
+    return face_keys[face_key_index(face, position)].x;
+}
+
+// Rows merged so far down each column of the slice, as quads only ever cover a column from the
+// row they start on
+var<workgroup> covered_rows: array<u32, MAX_SLICES>;
+// Whether each column's face in the current row is visible and not yet part of a quad
+var<workgroup> is_available: array<bool, MAX_SLICES>;
+// Quads starting in the current row up to and including each column
+var<workgroup> starts: array<u32, MAX_SLICES>;
+
+// Merges the faces of a slice into quads with a thread per column, finding the same quads in
+// the same order as the CPU mesher.
+//
+// Rows are merged one after another. The CPU mesher starts a quad in a row wherever a run of
+// available faces with the same key begins, and quads starting in the same row cover different
+// columns, so each run's thread can grow its quad at the same time as the others.
+// Returns the number of quads in the slice.
+fn merge_slice(face: u32, d: u32, u: u32, is_emitting: bool) -> u32 {
+    let axes = FACE_AXES[face];
+    let size = model_size();
+    let size_u = size[axes.y];
+    let size_v = size[axes.z];
+    let is_column = u < size_u;
+
+    covered_rows[u] = 0u;
+    var quad_count = 0u;
+    for (var v = 0u; v < size_v; v++) {
+        let position = slice_position(face, d, min(u, size_u - 1u), v);
+        is_available[u] = is_column && v >= covered_rows[u] && is_visible(face, position);
+        workgroupBarrier();
+
+        let key = face_key(face, position);
+        let is_start = is_available[u]
+            && (u == 0u || !is_available[u - 1u]
+                || face_key(face, slice_position(face, d, u - 1u, v)) != key);
+
+        // Inclusive prefix sum of the starts, giving each quad its place in the row
+        starts[u] = u32(is_start);
+        workgroupBarrier();
+        for (var offset = 1u; offset < MAX_SLICES; offset *= 2u) {
+            var sum = starts[u];
+            if (u >= offset) {
+                sum += starts[u - offset];
+            }
+            workgroupBarrier();
+            starts[u] = sum;
+            workgroupBarrier();
+        }
+        let row_quads = starts[MAX_SLICES - 1u];
+
+        if (is_start) {
+            var width = 1u;
+            while (u + width < size_u
+                && is_available[u + width]
+                && face_key(face, slice_position(face, d, u + width, v)) == key) {
+                width++;
+            }
+
+            // Faces below the row aren't part of any quad yet, as quads only start above them
+            // in columns they cover
+            var height = 1u;
+            loop {
+                if (v + height >= size_v) {
+                    break;
+                }
+                var is_row_matching = true;
+                for (var du = u; du < u + width; du++) {
+                    let below = slice_position(face, d, du, v + height);
+                    if (!is_visible(face, below) || face_key(face, below) != key) {
+                        is_row_matching = false;
+                        break;
+                    }
+                }
+                if (!is_row_matching) {
+                    break;
+                }
+                height++;
+            }
+
+            for (var du = u; du < u + width; du++) {
+                covered_rows[du] = v + height;
+            }
+
+            if (is_emitting) {
+                let instance_index = slice_counts[face * MAX_SLICES + d] + quad_count + starts[u] - 1u;
+                if (instance_index < params.instance_capacity) {
+                    instances[instance_index] = Instance(
+                        position.x | position.y << 8u | position.z << 16u | width << 24u,
+                        (key & 0xFFFFFFu) | height << 24u,
+                        key >> 24u
+                    );
+                }
+            }
+        }
+        quad_count += row_quads;
+        workgroupBarrier();
+    }
+    return quad_count;
+}
+
+// A workgroup per slice of each face direction
+@compute @workgroup_size(256)
+fn count_quads(
+    @builtin(workgroup_id) slice: vec3<u32>,
+    @builtin(local_invocation_index) u: u32
+) {
+    let face = slice.y;
+    let d = slice.x;
+    if (d >= model_size()[FACE_AXES[face].x]) {
+        return;
+    }
+    let quad_count = merge_slice(face, d, u, false);
+    if (u == 0u) {
+        slice_counts[face * MAX_SLICES + d] = quad_count;
+    }
+}
+
+// Lays the quads out face after face, slice after slice, and writes a draw for each face.
+// Draws are left empty when the quads don't fit in the instance buffer.
+@compute @workgroup_size(1)
+fn allocate_instances() {
+    var total = 0u;
+    for (var face = 0u; face < 6u; face++) {
+        let first_instance = total;
+        let size_d = model_size()[FACE_AXES[face].x];
+        for (var d = 0u; d < size_d; d++) {
+            let count = slice_counts[face * MAX_SLICES + d];
+            slice_counts[face * MAX_SLICES + d] = total;
+            total += count;
+        }
+        indirect_args[face] = DrawIndirectArgs(4u, total - first_instance, face * 4u, first_instance);
+    }
+
+    instance_total = total;
+    if (total > params.instance_capacity) {
+        for (var face = 0u; face < 6u; face++) {
+            indirect_args[face].instance_count = 0u;
+        }
+    }
+}
+
+@compute @workgroup_size(256)
+fn emit_quads(
+    @builtin(workgroup_id) slice: vec3<u32>,
+    @builtin(local_invocation_index) u: u32
+) {
+    let face = slice.y;
+    let d = slice.x;
+    if (d >= model_size()[FACE_AXES[face].x]) {
+        return;
+    }
+    merge_slice(face, d, u, true);
+}
@@ -15,6 +15,18 @@ struct Light {
 @group(0) @binding(0) var<storage, read> model_matrices: array<mat4x4<f32>>;
 @group(0) @binding(1) var<uniform> uniforms: Uniforms;
 @group(0) @binding(2) var<uniform> lights: array<Light, 32>;
+// encoding, palette offset, colour volume offset and packed colour volume size
+@group(0) @binding(3) var<storage, read> model_colours: array<vec4<u32>>;
+@group(0) @binding(4) var<storage, read> palettes: array<u32>; // rgba8
+@group(0) @binding(5) var<storage, read> colour_volumes: array<u32>; // Colour, then light << 16
+
+// Colour volume offset of models whose faces each have a single colour
+const NO_COLOUR_VOLUME = 0xFFFFFFFFu;
+
+// Matches ColourEncoding in color_conversion.rs
+const COLOUR_ENCODING_HSL = 0u;
+const COLOUR_ENCODING_RGB565 = 1u;
+const COLOUR_ENCODING_PALETTE = 2u;
 
 // Shadow texture and sampler
 @group(1) @binding(0) var shadow_texture: texture_depth_2d;
@@ -30,12 +42,17 @@ struct VertexOutput {
     @location(4) @interpolate(perspective, centroid) hue: f32,
     @location(5) @interpolate(perspective, centroid) saturation: f32,
     @location(6) @interpolate(perspective, centroid) lightness: f32,
+    @location(7) @interpolate(flat) opacity: f32,
+    @location(8) @interpolate(perspective, centroid) local_position: vec3<f32>,
+    @location(9) @interpolate(flat) model_index: u32,
+    @location(10) @interpolate(perspective, centroid) ambient_occlusion: f32,
 };
 
 struct Instance {
   @location(0) pos_x_extent: u32,// 5+5+5
   @location(1) color_y_extent: u32,
   @location(2) model_index: u32, // The index of the vertex in the vertex buffer
+  @location(3) light: u32, // Block light in the low 4 bits, sky light in the next 4
 }
 
 const positions = array<vec3<f32>, 24>(
@@ -120,6 +137,57 @@ fn convert_hsl_to_rgb(h: f32, s: f32, l: f32) -> vec3<f32> {
     return vec3<f32>(r, g, b);
 }
 
+fn convert_rgb_to_hsl(rgb: vec3<f32>) -> vec3<f32> {
+    let max_value = max(max(rgb.r, rgb.g), rgb.b);
+    let min_value = min(min(rgb.r, rgb.g), rgb.b);
+    let delta = max_value - min_value;
+    let l = (max_value + min_value) / 2.0;
+
+    if (delta == 0.0) {
+        return vec3(0.0, 0.0, l);
+    }
+
+    let s = select(delta / (2.0 - max_value - min_value), delta / (max_value + min_value), l <= 0.5);
+
+    var h = 0.0;
+    if (max_value == rgb.r) {
+        h = (rgb.g - rgb.b) / delta;
+    } else if (max_value == rgb.g) {
+        h = (rgb.b - rgb.r) / delta + 2.0;
+    } else {
+        h = (rgb.r - rgb.g) / delta + 4.0;
+    }
+    h = h / 6.0;
+    if (h < 0.0) {
+        h += 1.0;
+    }
+
+    return vec3(h, s, l);
+}
+
+fn get_rgb565_voxel(colour: u32) -> vec3<f32> {
+    return vec3(
+      f32((colour >> 11u) & 0x1Fu) / 31.0,
+      f32((colour >> 5u) & 0x3Fu) / 63.0,
+      f32(colour & 0x1Fu) / 31.0
+    );
+}
+
+// Decodes a packed voxel colour to hsl using the encoding of its model
+fn decode_voxel_colour(colour: u32, model_index: u32) -> vec3<f32> {
+    let model_colour = model_colours[model_index];
+    let encoding = model_colour.x;
+
+    if (encoding == COLOUR_ENCODING_RGB565) {
+        return convert_rgb_to_hsl(get_rgb565_voxel(colour));
+    }
+    if (encoding == COLOUR_ENCODING_PALETTE) {
+        let rgba = unpack4x8unorm(palettes[model_colour.y + (colour & 0xFFu)]);
+        return convert_rgb_to_hsl(rgba.rgb);
+    }
+    return get_hsl_voxel(colour);
+}
+
 fn unpack_ambient_occlusion(ao_packed: u32) -> vec4<f32> {
     // Extract AO values for 4 corners (2 bits each)
     let ao_corner0 = (ao_packed >> 6u) & 3u;  // Top-left
@@ -135,9 +203,29 @@ fn unpack_ambient_occlusion(ao_packed: u32) -> vec4<f32> {
     ) / 3.0;
 }
 
+// How bright a face is for the brighter of its block and sky light, matching light_brightness in
+// vxm_light.rs
+fn light_brightness(light: u32) -> f32 {
+    let level = max(light & 15u, (light >> 4u) & 15u);
+    return pow(0.8, f32(15u - level));
+}
 
-@vertex
-fn vs_main(@builtin(vertex_index) in_vertex_index: u32, instance: Instance) -> VertexOutput {
+// Darkens a voxel colour in occluded corners, saturating it where it is coloured enough
+fn shade_voxel_colour(hsl: vec3<f32>, ao_value: f32) -> vec3<f32> {
+    let l: f32 = hsl.z * (ao_value * 0.9 + 0.1);
+
+    // If the saturation is large enough to assume this is a colour, we increase the saturation with ao darkness
+    var s = hsl.y;
+    if( hsl.y > 0.2) {
+      let s_factor = (ao_value * 0.8 + 0.2);
+      s = hsl.y / s_factor;
+    }
+
+    return vec3(hsl.x, s, l);
+}
+
+// Transparent faces have no ambient occlusion, so their ao byte holds the opacity instead
+fn get_voxel_vertex(in_vertex_index: u32, instance: Instance, is_transparent: bool) -> VertexOutput {
     let unpacked_pos_x_extent = unpack4xU8(instance.pos_x_extent);
     let x_pos = f32(unpacked_pos_x_extent.x);
     let y_pos = f32(unpacked_pos_x_extent.y);
@@ -151,10 +239,7 @@ fn vs_main(@builtin(vertex_index) in_vertex_index: u32, instance: Instance) -> V
     let hsl = unpacked_color_y_extent.rg;
     let ao_packed = unpacked_color_y_extent.b;
 
-    let unpacked_hsl = get_hsl_voxel(instance.color_y_extent);
-    let unpacked_h = unpacked_hsl.x;
-    let unpacked_s = unpacked_hsl.y;
-    let unpacked_l = unpacked_hsl.z;
+    let unpacked_hsl = decode_voxel_colour(instance.color_y_extent & 0xFFFFu, instance.model_index);
 
     let local_vertex_index = in_vertex_index % 24u; // Ensure the index is within the bounds of the positions array
 
@@ -205,23 +290,20 @@ fn vs_main(@builtin(vertex_index) in_vertex_index: u32, instance: Instance) -> V
     let screen_uv = (projected_pos.xy / projected_pos.w) * 0.5 + 0.5;
 
     // Calculate ambient occlusion for this vertex based on its position within the quad
-    var ao_value = 0.0;
+    var ao_value = 1.0;
+    var opacity = 1.0;
     let vertex_in_quad = local_vertex_index % 4u;
 
-    let unpacked_ao = unpack_ambient_occlusion(ao_packed);
-    ao_value = unpacked_ao[vertex_in_quad];
-
-    let l: f32 = unpacked_l * (ao_value * 0.9 + 0.1);
-
-    // If the saturation is large enough to assume this is a colour, we increase the saturation with ao darkness
-    var s = unpacked_s;
-    if( unpacked_s > 0.2) {
-      let s_factor = (ao_value * 0.8 + 0.2);
-      s = unpacked_s / s_factor;
+    if (is_transparent) {
+      opacity = f32(ao_packed) / 255.0;
+    } else {
+      let unpacked_ao = unpack_ambient_occlusion(ao_packed);
+      ao_value = unpacked_ao[vertex_in_quad];
     }
+    ao_value *= light_brightness(instance.light);
 
-    let hsl1 = vec3(unpacked_h, s, l);
-    let albedo = convert_hsl_to_rgb(unpacked_h, s, l);
+    let hsl1 = shade_voxel_colour(unpacked_hsl, ao_value);
+    let albedo = convert_hsl_to_rgb(hsl1.x, hsl1.y, hsl1.z);
 
     var output: VertexOutput;
     output.position = projected_pos;  // Transform to clip space
@@ -232,10 +314,114 @@ fn vs_main(@builtin(vertex_index) in_vertex_index: u32, instance: Instance) -> V
     output.world_position = model_matrices[instance.model_index] * vec4<f32>(pos, 1.0);
     output.normal = normal;
     output.uv = screen_uv;
+    output.opacity = opacity;
+    output.local_position = pos;
+    output.model_index = instance.model_index;
+    output.ambient_occlusion = ao_value;
 
     return output;
 }
 
+@vertex
+fn vs_main(@builtin(vertex_index) in_vertex_index: u32, instance: Instance) -> VertexOutput {
+    return get_voxel_vertex(in_vertex_index, instance, false);
+}
+
+@vertex
+fn vs_transparent(@builtin(vertex_index) in_vertex_index: u32, instance: Instance) -> VertexOutput {
+    return get_voxel_vertex(in_vertex_index, instance, true);
+}
+
+// Colour of the voxel at a position in a model's colour volume, 0 for air and transparent voxels,
+// with the light there above it. Volumes are padded by a voxel of the model's neighbours on every
+// side.
+fn colour_volume_at(offset: u32, size: vec3<i32>, position: vec3<i32>) -> u32 {
+    let padded_size = size + 2;
+    let padded = clamp(position + 1, vec3(0), padded_size - 1);
+    return colour_volumes[offset + u32((padded.x * padded_size.y + padded.y) * padded_size.z + padded.z)];
+}
+
+// Ambient occlusion of the face corner a step along each of `step_u` and `step_v` from the middle
+// of the face of `front`, the voxel in front of it, matching face_ambient_occlusion in vxm_mesh.rs
+fn corner_ambient_occlusion(
+    offset: u32,
+    size: vec3<i32>,
+    front: vec3<i32>,
+    step_u: vec3<i32>,
+    step_v: vec3<i32>
+) -> f32 {
+    let side_1 = (colour_volume_at(offset, size, front + step_u) & 0xFFFFu) != 0u;
+    let side_2 = (colour_volume_at(offset, size, front + step_v) & 0xFFFFu) != 0u;
+    let corner = (colour_volume_at(offset, size, front + step_u + step_v) & 0xFFFFu) != 0u;
+    if (side_1 && side_2) {
+        return 0.0;
+    }
+    return f32(3u - u32(side_1) - u32(side_2) - u32(corner)) / 3.0;
+}
+
+// Quads of models with a colour volume span voxels of different colours, ambient occlusion and
+// light, so each fragment looks up the voxel it lies on and shades it from the voxels in front
+fn apply_colour_volume(vertex: VertexOutput) -> VertexOutput {
+    let model_colour = model_colours[vertex.model_index];
+    if (model_colour.z == NO_COLOUR_VOLUME) {
+      return vertex;
+    }
+
+    let size = vec3<i32>(
+      i32(model_colour.w & 0xFFu),
+      i32((model_colour.w >> 8u) & 0xFFu),
+      i32((model_colour.w >> 16u) & 0xFFu)
+    );
+    // Vertex normals point into the voxel, so half a step along them is inside it
+    let normal = vec3<i32>(round(vertex.normal));
+    let voxel = clamp(
+      vec3<i32>(floor(vertex.local_position + vertex.normal * 0.5)),
+      vec3(0),
+      size - vec3(1)
+    );
+    let front = voxel - normal;
+    let colour = colour_volume_at(model_colour.z, size, voxel) & 0xFFFFu;
+    let light = colour_volume_at(model_colour.z, size, front) >> 16u;
+
+    // The two axes the face lies on, and how far across the voxel's face the fragment is on each
+    var axis_u = vec3(1, 0, 0);
+    var axis_v = vec3(0, 1, 0);
+    if (normal.x != 0) {
+      axis_u = vec3(0, 1, 0);
+      axis_v = vec3(0, 0, 1);
+    } else if (normal.y != 0) {
+      axis_v = vec3(0, 0, 1);
+    }
+    let on_face = clamp(vertex.local_position - vec3<f32>(voxel), vec3(0.0), vec3(1.0));
+    let u = dot(on_face, vec3<f32>(axis_u));
+    let v = dot(on_face, vec3<f32>(axis_v));
+
+    // Blends the corners' occlusion across the face like the vertices of a single voxel quad
+    let ao = mix(
+      mix(
+        corner_ambient_occlusion(model_colour.z, size, front, -axis_u, -axis_v),
+        corner_ambient_occlusion(model_colour.z, size, front, axis_u, -axis_v),
+        u
+      ),
+      mix(
+        corner_ambient_occlusion(model_colour.z, size, front, -axis_u, axis_v),
+        corner_ambient_occlusion(model_colour.z, size, front, axis_u, axis_v),
+        u
+      ),
+      v
+    );
+    let ao_value = ao * light_brightness(light);
+
+    let hsl = shade_voxel_colour(decode_voxel_colour(colour, vertex.model_index), ao_value);
+    var output = vertex;
+    output.color = vec4(convert_hsl_to_rgb(hsl.x, hsl.y, hsl.z), 1.0);
+    output.hue = hsl.x;
+    output.saturation = hsl.y;
+    output.lightness = hsl.z;
+    output.ambient_occlusion = ao_value;
+    return output;
+}
+
 fn get_shadow_visibility(
     vertex: VertexOutput
 ) -> f32 {
@@ -380,7 +566,8 @@ fn apply_fog(
 }
 
 @fragment
-fn fs_main(vertex: VertexOutput) -> @location(0) vec4<f32> {
+fn fs_main(input: VertexOutput) -> @location(0) vec4<f32> {
+    let vertex = apply_colour_volume(input);
     let light_dir = normalize(vec3(1.0)); // Direction of the light source
     var view_dir = normalize(uniforms.camera_position.xyz - vertex.world_position.xyz);
 
@@ -398,16 +585,36 @@ fn fs_main(vertex: VertexOutput) -> @location(0) vec4<f32> {
 
    let fog_factor = apply_fog(vertex, view_dir);
 
+    var output_color = shadowed + apply_point_lights(vertex, view_dir);
+    output_color = mix(output_color, vec4(0.5, 0.5, 0.5, 1.0), vec4(fog_factor, 1.0)); // Apply fog effect
+
+    return output_color;
+}
 
-    // Debug
-    if(vertex.uv.x > 0.5){
-      return vec4(convert_hsl_to_rgb(vertex.hue, vertex.saturation, vertex.lightness), 1.0);
-    } else{
-      return vertex.color;
+// Tints what is behind transparent voxels with their colour. Upward facing surfaces such as water
+// get a rippled normal, bending the highlights and fresnel to fake refraction.
+@fragment
+fn fs_transparent(vertex: VertexOutput) -> @location(0) vec4<f32> {
+    let light_dir = normalize(vec3(1.0));
+    let view_dir = normalize(uniforms.camera_position.xyz - vertex.world_position.xyz);
+
+    // Vertex normals point into the voxel, see simple_lighting
+    var normal = -vertex.normal;
+    if (normal.y > 0.5) {
+      let p = vertex.world_position.xz;
+      normal = normalize(vec3(
+        sin(p.x * 0.7 + p.y * 0.3) * 0.08,
+        1.0,
+        cos(p.y * 0.9 - p.x * 0.2) * 0.08
+      ));
     }
 
-//    var output_color = shadowed + apply_point_lights(vertex, view_dir);
-//    output_color = mix(output_color, vec4(0.5, 0.5, 0.5, 1.0), vec4(fog_factor, 1.0)); // Apply fog effect
+    let fresnel = pow(1.0 - max(dot(view_dir, normal), 0.0), 5.0);
+    let specular = pow(max(dot(reflect(-light_dir, normal), view_dir), 0.0), 64.0);
+    let diffuse = max(dot(normal, light_dir), 0.0) * 0.5 + 0.5;
+
+    let colour = vertex.color.rgb * diffuse + vec3(specular);
+    let alpha = mix(vertex.opacity, 1.0, fresnel);
 
-//    return output_color;
-}
\ No newline at end of file
+    return vec4(colour, alpha);
+}
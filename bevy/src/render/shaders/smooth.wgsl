@@ -0,0 +1,38 @@
+struct Uniforms {
+  view_projection: mat4x4<f32>,
+  camera_position: vec4<f32>,
+}
+
+@group(0) @binding(0) var<storage, read> model_matrices: array<mat4x4<f32>>;
+@group(0) @binding(1) var<uniform> uniforms: Uniforms;
+
+struct Vertex {
+    @location(0) position: vec3<f32>,
+    @location(1) normal: vec3<f32>,
+    @location(2) colour: vec4<f32>,
+}
+
+struct VertexOutput {
+    @builtin(position) position: vec4<f32>,
+    @location(0) colour: vec4<f32>,
+    @location(1) normal: vec3<f32>,
+};
+
+// Each mesh is drawn as a single instance, whose index is the index of its model matrix
+@vertex
+fn vs_main(vertex: Vertex, @builtin(instance_index) model_index: u32) -> VertexOutput {
+    let model_matrix = model_matrices[model_index];
+
+    var output: VertexOutput;
+    output.position = uniforms.view_projection * model_matrix * vec4<f32>(vertex.position, 1.0);
+    output.colour = vertex.colour;
+    output.normal = normalize((model_matrix * vec4<f32>(vertex.normal, 0.0)).xyz);
+    return output;
+}
+
+@fragment
+fn fs_main(vertex: VertexOutput) -> @location(0) vec4<f32> {
+    let light_dir = normalize(vec3(1.0)); // Same light direction as the voxel faces
+    let diffuse = max(dot(normalize(vertex.normal), light_dir), 0.0) * 0.5 + 0.5;
+    return vec4(vertex.colour.rgb * diffuse, 1.0);
+}
@@ -1,30 +1,43 @@
-use crate::color_conversion::{convert_8bit_to_n_bits, convert_rgb_to_hsl_u8, create_hsl_voxel};
+use crate::color_conversion::{
+    convert_8bit_to_n_bits, convert_rgb_to_hsl_u8, encode_palette_colour, ColourEncoding,
+};
 use bevy::log::info;
 use bevy::prelude::*;
 use bevy::{
     asset::{io::Reader, AssetLoader, LoadContext},
     reflect::TypePath,
 };
+use serde::{Deserialize, Serialize};
 use std::convert::TryInto;
 use thiserror::Error;
 
-#[derive(Debug, Default, Clone)]
+#[derive(Debug, Default, Clone, PartialEq)]
 pub struct VxmVoxel {
     pub hsl: u16,
     pub emissive: bool,
+    /// 255 is fully opaque, anything less is meshed into the transparent pass
+    pub opacity: u8,
 }
 
 #[derive(Asset, TypePath)]
 pub struct VxmAsset {
     pub size: [u8; 3],
-    /// first bit air/solid, r, g, b, 5 bits
+    /// Packed 16 bit colours using `colour_encoding`, 0 being air
     pub voxel_array: Vec<Vec<Vec<VxmVoxel>>>,
     pub lights: Vec<VxmLight>,
+    pub colour_encoding: ColourEncoding,
+    /// Source colours of the model, indexed by [`ColourEncoding::Palette`] voxels
+    pub palette: Vec<[u8; 3]>,
 }
 
 #[derive(Default)]
 pub struct VxmAssetLoader;
 
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+pub struct VxmLoaderSettings {
+    pub colour_encoding: ColourEncoding,
+}
+
 pub struct VxmLight {
     pub min_pos: [u32; 3],
     pub max_pos: [u32; 3],
@@ -49,12 +62,12 @@ pub enum VxmAssetLoaderError {
 
 impl AssetLoader for VxmAssetLoader {
     type Asset = VxmAsset;
-    type Settings = ();
+    type Settings = VxmLoaderSettings;
     type Error = VxmAssetLoaderError;
     async fn load(
         &self,
         reader: &mut dyn Reader,
-        _settings: &(),
+        settings: &VxmLoaderSettings,
         _load_context: &mut LoadContext<'_>,
     ) -> Result<Self::Asset, Self::Error> {
         let mut bytes = Vec::new();
@@ -134,16 +147,22 @@ impl AssetLoader for VxmAssetLoader {
             let blue = reader.read_u8();
             let green = reader.read_u8();
             let red = reader.read_u8();
-            let _alpha = reader.read_u8();
+            let alpha = reader.read_u8();
             let emissive = reader.read_u8();
             palette.push(PaletteColor {
                 r: red,
                 g: green,
                 b: blue,
+                alpha,
                 emissive: emissive > 0,
             });
         }
 
+        let source_palette = palette
+            .iter()
+            .map(|colour| [colour.r, colour.g, colour.b])
+            .collect::<Vec<_>>();
+
         let max_layers = if version >= 12 { reader.read_u8() } else { 1 };
         let mut bounds_min = [u32::MAX, u32::MAX, u32::MAX];
         let mut bounds_max = [0, 0, 0];
@@ -226,13 +245,11 @@ impl AssetLoader for VxmAssetLoader {
             voxel.y -= bounds_min[1];
             voxel.z -= bounds_min[2];
             let colour = &palette[voxel.c as usize];
-            let r = colour.r as f32 / 255.0;
-            let g = colour.g as f32 / 255.0;
-            let b = colour.b as f32 / 255.0;
 
             voxel_array[voxel.x as usize][voxel.y as usize][voxel.z as usize] = VxmVoxel {
-                hsl: create_hsl_voxel(r, g, b),
+                hsl: encode_palette_colour(voxel.c, &source_palette, settings.colour_encoding),
                 emissive: colour.emissive,
+                opacity: colour.alpha,
             };
 
             if colour.emissive {
@@ -337,6 +354,8 @@ impl AssetLoader for VxmAssetLoader {
             size,
             voxel_array,
             lights,
+            colour_encoding: settings.colour_encoding,
+            palette: source_palette,
         })
     }
 
@@ -358,6 +377,7 @@ pub struct PaletteColor {
     pub r: u8,
     pub g: u8,
     pub b: u8,
+    pub alpha: u8,
     pub emissive: bool,
 }
 
@@ -0,0 +1,256 @@
+use serde::{Deserialize, Serialize};
+
+// How far apart in temperature and moisture biomes blend into each other
+const BIOME_BLEND_WIDTH: f32 = 0.15;
+
+/// A colour and how far it darkens per channel as the terrain colour noise rises
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct BiomePalette {
+    pub colour: [f32; 3],
+    pub variation: [f32; 3],
+}
+
+impl BiomePalette {
+    const fn new(colour: [f32; 3], variation: [f32; 3]) -> Self {
+        Self { colour, variation }
+    }
+
+    /// Colour of a voxel for colour noise between 0 and 1
+    pub fn colour_at(&self, colour_noise: f32) -> (f32, f32, f32) {
+        (
+            self.colour[0] - colour_noise * self.variation[0],
+            self.colour[1] - colour_noise * self.variation[1],
+            self.colour[2] - colour_noise * self.variation[2],
+        )
+    }
+
+    fn scaled(&self, weight: f32) -> Self {
+        Self::new(
+            self.colour.map(|channel| channel * weight),
+            self.variation.map(|channel| channel * weight),
+        )
+    }
+
+    fn add(&mut self, other: &Self) {
+        for channel in 0..3 {
+            self.colour[channel] += other.colour[channel];
+            self.variation[channel] += other.variation[channel];
+        }
+    }
+}
+
+/// How a biome shapes and colours the terrain, and the climate it grows in
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Biome {
+    pub name: String,
+    /// Climate the biome is strongest at, from -1 cold or dry to 1 hot or wet
+    pub temperature: f32,
+    pub moisture: f32,
+    /// Scales the hills of the heightfield, below 1 flattening them
+    pub height_scale: f32,
+    /// Raises or lowers the ground, as a fraction of the chunk height
+    pub height_offset: f32,
+    /// Topmost voxel of the ground
+    pub surface: BiomePalette,
+    /// Voxels under the surface, down to `subsurface_depth`
+    pub subsurface: BiomePalette,
+    pub subsurface_depth: f32,
+    /// Everything deeper than the subsurface
+    pub stone: BiomePalette,
+    /// Surface of the ground below [`SHORE_HEIGHT`], around and under the water
+    pub shore: BiomePalette,
+    /// Height above which the surface is snow, as a fraction of the chunk height
+    pub snow_line: f32,
+}
+
+/// Ground below this fraction of the chunk height is coloured as shore
+pub const SHORE_HEIGHT: f32 = 0.4;
+
+/// Surface above the snow line of any biome
+pub const SNOW: BiomePalette = BiomePalette::new([0.9, 0.9, 0.9], [0.1, 0.1, 0.1]);
+
+/// The biomes terrain is generated from, picked by temperature and moisture noise
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Biomes(pub Vec<Biome>);
+
+/// The biomes at a point, weighted by how close their climate is and mixed together
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BlendedBiome {
+    pub height_scale: f32,
+    pub height_offset: f32,
+    pub surface: BiomePalette,
+    pub subsurface: BiomePalette,
+    pub subsurface_depth: f32,
+    pub stone: BiomePalette,
+    pub shore: BiomePalette,
+    pub snow_line: f32,
+}
+
+impl Biomes {
+    /// The biome whose climate is closest to a temperature and moisture
+    pub fn nearest(&self, temperature: f32, moisture: f32) -> &Biome {
+        self.0
+            .iter()
+            .min_by(|a, b| {
+                let distance = |biome: &Biome| {
+                    (biome.temperature - temperature).powi(2) + (biome.moisture - moisture).powi(2)
+                };
+                distance(a).total_cmp(&distance(b))
+            })
+            .unwrap()
+    }
+
+    /// Blends the biomes nearest to a temperature and moisture, so terrain shape and colour
+    /// change smoothly across biome borders
+    pub fn blend(&self, temperature: f32, moisture: f32) -> BlendedBiome {
+        let distances = self
+            .0
+            .iter()
+            .map(|biome| {
+                (biome.temperature - temperature).powi(2) + (biome.moisture - moisture).powi(2)
+            })
+            .collect::<Vec<_>>();
+        // Measured from the nearest biome so its weight never underflows
+        let nearest = distances.iter().copied().fold(f32::INFINITY, f32::min);
+        let weights = distances
+            .iter()
+            .map(|distance| {
+                (-(distance - nearest) / (2.0 * BIOME_BLEND_WIDTH * BIOME_BLEND_WIDTH)).exp()
+            })
+            .collect::<Vec<_>>();
+        let total_weight = weights.iter().sum::<f32>();
+
+        let empty = BiomePalette::new([0.0; 3], [0.0; 3]);
+        let mut blended = BlendedBiome {
+            height_scale: 0.0,
+            height_offset: 0.0,
+            surface: empty,
+            subsurface: empty,
+            subsurface_depth: 0.0,
+            stone: empty,
+            shore: empty,
+            snow_line: 0.0,
+        };
+        for (biome, weight) in self.0.iter().zip(weights) {
+            let weight = weight / total_weight;
+            blended.height_scale += biome.height_scale * weight;
+            blended.height_offset += biome.height_offset * weight;
+            blended.surface.add(&biome.surface.scaled(weight));
+            blended.subsurface.add(&biome.subsurface.scaled(weight));
+            blended.subsurface_depth += biome.subsurface_depth * weight;
+            blended.stone.add(&biome.stone.scaled(weight));
+            blended.shore.add(&biome.shore.scaled(weight));
+            blended.snow_line += biome.snow_line * weight;
+        }
+        blended
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_biome(name: &str, temperature: f32, moisture: f32, shade: f32) -> Biome {
+        let palette = BiomePalette::new([shade; 3], [shade / 8.0; 3]);
+        Biome {
+            name: name.to_string(),
+            temperature,
+            moisture,
+            height_scale: shade * 2.0,
+            height_offset: shade - 0.5,
+            surface: palette,
+            subsurface: palette,
+            subsurface_depth: shade * 10.0,
+            stone: palette,
+            shore: palette,
+            snow_line: shade,
+        }
+    }
+
+    fn test_biomes() -> Biomes {
+        Biomes(vec![
+            test_biome("Cold", -0.7, 0.0, 0.2),
+            test_biome("Temperate", 0.0, 0.0, 0.5),
+            test_biome("Hot", 0.7, -0.7, 0.9),
+        ])
+    }
+
+    fn assert_close(a: f32, b: f32, what: &str) {
+        assert!((a - b).abs() < 1e-3, "{what} {a} is not {b}");
+    }
+
+    #[test]
+    fn the_same_climate_blends_the_same() {
+        let biomes = test_biomes();
+        for temperature in [-1.0, -0.35, 0.0, 0.42, 1.0] {
+            for moisture in [-1.0, -0.2, 0.0, 0.6] {
+                assert_eq!(
+                    biomes.blend(temperature, moisture),
+                    biomes.blend(temperature, moisture)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn a_lone_biome_blends_to_itself() {
+        let biome = test_biome("Only", 0.3, -0.4, 0.6);
+        let blended = Biomes(vec![biome.clone()]).blend(-0.9, 0.9);
+        assert_close(blended.height_scale, biome.height_scale, "height scale");
+        assert_close(blended.height_offset, biome.height_offset, "height offset");
+        assert_close(blended.snow_line, biome.snow_line, "snow line");
+        assert_close(
+            blended.subsurface_depth,
+            biome.subsurface_depth,
+            "subsurface depth",
+        );
+        for channel in 0..3 {
+            assert_close(
+                blended.surface.colour[channel],
+                biome.surface.colour[channel],
+                "surface colour",
+            );
+        }
+    }
+
+    #[test]
+    fn each_biome_dominates_at_its_own_climate() {
+        let biomes = test_biomes();
+        for biome in &biomes.0 {
+            let blended = biomes.blend(biome.temperature, biome.moisture);
+            assert_close(blended.height_scale, biome.height_scale, &biome.name);
+            assert_eq!(
+                biomes.nearest(biome.temperature, biome.moisture).name,
+                biome.name
+            );
+        }
+    }
+
+    #[test]
+    fn biomes_mix_evenly_halfway_between_them() {
+        let biomes = test_biomes();
+        let (cold, temperate) = (&biomes.0[0], &biomes.0[1]);
+        // The hot biome is far enough away not to weigh in
+        let blended = biomes.blend(-0.35, 0.0);
+        assert_close(
+            blended.height_scale,
+            (cold.height_scale + temperate.height_scale) / 2.0,
+            "height scale",
+        );
+    }
+
+    #[test]
+    fn blending_changes_smoothly_across_biome_borders() {
+        let biomes = test_biomes();
+        let mut previous = biomes.blend(-1.0, 0.0);
+        for step in 1..=2000 {
+            let blended = biomes.blend(-1.0 + step as f32 * 0.001, 0.0);
+            assert!(
+                (blended.height_scale - previous.height_scale).abs() < 0.01,
+                "height scale jumps at step {step}"
+            );
+            previous = blended;
+        }
+    }
+}
@@ -0,0 +1,609 @@
+use crate::color_conversion::{create_hsl_voxel, ColourEncoding};
+use crate::vxm::{PendingVxm, VxmAsset, VxmVoxel};
+use crate::vxm_biome::BiomePalette;
+use crate::vxm_rng::Rng;
+use bevy::asset::{AssetServer, Assets};
+use bevy::math::{IVec3, Quat, Vec3};
+use bevy::prelude::{Commands, Entity, Name, Transform};
+use serde::{Deserialize, Serialize};
+use std::f32::consts::FRAC_PI_2;
+
+// Attempts at finding a free spot for each piece of furniture before giving up on it
+const FURNITURE_ATTEMPTS: usize = 16;
+
+// Opacity of window glass, low enough to see into the building
+const GLASS_OPACITY: u8 = 96;
+
+// Largest footprint a building may roll, leaving room in a model for its roof overhang
+const MAX_FOOTPRINT: i32 = u8::MAX as i32 - 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum RoofStyle {
+    /// Level roof with a low wall around its edge
+    Flat,
+    /// Two slopes meeting at a ridge along the longer side, with the walls carried up to it at
+    /// the ends
+    Gabled,
+    /// Slopes on all four sides
+    Hipped,
+}
+
+/// Something placed on the floor of a building
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum FurnitureKind {
+    Table,
+    Stool,
+    Bed,
+    Shelf,
+    Barrel,
+    /// A .vxm model spawned as a child of the building, with the size in voxels it takes up
+    /// before being turned
+    Model {
+        path: String,
+        size: [i32; 3],
+    },
+}
+
+impl FurnitureKind {
+    fn size(&self) -> IVec3 {
+        match self {
+            FurnitureKind::Table => IVec3::new(3, 2, 2),
+            FurnitureKind::Stool => IVec3::new(1, 1, 1),
+            FurnitureKind::Bed => IVec3::new(2, 2, 4),
+            FurnitureKind::Shelf => IVec3::new(3, 3, 1),
+            FurnitureKind::Barrel => IVec3::new(2, 2, 2),
+            FurnitureKind::Model { size, .. } => IVec3::from(*size),
+        }
+    }
+
+    // Boxes the furniture is built from as their corner, size and whether they are cloth rather
+    // than wood
+    fn boxes(&self) -> Vec<(IVec3, IVec3, bool)> {
+        match self {
+            FurnitureKind::Table => vec![
+                (IVec3::new(0, 1, 0), IVec3::new(3, 1, 2), false),
+                (IVec3::new(0, 0, 0), IVec3::ONE, false),
+                (IVec3::new(2, 0, 0), IVec3::ONE, false),
+                (IVec3::new(0, 0, 1), IVec3::ONE, false),
+                (IVec3::new(2, 0, 1), IVec3::ONE, false),
+            ],
+            FurnitureKind::Stool => vec![(IVec3::ZERO, IVec3::ONE, false)],
+            FurnitureKind::Bed => vec![
+                (IVec3::ZERO, IVec3::new(2, 1, 4), false),
+                (IVec3::new(0, 1, 0), IVec3::new(2, 1, 4), true),
+            ],
+            FurnitureKind::Shelf => vec![
+                (IVec3::ZERO, IVec3::new(1, 3, 1), false),
+                (IVec3::new(2, 0, 0), IVec3::new(1, 3, 1), false),
+                (IVec3::new(1, 0, 0), IVec3::ONE, false),
+                (IVec3::new(1, 2, 0), IVec3::ONE, false),
+            ],
+            FurnitureKind::Barrel => vec![(IVec3::ZERO, IVec3::new(2, 2, 2), false)],
+            FurnitureKind::Model { .. } => Vec::new(),
+        }
+    }
+}
+
+/// Furniture to place on each floor of a building
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FurniturePlacement {
+    pub kind: FurnitureKind,
+    /// Most of the furniture on each floor, fewer where the floor runs out of room
+    pub per_floor: u32,
+}
+
+/// Rules a building is put together by. Ranges are smallest and largest, both included, with
+/// the seed picking within them.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BuildingSettings {
+    pub seed: u64,
+    /// Outside size in voxels along x
+    pub width: [i32; 2],
+    /// Outside size in voxels along z
+    pub depth: [i32; 2],
+    pub floors: [i32; 2],
+    /// Voxels from one floor to the next, including the floor itself
+    pub floor_height: i32,
+    /// Roof styles to pick from
+    pub roofs: Vec<RoofStyle>,
+    /// Voxels between the starts of windows along a wall
+    pub window_spacing: i32,
+    /// Chance of each spot along a wall having a window, from 0 to 1
+    pub window_chance: f32,
+    #[serde(default)]
+    pub furniture: Vec<FurniturePlacement>,
+    pub wall: BiomePalette,
+    /// Corner posts and the beams along each floor
+    pub trim: BiomePalette,
+    pub floor: BiomePalette,
+    pub roof: BiomePalette,
+    pub glass: BiomePalette,
+    pub wood: BiomePalette,
+    pub cloth: BiomePalette,
+}
+
+impl BuildingSettings {
+    /// Small timber framed house
+    pub fn cottage(seed: u64) -> Self {
+        Self {
+            seed,
+            width: [10, 14],
+            depth: [8, 12],
+            floors: [1, 2],
+            floor_height: 6,
+            roofs: vec![RoofStyle::Gabled, RoofStyle::Hipped],
+            window_spacing: 4,
+            window_chance: 0.6,
+            furniture: vec![
+                FurniturePlacement {
+                    kind: FurnitureKind::Bed,
+                    per_floor: 1,
+                },
+                FurniturePlacement {
+                    kind: FurnitureKind::Table,
+                    per_floor: 1,
+                },
+                FurniturePlacement {
+                    kind: FurnitureKind::Stool,
+                    per_floor: 2,
+                },
+                FurniturePlacement {
+                    kind: FurnitureKind::Shelf,
+                    per_floor: 1,
+                },
+            ],
+            wall: BiomePalette {
+                colour: [0.85, 0.8, 0.7],
+                variation: [0.0625, 0.0625, 0.0625],
+            },
+            trim: BiomePalette {
+                colour: [0.3, 0.2, 0.12],
+                variation: [0.03125, 0.03125, 0.03125],
+            },
+            floor: BiomePalette {
+                colour: [0.45, 0.32, 0.2],
+                variation: [0.0625, 0.0625, 0.03125],
+            },
+            roof: BiomePalette {
+                colour: [0.55, 0.25, 0.15],
+                variation: [0.09375, 0.0625, 0.03125],
+            },
+            glass: BiomePalette {
+                colour: [0.6, 0.75, 0.85],
+                variation: [0.0; 3],
+            },
+            wood: BiomePalette {
+                colour: [0.4, 0.28, 0.16],
+                variation: [0.0625, 0.0625, 0.03125],
+            },
+            cloth: BiomePalette {
+                colour: [0.6, 0.2, 0.2],
+                variation: [0.0625, 0.03125, 0.03125],
+            },
+        }
+    }
+
+    /// Large hall with tables, stools and barrels on every floor
+    pub fn tavern(seed: u64) -> Self {
+        Self {
+            width: [18, 24],
+            depth: [14, 18],
+            floors: [2, 3],
+            floor_height: 7,
+            roofs: vec![RoofStyle::Gabled],
+            window_spacing: 5,
+            window_chance: 0.8,
+            furniture: vec![
+                FurniturePlacement {
+                    kind: FurnitureKind::Table,
+                    per_floor: 4,
+                },
+                FurniturePlacement {
+                    kind: FurnitureKind::Stool,
+                    per_floor: 8,
+                },
+                FurniturePlacement {
+                    kind: FurnitureKind::Barrel,
+                    per_floor: 3,
+                },
+                FurniturePlacement {
+                    kind: FurnitureKind::Shelf,
+                    per_floor: 2,
+                },
+            ],
+            ..Self::cottage(seed)
+        }
+    }
+}
+
+/// A generated building, with its walls, floors, roof and built in furniture in one model and
+/// furniture models to spawn with it
+pub struct Building {
+    pub vxm: VxmAsset,
+    /// Paths of the furniture models and where they go relative to the building
+    pub furniture: Vec<(String, Transform)>,
+}
+
+// Voxels of a building being put together
+struct BuildingCanvas {
+    size: IVec3,
+    voxel_array: Vec<Vec<Vec<VxmVoxel>>>,
+}
+
+impl BuildingCanvas {
+    fn set(&mut self, position: IVec3, palette: &BiomePalette, rng: &mut Rng, opacity: u8) {
+        if position.cmplt(IVec3::ZERO).any() || position.cmpge(self.size).any() {
+            return;
+        }
+        let (r, g, b) = palette.colour_at(rng.next_f32());
+        self.voxel_array[position.x as usize][position.y as usize][position.z as usize] =
+            VxmVoxel {
+                hsl: create_hsl_voxel(r, g, b),
+                emissive: false,
+                opacity,
+            };
+    }
+
+    fn clear(&mut self, position: IVec3) {
+        self.voxel_array[position.x as usize][position.y as usize][position.z as usize] =
+            VxmVoxel::default();
+    }
+}
+
+/// Puts a building together from its settings, the same every time for the same settings.
+///
+/// The building stands on a floor at y 0, with a voxel of roof overhang around its walls. Its
+/// door is in the wall facing -z, and a ladder in the corner nearest the origin climbs through
+/// every floor.
+pub fn generate_building(settings: &BuildingSettings) -> Building {
+    let mut rng = Rng::new(settings.seed);
+    let width = rng
+        .range_inclusive(settings.width[0], settings.width[1])
+        .clamp(8, MAX_FOOTPRINT);
+    let depth = rng
+        .range_inclusive(settings.depth[0], settings.depth[1])
+        .clamp(8, MAX_FOOTPRINT);
+    let floor_height = settings.floor_height.max(4);
+    let roof = if settings.roofs.is_empty() {
+        RoofStyle::Flat
+    } else {
+        settings.roofs[rng.range_inclusive(0, settings.roofs.len() as i32 - 1) as usize]
+    };
+    // The walls end where the ceiling of the top floor is
+    let roof_height = match roof {
+        RoofStyle::Flat => 2,
+        RoofStyle::Gabled | RoofStyle::Hipped => (width.min(depth) + 3) / 2 + 1,
+    };
+    let max_floors = ((u8::MAX as i32 - roof_height) / floor_height).max(1);
+    let floors = rng
+        .range_inclusive(settings.floors[0], settings.floors[1])
+        .clamp(1, max_floors);
+    let top = floors * floor_height;
+
+    // The walls stand on x 1 to width and z 1 to depth, leaving a voxel each side for the roof
+    let size = IVec3::new(width + 2, top + roof_height, depth + 2);
+    let mut canvas = BuildingCanvas {
+        size,
+        voxel_array: vec![
+            vec![vec![VxmVoxel::default(); size.z as usize]; size.y as usize];
+            size.x as usize
+        ],
+    };
+    let (min, max) = (IVec3::new(1, 0, 1), IVec3::new(width, top, depth));
+    let is_wall = |x: i32, z: i32| x == min.x || x == max.x || z == min.z || z == max.z;
+    // The ladder and the hole it climbs through, kept clear of furniture
+    let ladder = IVec3::new(min.x + 1, 0, min.z + 1);
+    let is_stairwell = |x: i32, z: i32| {
+        (ladder.x..ladder.x + 2).contains(&x) && (ladder.z..ladder.z + 2).contains(&z)
+    };
+
+    for x in min.x..=max.x {
+        for z in min.z..=max.z {
+            for y in 0..=top {
+                let position = IVec3::new(x, y, z);
+                let is_floor = y % floor_height == 0;
+                if is_wall(x, z) {
+                    let is_corner = (x == min.x || x == max.x) && (z == min.z || z == max.z);
+                    let palette = if is_corner || is_floor {
+                        &settings.trim
+                    } else {
+                        &settings.wall
+                    };
+                    canvas.set(position, palette, &mut rng, u8::MAX);
+                } else if is_floor && (y == 0 || y == top || !is_stairwell(x, z)) {
+                    canvas.set(position, &settings.floor, &mut rng, u8::MAX);
+                }
+            }
+        }
+    }
+    for y in 1..top {
+        canvas.set(ladder.with_y(y), &settings.trim, &mut rng, u8::MAX);
+    }
+
+    // Door in the front wall, clear of the ladder
+    let door_x = rng.range_inclusive(min.x + 3, max.x - 3);
+    let door_height = (floor_height - 2).min(4);
+    for x in door_x..door_x + 2 {
+        for y in 1..=door_height {
+            canvas.clear(IVec3::new(x, y, min.z));
+        }
+    }
+
+    // Windows along every wall of every floor, two voxels square
+    let window_spacing = settings.window_spacing.max(3);
+    for floor in 0..floors {
+        let sill = floor * floor_height + 2;
+        let mut windows = Vec::new();
+        let mut along = 2;
+        while along + 2 < width - 1 {
+            windows.push((IVec3::new(min.x + along, sill, min.z), IVec3::X));
+            windows.push((IVec3::new(min.x + along, sill, max.z), IVec3::X));
+            along += window_spacing;
+        }
+        along = 2;
+        while along + 2 < depth - 1 {
+            windows.push((IVec3::new(min.x, sill, min.z + along), IVec3::Z));
+            windows.push((IVec3::new(max.x, sill, min.z + along), IVec3::Z));
+            along += window_spacing;
+        }
+        for (corner, direction) in windows {
+            let is_by_door = floor == 0
+                && corner.z == min.z
+                && direction == IVec3::X
+                && corner.x < door_x + 3
+                && corner.x + 2 > door_x - 1;
+            if is_by_door || rng.next_f32() >= settings.window_chance {
+                continue;
+            }
+            for step in 0..2 {
+                for y in 0..2 {
+                    let position = corner + direction * step + IVec3::Y * y;
+                    canvas.set(position, &settings.glass, &mut rng, GLASS_OPACITY);
+                }
+            }
+        }
+    }
+
+    match roof {
+        RoofStyle::Flat => {
+            for x in min.x..=max.x {
+                for z in min.z..=max.z {
+                    if is_wall(x, z) {
+                        canvas.set(IVec3::new(x, top + 1, z), &settings.roof, &mut rng, u8::MAX);
+                    }
+                }
+            }
+        }
+        RoofStyle::Gabled | RoofStyle::Hipped => {
+            // Slopes are two voxels thick so nothing shows through their steps
+            for layer in 0..roof_height - 1 {
+                let y = top + 1 + layer;
+                for x in 0..size.x {
+                    for z in 0..size.z {
+                        let from_x = x.min(size.x - 1 - x);
+                        let from_z = z.min(size.z - 1 - z);
+                        let from_edge = match roof {
+                            RoofStyle::Hipped => from_x.min(from_z),
+                            // Ridge along the longer side
+                            _ if width >= depth => from_z,
+                            _ => from_x,
+                        };
+                        let position = IVec3::new(x, y, z);
+                        if from_edge == layer || from_edge == layer + 1 {
+                            canvas.set(position, &settings.roof, &mut rng, u8::MAX);
+                        } else if from_edge > layer && roof == RoofStyle::Gabled {
+                            let is_gable = if width >= depth {
+                                x == min.x || x == max.x
+                            } else {
+                                z == min.z || z == max.z
+                            };
+                            if is_gable {
+                                canvas.set(position, &settings.wall, &mut rng, u8::MAX);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // Furniture goes anywhere inside that is clear of the walls, ladder and doorway
+    let mut furniture = Vec::new();
+    for floor in 0..floors {
+        let floor_y = floor * floor_height + 1;
+        let mut occupied = vec![false; (size.x * size.z) as usize];
+        for x in 0..size.x {
+            for z in 0..size.z {
+                let is_doorway = floor == 0 && (door_x..door_x + 2).contains(&x) && z <= min.z + 2;
+                if is_wall(x, z) || is_stairwell(x, z) || is_doorway {
+                    occupied[(x * size.z + z) as usize] = true;
+                }
+            }
+        }
+
+        for placement in &settings.furniture {
+            let unturned_size = placement.kind.size();
+            if unturned_size.y >= floor_height {
+                continue;
+            }
+            for _ in 0..placement.per_floor {
+                for _ in 0..FURNITURE_ATTEMPTS {
+                    let is_turned = rng.next_f32() < 0.5;
+                    let footprint = if is_turned {
+                        IVec3::new(unturned_size.z, unturned_size.y, unturned_size.x)
+                    } else {
+                        unturned_size
+                    };
+                    let x = rng.range_inclusive(min.x + 1, max.x - footprint.x);
+                    let z = rng.range_inclusive(min.z + 1, max.z - footprint.z);
+                    let cells = (x..x + footprint.x)
+                        .flat_map(|x| (z..z + footprint.z).map(move |z| (x * size.z + z) as usize))
+                        .collect::<Vec<_>>();
+                    if cells
+                        .iter()
+                        .any(|&cell| occupied.get(cell).is_none_or(|&occupied| occupied))
+                    {
+                        continue;
+                    }
+                    for cell in cells {
+                        occupied[cell] = true;
+                    }
+
+                    let corner = IVec3::new(x, floor_y, z);
+                    if let FurnitureKind::Model { path, .. } = &placement.kind {
+                        // Turning a quarter about y swings the model's x along -z, so it is
+                        // shifted back over its spot
+                        let transform = if is_turned {
+                            Transform::from_translation(
+                                corner.as_vec3() + Vec3::Z * unturned_size.x as f32,
+                            )
+                            .with_rotation(Quat::from_rotation_y(FRAC_PI_2))
+                        } else {
+                            Transform::from_translation(corner.as_vec3())
+                        };
+                        furniture.push((path.clone(), transform));
+                    }
+                    for (box_corner, box_size, is_cloth) in placement.kind.boxes() {
+                        let palette = if is_cloth {
+                            &settings.cloth
+                        } else {
+                            &settings.wood
+                        };
+                        for box_x in 0..box_size.x {
+                            for box_y in 0..box_size.y {
+                                for box_z in 0..box_size.z {
+                                    let local = box_corner + IVec3::new(box_x, box_y, box_z);
+                                    let local = if is_turned {
+                                        IVec3::new(local.z, local.y, local.x)
+                                    } else {
+                                        local
+                                    };
+                                    canvas.set(corner + local, palette, &mut rng, u8::MAX);
+                                }
+                            }
+                        }
+                    }
+                    break;
+                }
+            }
+        }
+    }
+
+    Building {
+        vxm: VxmAsset {
+            size: [size.x as u8, size.y as u8, size.z as u8],
+            voxel_array: canvas.voxel_array,
+            lights: Vec::new(),
+            colour_encoding: ColourEncoding::Hsl,
+            palette: Vec::new(),
+        },
+        furniture,
+    }
+}
+
+/// Generates a building and spawns it at `transform`, with its furniture models as children
+pub fn spawn_building(
+    commands: &mut Commands,
+    asset_server: &AssetServer,
+    vxm_assets: &mut Assets<VxmAsset>,
+    settings: &BuildingSettings,
+    transform: Transform,
+) -> Entity {
+    let building = generate_building(settings);
+    let mut entity = commands.spawn((
+        Name::new(format!("Building {}", settings.seed)),
+        PendingVxm(vxm_assets.add(building.vxm)),
+        transform,
+    ));
+    for (path, furniture_transform) in building.furniture {
+        entity.with_child((
+            Name::new(path.clone()),
+            PendingVxm(asset_server.load(path)),
+            furniture_transform,
+        ));
+    }
+    entity.id()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_solid(building: &Building, x: i32, y: i32, z: i32) -> bool {
+        building.vxm.voxel_array[x as usize][y as usize][z as usize].hsl != 0
+    }
+
+    #[test]
+    fn the_same_seed_builds_the_same_building() {
+        for building in [BuildingSettings::cottage, BuildingSettings::tavern] {
+            let first = generate_building(&building(21));
+            let second = generate_building(&building(21));
+            assert_eq!(first.vxm.size, second.vxm.size);
+            assert_eq!(first.vxm.voxel_array, second.vxm.voxel_array);
+            assert_eq!(first.furniture, second.furniture);
+            assert_ne!(
+                first.vxm.voxel_array,
+                generate_building(&building(22)).vxm.voxel_array
+            );
+        }
+    }
+
+    #[test]
+    fn buildings_stand_on_a_whole_floor_within_their_size_ranges() {
+        for seed in 0..16 {
+            let settings = BuildingSettings::tavern(seed);
+            let building = generate_building(&settings);
+            let [size_x, _, size_z] = building.vxm.size.map(i32::from);
+            let (width, depth) = (size_x - 2, size_z - 2);
+            assert!((settings.width[0]..=settings.width[1]).contains(&width));
+            assert!((settings.depth[0]..=settings.depth[1]).contains(&depth));
+            for x in 1..=width {
+                for z in 1..=depth {
+                    assert!(is_solid(&building, x, 0, z), "floor at {x} {z}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn the_door_opens_in_the_front_wall() {
+        for seed in 0..16 {
+            let settings = BuildingSettings::cottage(seed);
+            let building = generate_building(&settings);
+            let width = building.vxm.size[0] as i32 - 2;
+            let door_height = (settings.floor_height - 2).min(4);
+            let door_columns = (1..=width)
+                .filter(|&x| (1..=door_height).all(|y| !is_solid(&building, x, y, 1)))
+                .count();
+            assert_eq!(door_columns, 2, "seed {seed}");
+        }
+    }
+
+    #[test]
+    fn furniture_models_are_placed_inside_the_walls() {
+        let settings = BuildingSettings {
+            furniture: vec![FurniturePlacement {
+                kind: FurnitureKind::Model {
+                    path: "chest.vxm".to_string(),
+                    size: [3, 2, 2],
+                },
+                per_floor: 2,
+            }],
+            ..BuildingSettings::tavern(8)
+        };
+        let building = generate_building(&settings);
+        let [size_x, size_y, size_z] = building.vxm.size.map(f32::from);
+        assert!(!building.furniture.is_empty());
+        for (path, transform) in &building.furniture {
+            assert_eq!(path, "chest.vxm");
+            // The corner the model is turned about, then its far corner
+            for corner in [Vec3::ZERO, Vec3::new(3.0, 2.0, 2.0)] {
+                // Off by a little float error when the model is turned
+                let placed = transform.transform_point(corner).round();
+                assert!(placed.x >= 2.0 && placed.x <= size_x - 2.0, "{placed}");
+                assert!(placed.z >= 2.0 && placed.z <= size_z - 2.0, "{placed}");
+                assert!(placed.y >= 1.0 && placed.y < size_y, "{placed}");
+            }
+        }
+    }
+}
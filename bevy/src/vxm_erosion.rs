@@ -0,0 +1,337 @@
+use crate::vxm_biome::BiomePalette;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, OnceLock};
+
+// How many columns away each iteration reads from. Flow into a column depends on the outflows of
+// its neighbours, which depend on their own neighbours, and slumping reads one column further.
+const REACH_PER_ITERATION: i32 = 3;
+
+// Share of the material above the talus slope that slumps to each lower neighbour per iteration
+const THERMAL_RATE: f32 = 0.125;
+
+const NEIGHBOURS: [(i32, i32); 4] = [(-1, 0), (1, 0), (0, -1), (0, 1)];
+
+// Columns of chunks kept eroded, enough for every column around the camera to be generated
+// top to bottom before it is dropped
+const CACHED_COLUMNS: usize = 64;
+
+/// Rain washing the heightfield down into valleys and riverbeds, and loose ground slumping off
+/// slopes steeper than it can rest on
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ErosionSettings {
+    pub iterations: i32,
+    /// Water added to every column each iteration, in voxels
+    pub rain: f32,
+    /// Sediment water can carry per voxel of water flowing out of a column
+    pub sediment_capacity: f32,
+    /// Share of the spare capacity of flowing water picked up from the ground each iteration
+    pub erosion_rate: f32,
+    /// Share of the sediment over capacity dropped each iteration
+    pub deposition_rate: f32,
+    /// Share of the water that evaporates each iteration
+    pub evaporation: f32,
+    /// Steepest slope loose ground rests at, in voxels of height per voxel
+    pub talus: f32,
+    /// Colour of the sediment dropped in valleys and riverbeds
+    pub sediment: BiomePalette,
+}
+
+impl ErosionSettings {
+    /// Columns around a region that must be eroded along with it for the region to come out the
+    /// same whatever region it is part of
+    pub fn reach(&self) -> i32 {
+        self.iterations.max(0) * REACH_PER_ITERATION
+    }
+}
+
+/// Erodes a square heightfield of `size` columns in place, returning the depth of sediment
+/// dropped on each column.
+///
+/// Every iteration reads the whole heightfield before writing any of it, so each column only
+/// depends on the columns within [`ErosionSettings::reach`] of it. Columns closer than that to
+/// the edge see no ground beyond it and come out wrong, so callers pad the region they need.
+pub fn erode(heights: &mut [f32], size: i32, settings: &ErosionSettings) -> Vec<f32> {
+    let column_count = (size * size) as usize;
+    let original_heights = heights.to_vec();
+    let mut water = vec![0.0f32; column_count];
+    let mut sediment = vec![0.0f32; column_count];
+    let mut outflows = vec![[0.0f32; 4]; column_count];
+    let mut next = vec![0.0f32; column_count];
+    let mut next_water = vec![0.0f32; column_count];
+    let mut next_sediment = vec![0.0f32; column_count];
+
+    let neighbour = |index: usize, (dx, dz): (i32, i32)| {
+        let x = index as i32 / size + dx;
+        let z = index as i32 % size + dz;
+        (x >= 0 && x < size && z >= 0 && z < size).then(|| (x * size + z) as usize)
+    };
+
+    for _ in 0..settings.iterations {
+        for water in &mut water {
+            *water += settings.rain;
+        }
+
+        // Water runs to lower neighbours, at most half the drop so it levels out rather than
+        // sloshing back and forth
+        for index in 0..column_count {
+            let surface = heights[index] + water[index];
+            let mut drops = [0.0; 4];
+            for (direction, &offset) in NEIGHBOURS.iter().enumerate() {
+                if let Some(neighbour) = neighbour(index, offset) {
+                    drops[direction] = (surface - heights[neighbour] - water[neighbour]).max(0.0);
+                }
+            }
+            let total_drop = drops.iter().sum::<f32>();
+            let total_outflow = water[index].min(total_drop * 0.5);
+            outflows[index] = if total_drop > 0.0 {
+                drops.map(|drop| total_outflow * drop / total_drop)
+            } else {
+                [0.0; 4]
+            };
+        }
+
+        for index in 0..column_count {
+            let total_outflow = outflows[index].iter().sum::<f32>();
+            let carried_share = if water[index] > 0.0 {
+                total_outflow / water[index]
+            } else {
+                0.0
+            };
+            let mut water_left = water[index] - total_outflow;
+            let mut sediment_left = sediment[index] * (1.0 - carried_share);
+            for (direction, &(dx, dz)) in NEIGHBOURS.iter().enumerate() {
+                let Some(neighbour) = neighbour(index, (dx, dz)) else {
+                    continue;
+                };
+                // The neighbour's flow back towards this column is in the opposite direction
+                let inflow = outflows[neighbour][direction ^ 1];
+                if inflow > 0.0 {
+                    water_left += inflow;
+                    sediment_left += sediment[neighbour] * inflow / water[neighbour];
+                }
+            }
+
+            // Faster water carries more sediment, picking it up from the ground or dropping it
+            let capacity = settings.sediment_capacity * total_outflow;
+            if sediment_left > capacity {
+                let deposited = (sediment_left - capacity) * settings.deposition_rate;
+                next[index] = heights[index] + deposited;
+                sediment_left -= deposited;
+            } else {
+                let eroded = (capacity - sediment_left) * settings.erosion_rate;
+                next[index] = heights[index] - eroded;
+                sediment_left += eroded;
+            }
+
+            next_water[index] = water_left * (1.0 - settings.evaporation);
+            next_sediment[index] = sediment_left;
+        }
+        heights.copy_from_slice(&next);
+        water.copy_from_slice(&next_water);
+        sediment.copy_from_slice(&next_sediment);
+
+        for index in 0..column_count {
+            let mut slumped = 0.0;
+            for &offset in &NEIGHBOURS {
+                let Some(neighbour) = neighbour(index, offset) else {
+                    continue;
+                };
+                let slope = heights[index] - heights[neighbour];
+                if slope > settings.talus {
+                    slumped -= (slope - settings.talus) * THERMAL_RATE;
+                } else if -slope > settings.talus {
+                    slumped += (-slope - settings.talus) * THERMAL_RATE;
+                }
+            }
+            next[index] = heights[index] + slumped;
+        }
+        heights.copy_from_slice(&next);
+    }
+
+    // Whatever the water still carries settles where it is
+    for index in 0..column_count {
+        heights[index] += sediment[index];
+    }
+    heights
+        .iter()
+        .zip(original_heights)
+        .map(|(height, original)| (height - original).max(0.0))
+        .collect()
+}
+
+/// A padded heightfield after erosion, with the depth of sediment dropped on each column
+pub struct ErodedColumn {
+    pub heights: Vec<f32>,
+    pub sediment: Vec<f32>,
+}
+
+/// Eroded heightfields of the columns of chunks most recently generated, by chunk column.
+///
+/// Every chunk stacked in a column erodes the same padded heightfield, so it is eroded once
+/// for the whole column. Chunks of a column generating at the same time wait for the first of
+/// them to erode it rather than each eroding it again.
+#[derive(Default)]
+pub struct ErosionCache {
+    columns: Mutex<VecDeque<((i32, i32), CachedColumn)>>,
+}
+
+// Filled by the first chunk of the column to need it
+type CachedColumn = Arc<OnceLock<Arc<ErodedColumn>>>;
+
+impl ErosionCache {
+    /// The eroded heightfield of a column of chunks, from `erode` if it isn't cached
+    pub fn get_or_erode(
+        &self,
+        column: (i32, i32),
+        erode: impl FnOnce() -> ErodedColumn,
+    ) -> Arc<ErodedColumn> {
+        let eroded = {
+            let mut columns = self.columns.lock().unwrap();
+            match columns.iter().find(|(cached, _)| *cached == column) {
+                Some((_, eroded)) => eroded.clone(),
+                None => {
+                    if columns.len() == CACHED_COLUMNS {
+                        columns.pop_front();
+                    }
+                    let eroded = Arc::new(OnceLock::new());
+                    columns.push_back((column, eroded.clone()));
+                    eroded
+                }
+            }
+        };
+        // Eroded outside the lock so other columns aren't held up
+        eroded.get_or_init(|| Arc::new(erode())).clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    fn test_settings(iterations: i32) -> ErosionSettings {
+        ErosionSettings {
+            iterations,
+            rain: 0.02,
+            sediment_capacity: 0.5,
+            erosion_rate: 0.3,
+            deposition_rate: 0.3,
+            evaporation: 0.05,
+            talus: 1.5,
+            sediment: BiomePalette {
+                colour: [0.45, 0.38, 0.28],
+                variation: [0.0; 3],
+            },
+        }
+    }
+
+    // Rolling hills over a square of world columns starting at `origin`, laid out x, then z
+    fn hills(origin: [i32; 2], size: i32) -> Vec<f32> {
+        (0..size)
+            .flat_map(|x| (0..size).map(move |z| (origin[0] + x, origin[1] + z)))
+            .map(|(x, z)| {
+                let (x, z) = (x as f32, z as f32);
+                40.0 + 12.0 * (x * 0.21).sin() * (z * 0.17).cos()
+                    + 5.0 * (x * 0.05 + z * 0.09).sin()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn the_same_heightfield_erodes_the_same() {
+        let settings = test_settings(8);
+        let mut first = hills([0, 0], 32);
+        let mut second = first.clone();
+        let first_sediment = erode(&mut first, 32, &settings);
+        let second_sediment = erode(&mut second, 32, &settings);
+        assert_eq!(first, second);
+        assert_eq!(first_sediment, second_sediment);
+    }
+
+    #[test]
+    fn level_ground_doesnt_erode() {
+        let mut heights = vec![20.0; 16 * 16];
+        let sediment = erode(&mut heights, 16, &test_settings(8));
+        assert!(heights.iter().all(|&height| height == 20.0));
+        assert!(sediment.iter().all(|&depth| depth == 0.0));
+    }
+
+    #[test]
+    fn rain_wears_hills_down_and_drops_sediment_in_valleys() {
+        let original = hills([0, 0], 32);
+        let mut heights = original.clone();
+        let sediment = erode(&mut heights, 32, &test_settings(16));
+
+        assert!(sediment.iter().all(|&depth| depth >= 0.0));
+        assert!(sediment.iter().any(|&depth| depth > 0.0));
+        let highest = |heights: &[f32]| heights.iter().copied().fold(f32::MIN, f32::max);
+        assert!(highest(&heights) < highest(&original));
+        // Sediment is only reported where the ground ended up higher
+        for ((height, original), depth) in heights.iter().zip(&original).zip(&sediment) {
+            assert_eq!(*depth, (height - original).max(0.0));
+        }
+    }
+
+    #[test]
+    fn padded_regions_erode_the_same_as_the_ground_around_them() {
+        let settings = test_settings(4);
+        let reach = settings.reach();
+        let whole_size = 96;
+        let mut whole = hills([0, 0], whole_size);
+        let whole_sediment = erode(&mut whole, whole_size, &settings);
+
+        // A region in the middle of the whole heightfield, padded by the reach on every side
+        let (region_origin, region_size) = (40, 16);
+        let padded_size = region_size + 2 * reach;
+        let padded_origin = region_origin - reach;
+        let mut padded = hills([padded_origin; 2], padded_size);
+        let padded_sediment = erode(&mut padded, padded_size, &settings);
+
+        for x in 0..region_size {
+            for z in 0..region_size {
+                let padded_i = ((x + reach) * padded_size + z + reach) as usize;
+                let whole_i = ((x + region_origin) * whole_size + z + region_origin) as usize;
+                assert_eq!(padded[padded_i], whole[whole_i], "column {x} {z}");
+                assert_eq!(padded_sediment[padded_i], whole_sediment[whole_i]);
+            }
+        }
+    }
+
+    #[test]
+    fn each_column_is_eroded_once() {
+        let cache = ErosionCache::default();
+        let erosions = Cell::new(0);
+        let erode = || {
+            erosions.set(erosions.get() + 1);
+            ErodedColumn {
+                heights: vec![1.0],
+                sediment: vec![0.0],
+            }
+        };
+        cache.get_or_erode((0, 0), erode);
+        cache.get_or_erode((0, 0), erode);
+        cache.get_or_erode((0, 1), erode);
+        assert_eq!(erosions.get(), 2);
+    }
+
+    #[test]
+    fn the_longest_cached_columns_are_dropped_first() {
+        let cache = ErosionCache::default();
+        let erosions = Cell::new(0);
+        let erode = || {
+            erosions.set(erosions.get() + 1);
+            ErodedColumn {
+                heights: Vec::new(),
+                sediment: Vec::new(),
+            }
+        };
+        for x in 0..=CACHED_COLUMNS as i32 {
+            cache.get_or_erode((x, 0), erode);
+        }
+        cache.get_or_erode((CACHED_COLUMNS as i32, 0), erode);
+        assert_eq!(erosions.get(), CACHED_COLUMNS + 1);
+        cache.get_or_erode((0, 0), erode);
+        assert_eq!(erosions.get(), CACHED_COLUMNS + 2);
+    }
+}
@@ -0,0 +1,435 @@
+use crate::vxm::{VxmAsset, VxmVoxel};
+use crate::vxm_mesh::{is_opaque_voxel, VoxelNeighbourhood};
+use bevy::asset::AssetId;
+use bevy::prelude::Resource;
+use std::collections::{HashMap, VecDeque};
+
+/// Brightest light level, of emissive voxels and of voxels open to the sky
+pub const MAX_LIGHT: u8 = 15;
+
+/// Packed light of voxels beyond every lit model, which are taken to be open to the sky
+pub const OPEN_SKY_LIGHT: u8 = MAX_LIGHT << 4;
+
+// Directions light spreads in from a voxel to its neighbours
+const DIRECTIONS: [[i32; 3]; 6] = [
+    [1, 0, 0],
+    [-1, 0, 0],
+    [0, 1, 0],
+    [0, -1, 0],
+    [0, 0, 1],
+    [0, 0, -1],
+];
+
+const DOWN: [i32; 3] = [0, -1, 0];
+
+/// How bright a face is for its packed block and sky light, from about 0.04 in the dark to 1.
+/// Matches `light_brightness` in shader.wgsl.
+pub fn light_brightness(light: u8) -> f32 {
+    let level = (light & MAX_LIGHT).max(light >> 4);
+    0.8f32.powi((MAX_LIGHT - level) as i32)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum LightChannel {
+    /// Light given off by emissive voxels
+    Block,
+    /// Light falling from the sky, which passes straight down at full strength
+    Sky,
+}
+
+impl LightChannel {
+    fn shift(self) -> u8 {
+        match self {
+            LightChannel::Block => 0,
+            LightChannel::Sky => 4,
+        }
+    }
+
+    // Level a voxel gets from a neighbour of `level` in `direction` from it
+    fn spread(self, level: u8, direction: [i32; 3]) -> u8 {
+        if self == LightChannel::Sky && direction == DOWN && level == MAX_LIGHT {
+            MAX_LIGHT
+        } else {
+            level.saturating_sub(1)
+        }
+    }
+}
+
+// What a voxel does to light, kept to find the voxels an edit changed
+const OPAQUE: u8 = 1;
+const EMISSIVE: u8 = 2;
+
+fn light_flags(voxel: &VxmVoxel) -> u8 {
+    (is_opaque_voxel(voxel) as u8 * OPAQUE) | (voxel.emissive as u8 * EMISSIVE)
+}
+
+/// Block and sky light of every voxel of a model, spread from emissive voxels and the sky a
+/// level at a time through every voxel that isn't opaque, as in Minecraft.
+///
+/// Each voxel's light is packed into a byte, block light in the low 4 bits and sky light in
+/// the high 4. Light spreads in from the lit models of the neighbourhood across the model's
+/// borders, and sunlight from beyond them where nothing is lit, but not back out into them.
+#[derive(PartialEq)]
+pub struct LightVolume {
+    size: [usize; 3],
+    light: Vec<u8>,
+    flags: Vec<u8>,
+}
+
+impl LightVolume {
+    /// Lights a model from scratch
+    pub fn compute(neighbourhood: &VoxelNeighbourhood) -> Self {
+        let vxm = neighbourhood.centre;
+        let size = vxm.size.map(usize::from);
+        let mut volume = Self {
+            size,
+            light: vec![0; size[0] * size[1] * size[2]],
+            flags: Self::flags_of(vxm),
+        };
+
+        for channel in [LightChannel::Block, LightChannel::Sky] {
+            let mut queue = VecDeque::new();
+            for x in 0..size[0] {
+                for y in 0..size[1] {
+                    for z in 0..size[2] {
+                        let position = [x, y, z];
+                        let level = volume.seed_level(neighbourhood, position, channel);
+                        if level > 0 {
+                            volume.set_level(position, channel, level);
+                            queue.push_back(position);
+                        }
+                    }
+                }
+            }
+            volume.spread(channel, queue);
+        }
+        volume
+    }
+
+    /// Relights the voxels around those that have become or stopped being opaque or emissive
+    /// since the model was last lit, returning whether any had.
+    ///
+    /// Light is first taken away from everything the changed voxels lit, then spread back in
+    /// from the edge of the darkened area, so only the voxels the change reaches are visited.
+    pub fn update(&mut self, neighbourhood: &VoxelNeighbourhood) -> bool {
+        let flags = Self::flags_of(neighbourhood.centre);
+        let mut changed = Vec::new();
+        for x in 0..self.size[0] {
+            for y in 0..self.size[1] {
+                for z in 0..self.size[2] {
+                    let index = self.index([x, y, z]);
+                    if flags[index] != self.flags[index] {
+                        changed.push([x, y, z]);
+                    }
+                }
+            }
+        }
+        self.flags = flags;
+        if changed.is_empty() {
+            return false;
+        }
+
+        for channel in [LightChannel::Block, LightChannel::Sky] {
+            let mut removal = VecDeque::new();
+            let mut darkened = Vec::new();
+            let mut relight = VecDeque::new();
+            for &position in &changed {
+                removal.push_back((position, self.level(position, channel)));
+                self.set_level(position, channel, 0);
+                darkened.push(position);
+            }
+
+            while let Some((position, level)) = removal.pop_front() {
+                for direction in DIRECTIONS {
+                    let Some(neighbour) = self.neighbour(position, direction) else {
+                        continue;
+                    };
+                    let neighbour_level = self.level(neighbour, channel);
+                    if neighbour_level == 0 {
+                        continue;
+                    }
+                    // Anything dimmer was lit from here, anything as bright is lit from elsewhere
+                    // and spreads back into the darkened voxels
+                    let is_lit_from_here =
+                        neighbour_level < level || channel.spread(level, direction) == MAX_LIGHT;
+                    if is_lit_from_here {
+                        self.set_level(neighbour, channel, 0);
+                        removal.push_back((neighbour, neighbour_level));
+                        darkened.push(neighbour);
+                    } else {
+                        relight.push_back(neighbour);
+                    }
+                }
+            }
+
+            for position in darkened {
+                let level = self.seed_level(neighbourhood, position, channel);
+                if level > self.level(position, channel) {
+                    self.set_level(position, channel, level);
+                    relight.push_back(position);
+                }
+            }
+            self.spread(channel, relight);
+        }
+        true
+    }
+
+    /// Packed light of a voxel of the model
+    pub fn light_at(&self, position: [usize; 3]) -> u8 {
+        self.light[self.index(position)]
+    }
+
+    fn flags_of(vxm: &VxmAsset) -> Vec<u8> {
+        vxm.voxel_array
+            .iter()
+            .flatten()
+            .flatten()
+            .map(light_flags)
+            .collect()
+    }
+
+    fn index(&self, [x, y, z]: [usize; 3]) -> usize {
+        (x * self.size[1] + y) * self.size[2] + z
+    }
+
+    fn level(&self, position: [usize; 3], channel: LightChannel) -> u8 {
+        (self.light[self.index(position)] >> channel.shift()) & MAX_LIGHT
+    }
+
+    fn set_level(&mut self, position: [usize; 3], channel: LightChannel, level: u8) {
+        let index = self.index(position);
+        let shift = channel.shift();
+        self.light[index] = (self.light[index] & !(MAX_LIGHT << shift)) | level << shift;
+    }
+
+    fn is_opaque(&self, position: [usize; 3]) -> bool {
+        self.flags[self.index(position)] & OPAQUE != 0
+    }
+
+    // Neighbouring voxel within the model
+    fn neighbour(&self, position: [usize; 3], direction: [i32; 3]) -> Option<[usize; 3]> {
+        let mut neighbour = [0; 3];
+        for axis in 0..3 {
+            let coordinate = position[axis] as i32 + direction[axis];
+            if coordinate < 0 || coordinate >= self.size[axis] as i32 {
+                return None;
+            }
+            neighbour[axis] = coordinate as usize;
+        }
+        Some(neighbour)
+    }
+
+    // Light a voxel gets before spreading, from being emissive or from beyond the model's
+    // borders. Where nothing is lit beyond them it is open sky, except below.
+    fn seed_level(
+        &self,
+        neighbourhood: &VoxelNeighbourhood,
+        position: [usize; 3],
+        channel: LightChannel,
+    ) -> u8 {
+        let flags = self.flags[self.index(position)];
+        let mut level = if channel == LightChannel::Block && flags & EMISSIVE != 0 {
+            MAX_LIGHT
+        } else {
+            0
+        };
+        if flags & OPAQUE != 0 {
+            return level;
+        }
+
+        for direction in DIRECTIONS {
+            if self.neighbour(position, direction).is_some() {
+                continue;
+            }
+            let outside = [0, 1, 2].map(|axis| position[axis] as i32 + direction[axis]);
+            let outside_level = match neighbourhood.light_at(outside[0], outside[1], outside[2]) {
+                Some(light) => (light >> channel.shift()) & MAX_LIGHT,
+                None if channel == LightChannel::Sky && direction != DOWN => MAX_LIGHT,
+                None => 0,
+            };
+            // Light comes in travelling the opposite way to the direction looked in
+            let incoming = [0, 1, 2].map(|axis| -direction[axis]);
+            level = level.max(channel.spread(outside_level, incoming));
+        }
+        level
+    }
+
+    // Spreads light outwards from the queued voxels until it fades out or meets brighter light
+    fn spread(&mut self, channel: LightChannel, mut queue: VecDeque<[usize; 3]>) {
+        while let Some(position) = queue.pop_front() {
+            let level = self.level(position, channel);
+            for direction in DIRECTIONS {
+                let Some(neighbour) = self.neighbour(position, direction) else {
+                    continue;
+                };
+                if self.is_opaque(neighbour) {
+                    continue;
+                }
+                let spread_level = channel.spread(level, direction);
+                if spread_level > self.level(neighbour, channel) {
+                    self.set_level(neighbour, channel, spread_level);
+                    queue.push_back(neighbour);
+                }
+            }
+        }
+    }
+}
+
+/// Light of every meshed voxel model, by the model it lights
+#[derive(Resource, Default)]
+pub struct VoxelLightStore(pub HashMap<AssetId<VxmAsset>, LightVolume>);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color_conversion::ColourEncoding;
+    use crate::vxm_rng::Rng;
+
+    const STONE: VxmVoxel = VxmVoxel {
+        hsl: 0x8421,
+        emissive: false,
+        opacity: u8::MAX,
+    };
+
+    const TORCH: VxmVoxel = VxmVoxel {
+        hsl: 0x8c61,
+        emissive: true,
+        opacity: u8::MAX,
+    };
+
+    fn test_model(size: [usize; 3], mut voxel_at: impl FnMut([usize; 3]) -> VxmVoxel) -> VxmAsset {
+        let mut voxel_array = vec![vec![vec![VxmVoxel::default(); size[2]]; size[1]]; size[0]];
+        for (x, column) in voxel_array.iter_mut().enumerate() {
+            for (y, row) in column.iter_mut().enumerate() {
+                for (z, voxel) in row.iter_mut().enumerate() {
+                    *voxel = voxel_at([x, y, z]);
+                }
+            }
+        }
+        VxmAsset {
+            size: size.map(|size| size as u8),
+            voxel_array,
+            lights: Vec::new(),
+            colour_encoding: ColourEncoding::Hsl,
+            palette: Vec::new(),
+        }
+    }
+
+    fn block_light(volume: &LightVolume, position: [usize; 3]) -> u8 {
+        volume.light_at(position) & MAX_LIGHT
+    }
+
+    fn sky_light(volume: &LightVolume, position: [usize; 3]) -> u8 {
+        volume.light_at(position) >> 4
+    }
+
+    fn positions(size: [usize; 3]) -> impl Iterator<Item = [usize; 3]> {
+        (0..size[0])
+            .flat_map(move |x| (0..size[1]).flat_map(move |y| (0..size[2]).map(move |z| [x, y, z])))
+    }
+
+    #[test]
+    fn a_torch_lights_a_sealed_room() {
+        let size = [11, 11, 11];
+        let torch = [5, 5, 5];
+        let vxm = test_model(size, |position| {
+            if position == torch {
+                TORCH
+            } else if position.iter().any(|&p| p == 0 || p == 10) {
+                STONE
+            } else {
+                VxmVoxel::default()
+            }
+        });
+        let volume = LightVolume::compute(&VoxelNeighbourhood::isolated(&vxm));
+
+        assert_eq!(block_light(&volume, torch), MAX_LIGHT);
+        for position in positions(size) {
+            if position == torch {
+                continue;
+            }
+            if is_opaque_voxel(&vxm.voxel_array[position[0]][position[1]][position[2]]) {
+                assert_eq!(volume.light_at(position), 0, "wall at {position:?} is lit");
+                continue;
+            }
+            let distance = (0..3)
+                .map(|axis| position[axis].abs_diff(torch[axis]) as u8)
+                .sum::<u8>();
+            assert_eq!(
+                block_light(&volume, position),
+                MAX_LIGHT - distance,
+                "torch light at {position:?}"
+            );
+            assert_eq!(sky_light(&volume, position), 0, "sky light at {position:?}");
+        }
+    }
+
+    #[test]
+    fn caves_stay_dark_under_sky_light() {
+        let size = [12, 16, 12];
+        let ground = 10;
+        let is_cave = |[x, y, z]: [usize; 3]| {
+            (3..9).contains(&x) && (2..6).contains(&y) && (3..9).contains(&z)
+        };
+        let vxm = test_model(size, |position| {
+            if position[1] < ground && !is_cave(position) {
+                STONE
+            } else {
+                VxmVoxel::default()
+            }
+        });
+        let volume = LightVolume::compute(&VoxelNeighbourhood::isolated(&vxm));
+
+        for position in positions(size) {
+            if is_cave(position) {
+                assert_eq!(volume.light_at(position), 0, "cave at {position:?} is lit");
+            } else if position[1] >= ground {
+                assert_eq!(
+                    sky_light(&volume, position),
+                    MAX_LIGHT,
+                    "open sky at {position:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn updating_after_edits_matches_lighting_from_scratch() {
+        let mut rng = Rng::new(50);
+        for _ in 0..20 {
+            let size = [
+                rng.range_inclusive(4, 14) as usize,
+                rng.range_inclusive(4, 14) as usize,
+                rng.range_inclusive(4, 14) as usize,
+            ];
+            let mut vxm = test_model(size, |_| match rng.next_f32() {
+                roll if roll < 0.02 => TORCH,
+                roll if roll < 0.45 => STONE,
+                _ => VxmVoxel::default(),
+            });
+            let mut volume = LightVolume::compute(&VoxelNeighbourhood::isolated(&vxm));
+
+            for _ in 0..10 {
+                let [x, y, z] = [0, 1, 2].map(|axis| rng.range_inclusive(0, size[axis] as i32 - 1));
+                let voxel = &mut vxm.voxel_array[x as usize][y as usize][z as usize];
+                *voxel = match (voxel.hsl != 0, rng.next_f32() < 0.2) {
+                    (true, _) => VxmVoxel::default(),
+                    (false, true) => TORCH,
+                    (false, false) => STONE,
+                };
+
+                let neighbourhood = VoxelNeighbourhood::isolated(&vxm);
+                assert!(volume.update(&neighbourhood));
+                let relit = LightVolume::compute(&neighbourhood);
+                for position in positions(size) {
+                    assert_eq!(
+                        volume.light_at(position),
+                        relit.light_at(position),
+                        "light at {position:?} after editing {x} {y} {z} of a {size:?} model"
+                    );
+                }
+            }
+            assert!(!volume.update(&VoxelNeighbourhood::isolated(&vxm)));
+        }
+    }
+}
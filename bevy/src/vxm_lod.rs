@@ -0,0 +1,543 @@
+use crate::vxm::{PendingVxm, VxmAsset, VxmVoxel};
+use crate::vxm_light::{LightVolume, VoxelLightStore};
+use crate::vxm_mesh::{
+    create_mesh_on_vxm_import_system, ColourVolumeMesh, MeshedVoxels, StandardVoxelMesh,
+    VoxelNeighbourhood,
+};
+use crate::vxm_region::TerrainChunk;
+use crate::vxm_surface_nets::SmoothVoxelMesh;
+use bevy::app::{App, Plugin, PostUpdate, Update};
+use bevy::asset::{AssetEvent, AssetId, Assets, Handle};
+use bevy::ecs::relationship::RelationshipTarget;
+use bevy::prelude::{
+    Camera, Commands, Component, DetectChangesMut, Entity, EventReader, GlobalTransform, Has,
+    IntoScheduleConfigs, Name, ParamSet, Projection, Query, Res, ResMut, Resource, Transform, Vec3,
+    Visibility, With, Without,
+};
+use bevy::transform::helper::TransformHelper;
+use bevy::transform::TransformSystem;
+use std::collections::HashSet;
+
+// How far in levels past a switching point an entity has to move before it switches back, so
+// entities on the edge don't flicker between levels as the camera moves
+const LOD_HYSTERESIS: f32 = 0.15;
+
+/// How detailed voxel entities with a [`VoxelLod`] are drawn
+#[derive(Resource, Clone, Copy)]
+pub struct VoxelLodSettings {
+    /// Most voxels of a level that may fit across the height of the screen before the next,
+    /// coarser level is used. About the screen height in pixels switches once voxels shrink
+    /// below a pixel, where the change can't be seen.
+    pub voxels_per_screen_height: f32,
+}
+
+impl Default for VoxelLodSettings {
+    fn default() -> Self {
+        Self {
+            voxels_per_screen_height: 600.0,
+        }
+    }
+}
+
+/// Draws a voxel entity from coarser, downsampled copies of its model as it gets smaller on
+/// screen, so distant models and terrain take fewer instances.
+///
+/// Each level halves the resolution of the one before it. The levels are meshed on their own
+/// entities when the model is, and are rebuilt when the entity is given a different model or
+/// its model is edited.
+#[derive(Component)]
+pub struct VoxelLod {
+    /// Levels below full resolution
+    pub levels: u32,
+    current_level: u32,
+    /// Model the levels were built from
+    model: Option<AssetId<VxmAsset>>,
+    /// Size of the full resolution model in voxels
+    size: Vec3,
+}
+
+impl VoxelLod {
+    pub fn new(levels: u32) -> Self {
+        Self {
+            levels,
+            current_level: 0,
+            model: None,
+            size: Vec3::ZERO,
+        }
+    }
+}
+
+/// A downsampled level of a [`VoxelLod`] entity, scaled up to cover the same space
+#[derive(Component)]
+pub struct VoxelLodLevel(pub u32);
+
+/// A level of a terrain chunk, lit and meshed against the same level of the chunks around it so
+/// faces between chunks are culled and light carries across them as it does at full resolution
+#[derive(Component)]
+pub struct TerrainChunkLevel {
+    pub position: (i32, i32, i32),
+    pub handle: Handle<VxmAsset>,
+}
+
+/// The [`VoxelLod`] entity a level is drawn in place of. Levels aren't children of it, so
+/// hiding it for a coarser level doesn't hide that level too.
+#[derive(Component)]
+#[relationship(relationship_target = VoxelLodLevels)]
+pub struct VoxelLodOf(pub Entity);
+
+/// Levels of a [`VoxelLod`] entity, despawned along with it
+#[derive(Component)]
+#[relationship_target(relationship = VoxelLodOf, linked_spawn)]
+pub struct VoxelLodLevels(Vec<Entity>);
+
+/// Halves the resolution of a model, each voxel taking the most common solid voxel of the 2x2x2
+/// block it covers.
+///
+/// A block is solid when at least half of it is, so surfaces stay within a voxel of where they
+/// were and thin details fade out rather than thickening. Ties go to the higher voxel, so the
+/// surface colour of flat ground shows from above rather than what is under it.
+pub fn downsample(vxm: &VxmAsset) -> VxmAsset {
+    let size = vxm.size.map(|size| size.div_ceil(2));
+    let mut voxel_array =
+        vec![vec![vec![VxmVoxel::default(); size[2] as usize]; size[1] as usize]; size[0] as usize];
+
+    let mut block = Vec::with_capacity(8);
+    for x in 0..size[0] as usize {
+        for y in 0..size[1] as usize {
+            for z in 0..size[2] as usize {
+                block.clear();
+                let mut block_size = 0;
+                for block_x in x * 2..(x * 2 + 2).min(vxm.size[0] as usize) {
+                    for block_y in y * 2..(y * 2 + 2).min(vxm.size[1] as usize) {
+                        for block_z in z * 2..(z * 2 + 2).min(vxm.size[2] as usize) {
+                            block_size += 1;
+                            let voxel = &vxm.voxel_array[block_x][block_y][block_z];
+                            // Every colour encoding reserves 0 for air
+                            if voxel.hsl != 0 {
+                                block.push((block_y, voxel));
+                            }
+                        }
+                    }
+                }
+                if block.len() * 2 < block_size {
+                    continue;
+                }
+
+                let mut most_common = (0, 0, block[0].1);
+                for &(block_y, voxel) in &block {
+                    let count = block.iter().filter(|(_, other)| *other == voxel).count();
+                    if (count, block_y) > (most_common.0, most_common.1) {
+                        most_common = (count, block_y, voxel);
+                    }
+                }
+                voxel_array[x][y][z] = most_common.2.clone();
+            }
+        }
+    }
+
+    VxmAsset {
+        size,
+        voxel_array,
+        // The full resolution model already spawned its lights
+        lights: Vec::new(),
+        colour_encoding: vxm.colour_encoding,
+        palette: vxm.palette.clone(),
+    }
+}
+
+/// Downsamples the model of every [`VoxelLod`] entity about to be meshed and spawns its levels,
+/// replacing any built from an earlier model or before the model was edited.
+///
+/// Terrain chunks keep their model once meshed, so their levels are rebuilt when it's edited in
+/// place too. Levels of a solid chunk are built along with it, so the levels beside it see its
+/// voxels, but aren't meshed until it is.
+pub fn spawn_lod_levels_system(
+    mut lod_entities: Query<(
+        Entity,
+        &mut VoxelLod,
+        Option<&PendingVxm>,
+        Option<&TerrainChunk>,
+        Option<&VoxelLodLevels>,
+        Has<MeshedVoxels>,
+        Has<ColourVolumeMesh>,
+        Has<SmoothVoxelMesh>,
+        Has<StandardVoxelMesh>,
+    )>,
+    waiting_levels: Query<&TerrainChunkLevel, (Without<PendingVxm>, Without<MeshedVoxels>)>,
+    mut events: EventReader<AssetEvent<VxmAsset>>,
+    mut vxm_assets: ResMut<Assets<VxmAsset>>,
+    mut light_store: ResMut<VoxelLightStore>,
+    mut commands: Commands,
+) {
+    let edited = events
+        .read()
+        .filter_map(|event| match event {
+            AssetEvent::Modified { id } => Some(*id),
+            _ => None,
+        })
+        .collect::<HashSet<_>>();
+
+    for (
+        entity,
+        mut lod,
+        pending_vxm,
+        terrain_chunk,
+        levels,
+        is_meshed,
+        is_colour_volume_mesh,
+        is_smooth_mesh,
+        is_standard_mesh,
+    ) in lod_entities.iter_mut()
+    {
+        let Some(handle) = pending_vxm
+            .map(|pending_vxm| &pending_vxm.0)
+            .or(terrain_chunk.map(|chunk| &chunk.handle))
+        else {
+            continue;
+        };
+        let is_meshing = pending_vxm.is_some() || is_meshed;
+        if lod.model == Some(handle.id()) && !edited.contains(&handle.id()) {
+            // A solid chunk that has come into view is meshed along with its levels
+            if is_meshing {
+                for level in levels.into_iter().flat_map(|levels| levels.iter()) {
+                    if let Ok(chunk_level) = waiting_levels.get(level) {
+                        commands
+                            .entity(level)
+                            .insert(PendingVxm(chunk_level.handle.clone()));
+                    }
+                }
+            }
+            continue;
+        }
+        let Some(vxm) = vxm_assets.get(handle) else {
+            continue;
+        };
+        if let Some(levels) = levels {
+            for level in levels.iter() {
+                commands.entity(level).despawn();
+            }
+        }
+
+        lod.model = Some(handle.id());
+        lod.size = Vec3::new(vxm.size[0] as f32, vxm.size[1] as f32, vxm.size[2] as f32);
+        lod.current_level = 0;
+        let mut chain = Vec::new();
+        for _ in 0..lod.levels {
+            let previous = chain.last().unwrap_or(vxm);
+            if previous.size.iter().all(|&size| size <= 1) {
+                break;
+            }
+            let next = downsample(previous);
+            chain.push(next);
+        }
+
+        for (index, level_vxm) in chain.into_iter().enumerate() {
+            let level = index as u32 + 1;
+            // Downsampling keeps a solid chunk solid, so its levels are lit the same wherever
+            // they are like it is
+            let solid_light = (!is_meshing)
+                .then(|| LightVolume::compute(&VoxelNeighbourhood::isolated(&level_vxm)));
+            let level_handle = vxm_assets.add(level_vxm);
+            let mut level_entity = commands.spawn((
+                Name::new(format!("LOD {level}")),
+                VoxelLodOf(entity),
+                VoxelLodLevel(level),
+                // Placed over the entity by follow_lod_entity_system
+                Transform::default(),
+                Visibility::Hidden,
+            ));
+            if let Some(light) = solid_light {
+                light_store.0.insert(level_handle.id(), light);
+            } else {
+                level_entity.insert(PendingVxm(level_handle.clone()));
+            }
+            if let Some(chunk) = terrain_chunk {
+                level_entity.insert(TerrainChunkLevel {
+                    position: chunk.position,
+                    handle: level_handle,
+                });
+            }
+            if is_colour_volume_mesh {
+                level_entity.insert(ColourVolumeMesh);
+            }
+            if is_smooth_mesh {
+                level_entity.insert(SmoothVoxelMesh);
+            }
+            if is_standard_mesh {
+                level_entity.insert(StandardVoxelMesh);
+            }
+        }
+    }
+}
+
+/// Places each level over its entity, scaled up by the voxels each of its voxels covers.
+///
+/// Runs before transforms are propagated and works out where the entity is from its own
+/// hierarchy, so levels of moving entities don't trail a frame behind.
+pub fn follow_lod_entity_system(
+    mut transforms: ParamSet<(
+        TransformHelper,
+        Query<(Entity, &VoxelLodOf, &VoxelLodLevel, &mut Transform)>,
+    )>,
+) {
+    let levels = transforms
+        .p1()
+        .iter()
+        .map(|(level_entity, lod_of, level, _)| (level_entity, lod_of.0, level.0))
+        .collect::<Vec<_>>();
+    let placed = levels
+        .into_iter()
+        .filter_map(|(level_entity, entity, level)| {
+            let global_transform = transforms.p0().compute_global_transform(entity).ok()?;
+            let mut transform = global_transform.compute_transform();
+            transform.scale *= (1 << level) as f32;
+            Some((level_entity, transform))
+        })
+        .collect::<Vec<_>>();
+
+    let mut level_transforms = transforms.p1();
+    for (level_entity, transform) in placed {
+        if let Ok((_, _, _, mut level_transform)) = level_transforms.get_mut(level_entity) {
+            level_transform.set_if_neq(transform);
+        }
+    }
+}
+
+/// Shows the level of each [`VoxelLod`] entity that matches how large its voxels are on screen,
+/// hiding the rest.
+///
+/// A level is only switched to once it has been meshed, so entities never vanish while a level
+/// is being built.
+pub fn select_lod_level_system(
+    camera: Query<(&GlobalTransform, &Projection), With<Camera>>,
+    mut lod_entities: Query<
+        (
+            &mut VoxelLod,
+            &VoxelLodLevels,
+            &GlobalTransform,
+            &mut Visibility,
+            Has<PendingVxm>,
+        ),
+        Without<VoxelLodLevel>,
+    >,
+    mut levels: Query<(&VoxelLodLevel, &mut Visibility, Has<PendingVxm>), Without<VoxelLod>>,
+    settings: Res<VoxelLodSettings>,
+) {
+    let Ok((camera_transform, Projection::Perspective(perspective))) = camera.single() else {
+        return;
+    };
+    let screen_height_at_unit_distance = 2.0 * (perspective.fov * 0.5).tan();
+
+    for (mut lod, lod_levels, transform, mut visibility, is_pending) in lod_entities.iter_mut() {
+        if is_pending {
+            continue;
+        }
+
+        let centre = transform.transform_point(lod.size * 0.5);
+        let voxel_size = transform.scale().max_element();
+        let distance = camera_transform
+            .translation()
+            .distance(centre)
+            .max(perspective.near);
+        let voxels_across_screen = distance * screen_height_at_unit_distance / voxel_size;
+        // The level at which voxels across the screen would just reach the limit
+        let detail = (voxels_across_screen / settings.voxels_per_screen_height).log2();
+
+        let current_level = lod.current_level as f32;
+        if detail > current_level + LOD_HYSTERESIS || detail < current_level - 1.0 - LOD_HYSTERESIS
+        {
+            let target_level = (detail.ceil().max(0.0) as u32).min(lod_levels.len() as u32);
+            let is_target_meshed = target_level == 0
+                || lod_levels.iter().any(|level_entity| {
+                    levels
+                        .get(level_entity)
+                        .is_ok_and(|(level, _, is_level_pending)| {
+                            level.0 == target_level && !is_level_pending
+                        })
+                });
+            if is_target_meshed {
+                lod.current_level = target_level;
+            }
+        }
+
+        // Set every frame, as meshing makes whatever it meshes visible
+        visibility.set_if_neq(if lod.current_level == 0 {
+            Visibility::Visible
+        } else {
+            Visibility::Hidden
+        });
+        for level_entity in lod_levels.iter() {
+            if let Ok((level, mut level_visibility, _)) = levels.get_mut(level_entity) {
+                level_visibility.set_if_neq(if level.0 == lod.current_level {
+                    Visibility::Visible
+                } else {
+                    Visibility::Hidden
+                });
+            }
+        }
+    }
+}
+
+pub struct VoxelLodPlugin;
+
+impl Plugin for VoxelLodPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<VoxelLodSettings>()
+            .add_systems(
+                Update,
+                (
+                    spawn_lod_levels_system.before(create_mesh_on_vxm_import_system),
+                    select_lod_level_system.after(create_mesh_on_vxm_import_system),
+                ),
+            )
+            .add_systems(
+                PostUpdate,
+                follow_lod_entity_system.before(TransformSystem::TransformPropagate),
+            );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color_conversion::ColourEncoding;
+    use crate::vxm::VxmLight;
+
+    const GRASS: VxmVoxel = VxmVoxel {
+        hsl: 0x4a62,
+        emissive: false,
+        opacity: u8::MAX,
+    };
+
+    const DIRT: VxmVoxel = VxmVoxel {
+        hsl: 0x1842,
+        emissive: false,
+        opacity: u8::MAX,
+    };
+
+    fn test_model(size: [usize; 3], mut voxel_at: impl FnMut([usize; 3]) -> VxmVoxel) -> VxmAsset {
+        let mut voxel_array = vec![vec![vec![VxmVoxel::default(); size[2]]; size[1]]; size[0]];
+        for (x, column) in voxel_array.iter_mut().enumerate() {
+            for (y, row) in column.iter_mut().enumerate() {
+                for (z, voxel) in row.iter_mut().enumerate() {
+                    *voxel = voxel_at([x, y, z]);
+                }
+            }
+        }
+        VxmAsset {
+            size: size.map(|size| size as u8),
+            voxel_array,
+            lights: Vec::new(),
+            colour_encoding: ColourEncoding::Hsl,
+            palette: Vec::new(),
+        }
+    }
+
+    // A single 2x2x2 block with the given voxels solid, laid out x, then y, then z
+    fn block(voxels: [Option<VxmVoxel>; 8]) -> VxmAsset {
+        test_model([2; 3], |[x, y, z]| {
+            voxels[(x * 2 + y) * 2 + z].clone().unwrap_or_default()
+        })
+    }
+
+    #[test]
+    fn sizes_halve_rounding_up() {
+        let mut vxm = test_model([5, 2, 8], |_| GRASS);
+        vxm.lights.push(VxmLight {
+            min_pos: [0; 3],
+            max_pos: [1; 3],
+            color: [1.0; 3],
+            intensity: 1.0,
+        });
+        let downsampled = downsample(&vxm);
+        assert_eq!(downsampled.size, [3, 1, 4]);
+        assert_eq!(downsampled.voxel_array.len(), 3);
+        assert_eq!(downsampled.voxel_array[0].len(), 1);
+        assert_eq!(downsampled.voxel_array[0][0].len(), 4);
+        assert!(downsampled.lights.is_empty());
+    }
+
+    #[test]
+    fn blocks_at_least_half_solid_stay_solid() {
+        for solid_count in 0..=8 {
+            let voxels = std::array::from_fn(|i| (i < solid_count).then_some(DIRT));
+            let downsampled = downsample(&block(voxels));
+            let expected = if solid_count >= 4 {
+                DIRT
+            } else {
+                VxmVoxel::default()
+            };
+            assert_eq!(
+                downsampled.voxel_array[0][0][0], expected,
+                "{solid_count} solid voxels"
+            );
+        }
+    }
+
+    #[test]
+    fn partial_blocks_on_odd_edges_count_only_the_voxels_they_cover() {
+        // The last block along x covers one layer of 4 voxels, 2 of them solid
+        let vxm = test_model([3, 2, 2], |[x, _, z]| {
+            if x < 2 || z == 0 {
+                DIRT
+            } else {
+                VxmVoxel::default()
+            }
+        });
+        assert_eq!(downsample(&vxm).voxel_array[1][0][0], DIRT);
+
+        let vxm = test_model([3, 2, 2], |[x, y, z]| {
+            if x < 2 || (y, z) == (0, 0) {
+                DIRT
+            } else {
+                VxmVoxel::default()
+            }
+        });
+        assert_eq!(downsample(&vxm).voxel_array[1][0][0], VxmVoxel::default());
+    }
+
+    #[test]
+    fn the_most_common_voxel_wins() {
+        // More dirt than grass, even though the grass is on top
+        let downsampled = downsample(&block([
+            Some(DIRT),
+            Some(DIRT),
+            Some(GRASS),
+            None,
+            Some(DIRT),
+            None,
+            None,
+            None,
+        ]));
+        assert_eq!(downsampled.voxel_array[0][0][0], DIRT);
+    }
+
+    #[test]
+    fn ties_go_to_the_higher_voxel() {
+        // Flat ground, grass over dirt
+        let ground = block([
+            Some(DIRT),
+            Some(DIRT),
+            Some(GRASS),
+            Some(GRASS),
+            Some(DIRT),
+            Some(DIRT),
+            Some(GRASS),
+            Some(GRASS),
+        ]);
+        assert_eq!(downsample(&ground).voxel_array[0][0][0], GRASS);
+
+        // The same the other way up
+        let overhang = block([
+            Some(GRASS),
+            Some(GRASS),
+            Some(DIRT),
+            Some(DIRT),
+            Some(GRASS),
+            Some(GRASS),
+            Some(DIRT),
+            Some(DIRT),
+        ]);
+        assert_eq!(downsample(&overhang).voxel_array[0][0][0], DIRT);
+    }
+}
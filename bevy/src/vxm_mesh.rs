@@ -1,16 +1,28 @@
-use crate::color_conversion::get_hsl_voxel;
-use crate::render::main::{InstanceData, InstanceMaterialData};
+use crate::color_conversion::{decode_voxel_colour, get_hsl_voxel};
+use crate::render::compute_mesher::GpuMesher;
+use crate::render::main::{
+    ColourVolume, GpuMeshedVoxels, InstanceData, InstanceMaterialData, SmoothMeshData,
+    TransparentInstanceData, VoxelColourData,
+};
 use crate::vxm::{PendingVxm, VxmAsset, VxmVoxel};
-use bevy::asset::{Assets, RenderAssetUsages};
+use crate::vxm_light::{light_brightness, LightVolume, VoxelLightStore, OPEN_SKY_LIGHT};
+use crate::vxm_lod::{TerrainChunkLevel, VoxelLodLevel};
+use crate::vxm_region::TerrainChunk;
+use crate::vxm_surface_nets::{surface_nets, SmoothVoxelMesh};
+use bevy::asset::{AssetEvent, Assets, RenderAssetUsages};
 use bevy::log::info;
 use bevy::prelude::*;
-use bevy::render::mesh::{Indices, PrimitiveTopology};
+use bevy::render::mesh::{Indices, MeshVertexAttribute, PrimitiveTopology};
 use bevy::render::primitives::Aabb;
+use bevy::render::render_resource::VertexFormat;
 use bevy::render::view::VisibilityClass;
 use rayon::prelude::*;
+use std::collections::{HashMap, VecDeque};
+use std::ops::Range;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-#[derive(Component, Clone)]
+#[derive(Component, Clone, Copy)]
 pub enum MeshedVoxelsFace {
     Back = 0,
     Front = 1,
@@ -20,345 +32,1175 @@ pub enum MeshedVoxelsFace {
     Top = 5,
 }
 
-fn generate_instance_data_z(vxm: &VxmAsset, is_front_face: bool) -> Vec<InstanceData> {
-    let size_x = vxm.size[0] as usize;
-    let size_y = vxm.size[1] as usize;
-    let size_z = vxm.size[2] as usize;
+const ALL_FACES: [MeshedVoxelsFace; 6] = [
+    MeshedVoxelsFace::Back,
+    MeshedVoxelsFace::Front,
+    MeshedVoxelsFace::Left,
+    MeshedVoxelsFace::Right,
+    MeshedVoxelsFace::Bottom,
+    MeshedVoxelsFace::Top,
+];
 
-    let idx = |x, y, z| x * size_y * size_z + y * size_z + z;
-
-    let mut visited_voxels = vec![false; size_x * size_y * size_z];
-
-    // Create a closure for checking voxels
-    let check_voxel = |visited_voxels: &Vec<bool>, x: usize, y: usize, z: usize, hsl: u16| {
-        let is_face_hidden = if is_front_face {
-            z < (vxm.size[2] - 1) as usize && is_solid_voxel(&vxm.voxel_array[x][y][z + 1])
-        } else {
-            z > 0 && is_solid_voxel(&vxm.voxel_array[x][y][z - 1])
-        };
+// Every colour encoding reserves 0 for air
+fn is_solid_voxel(voxel: &VxmVoxel) -> bool {
+    voxel.hsl != 0
+}
 
-        let is_visited = visited_voxels[idx(x, y, z)];
+pub(crate) fn is_opaque_voxel(voxel: &VxmVoxel) -> bool {
+    is_solid_voxel(voxel) && voxel.opacity == u8::MAX
+}
 
-        !is_solid_voxel(&vxm.voxel_array[x][y][z])
-            || vxm.voxel_array[x][y][z].hsl != hsl
-            || is_visited
-            || is_face_hidden
-    };
+/// Which voxels a set of instances is meshed from, transparent voxels being drawn in their own
+/// blended pass after the opaque ones
+#[derive(Clone, Copy, PartialEq)]
+pub enum VoxelLayer {
+    Opaque,
+    Transparent,
+}
 
-    let mut instance_data =
-        Vec::with_capacity(vxm.size[0] as usize * vxm.size[1] as usize * vxm.size[2] as usize / 4);
+impl VoxelLayer {
+    fn contains(self, voxel: &VxmVoxel) -> bool {
+        match self {
+            VoxelLayer::Opaque => is_opaque_voxel(voxel),
+            VoxelLayer::Transparent => is_solid_voxel(voxel) && !is_opaque_voxel(voxel),
+        }
+    }
 
-    (0..vxm.size[2] as usize).into_iter().for_each(|z| {
-        for x in 0..vxm.size[0] as usize {
-            for y in 0..vxm.size[1] as usize {
-                let voxel = &vxm.voxel_array[x][y][z];
+    // Transparent voxels don't hide what is behind them, but transparent faces are still
+    // hidden by whatever they touch so water has no internal faces
+    fn is_hidden_by(self, voxel: &VxmVoxel) -> bool {
+        match self {
+            VoxelLayer::Opaque => is_opaque_voxel(voxel),
+            VoxelLayer::Transparent => is_solid_voxel(voxel),
+        }
+    }
+}
 
-                if check_voxel(&visited_voxels, x, y, z, voxel.hsl) {
-                    continue;
-                }
+// Unit cube corners of each face, in the same order as the positions array in shader.wgsl
+const FACE_VERTEX_OFFSETS: [[[i32; 3]; 4]; 6] = [
+    [[1, 0, 0], [0, 0, 0], [1, 1, 0], [0, 1, 0]],
+    [[0, 0, 1], [1, 0, 1], [0, 1, 1], [1, 1, 1]],
+    [[0, 0, 0], [0, 0, 1], [0, 1, 0], [0, 1, 1]],
+    [[1, 0, 1], [1, 0, 0], [1, 1, 1], [1, 1, 0]],
+    [[0, 0, 0], [1, 0, 0], [0, 0, 1], [1, 0, 1]],
+    [[1, 1, 0], [0, 1, 0], [1, 1, 1], [0, 1, 1]],
+];
+
+pub(crate) const FACE_NORMALS: [[i32; 3]; 6] = [
+    [0, 0, -1],
+    [0, 0, 1],
+    [-1, 0, 0],
+    [1, 0, 0],
+    [0, -1, 0],
+    [0, 1, 0],
+];
+
+// Offsets of the 26 chunks around a chunk
+const NEIGHBOUR_OFFSETS: [[i32; 3]; 26] = {
+    let mut offsets = [[0; 3]; 26];
+    let mut i = 0;
+    let mut index = 0;
+    while index < 27 {
+        let offset = [index / 9 - 1, index / 3 % 3 - 1, index % 3 - 1];
+        if index != 13 {
+            offsets[i] = offset;
+            i += 1;
+        }
+        index += 1;
+    }
+    offsets
+};
+
+/// The voxels of a model along with the terrain chunks loaded around it, so faces on chunk
+/// borders can be culled and have ambient occlusion computed against their neighbours
+pub struct VoxelNeighbourhood<'a> {
+    pub centre: &'a VxmAsset,
+    /// Indexed by [`neighbour_index`], the centre slot is never read
+    pub neighbours: [Option<&'a VxmAsset>; 27],
+    /// Light of the centre and its neighbours, indexed by [`neighbour_index`], for those that
+    /// have been lit
+    pub lights: [Option<&'a LightVolume>; 27],
+}
 
-                let mut x_extent = 1u8;
-                let mut y_extent = 1u8;
-                let max_extent_y = vxm.size[1] as usize - y;
-                let max_extent_x = vxm.size[0] as usize - x;
+/// Index of the chunk offset by -1, 0 or 1 on each axis in [`VoxelNeighbourhood::neighbours`]
+pub fn neighbour_index(offset: [i32; 3]) -> usize {
+    ((offset[0] + 1) * 9 + (offset[1] + 1) * 3 + (offset[2] + 1)) as usize
+}
 
-                let mut is_x_extendable = true;
-                let mut is_y_extendable = true;
+impl<'a> VoxelNeighbourhood<'a> {
+    /// A model with nothing around it, as used for standalone vxm models
+    pub fn isolated(centre: &'a VxmAsset) -> Self {
+        Self {
+            centre,
+            neighbours: [None; 27],
+            lights: [None; 27],
+        }
+    }
 
-                while (is_x_extendable || is_y_extendable)
-                    && ((x_extent as usize) < max_extent_x)
-                    && ((y_extent as usize) < max_extent_y)
-                {
-                    is_x_extendable = !(0..y_extent as usize).any(|dy| {
-                        check_voxel(&visited_voxels, x + x_extent as usize, y + dy, z, voxel.hsl)
-                    });
-                    if is_x_extendable {
-                        x_extent += 1;
-                    }
+    /// The same neighbourhood with the centre lit by `light`
+    pub fn with_light(mut self, light: Option<&'a LightVolume>) -> Self {
+        self.lights[neighbour_index([0; 3])] = light;
+        self
+    }
 
-                    is_y_extendable = !(0..x_extent as usize).any(|dx| {
-                        check_voxel(&visited_voxels, x + dx, y + y_extent as usize, z, voxel.hsl)
-                    });
-                    if is_y_extendable {
-                        y_extent += 1;
-                    }
-                }
+    // Index of the model a position lies in, the model, and the position within it. Positions
+    // outside the model and its loaded neighbours lie in nothing.
+    fn locate(&self, x: i32, y: i32, z: i32) -> Option<(usize, &'a VxmAsset, [usize; 3])> {
+        let mut position = [x, y, z];
+        let mut offset = [0; 3];
+        for axis in 0..3 {
+            if position[axis] < 0 {
+                offset[axis] = -1;
+            } else if position[axis] >= self.centre.size[axis] as i32 {
+                offset[axis] = 1;
+                position[axis] -= self.centre.size[axis] as i32;
+            }
+        }
 
-                for dx in 0..x_extent as usize {
-                    for dy in 0..y_extent as usize {
-                        visited_voxels[idx(x + dx, y + dy, z)] = true;
-                    }
-                }
+        let index = neighbour_index(offset);
+        let vxm = if offset == [0; 3] {
+            self.centre
+        } else {
+            self.neighbours[index]?
+        };
 
-                instance_data.push(InstanceData {
-                    position: [x as u8, y as u8, z as u8],
-                    width: x_extent,
-                    height: y_extent,
-                    hsl: voxel.hsl,
-                    ambient_occlusion: 3,
-                });
+        // Negative positions count back from the far side of the neighbour
+        for axis in 0..3 {
+            if position[axis] < 0 {
+                position[axis] += vxm.size[axis] as i32;
             }
         }
-    });
-    instance_data
-}
 
-// Checks if 1st bit of 16 bit value is 1
-fn is_solid_voxel(voxel: &VxmVoxel) -> bool {
-    (voxel.hsl >> 15) & 0x01 == 1
-}
+        let in_bounds =
+            (0..3).all(|axis| position[axis] >= 0 && position[axis] < vxm.size[axis] as i32);
+        if !in_bounds {
+            return None;
+        }
+        Some((index, vxm, position.map(|p| p as usize)))
+    }
 
-fn generate_instance_data_x(vxm: &VxmAsset, is_right_face: bool) -> Vec<InstanceData> {
-    let size_x = vxm.size[0] as usize;
-    let size_y = vxm.size[1] as usize;
-    let size_z = vxm.size[2] as usize;
+    // Voxels outside the model and its loaded neighbours are treated as air
+    pub(crate) fn voxel_at(&self, x: i32, y: i32, z: i32) -> Option<&'a VxmVoxel> {
+        let (_, vxm, [x, y, z]) = self.locate(x, y, z)?;
+        Some(&vxm.voxel_array[x][y][z])
+    }
 
-    let idx = |x, y, z| x * size_y * size_z + y * size_z + z;
+    /// Packed block and sky light of a voxel, or `None` if it isn't in a lit model
+    pub fn light_at(&self, x: i32, y: i32, z: i32) -> Option<u8> {
+        let (index, _, position) = self.locate(x, y, z)?;
+        Some(self.lights[index]?.light_at(position))
+    }
 
-    let mut visited_voxels = vec![false; size_x * size_y * size_z];
+    // Only opaque voxels occlude light
+    fn is_opaque_at(&self, x: i32, y: i32, z: i32) -> bool {
+        self.voxel_at(x, y, z).is_some_and(is_opaque_voxel)
+    }
+}
 
-    // Create a closure for checking voxels
-    let check_voxel = |visited_voxels: &Vec<bool>, x: usize, y: usize, z: usize, hsl: u16| {
-        let is_face_hidden = if is_right_face {
-            x < (vxm.size[0] - 1) as usize && is_solid_voxel(&vxm.voxel_array[x + 1][y][z])
-        } else {
-            x > 0 && is_solid_voxel(&vxm.voxel_array[x - 1][y][z])
-        };
+/// Ambient occlusion of a single face corner, from 0 (fully occluded) to 3 (unoccluded)
+fn vertex_ambient_occlusion(side_1: bool, side_2: bool, corner: bool) -> u8 {
+    if side_1 && side_2 {
+        return 0;
+    }
+    3 - side_1 as u8 - side_2 as u8 - corner as u8
+}
 
-        let is_visited = visited_voxels[idx(x, y, z)];
+/// Packs the ambient occlusion of the 4 corners of a voxel face, 2 bits each,
+/// in the order `unpack_ambient_occlusion` in shader.wgsl reads them
+fn face_ambient_occlusion(
+    neighbourhood: &VoxelNeighbourhood,
+    x: usize,
+    y: usize,
+    z: usize,
+    face: MeshedVoxelsFace,
+) -> u8 {
+    let face = face as usize;
+    let normal = FACE_NORMALS[face];
+    // The layer of voxels in front of the face
+    let front = [
+        x as i32 + normal[0],
+        y as i32 + normal[1],
+        z as i32 + normal[2],
+    ];
+
+    let mut ao = 0u8;
+    for (vertex, offset) in FACE_VERTEX_OFFSETS[face].iter().enumerate() {
+        // Step towards the corner along the two axes the face lies on
+        let mut steps = [[0i32; 3]; 2];
+        let mut step_count = 0;
+        for axis in 0..3 {
+            if normal[axis] == 0 {
+                steps[step_count][axis] = if offset[axis] == 0 { -1 } else { 1 };
+                step_count += 1;
+            }
+        }
+        let [side_1, side_2] = steps;
+
+        let is_side_1_solid = neighbourhood.is_opaque_at(
+            front[0] + side_1[0],
+            front[1] + side_1[1],
+            front[2] + side_1[2],
+        );
+        let is_side_2_solid = neighbourhood.is_opaque_at(
+            front[0] + side_2[0],
+            front[1] + side_2[1],
+            front[2] + side_2[2],
+        );
+        let is_corner_solid = neighbourhood.is_opaque_at(
+            front[0] + side_1[0] + side_2[0],
+            front[1] + side_1[1] + side_2[1],
+            front[2] + side_1[2] + side_2[2],
+        );
+
+        let vertex_ao = vertex_ambient_occlusion(is_side_1_solid, is_side_2_solid, is_corner_solid);
+        ao |= vertex_ao << (6 - vertex * 2);
+    }
+    ao
+}
 
-        !is_solid_voxel(&vxm.voxel_array[x][y][z])
-            || vxm.voxel_array[x][y][z].hsl != hsl
-            || is_visited
-            || is_face_hidden
-    };
+// Axis faces point along, and the two axes its quads extend over as width and height
+fn face_axes(face: MeshedVoxelsFace) -> (usize, usize, usize) {
+    match face {
+        MeshedVoxelsFace::Back | MeshedVoxelsFace::Front => (2, 0, 1),
+        MeshedVoxelsFace::Left | MeshedVoxelsFace::Right => (0, 1, 2),
+        MeshedVoxelsFace::Bottom | MeshedVoxelsFace::Top => (1, 0, 2),
+    }
+}
 
-    let mut instance_data =
-        Vec::with_capacity(vxm.size[0] as usize * vxm.size[1] as usize * vxm.size[2] as usize / 4);
+/// Greedy meshes the faces of one layer of a model pointing in one direction, within a range of
+/// slices along the face normal.
+///
+/// Each slice is stored as rows of u64 occupancy masks, so visible faces are found a word at a
+/// time by masking out the voxels that hide them in the slice in front. Runs of set bits are then
+/// merged into quads, only joining faces with matching colour, light and ambient occlusion, or
+/// matching colour, light and opacity for transparent faces. Without `match_colours` any visible
+/// faces are joined, leaving their colour, ambient occlusion and light to be worked out per
+/// fragment from the model's colour volume.
+///
+/// Faces take the light of the voxel in front of them, and are fully lit by the sky where it
+/// isn't known.
+pub fn generate_instance_data(
+    neighbourhood: &VoxelNeighbourhood,
+    face: MeshedVoxelsFace,
+    layer: VoxelLayer,
+    match_colours: bool,
+    slices: Range<usize>,
+) -> Vec<InstanceData> {
+    let vxm = neighbourhood.centre;
+    let (axis_d, axis_u, axis_v) = face_axes(face);
+    let size_d = vxm.size[axis_d] as usize;
+    let size_u = vxm.size[axis_u] as usize;
+    let size_v = vxm.size[axis_v] as usize;
+    let words = size_u.div_ceil(64);
+    let step = FACE_NORMALS[face as usize][axis_d];
+    let slices = slices.start.min(size_d)..slices.end.min(size_d);
+
+    let to_xyz = |d: usize, u: usize, v: usize| {
+        let mut position = [0; 3];
+        position[axis_d] = d;
+        position[axis_u] = u;
+        position[axis_v] = v;
+        position
+    };
 
-    (0..vxm.size[0] as usize).into_iter().for_each(|x| {
-        for z in 0..vxm.size[2] as usize {
-            for y in 0..vxm.size[1] as usize {
-                let voxel = &vxm.voxel_array[x][y][z];
+    // Occupancy of the meshed slices plus one either side, which may lie in a neighbouring chunk
+    let first_slice = slices.start as i32 - 1;
+    let slice_count = slices.len() + 2;
+    let row_index = |d: i32, v: usize| (((d - first_slice) as usize) * size_v + v) * words;
+
+    let mut occupancy = vec![0u64; slice_count * size_v * words];
+    let mut hiding = vec![0u64; slice_count * size_v * words];
+    for d in first_slice..first_slice + slice_count as i32 {
+        let is_inside = d >= 0 && d < size_d as i32;
+        for v in 0..size_v {
+            let row = row_index(d, v);
+            for u in 0..size_u {
+                let voxel = if is_inside {
+                    let [x, y, z] = to_xyz(d as usize, u, v);
+                    Some(&vxm.voxel_array[x][y][z])
+                } else {
+                    let mut position = [0; 3];
+                    position[axis_d] = d;
+                    position[axis_u] = u as i32;
+                    position[axis_v] = v as i32;
+                    neighbourhood.voxel_at(position[0], position[1], position[2])
+                };
+                let Some(voxel) = voxel else {
+                    continue;
+                };
+                if layer.contains(voxel) {
+                    occupancy[row + u / 64] |= 1 << (u % 64);
+                }
+                if layer.is_hidden_by(voxel) {
+                    hiding[row + u / 64] |= 1 << (u % 64);
+                }
+            }
+        }
+    }
 
-                if check_voxel(&visited_voxels, x, y, z, voxel.hsl) {
+    let mut instance_data = Vec::with_capacity(slices.len() * size_u * size_v / 4);
+    let mut visible = vec![0u64; size_v * words];
+    // Colour, ambient occlusion or opacity, and light of each visible face in the slice, all left
+    // at 0 without matching colours so faces merge on occupancy alone
+    let mut keys = vec![0u32; size_v * size_u];
+
+    for d in slices {
+        for v in 0..size_v {
+            // Faces are hidden by the slice they point into
+            let row = row_index(d as i32, v);
+            let hidden_row = row_index(d as i32 + step, v);
+            for word in 0..words {
+                let mut mask = occupancy[row + word] & !hiding[hidden_row + word];
+                visible[v * words + word] = mask;
+                if !match_colours {
                     continue;
                 }
 
-                let mut z_extent = 1u8;
-                let mut y_extent = 1u8;
-                let max_extent_y = vxm.size[1] as usize - y;
-                let max_extent_z = vxm.size[2] as usize - z;
+                while mask != 0 {
+                    let u = word * 64 + mask.trailing_zeros() as usize;
+                    mask &= mask - 1;
+                    let [x, y, z] = to_xyz(d, u, v);
+                    let voxel = &vxm.voxel_array[x][y][z];
+                    // Transparent faces have no ambient occlusion, so carry their opacity instead
+                    let shading = match layer {
+                        VoxelLayer::Opaque => face_ambient_occlusion(neighbourhood, x, y, z, face),
+                        VoxelLayer::Transparent => voxel.opacity,
+                    };
+                    let normal = FACE_NORMALS[face as usize];
+                    let light = neighbourhood
+                        .light_at(
+                            x as i32 + normal[0],
+                            y as i32 + normal[1],
+                            z as i32 + normal[2],
+                        )
+                        .unwrap_or(OPEN_SKY_LIGHT);
+                    keys[v * size_u + u] =
+                        voxel.hsl as u32 | (shading as u32) << 16 | (light as u32) << 24;
+                }
+            }
+        }
 
-                let mut is_z_extendable = true;
-                let mut is_y_extendable = true;
+        let is_visible = |visible: &[u64], u: usize, v: usize| {
+            (visible[v * words + u / 64] >> (u % 64)) & 1 == 1
+        };
 
-                while (is_z_extendable || is_y_extendable)
-                    && ((z_extent as usize) < max_extent_z)
-                    && ((y_extent as usize) < max_extent_y)
-                {
-                    is_z_extendable = !(0..y_extent as usize).any(|dy| {
-                        check_voxel(&visited_voxels, x, y + dy, z + z_extent as usize, voxel.hsl)
-                    });
-                    if is_z_extendable {
-                        z_extent += 1;
+        for v in 0..size_v {
+            for word in 0..words {
+                while visible[v * words + word] != 0 {
+                    let u = word * 64 + visible[v * words + word].trailing_zeros() as usize;
+                    let key = keys[v * size_u + u];
+
+                    let mut width = 1;
+                    while u + width < size_u
+                        && is_visible(&visible, u + width, v)
+                        && keys[v * size_u + u + width] == key
+                    {
+                        width += 1;
                     }
 
-                    is_y_extendable = !(0..z_extent as usize).any(|dz| {
-                        check_voxel(&visited_voxels, x, y + y_extent as usize, z + dz, voxel.hsl)
-                    });
-                    if is_y_extendable {
-                        y_extent += 1;
+                    let mut height = 1;
+                    while v + height < size_v
+                        && (u..u + width).all(|du| {
+                            is_visible(&visible, du, v + height)
+                                && keys[(v + height) * size_u + du] == key
+                        })
+                    {
+                        height += 1;
                     }
-                }
 
-                for dz in 0..z_extent as usize {
-                    for dy in 0..y_extent as usize {
-                        visited_voxels[idx(x, y + dy, z + dz)] = true;
+                    for dv in v..v + height {
+                        for du in u..u + width {
+                            visible[dv * words + du / 64] &= !(1 << (du % 64));
+                        }
                     }
-                }
 
-                instance_data.push(InstanceData {
-                    position: [x as u8, y as u8, z as u8],
-                    width: y_extent,
-                    height: z_extent,
-                    hsl: voxel.hsl,
-                    ambient_occlusion: 3,
-                });
+                    let [x, y, z] = to_xyz(d, u, v);
+                    instance_data.push(InstanceData {
+                        position: [x as u8, y as u8, z as u8],
+                        width: width as u8,
+                        height: height as u8,
+                        hsl: key as u16,
+                        ambient_occlusion: (key >> 16) as u8,
+                        light: (key >> 24) as u8,
+                        _padding: [0; 3],
+                    });
+                }
             }
         }
-    });
+    }
     instance_data
 }
 
-fn generate_instance_data_y(vxm: &VxmAsset, is_top_face: bool) -> Vec<InstanceData> {
-    let size_x = vxm.size[0] as usize;
-    let size_y = vxm.size[1] as usize;
-    let size_z = vxm.size[2] as usize;
+#[derive(Component)]
+pub struct MeshedVoxels;
+
+/// Merges quads across voxel colours, ambient occlusion and light, with each fragment working them
+/// out from a volume of the model's voxel colours and light. Noisy colours such as terrain's and
+/// uneven ground otherwise break faces into mostly 1x1 quads.
+#[derive(Component, Default)]
+pub struct ColourVolumeMesh;
+
+/// How the opaque voxels of a model are meshed, transparent voxels are always faces
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum OpaqueMeshing {
+    Faces,
+    ColourVolume,
+    Smooth,
+}
+
+impl OpaqueMeshing {
+    fn new(is_colour_volume: bool, is_smooth: bool) -> Self {
+        match (is_colour_volume, is_smooth) {
+            (_, true) => OpaqueMeshing::Smooth,
+            (true, false) => OpaqueMeshing::ColourVolume,
+            (false, false) => OpaqueMeshing::Faces,
+        }
+    }
 
-    let idx = |x, y, z| x * size_y * size_z + y * size_z + z;
+    // Smooth meshed models leave their opaque voxels to the surface nets mesher
+    fn mesh_slices(
+        self,
+        neighbourhood: &VoxelNeighbourhood,
+        face: MeshedVoxelsFace,
+        slices: Range<usize>,
+    ) -> Vec<InstanceData> {
+        match self {
+            OpaqueMeshing::Faces => {
+                generate_instance_data(neighbourhood, face, VoxelLayer::Opaque, true, slices)
+            }
+            OpaqueMeshing::ColourVolume => {
+                generate_instance_data(neighbourhood, face, VoxelLayer::Opaque, false, slices)
+            }
+            OpaqueMeshing::Smooth => Vec::new(),
+        }
+    }
+}
 
-    let mut visited_voxels = vec![false; size_x * size_y * size_z];
+/// Colours of the opaque voxels of a model padded by a voxel of its neighbours on every side, x
+/// major like the voxel array, with the light at each voxel above its colour. Air and transparent
+/// voxels have no colour, so the shader can read which voxels occlude a face from it too.
+fn create_colour_volume(neighbourhood: &VoxelNeighbourhood) -> Vec<u32> {
+    let [size_x, size_y, size_z] = neighbourhood.centre.size.map(i32::from);
+    let mut volume = Vec::with_capacity(((size_x + 2) * (size_y + 2) * (size_z + 2)) as usize);
+    for x in -1..=size_x {
+        for y in -1..=size_y {
+            for z in -1..=size_z {
+                let colour = neighbourhood
+                    .voxel_at(x, y, z)
+                    .filter(|voxel| is_opaque_voxel(voxel))
+                    .map_or(0, |voxel| voxel.hsl);
+                let light = neighbourhood.light_at(x, y, z).unwrap_or(OPEN_SKY_LIGHT);
+                volume.push(colour as u32 | (light as u32) << 16);
+            }
+        }
+    }
+    volume
+}
 
-    // Create a closure for checking voxels
-    let check_voxel = |visited_voxels: &Vec<bool>, x: usize, y: usize, z: usize, hsl: u16| {
-        let is_face_hidden = if is_top_face {
-            y < (vxm.size[1] - 1) as usize && is_solid_voxel(&vxm.voxel_array[x][y + 1][z])
-        } else {
-            y > 0 && is_solid_voxel(&vxm.voxel_array[x][y - 1][z])
-        };
+// Greedy meshes every slice of a face direction, returning the opaque and transparent instances
+fn mesh_face(
+    neighbourhood: &VoxelNeighbourhood,
+    face: MeshedVoxelsFace,
+    opaque_meshing: OpaqueMeshing,
+) -> (Vec<InstanceData>, Vec<InstanceData>) {
+    let size_d = neighbourhood.centre.size[face_axes(face).0] as usize;
+    (
+        opaque_meshing.mesh_slices(neighbourhood, face, 0..size_d),
+        generate_instance_data(
+            neighbourhood,
+            face,
+            VoxelLayer::Transparent,
+            true,
+            0..size_d,
+        ),
+    )
+}
 
-        let is_visited = visited_voxels[idx(x, y, z)];
+/// Where models are greedy meshed. The GPU mesher produces the same faces as the CPU.
+#[derive(Resource, Default, Clone, Copy, PartialEq)]
+pub enum MeshingBackend {
+    #[default]
+    Cpu,
+    Gpu,
+}
 
-        !is_solid_voxel(&vxm.voxel_array[x][y][z])
-            || vxm.voxel_array[x][y][z].hsl != hsl
-            || is_visited
-            || is_face_hidden
-    };
+/// How long models may spend being lit and meshed each frame. Models still pending once it runs
+/// out wait for the next frame, so streaming in many chunks at once doesn't stall a frame.
+#[derive(Resource, Clone, Copy)]
+pub struct MeshingBudget {
+    pub max_time_per_frame: Duration,
+}
 
-    let mut instance_data =
-        Vec::with_capacity(vxm.size[0] as usize * vxm.size[1] as usize * vxm.size[2] as usize / 4);
+impl Default for MeshingBudget {
+    fn default() -> Self {
+        Self {
+            max_time_per_frame: Duration::from_millis(4),
+        }
+    }
+}
 
-    (0..vxm.size[1] as usize).into_iter().for_each(|y| {
-        for x in 0..vxm.size[0] as usize {
-            for z in 0..vxm.size[2] as usize {
-                let voxel = &vxm.voxel_array[x][y][z];
+/// Terrain chunks, and levels of them, waiting to be relit and have their borders re-meshed
+/// after a chunk beside them has changed. Each is its own piece of work within the
+/// [`MeshingBudget`], so a chunk arriving among 26 meshed neighbours doesn't re-mesh them all in
+/// one frame.
+#[derive(Resource, Default)]
+pub struct NeighbourRemeshQueue(VecDeque<NeighbourRemesh>);
+
+struct NeighbourRemesh {
+    entity: Entity,
+    position: (i32, i32, i32),
+    // The level of detail of levels of terrain chunks, which are meshed against that level
+    level: Option<u32>,
+    // The side of the neighbour the changed chunk is on
+    side: [i32; 3],
+}
 
-                if check_voxel(&visited_voxels, x, y, z, voxel.hsl) {
-                    continue;
-                }
+impl NeighbourRemeshQueue {
+    /// Queues the chunks loaded around a chunk position to be re-meshed against it
+    pub(crate) fn queue_around(
+        &mut self,
+        position: (i32, i32, i32),
+        level: Option<u32>,
+        loaded_chunks: &HashMap<(i32, i32, i32), (Entity, AssetId<VxmAsset>)>,
+    ) {
+        for offset in NEIGHBOUR_OFFSETS {
+            let neighbour_position = (
+                position.0 + offset[0],
+                position.1 + offset[1],
+                position.2 + offset[2],
+            );
+            if let Some(&(entity, _)) = loaded_chunks.get(&neighbour_position) {
+                self.0.push_back(NeighbourRemesh {
+                    entity,
+                    position: neighbour_position,
+                    level,
+                    side: offset.map(|o| -o),
+                });
+            }
+        }
+    }
+}
 
-                let mut x_extent = 1u8;
-                let mut z_extent = 1u8;
-                let max_extent_z = vxm.size[2] as usize - z;
-                let max_extent_x = vxm.size[0] as usize - x;
-
-                let mut is_x_extendable = true;
-                let mut is_z_extendable = true;
-
-                // Get ambient occlusion value for each corner
-                let mut ao_00 = 3u8;
-                let mut ao_01 = 3u8;
-                let mut ao_10 = 3u8;
-                let mut ao_11 = 3u8;
-
-                // if y < (vxm.size[1] - 1) as usize {
-                //     let minus_x_check = x > 0 && is_solid_voxel(&vxm.voxel_array[x - 1][y + 1][z]);
-                //     let plus_x_check = x < (vxm.size[0] - 1) as usize
-                //         && is_solid_voxel(&vxm.voxel_array[x + 1][y + 1][z]);
-                //     let minus_z_check = z > 0 && is_solid_voxel(&vxm.voxel_array[x][y + 1][z - 1]);
-                //     let plus_z_check = z < (vxm.size[2] - 1) as usize
-                //         && is_solid_voxel(&vxm.voxel_array[x][y + 1][z + 1]);
-                //     let corner_00 =
-                //         x > 0 && z > 0 && is_solid_voxel(&vxm.voxel_array[x - 1][y + 1][z - 1]);
-                //     let corner_01 = x > 0
-                //         && z < (vxm.size[2] - 1) as usize
-                //         && is_solid_voxel(&vxm.voxel_array[x - 1][y + 1][z + 1]);
-                //     let corner_10 = x < (vxm.size[0] - 1) as usize
-                //         && z > 0
-                //         && is_solid_voxel(&vxm.voxel_array[x + 1][y + 1][z - 1]);
-                //     let corner_11 = x < (vxm.size[0] - 1) as usize
-                //         && z < (vxm.size[2] - 1) as usize
-                //         && is_solid_voxel(&vxm.voxel_array[x + 1][y + 1][z + 1]);
-                // 
-                //     if minus_x_check && minus_z_check {
-                //         ao_00 = 0;
-                //     } else {
-                //         ao_00 =
-                //             3 - (corner_00 as u8) - (minus_x_check as u8) - (minus_z_check as u8);
-                //     }
-                // }
-
-                // 2 bits for each corner in this order of vertices
-                let ao = (ao_01 << 6) | (ao_11 << 4) | (ao_00 << 2) | ao_10;
-
-                while (is_x_extendable || is_z_extendable)
-                    && ((x_extent as usize) < max_extent_x)
-                    && ((z_extent as usize) < max_extent_z)
-                {
-                    is_x_extendable = !(0..z_extent as usize).any(|dz| {
-                        check_voxel(&visited_voxels, x + x_extent as usize, y, z + dz, voxel.hsl)
-                    });
-                    if is_x_extendable {
-                        x_extent += 1;
-                    }
+// Whether the GPU mesher matches colours when merging opaque quads, or None where the opaque
+// voxels aren't meshed into quads at all
+fn gpu_match_colours(opaque_meshing: OpaqueMeshing) -> Option<bool> {
+    match opaque_meshing {
+        OpaqueMeshing::Faces => Some(true),
+        OpaqueMeshing::ColourVolume => Some(false),
+        OpaqueMeshing::Smooth => None,
+    }
+}
 
-                    is_z_extendable = !(0..x_extent as usize).any(|dx| {
-                        check_voxel(&visited_voxels, x + dx, y, z + z_extent as usize, voxel.hsl)
-                    });
-                    if is_z_extendable {
-                        z_extent += 1;
-                    }
-                }
+// Meshes both layers of a model on the GPU, leaving them in the mesher's buffers to be drawn from
+fn mesh_on_gpu(
+    gpu_mesher: &GpuMesher,
+    neighbourhood: &VoxelNeighbourhood,
+    opaque_meshing: OpaqueMeshing,
+) -> GpuMeshedVoxels {
+    GpuMeshedVoxels {
+        opaque: gpu_match_colours(opaque_meshing).map(|match_colours| {
+            Arc::new(gpu_mesher.mesh(neighbourhood, VoxelLayer::Opaque, match_colours))
+        }),
+        transparent: Arc::new(gpu_mesher.mesh(neighbourhood, VoxelLayer::Transparent, true)),
+    }
+}
 
-                for dx in 0..x_extent as usize {
-                    for dz in 0..z_extent as usize {
-                        visited_voxels[idx(x + dx, y, z + dz)] = true;
-                    }
+/// Re-meshes a model meshed on the GPU in full, as the GPU mesher has no way to re-mesh part of
+/// one. Its faces hold no instances, only the colour volume, which is refreshed along with it.
+fn remesh_on_gpu(
+    gpu_mesher: &GpuMesher,
+    neighbourhood: &VoxelNeighbourhood,
+    children: &Children,
+    opaque_meshing: OpaqueMeshing,
+    gpu_meshed: &mut GpuMeshedVoxels,
+    face_data: &mut Query<(
+        &MeshedVoxelsFace,
+        &mut InstanceMaterialData,
+        &mut TransparentInstanceData,
+        &mut VoxelColourData,
+    )>,
+) {
+    let colour_volume = create_neighbourhood_colour_volume(neighbourhood, opaque_meshing);
+    let children: &[Entity] = children;
+    for &child in children {
+        if let Ok((_, _, _, mut colour_data)) = face_data.get_mut(child) {
+            set_colour_volume(&mut colour_data, &colour_volume);
+        }
+    }
+    *gpu_meshed = mesh_on_gpu(gpu_mesher, neighbourhood, opaque_meshing);
+}
+
+/// Meshes models meshed on the GPU again once the mesher has counted their quads, if there were
+/// more than it first made room for. The mesher is polled without waiting, so counts come back
+/// over the frames after a model is meshed, and a model that overflowed isn't drawn until then.
+pub fn grow_gpu_meshes_system(
+    gpu_mesher: Option<Res<GpuMesher>>,
+    mut gpu_meshed_models: Query<&mut GpuMeshedVoxels>,
+) {
+    let Some(gpu_mesher) = gpu_mesher else {
+        return;
+    };
+    gpu_mesher.poll();
+    for mut gpu_meshed in &mut gpu_meshed_models {
+        let opaque = gpu_meshed
+            .opaque
+            .as_ref()
+            .and_then(|opaque| gpu_mesher.remesh_if_overflowed(opaque));
+        if let Some(opaque) = opaque {
+            gpu_meshed.opaque = Some(Arc::new(opaque));
+        }
+        if let Some(transparent) = gpu_mesher.remesh_if_overflowed(&gpu_meshed.transparent) {
+            gpu_meshed.transparent = Arc::new(transparent);
+        }
+    }
+}
+
+/// Meshes a voxel model into a standard Bevy [`Mesh`] held in [`Mesh3d`] instead of instances for
+/// the voxel renderer, so it can be used by stock Bevy rendering, picking or physics crates
+#[derive(Component, Default)]
+pub struct StandardVoxelMesh;
+
+/// Ambient occlusion of each vertex of a [`StandardVoxelMesh`], from 0 (fully occluded) to 1
+pub const ATTRIBUTE_AMBIENT_OCCLUSION: MeshVertexAttribute = MeshVertexAttribute::new(
+    "Vertex_AmbientOcclusion",
+    1_803_437_611,
+    VertexFormat::Float32,
+);
+
+/// Builds an indexed triangle list from the greedy quads of each face direction, given as the
+/// opaque and transparent instances [`mesh_face`] produces.
+///
+/// Vertex colours are decoded with the model's colour encoding and have ambient occlusion and
+/// light baked in the same way the voxel shader applies them, with opacity in alpha for
+/// transparent voxels.
+/// The raw ambient occlusion is kept in [`ATTRIBUTE_AMBIENT_OCCLUSION`] for custom materials.
+pub fn create_standard_mesh(
+    vxm: &VxmAsset,
+    faces: &[(MeshedVoxelsFace, &[InstanceData], &[InstanceData])],
+) -> Mesh {
+    let quad_count = faces
+        .iter()
+        .map(|(_, opaque, transparent)| opaque.len() + transparent.len())
+        .sum::<usize>();
+    let mut positions = Vec::with_capacity(quad_count * 4);
+    let mut normals = Vec::with_capacity(quad_count * 4);
+    let mut colours = Vec::with_capacity(quad_count * 4);
+    let mut ambient_occlusion = Vec::with_capacity(quad_count * 4);
+    let mut indices = Vec::with_capacity(quad_count * 6);
+
+    for &(face, opaque, transparent) in faces {
+        let (axis_d, axis_u, axis_v) = face_axes(face);
+        let normal = FACE_NORMALS[face as usize].map(|n| n as f32);
+        let layers = [
+            (VoxelLayer::Opaque, opaque),
+            (VoxelLayer::Transparent, transparent),
+        ];
+
+        for (layer, instances) in layers {
+            for instance in instances {
+                let (r, g, b) =
+                    decode_voxel_colour(instance.hsl, vxm.colour_encoding, &vxm.palette);
+                let colour = Color::srgb(r, g, b).to_linear();
+                // Transparent instances carry opacity in place of ambient occlusion
+                let alpha = match layer {
+                    VoxelLayer::Opaque => 1.0,
+                    VoxelLayer::Transparent => instance.ambient_occlusion as f32 / 255.0,
+                };
+
+                // The shader draws these corners as a triangle strip
+                let first_index = positions.len() as u32;
+                indices.extend([0, 1, 2, 2, 1, 3].map(|corner| first_index + corner));
+
+                for (vertex, offset) in FACE_VERTEX_OFFSETS[face as usize].iter().enumerate() {
+                    let mut position = instance.position.map(|p| p as f32);
+                    position[axis_d] += offset[axis_d] as f32;
+                    position[axis_u] += (offset[axis_u] * instance.width as i32) as f32;
+                    position[axis_v] += (offset[axis_v] * instance.height as i32) as f32;
+
+                    let ao = match layer {
+                        VoxelLayer::Opaque => {
+                            ((instance.ambient_occlusion >> (6 - vertex * 2)) & 3) as f32 / 3.0
+                        }
+                        VoxelLayer::Transparent => 1.0,
+                    };
+                    let shade = ao * light_brightness(instance.light) * 0.9 + 0.1;
+
+                    positions.push(position);
+                    normals.push(normal);
+                    colours.push([
+                        colour.red * shade,
+                        colour.green * shade,
+                        colour.blue * shade,
+                        alpha,
+                    ]);
+                    ambient_occlusion.push(ao);
                 }
+            }
+        }
+    }
 
-                instance_data.push(InstanceData {
-                    position: [x as u8, y as u8, z as u8],
-                    width: x_extent,
-                    height: z_extent,
-                    hsl: voxel.hsl,
-                    ambient_occlusion: ao,
-                });
+    Mesh::new(
+        PrimitiveTopology::TriangleList,
+        RenderAssetUsages::default(),
+    )
+    .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, positions)
+    .with_inserted_attribute(Mesh::ATTRIBUTE_NORMAL, normals)
+    .with_inserted_attribute(Mesh::ATTRIBUTE_COLOR, colours)
+    .with_inserted_attribute(ATTRIBUTE_AMBIENT_OCCLUSION, ambient_occlusion)
+    .with_inserted_indices(Indices::U32(indices))
+}
+
+// Greedy meshes every face direction of a model into a standard Bevy mesh
+fn mesh_standard(neighbourhood: &VoxelNeighbourhood) -> Mesh {
+    let meshed_faces = ALL_FACES
+        .par_iter()
+        .map(|&face| (face, mesh_face(neighbourhood, face, OpaqueMeshing::Faces)))
+        .collect::<Vec<_>>();
+    let faces = meshed_faces
+        .iter()
+        .map(|(face, (opaque, transparent))| (*face, opaque.as_slice(), transparent.as_slice()))
+        .collect::<Vec<_>>();
+    create_standard_mesh(neighbourhood.centre, &faces)
+}
+
+/// Builds the neighbourhood of a terrain chunk from the chunks currently loaded around it, along
+/// with the light of those that have been lit
+pub(crate) fn terrain_neighbourhood<'a>(
+    vxm: &'a VxmAsset,
+    position: (i32, i32, i32),
+    loaded_chunks: &HashMap<(i32, i32, i32), (Entity, AssetId<VxmAsset>)>,
+    vxm_assets: &'a Assets<VxmAsset>,
+    light_store: &'a VoxelLightStore,
+) -> VoxelNeighbourhood<'a> {
+    let mut neighbourhood = VoxelNeighbourhood::isolated(vxm);
+    for dx in -1..=1 {
+        for dy in -1..=1 {
+            for dz in -1..=1 {
+                let neighbour_position = (position.0 + dx, position.1 + dy, position.2 + dz);
+                let index = neighbour_index([dx, dy, dz]);
+                let id = loaded_chunks.get(&neighbour_position).map(|(_, id)| *id);
+                neighbourhood.lights[index] = id.and_then(|id| light_store.0.get(&id));
+                if neighbour_position == position {
+                    continue;
+                }
+                neighbourhood.neighbours[index] = id.and_then(|id| vxm_assets.get(id));
             }
         }
-    });
-    instance_data
+    }
+    neighbourhood
 }
 
-#[derive(Component)]
-pub struct MeshedVoxels;
+// The colour volume of a model meshed with one, which also holds its neighbours' voxels and light
+fn create_neighbourhood_colour_volume(
+    neighbourhood: &VoxelNeighbourhood,
+    opaque_meshing: OpaqueMeshing,
+) -> Option<Arc<Vec<u32>>> {
+    (opaque_meshing == OpaqueMeshing::ColourVolume)
+        .then(|| Arc::new(create_colour_volume(neighbourhood)))
+}
+
+fn set_colour_volume(colour_data: &mut VoxelColourData, colours: &Option<Arc<Vec<u32>>>) {
+    if let (Some(volume), Some(colours)) = (&mut colour_data.volume, colours) {
+        volume.colours = colours.clone();
+    }
+}
+
+/// Re-meshes the faces of an already meshed chunk that change when a neighbour loads on the
+/// side given by `offset`.
+///
+/// Faces pointing into the neighbour can now be culled, so only their border slice is
+/// re-meshed. Faces running perpendicular to the border may gain ambient occlusion along it,
+/// so they are re-meshed in full, unless the chunk has a colour volume where the shader works
+/// out ambient occlusion from the neighbour's voxels instead. Faces pointing away from the
+/// neighbour are unaffected.
+fn remesh_chunk_border(
+    neighbourhood: &VoxelNeighbourhood,
+    offset: [i32; 3],
+    children: &Children,
+    opaque_meshing: OpaqueMeshing,
+    face_data: &mut Query<(
+        &MeshedVoxelsFace,
+        &mut InstanceMaterialData,
+        &mut TransparentInstanceData,
+        &mut VoxelColourData,
+    )>,
+) {
+    let vxm = neighbourhood.centre;
+    let colour_volume = create_neighbourhood_colour_volume(neighbourhood, opaque_meshing);
+    let children: &[Entity] = children;
+    for &child in children {
+        let Ok((face, mut instance_data, mut transparent_data, mut colour_data)) =
+            face_data.get_mut(child)
+        else {
+            continue;
+        };
+        set_colour_volume(&mut colour_data, &colour_volume);
+        let face = *face;
+        let normal = FACE_NORMALS[face as usize];
+        let (axis_d, _, _) = face_axes(face);
+
+        if normal == offset {
+            let border = if offset[axis_d] > 0 {
+                vxm.size[axis_d] as usize - 1
+            } else {
+                0
+            };
+            let remesh_border =
+                |instances: &[InstanceData], border_instances: Vec<InstanceData>| {
+                    let mut remeshed = instances
+                        .iter()
+                        .filter(|instance| instance.position[axis_d] as usize != border)
+                        .copied()
+                        .collect::<Vec<_>>();
+                    remeshed.extend(border_instances);
+                    Arc::new(remeshed)
+                };
+            instance_data.0 = remesh_border(
+                &instance_data,
+                opaque_meshing.mesh_slices(neighbourhood, face, border..border + 1),
+            );
+            transparent_data.0 = remesh_border(
+                &transparent_data,
+                generate_instance_data(
+                    neighbourhood,
+                    face,
+                    VoxelLayer::Transparent,
+                    true,
+                    border..border + 1,
+                ),
+            );
+        } else if offset[axis_d] == 0 && opaque_meshing != OpaqueMeshing::ColourVolume {
+            let (opaque, transparent) = mesh_face(neighbourhood, face, opaque_meshing);
+            instance_data.0 = Arc::new(opaque);
+            transparent_data.0 = Arc::new(transparent);
+        }
+    }
+}
+
+/// Re-meshes every face of an already meshed model, after its voxels or light have changed
+fn remesh_all_faces(
+    neighbourhood: &VoxelNeighbourhood,
+    children: &Children,
+    opaque_meshing: OpaqueMeshing,
+    face_data: &mut Query<(
+        &MeshedVoxelsFace,
+        &mut InstanceMaterialData,
+        &mut TransparentInstanceData,
+        &mut VoxelColourData,
+    )>,
+) {
+    let colour_volume = create_neighbourhood_colour_volume(neighbourhood, opaque_meshing);
+    let children: &[Entity] = children;
+    for &child in children {
+        let Ok((face, mut instance_data, mut transparent_data, mut colour_data)) =
+            face_data.get_mut(child)
+        else {
+            continue;
+        };
+        set_colour_volume(&mut colour_data, &colour_volume);
+        let (opaque, transparent) = mesh_face(neighbourhood, *face, opaque_meshing);
+        instance_data.0 = Arc::new(opaque);
+        transparent_data.0 = Arc::new(transparent);
+    }
+}
 
 /// Removes PendingVxm to signify that the mesh has been created
+///
+/// Models are lit before they are meshed, with faces taking the light in front of them. Terrain
+/// chunks are lit and meshed against the chunks loaded around them, and their levels of detail
+/// against the same level of those chunks. Any already meshed neighbours are queued on the
+/// [`NeighbourRemeshQueue`] to have their borders re-meshed now that this chunk's voxels are
+/// known. Neighbours whose light changes with this chunk there, as light spreads in from it or it
+/// shades them from the sky, are re-meshed in full.
+///
+/// Models with [`StandardVoxelMesh`] get a [`Mesh3d`] instead of face instances, which is
+/// rebuilt whole when a neighbouring chunk loads. Models with [`SmoothVoxelMesh`] get a
+/// [`SmoothMeshData`] surface for their opaque voxels, rebuilt when any of the 26 chunks around
+/// them loads as the surface blends across chunk edges and corners.
+///
+/// At least one model or queued neighbour is meshed each frame, then more until the
+/// [`MeshingBudget`] is spent. Queued neighbours go first, so borders don't stay open for long.
 pub fn create_mesh_on_vxm_import_system(
-    pending_vxms: Query<(Entity, &PendingVxm, &Transform)>,
+    pending_vxms: Query<(
+        Entity,
+        &PendingVxm,
+        &Transform,
+        Option<&TerrainChunk>,
+        Option<(&TerrainChunkLevel, &VoxelLodLevel)>,
+        Has<StandardVoxelMesh>,
+        Has<ColourVolumeMesh>,
+        Has<SmoothVoxelMesh>,
+    )>,
+    terrain_chunks: Query<(Entity, &TerrainChunk)>,
+    terrain_levels: Query<(Entity, &TerrainChunkLevel, &VoxelLodLevel)>,
+    meshed_children: Query<
+        (&Children, Has<ColourVolumeMesh>, Has<SmoothVoxelMesh>),
+        With<MeshedVoxels>,
+    >,
+    mut smooth_meshes: Query<&mut SmoothMeshData, With<MeshedVoxels>>,
+    standard_meshes: Query<&Mesh3d, (With<StandardVoxelMesh>, With<MeshedVoxels>)>,
+    mut face_data: Query<(
+        &MeshedVoxelsFace,
+        &mut InstanceMaterialData,
+        &mut TransparentInstanceData,
+        &mut VoxelColourData,
+    )>,
     vxm_assets: Res<Assets<VxmAsset>>,
+    mut light_store: ResMut<VoxelLightStore>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    meshing_backend: Res<MeshingBackend>,
+    meshing_budget: Res<MeshingBudget>,
+    gpu_mesher: Option<Res<GpuMesher>>,
+    mut gpu_meshed_models: Query<&mut GpuMeshedVoxels>,
+    mut neighbour_remeshes: ResMut<NeighbourRemeshQueue>,
     mut commands: Commands,
 ) {
-    for (entity, pending_vxm, _) in pending_vxms.iter() {
+    let frame_start = Instant::now();
+    let loaded_chunks = terrain_chunks
+        .iter()
+        .map(|(entity, chunk)| (chunk.position, (entity, chunk.handle.id())))
+        .collect::<HashMap<_, _>>();
+    let mut loaded_levels: HashMap<u32, HashMap<_, _>> = HashMap::new();
+    for (entity, chunk_level, level) in terrain_levels.iter() {
+        loaded_levels
+            .entry(level.0)
+            .or_default()
+            .insert(chunk_level.position, (entity, chunk_level.handle.id()));
+    }
+
+    let mut pending_vxms = pending_vxms.iter();
+    while frame_start.elapsed() <= meshing_budget.max_time_per_frame {
+        if let Some(remesh) = neighbour_remeshes.0.pop_front() {
+            let Some(loaded) = (match remesh.level {
+                None => Some(&loaded_chunks),
+                Some(level) => loaded_levels.get(&level),
+            }) else {
+                continue;
+            };
+            // The neighbour may have been unloaded while it waited
+            let Some(&(neighbour_entity, neighbour_id)) = loaded
+                .get(&remesh.position)
+                .filter(|(entity, _)| *entity == remesh.entity)
+            else {
+                continue;
+            };
+            let Some(neighbour_vxm) = vxm_assets.get(neighbour_id) else {
+                continue;
+            };
+            let is_face_neighbour = FACE_NORMALS.contains(&remesh.side);
+            // Light only crosses the faces of chunks
+            let mut is_relit = false;
+            if is_face_neighbour && light_store.0.contains_key(&neighbour_id) {
+                let neighbour_light = LightVolume::compute(&terrain_neighbourhood(
+                    neighbour_vxm,
+                    remesh.position,
+                    loaded,
+                    &vxm_assets,
+                    &light_store,
+                ));
+                if light_store.0.get(&neighbour_id) != Some(&neighbour_light) {
+                    light_store.0.insert(neighbour_id, neighbour_light);
+                    is_relit = true;
+                }
+            }
+            let neighbour_neighbourhood = terrain_neighbourhood(
+                neighbour_vxm,
+                remesh.position,
+                loaded,
+                &vxm_assets,
+                &light_store,
+            );
+            if let Ok(mut smooth_mesh) = smooth_meshes.get_mut(neighbour_entity) {
+                *smooth_mesh = surface_nets(&neighbour_neighbourhood);
+            }
+            // Faces are only affected by the chunks they point into
+            if !is_face_neighbour {
+                continue;
+            }
+            if let Ok(mesh) = standard_meshes.get(neighbour_entity) {
+                meshes.insert(&mesh.0, mesh_standard(&neighbour_neighbourhood));
+                continue;
+            }
+            // Chunks still waiting to be meshed will see the changed chunk when they are
+            let Ok((children, is_neighbour_colour_volume, is_neighbour_smooth)) =
+                meshed_children.get(neighbour_entity)
+            else {
+                continue;
+            };
+            let neighbour_meshing =
+                OpaqueMeshing::new(is_neighbour_colour_volume, is_neighbour_smooth);
+            if let (Ok(mut neighbour_gpu_meshed), Some(gpu_mesher)) =
+                (gpu_meshed_models.get_mut(neighbour_entity), &gpu_mesher)
+            {
+                remesh_on_gpu(
+                    gpu_mesher,
+                    &neighbour_neighbourhood,
+                    children,
+                    neighbour_meshing,
+                    &mut neighbour_gpu_meshed,
+                    &mut face_data,
+                );
+            } else if is_relit {
+                remesh_all_faces(
+                    &neighbour_neighbourhood,
+                    children,
+                    neighbour_meshing,
+                    &mut face_data,
+                );
+            } else {
+                remesh_chunk_border(
+                    &neighbour_neighbourhood,
+                    remesh.side,
+                    children,
+                    neighbour_meshing,
+                    &mut face_data,
+                );
+            }
+            continue;
+        }
+
+        let Some((
+            entity,
+            pending_vxm,
+            _,
+            terrain_chunk,
+            terrain_level,
+            is_standard_mesh,
+            is_colour_volume_mesh,
+            is_smooth_mesh,
+        )) = pending_vxms.next()
+        else {
+            break;
+        };
         match vxm_assets.get(&pending_vxm.0) {
             Some(vxm) => {
-                let start_time = std::time::Instant::now();
-
-                let ((z_instance_data, x_instance_data), y_instance_data) = rayon::join(
-                    || {
-                        rayon::join(
-                            || {
-                                rayon::join(
-                                    || generate_instance_data_z(vxm, false),
-                                    || generate_instance_data_z(vxm, true),
-                                )
-                            },
-                            || {
-                                rayon::join(
-                                    || generate_instance_data_x(vxm, false),
-                                    || generate_instance_data_x(vxm, true),
-                                )
-                            },
-                        )
-                    },
-                    || {
-                        rayon::join(
-                            || generate_instance_data_y(vxm, true),
-                            || generate_instance_data_y(vxm, false),
-                        )
-                    },
-                );
+                let start_time = Instant::now();
+
+                // Levels of terrain chunks are lit and meshed against the same level of the
+                // chunks around them, as if they were chunks of a coarser world
+                let terrain = match (terrain_chunk, terrain_level) {
+                    (Some(chunk), _) => Some((chunk.position, None, &loaded_chunks)),
+                    (None, Some((chunk_level, level))) => loaded_levels
+                        .get(&level.0)
+                        .map(|loaded_levels| (chunk_level.position, Some(level.0), loaded_levels)),
+                    (None, None) => None,
+                };
+
+                let light = LightVolume::compute(&match terrain {
+                    Some((position, _, loaded_chunks)) => terrain_neighbourhood(
+                        vxm,
+                        position,
+                        loaded_chunks,
+                        &vxm_assets,
+                        &light_store,
+                    ),
+                    None => VoxelNeighbourhood::isolated(vxm),
+                });
+                light_store.0.insert(pending_vxm.0.id(), light);
+
+                let neighbourhood = match terrain {
+                    Some((position, _, loaded_chunks)) => terrain_neighbourhood(
+                        vxm,
+                        position,
+                        loaded_chunks,
+                        &vxm_assets,
+                        &light_store,
+                    ),
+                    None => VoxelNeighbourhood::isolated(vxm),
+                }
+                .with_light(light_store.0.get(&pending_vxm.0.id()));
+
+                let opaque_meshing = OpaqueMeshing::new(is_colour_volume_mesh, is_smooth_mesh);
+
+                // Standard meshes are built on the CPU from the instances, so they're never left
+                // on the GPU
+                let gpu_meshed = match (*meshing_backend, &gpu_mesher) {
+                    (MeshingBackend::Gpu, Some(gpu_mesher)) if !is_standard_mesh => {
+                        Some(mesh_on_gpu(gpu_mesher, &neighbourhood, opaque_meshing))
+                    }
+                    _ => None,
+                };
+
+                // Faces of models meshed on the GPU are left without instances
+                let ((z_instance_data, x_instance_data), y_instance_data) = match gpu_meshed {
+                    Some(_) => Default::default(),
+                    None => rayon::join(
+                        || {
+                            rayon::join(
+                                || {
+                                    rayon::join(
+                                        || {
+                                            mesh_face(
+                                                &neighbourhood,
+                                                MeshedVoxelsFace::Back,
+                                                opaque_meshing,
+                                            )
+                                        },
+                                        || {
+                                            mesh_face(
+                                                &neighbourhood,
+                                                MeshedVoxelsFace::Front,
+                                                opaque_meshing,
+                                            )
+                                        },
+                                    )
+                                },
+                                || {
+                                    rayon::join(
+                                        || {
+                                            mesh_face(
+                                                &neighbourhood,
+                                                MeshedVoxelsFace::Left,
+                                                opaque_meshing,
+                                            )
+                                        },
+                                        || {
+                                            mesh_face(
+                                                &neighbourhood,
+                                                MeshedVoxelsFace::Right,
+                                                opaque_meshing,
+                                            )
+                                        },
+                                    )
+                                },
+                            )
+                        },
+                        || {
+                            rayon::join(
+                                || mesh_face(&neighbourhood, MeshedVoxelsFace::Top, opaque_meshing),
+                                || {
+                                    mesh_face(
+                                        &neighbourhood,
+                                        MeshedVoxelsFace::Bottom,
+                                        opaque_meshing,
+                                    )
+                                },
+                            )
+                        },
+                    ),
+                };
 
                 let (back_instance_data, front_instance_data) = z_instance_data;
                 let (left_instance_data, right_instance_data) = x_instance_data;
                 let (top_instance_data, bottom_instance_data) = y_instance_data;
 
-                let instance_count = front_instance_data.len()
-                    + back_instance_data.len()
-                    + left_instance_data.len()
-                    + right_instance_data.len()
-                    + top_instance_data.len()
-                    + bottom_instance_data.len();
+                let instance_count = [
+                    &front_instance_data,
+                    &back_instance_data,
+                    &left_instance_data,
+                    &right_instance_data,
+                    &top_instance_data,
+                    &bottom_instance_data,
+                ]
+                .iter()
+                .map(|(opaque, transparent)| opaque.len() + transparent.len())
+                .sum::<usize>();
+
+                let smooth_mesh = is_smooth_mesh.then(|| surface_nets(&neighbourhood));
+
+                if let Some((position, level, loaded_chunks)) = terrain {
+                    neighbour_remeshes.queue_around(position, level, loaded_chunks);
+                }
 
                 let end_time = start_time.elapsed();
 
@@ -370,9 +1212,17 @@ pub fn create_mesh_on_vxm_import_system(
                     end_time.as_micros() as f32 / 1000.0
                 );
 
-                if instance_count == 0 {
+                if let Some(smooth_mesh) = &smooth_mesh {
+                    info!(
+                        "{:?} size smooth mesh created {:?} triangles",
+                        vxm.size,
+                        smooth_mesh.indices.len() / 3
+                    );
+                }
+
+                if instance_count == 0 && smooth_mesh.is_none() && gpu_meshed.is_none() {
                     info!("No instances created, skipping mesh creation");
-                    return;
+                    continue;
                 }
 
                 let aabb = Aabb::from_min_max(
@@ -416,6 +1266,36 @@ pub fn create_mesh_on_vxm_import_system(
                     commands.entity(entity).add_child(child);
                 }
 
+                if is_standard_mesh {
+                    let faces = [
+                        (MeshedVoxelsFace::Back, &back_instance_data),
+                        (MeshedVoxelsFace::Front, &front_instance_data),
+                        (MeshedVoxelsFace::Left, &left_instance_data),
+                        (MeshedVoxelsFace::Right, &right_instance_data),
+                        (MeshedVoxelsFace::Bottom, &bottom_instance_data),
+                        (MeshedVoxelsFace::Top, &top_instance_data),
+                    ]
+                    .map(|(face, (opaque, transparent))| {
+                        (face, opaque.as_slice(), transparent.as_slice())
+                    });
+                    let mesh = meshes.add(create_standard_mesh(vxm, &faces));
+
+                    commands.entity(entity).remove::<PendingVxm>();
+                    commands
+                        .entity(entity)
+                        .insert((aabb, MeshedVoxels, Mesh3d(mesh)));
+                    continue;
+                }
+
+                let colours = VoxelColourData {
+                    encoding: vxm.colour_encoding,
+                    palette: Arc::new(vxm.palette.clone()),
+                    volume: (opaque_meshing == OpaqueMeshing::ColourVolume).then(|| ColourVolume {
+                        size: vxm.size,
+                        colours: Arc::new(create_colour_volume(&neighbourhood)),
+                    }),
+                };
+
                 commands.entity(entity).remove::<PendingVxm>();
                 commands
                     .entity(entity)
@@ -429,7 +1309,9 @@ pub fn create_mesh_on_vxm_import_system(
                     .with_child((
                         Name::new("Front face instance data"),
                         MeshedVoxelsFace::Front,
-                        InstanceMaterialData(Arc::new(front_instance_data.clone())),
+                        InstanceMaterialData(Arc::new(front_instance_data.0.clone())),
+                        TransparentInstanceData(Arc::new(front_instance_data.1.clone())),
+                        colours.clone(),
                         Transform::from_xyz(0.0, 0.0, 0.0),
                         Visibility::Inherited,
                         InheritedVisibility::VISIBLE,
@@ -438,7 +1320,9 @@ pub fn create_mesh_on_vxm_import_system(
                     .with_child((
                         Name::new("Back face instance data"),
                         MeshedVoxelsFace::Back,
-                        InstanceMaterialData(Arc::new(back_instance_data.clone())),
+                        InstanceMaterialData(Arc::new(back_instance_data.0.clone())),
+                        TransparentInstanceData(Arc::new(back_instance_data.1.clone())),
+                        colours.clone(),
                         Transform::from_xyz(0.0, 0.0, 0.0),
                         Visibility::Inherited,
                         InheritedVisibility::VISIBLE,
@@ -447,7 +1331,9 @@ pub fn create_mesh_on_vxm_import_system(
                     .with_child((
                         Name::new("Right face instance data"),
                         MeshedVoxelsFace::Right,
-                        InstanceMaterialData(Arc::new(right_instance_data.clone())),
+                        InstanceMaterialData(Arc::new(right_instance_data.0.clone())),
+                        TransparentInstanceData(Arc::new(right_instance_data.1.clone())),
+                        colours.clone(),
                         Transform::from_xyz(0.0, 0.0, 0.0),
                         Visibility::Inherited,
                         InheritedVisibility::VISIBLE,
@@ -456,7 +1342,9 @@ pub fn create_mesh_on_vxm_import_system(
                     .with_child((
                         Name::new("Left face instance data"),
                         MeshedVoxelsFace::Left,
-                        InstanceMaterialData(Arc::new(left_instance_data.clone())),
+                        InstanceMaterialData(Arc::new(left_instance_data.0.clone())),
+                        TransparentInstanceData(Arc::new(left_instance_data.1.clone())),
+                        colours.clone(),
                         Transform::from_xyz(0.0, 0.0, 0.0),
                         Visibility::Inherited,
                         InheritedVisibility::VISIBLE,
@@ -465,7 +1353,9 @@ pub fn create_mesh_on_vxm_import_system(
                     .with_child((
                         Name::new("Top face instance data"),
                         MeshedVoxelsFace::Top,
-                        InstanceMaterialData(Arc::new(top_instance_data.clone())),
+                        InstanceMaterialData(Arc::new(top_instance_data.0.clone())),
+                        TransparentInstanceData(Arc::new(top_instance_data.1.clone())),
+                        colours.clone(),
                         Transform::from_xyz(0.0, 0.0, 0.0),
                         Visibility::Inherited,
                         InheritedVisibility::VISIBLE,
@@ -474,14 +1364,697 @@ pub fn create_mesh_on_vxm_import_system(
                     .with_child((
                         Name::new("Bottom face instance data"),
                         MeshedVoxelsFace::Bottom,
-                        InstanceMaterialData(Arc::new(bottom_instance_data.clone())),
+                        InstanceMaterialData(Arc::new(bottom_instance_data.0.clone())),
+                        TransparentInstanceData(Arc::new(bottom_instance_data.1.clone())),
+                        colours.clone(),
                         Transform::from_xyz(0.0, 0.0, 0.0),
                         Visibility::Inherited,
                         InheritedVisibility::VISIBLE,
                         ViewVisibility::default(),
                     ));
+
+                if let Some(smooth_mesh) = smooth_mesh {
+                    commands.entity(entity).insert(smooth_mesh);
+                }
+                if let Some(gpu_meshed) = gpu_meshed {
+                    commands.entity(entity).insert(gpu_meshed);
+                }
             }
             None => {}
         }
     }
 }
+
+/// Relights and re-meshes terrain chunks whose voxels have been edited, and drops the light of
+/// models that have been removed.
+///
+/// Light is only updated around the voxels that changed. It doesn't spread on into neighbouring
+/// chunks until they are re-meshed themselves.
+pub fn remesh_edited_chunks_system(
+    mut events: EventReader<AssetEvent<VxmAsset>>,
+    terrain_chunks: Query<(Entity, &TerrainChunk)>,
+    meshed_chunks: Query<
+        (
+            Option<&Children>,
+            Option<&Mesh3d>,
+            Has<ColourVolumeMesh>,
+            Has<SmoothVoxelMesh>,
+        ),
+        With<MeshedVoxels>,
+    >,
+    mut smooth_meshes: Query<&mut SmoothMeshData>,
+    mut face_data: Query<(
+        &MeshedVoxelsFace,
+        &mut InstanceMaterialData,
+        &mut TransparentInstanceData,
+        &mut VoxelColourData,
+    )>,
+    mut gpu_meshed_models: Query<&mut GpuMeshedVoxels>,
+    vxm_assets: Res<Assets<VxmAsset>>,
+    mut light_store: ResMut<VoxelLightStore>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    gpu_mesher: Option<Res<GpuMesher>>,
+) {
+    let mut loaded_chunks = None;
+    for event in events.read() {
+        let id = match event {
+            AssetEvent::Removed { id } => {
+                light_store.0.remove(id);
+                continue;
+            }
+            AssetEvent::Modified { id } => *id,
+            _ => continue,
+        };
+        let loaded_chunks = loaded_chunks.get_or_insert_with(|| {
+            terrain_chunks
+                .iter()
+                .map(|(entity, chunk)| (chunk.position, (entity, chunk.handle.id())))
+                .collect::<HashMap<_, _>>()
+        });
+        let Some((&position, &(entity, _))) = loaded_chunks
+            .iter()
+            .find(|(_, (_, chunk_id))| *chunk_id == id)
+        else {
+            continue;
+        };
+        let Ok((children, mesh, is_colour_volume_mesh, is_smooth_mesh)) = meshed_chunks.get(entity)
+        else {
+            continue;
+        };
+        let Some(vxm) = vxm_assets.get(id) else {
+            continue;
+        };
+        let Some(mut light) = light_store.0.remove(&id) else {
+            continue;
+        };
+        light.update(&terrain_neighbourhood(
+            vxm,
+            position,
+            loaded_chunks,
+            &vxm_assets,
+            &light_store,
+        ));
+        light_store.0.insert(id, light);
+
+        let neighbourhood =
+            terrain_neighbourhood(vxm, position, loaded_chunks, &vxm_assets, &light_store);
+        if let Some(mesh) = mesh {
+            meshes.insert(&mesh.0, mesh_standard(&neighbourhood));
+            continue;
+        }
+        if let Ok(mut smooth_mesh) = smooth_meshes.get_mut(entity) {
+            *smooth_mesh = surface_nets(&neighbourhood);
+        }
+        let Some(children) = children else {
+            continue;
+        };
+        let opaque_meshing = OpaqueMeshing::new(is_colour_volume_mesh, is_smooth_mesh);
+        if let (Ok(mut gpu_meshed), Some(gpu_mesher)) =
+            (gpu_meshed_models.get_mut(entity), &gpu_mesher)
+        {
+            remesh_on_gpu(
+                gpu_mesher,
+                &neighbourhood,
+                children,
+                opaque_meshing,
+                &mut gpu_meshed,
+                &mut face_data,
+            );
+            continue;
+        }
+        remesh_all_faces(&neighbourhood, children, opaque_meshing, &mut face_data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vxm_rng::Rng;
+
+    const STONE: VxmVoxel = VxmVoxel {
+        hsl: 0x8421,
+        emissive: false,
+        opacity: u8::MAX,
+    };
+
+    const GLASS: VxmVoxel = VxmVoxel {
+        hsl: 0x8842,
+        emissive: false,
+        opacity: 128,
+    };
+
+    // A model with the voxels `voxel_at` gives and air everywhere else
+    fn test_model(
+        size: [u8; 3],
+        mut voxel_at: impl FnMut([usize; 3]) -> Option<VxmVoxel>,
+    ) -> VxmAsset {
+        let [x_dim, y_dim, z_dim] = size.map(usize::from);
+        let mut voxel_array = vec![vec![vec![VxmVoxel::default(); z_dim]; y_dim]; x_dim];
+        for x in 0..x_dim {
+            for y in 0..y_dim {
+                for z in 0..z_dim {
+                    voxel_array[x][y][z] = voxel_at([x, y, z]).unwrap_or_default();
+                }
+            }
+        }
+        VxmAsset {
+            size,
+            voxel_array,
+            lights: Vec::new(),
+            colour_encoding: Default::default(),
+            palette: Vec::new(),
+        }
+    }
+
+    // A 3x3x3 model of the centre voxel and the given opaque voxels
+    fn centre_voxel_with(others: &[[i32; 3]]) -> VxmAsset {
+        test_model([3, 3, 3], |position| {
+            let position = position.map(|p| p as i32);
+            (position == [1; 3] || others.contains(&position)).then_some(STONE)
+        })
+    }
+
+    fn vertex_ao(ao: u8, vertex: usize) -> u8 {
+        (ao >> (6 - vertex * 2)) & 3
+    }
+
+    // The voxels in front of a face of the centre voxel that share one of its corners with the
+    // voxel directly in front: one beside it along each axis of the face, and one diagonal
+    fn corner_occluders(face: MeshedVoxelsFace, vertex: usize) -> [[i32; 3]; 3] {
+        let normal = FACE_NORMALS[face as usize];
+        let front = [0, 1, 2].map(|axis| 1 + normal[axis]);
+        let corner = [0, 1, 2].map(|axis| 1 + FACE_VERTEX_OFFSETS[face as usize][vertex][axis]);
+
+        // Cells touching the corner are at the corner or one below it on each axis
+        let mut sides = Vec::new();
+        let mut diagonal = front;
+        for axis in 0..3 {
+            if normal[axis] != 0 {
+                continue;
+            }
+            let other = if corner[axis] == front[axis] {
+                corner[axis] - 1
+            } else {
+                corner[axis]
+            };
+            let mut side = front;
+            side[axis] = other;
+            sides.push(side);
+            diagonal[axis] = other;
+        }
+        [sides[0], sides[1], diagonal]
+    }
+
+    fn centre_ao(vxm: &VxmAsset, face: MeshedVoxelsFace) -> u8 {
+        face_ambient_occlusion(&VoxelNeighbourhood::isolated(vxm), 1, 1, 1, face)
+    }
+
+    #[test]
+    fn open_faces_are_unoccluded() {
+        let vxm = centre_voxel_with(&[]);
+        for face in ALL_FACES {
+            assert_eq!(centre_ao(&vxm, face), 0xFF, "face {}", face as usize);
+        }
+    }
+
+    #[test]
+    fn two_sides_fully_occlude_a_corner() {
+        for face in ALL_FACES {
+            for vertex in 0..4 {
+                let [side_1, side_2, diagonal] = corner_occluders(face, vertex);
+                for others in [vec![side_1, side_2], vec![side_1, side_2, diagonal]] {
+                    let ao = centre_ao(&centre_voxel_with(&others), face);
+                    assert_eq!(
+                        vertex_ao(ao, vertex),
+                        0,
+                        "face {} vertex {vertex}",
+                        face as usize
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn a_side_and_the_diagonal_occlude_a_corner_twice() {
+        for face in ALL_FACES {
+            for vertex in 0..4 {
+                let [side_1, side_2, diagonal] = corner_occluders(face, vertex);
+                for side in [side_1, side_2] {
+                    let ao = centre_ao(&centre_voxel_with(&[side, diagonal]), face);
+                    assert_eq!(
+                        vertex_ao(ao, vertex),
+                        1,
+                        "face {} vertex {vertex}",
+                        face as usize
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn a_single_neighbour_occludes_a_corner_once() {
+        for face in ALL_FACES {
+            for vertex in 0..4 {
+                for occluder in corner_occluders(face, vertex) {
+                    let ao = centre_ao(&centre_voxel_with(&[occluder]), face);
+                    assert_eq!(
+                        vertex_ao(ao, vertex),
+                        2,
+                        "face {} vertex {vertex}",
+                        face as usize
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn voxels_level_with_a_face_do_not_occlude_it() {
+        for face in ALL_FACES {
+            let normal = FACE_NORMALS[face as usize];
+            // Every voxel around the centre in its own layer, or behind it
+            let mut others = Vec::new();
+            for offset in NEIGHBOUR_OFFSETS {
+                let is_in_front =
+                    (0..3).any(|axis| normal[axis] != 0 && offset[axis] == normal[axis]);
+                if !is_in_front {
+                    others.push([0, 1, 2].map(|axis| 1 + offset[axis]));
+                }
+            }
+            assert_eq!(
+                centre_ao(&centre_voxel_with(&others), face),
+                0xFF,
+                "face {}",
+                face as usize
+            );
+        }
+    }
+
+    #[test]
+    fn transparent_voxels_do_not_occlude() {
+        let vxm = test_model([3, 3, 3], |position| {
+            (position == [1; 3]).then_some(STONE).or(Some(GLASS))
+        });
+        for face in ALL_FACES {
+            assert_eq!(centre_ao(&vxm, face), 0xFF, "face {}", face as usize);
+        }
+    }
+
+    #[test]
+    fn occlusion_reads_neighbouring_chunks() {
+        let centre = test_model([1, 1, 1], |_| Some(STONE));
+        let beside_above = test_model([1, 1, 1], |_| Some(STONE));
+        let mut neighbourhood = VoxelNeighbourhood::isolated(&centre);
+        neighbourhood.neighbours[neighbour_index([1, 1, 0])] = Some(&beside_above);
+
+        // Only the top face corners towards +x touch the voxel up and along x
+        let ao = face_ambient_occlusion(&neighbourhood, 0, 0, 0, MeshedVoxelsFace::Top);
+        for vertex in 0..4 {
+            let expected = if FACE_VERTEX_OFFSETS[MeshedVoxelsFace::Top as usize][vertex][0] == 1 {
+                2
+            } else {
+                3
+            };
+            assert_eq!(vertex_ao(ao, vertex), expected, "vertex {vertex}");
+        }
+        assert_eq!(
+            face_ambient_occlusion(&neighbourhood, 0, 0, 0, MeshedVoxelsFace::Bottom),
+            0xFF
+        );
+    }
+
+    // Air, glass and two colours of stone, with few enough colours that faces merge
+    fn random_model(rng: &mut Rng, size: [u8; 3]) -> VxmAsset {
+        let stone = VxmVoxel {
+            hsl: 0x9063,
+            ..STONE
+        };
+        test_model(size, |_| match rng.next_f32() {
+            chance if chance < 0.45 => None,
+            chance if chance < 0.55 => Some(GLASS),
+            chance if chance < 0.8 => Some(STONE),
+            _ => Some(stone.clone()),
+        })
+    }
+
+    // Sizes covering single voxels, several mask words along each axis and flat slabs
+    const MODEL_SIZES: [[u8; 3]; 5] = [[1, 1, 1], [5, 4, 3], [70, 3, 4], [3, 66, 5], [4, 5, 130]];
+
+    // Whether a face of a voxel should be meshed, checked voxel by voxel
+    fn is_face_visible(
+        neighbourhood: &VoxelNeighbourhood,
+        position: [usize; 3],
+        face: MeshedVoxelsFace,
+        layer: VoxelLayer,
+    ) -> bool {
+        let [x, y, z] = position;
+        let normal = FACE_NORMALS[face as usize];
+        let front = neighbourhood.voxel_at(
+            x as i32 + normal[0],
+            y as i32 + normal[1],
+            z as i32 + normal[2],
+        );
+        layer.contains(&neighbourhood.centre.voxel_array[x][y][z])
+            && !front.is_some_and(|voxel| layer.is_hidden_by(voxel))
+    }
+
+    // Every voxel face a quad covers
+    fn covered_faces(instance: &InstanceData, face: MeshedVoxelsFace) -> Vec<[usize; 3]> {
+        let (_, axis_u, axis_v) = face_axes(face);
+        let mut faces = Vec::new();
+        for dv in 0..instance.height as usize {
+            for du in 0..instance.width as usize {
+                let mut position = instance.position.map(usize::from);
+                position[axis_u] += du;
+                position[axis_v] += dv;
+                faces.push(position);
+            }
+        }
+        faces
+    }
+
+    // Checks each visible face is covered by exactly one quad, no hidden face is covered, and
+    // quads only cover faces of their own colour, shading and light
+    fn assert_faces_covered_once(
+        neighbourhood: &VoxelNeighbourhood,
+        face: MeshedVoxelsFace,
+        layer: VoxelLayer,
+        match_colours: bool,
+        instances: &[InstanceData],
+    ) {
+        let vxm = neighbourhood.centre;
+        let [x_dim, y_dim, z_dim] = vxm.size.map(usize::from);
+        let mut coverage = vec![vec![vec![0; z_dim]; y_dim]; x_dim];
+        for instance in instances {
+            for [x, y, z] in covered_faces(instance, face) {
+                assert!(x < x_dim && y < y_dim && z < z_dim, "quad leaves the model");
+                coverage[x][y][z] += 1;
+
+                // Without matching colours the shader shades each fragment itself
+                if !match_colours {
+                    continue;
+                }
+                let voxel = &vxm.voxel_array[x][y][z];
+                assert_eq!(
+                    instance.hsl, voxel.hsl,
+                    "quad merges colours at {x} {y} {z}"
+                );
+                let shading = match layer {
+                    VoxelLayer::Opaque => face_ambient_occlusion(neighbourhood, x, y, z, face),
+                    VoxelLayer::Transparent => voxel.opacity,
+                };
+                assert_eq!(instance.ambient_occlusion, shading, "quad merges shading");
+            }
+        }
+
+        for x in 0..x_dim {
+            for y in 0..y_dim {
+                for z in 0..z_dim {
+                    let expected = is_face_visible(neighbourhood, [x, y, z], face, layer) as u32;
+                    assert_eq!(
+                        coverage[x][y][z], expected,
+                        "face {} of voxel {x} {y} {z} of a {:?} model",
+                        face as usize, vxm.size
+                    );
+                }
+            }
+        }
+    }
+
+    fn mesh_every_slice(
+        neighbourhood: &VoxelNeighbourhood,
+        face: MeshedVoxelsFace,
+        layer: VoxelLayer,
+        match_colours: bool,
+    ) -> Vec<InstanceData> {
+        let size_d = neighbourhood.centre.size[face_axes(face).0] as usize;
+        generate_instance_data(neighbourhood, face, layer, match_colours, 0..size_d)
+    }
+
+    #[test]
+    fn greedy_quads_cover_each_visible_face_exactly_once() {
+        let mut rng = Rng::new(29);
+        for size in MODEL_SIZES {
+            let vxm = random_model(&mut rng, size);
+            let neighbourhood = VoxelNeighbourhood::isolated(&vxm);
+            for face in ALL_FACES {
+                for (layer, match_colours) in [
+                    (VoxelLayer::Opaque, true),
+                    (VoxelLayer::Opaque, false),
+                    (VoxelLayer::Transparent, true),
+                ] {
+                    let instances = mesh_every_slice(&neighbourhood, face, layer, match_colours);
+                    assert_faces_covered_once(
+                        &neighbourhood,
+                        face,
+                        layer,
+                        match_colours,
+                        &instances,
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn colour_volume_quads_merge_across_colours_and_shading() {
+        // A floor of every colour with a pillar shading the faces around its foot
+        let mut rng = Rng::new(34);
+        let vxm = test_model([8, 2, 8], |[x, y, z]| {
+            let hsl = 0x8000 | rng.range_inclusive(1, 0x7FFF) as u16;
+            (y == 0 || [x, z] == [4, 4]).then_some(VxmVoxel { hsl, ..STONE })
+        });
+        let neighbourhood = VoxelNeighbourhood::isolated(&vxm);
+
+        let top = mesh_every_slice(
+            &neighbourhood,
+            MeshedVoxelsFace::Top,
+            VoxelLayer::Opaque,
+            false,
+        );
+        // The floor's top is only split into 4 quads around the pillar's foot, then the pillar's
+        // top makes 5
+        assert_eq!(top.len(), 5);
+        assert!(top
+            .iter()
+            .all(|instance| instance.hsl == 0 && instance.light == 0));
+    }
+
+    #[test]
+    fn colour_volume_holds_the_neighbours_voxels_and_light() {
+        let vxm = test_model([2, 2, 2], |position| match position {
+            [0, 0, 0] => Some(STONE),
+            [1, 0, 0] => Some(GLASS),
+            _ => None,
+        });
+        let neighbour = test_model([2, 2, 2], |_| Some(STONE));
+        let mut neighbourhood = VoxelNeighbourhood::isolated(&vxm);
+        neighbourhood.neighbours[neighbour_index([1, 0, 0])] = Some(&neighbour);
+
+        let volume = create_colour_volume(&neighbourhood);
+        assert_eq!(volume.len(), 4 * 4 * 4);
+        let at = |[x, y, z]: [i32; 3]| volume[(((x + 1) * 4 + y + 1) * 4 + z + 1) as usize];
+        assert_eq!(at([0, 0, 0]) & 0xFFFF, STONE.hsl as u32);
+        // Transparent voxels neither colour nor occlude colour volume faces
+        assert_eq!(at([1, 0, 0]) & 0xFFFF, 0);
+        assert_eq!(at([2, 0, 0]) & 0xFFFF, STONE.hsl as u32);
+        assert_eq!(at([-1, 0, 0]) & 0xFFFF, 0);
+        assert!(volume
+            .iter()
+            .all(|voxel| voxel >> 16 == OPEN_SKY_LIGHT as u32));
+    }
+
+    #[test]
+    fn faces_hidden_by_neighbouring_chunks_are_not_meshed() {
+        let mut rng = Rng::new(290);
+        for size in MODEL_SIZES {
+            let vxm = random_model(&mut rng, size);
+            let neighbours = NEIGHBOUR_OFFSETS.map(|_| random_model(&mut rng, size));
+            let mut neighbourhood = VoxelNeighbourhood::isolated(&vxm);
+            for (offset, neighbour) in NEIGHBOUR_OFFSETS.iter().zip(&neighbours) {
+                neighbourhood.neighbours[neighbour_index(*offset)] = Some(neighbour);
+            }
+            for face in ALL_FACES {
+                for layer in [VoxelLayer::Opaque, VoxelLayer::Transparent] {
+                    let instances = mesh_every_slice(&neighbourhood, face, layer, true);
+                    assert_faces_covered_once(&neighbourhood, face, layer, true, &instances);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn transparent_voxels_leave_the_faces_behind_them_uncovered() {
+        let stone = test_model([1, 1, 1], |_| Some(STONE));
+        let glass = test_model([1, 1, 1], |_| Some(GLASS));
+        let mut neighbourhood = VoxelNeighbourhood::isolated(&stone);
+        neighbourhood.neighbours[neighbour_index([1, 0, 0])] = Some(&glass);
+        let faces = mesh_every_slice(
+            &neighbourhood,
+            MeshedVoxelsFace::Right,
+            VoxelLayer::Opaque,
+            true,
+        );
+        assert_eq!(faces.len(), 1, "stone face behind neighbouring glass");
+
+        // Within a model the glass doesn't draw a face over the stone either, the stone's face
+        // shows through it
+        let vxm = test_model([2, 1, 1], |[x, _, _]| {
+            Some(if x == 0 { STONE } else { GLASS })
+        });
+        let neighbourhood = VoxelNeighbourhood::isolated(&vxm);
+        let faces = mesh_every_slice(
+            &neighbourhood,
+            MeshedVoxelsFace::Right,
+            VoxelLayer::Opaque,
+            true,
+        );
+        assert_eq!(faces.len(), 1, "stone face behind glass");
+        assert_eq!(faces[0].position, [0, 0, 0]);
+        let faces = mesh_every_slice(
+            &neighbourhood,
+            MeshedVoxelsFace::Left,
+            VoxelLayer::Transparent,
+            true,
+        );
+        assert!(faces.is_empty(), "glass face over stone");
+    }
+
+    #[test]
+    fn meshing_slices_separately_matches_meshing_them_together() {
+        let mut rng = Rng::new(2900);
+        let vxm = random_model(&mut rng, [6, 7, 8]);
+        let neighbourhood = VoxelNeighbourhood::isolated(&vxm);
+        for face in ALL_FACES {
+            let size_d = vxm.size[face_axes(face).0] as usize;
+            let together = mesh_every_slice(&neighbourhood, face, VoxelLayer::Opaque, true);
+            let separately = (0..size_d)
+                .flat_map(|d| {
+                    generate_instance_data(&neighbourhood, face, VoxelLayer::Opaque, true, d..d + 1)
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(
+                bytemuck::cast_slice::<_, u8>(&together),
+                bytemuck::cast_slice::<_, u8>(&separately)
+            );
+        }
+    }
+
+    #[test]
+    fn standard_meshes_have_four_vertices_and_six_indices_per_quad() {
+        // Every face of a single voxel, and of a solid 2x2x2 block where each side merges into one
+        // quad, takes a quad
+        for size in [[1, 1, 1], [2, 2, 2]] {
+            let vxm = test_model(size, |_| Some(STONE));
+            let mesh = mesh_standard(&VoxelNeighbourhood::isolated(&vxm));
+            assert_eq!(mesh.count_vertices(), 6 * 4, "{size:?} block");
+            assert_eq!(
+                mesh.indices().map(Indices::len),
+                Some(6 * 6),
+                "{size:?} block"
+            );
+            let positions = mesh
+                .attribute(Mesh::ATTRIBUTE_POSITION)
+                .and_then(|positions| positions.as_float3())
+                .unwrap();
+            let extent = f32::from(size[0]);
+            assert!(positions.iter().flatten().all(|&p| p == 0.0 || p == extent));
+        }
+
+        // Alternating colours keep each side of the block from merging, leaving a quad per voxel
+        // face
+        let stone = VxmVoxel {
+            hsl: 0x9063,
+            ..STONE
+        };
+        let vxm = test_model([2, 2, 2], |[x, y, z]| {
+            Some(if (x + y + z) % 2 == 0 {
+                STONE
+            } else {
+                stone.clone()
+            })
+        });
+        let mesh = mesh_standard(&VoxelNeighbourhood::isolated(&vxm));
+        assert_eq!(mesh.count_vertices(), 24 * 4);
+        assert_eq!(mesh.indices().map(Indices::len), Some(24 * 6));
+    }
+
+    // A GPU mesher on whichever adapter is available, software ones included, or None where
+    // there's no adapter at all
+    fn headless_gpu_mesher() -> Option<GpuMesher> {
+        let instance = wgpu::Instance::default();
+        let adapter =
+            pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions::default()))
+                .ok()?;
+        let (device, queue) =
+            pollster::block_on(adapter.request_device(&wgpu::DeviceDescriptor::default())).ok()?;
+        Some(GpuMesher::new(Arc::new(device), queue))
+    }
+
+    #[test]
+    #[ignore = "needs a GPU adapter, a software one will do"]
+    fn gpu_meshing_matches_the_cpu_mesher() {
+        let gpu_mesher = headless_gpu_mesher().expect("No GPU adapter found");
+        let mut rng = Rng::new(35);
+        // Models as long as they can be along each axis, filling every thread of a slice
+        let longest_models = [[255, 4, 3], [3, 255, 4], [4, 3, 255]];
+        for size in MODEL_SIZES.into_iter().chain(longest_models) {
+            let vxm = random_model(&mut rng, size);
+            let neighbours = NEIGHBOUR_OFFSETS.map(|_| random_model(&mut rng, size));
+            let mut neighbourhood = VoxelNeighbourhood::isolated(&vxm);
+            for (offset, neighbour) in NEIGHBOUR_OFFSETS.iter().zip(&neighbours) {
+                neighbourhood.neighbours[neighbour_index(*offset)] = Some(neighbour);
+            }
+            let light = LightVolume::compute(&neighbourhood);
+
+            for light in [None, Some(&light)] {
+                let neighbourhood = VoxelNeighbourhood {
+                    neighbours: neighbourhood.neighbours,
+                    ..VoxelNeighbourhood::isolated(&vxm)
+                }
+                .with_light(light);
+                for opaque_meshing in [
+                    OpaqueMeshing::Faces,
+                    OpaqueMeshing::ColourVolume,
+                    OpaqueMeshing::Smooth,
+                ] {
+                    let gpu_opaque = gpu_match_colours(opaque_meshing)
+                        .map(|match_colours| {
+                            gpu_mesher.mesh_and_read_back(
+                                &neighbourhood,
+                                VoxelLayer::Opaque,
+                                match_colours,
+                            )
+                        })
+                        .unwrap_or_default();
+                    let gpu_transparent = gpu_mesher.mesh_and_read_back(
+                        &neighbourhood,
+                        VoxelLayer::Transparent,
+                        true,
+                    );
+                    for (face, (opaque, transparent)) in ALL_FACES
+                        .into_iter()
+                        .zip(gpu_opaque.iter().zip(&gpu_transparent))
+                    {
+                        let (cpu_opaque, cpu_transparent) =
+                            mesh_face(&neighbourhood, face, opaque_meshing);
+                        assert_eq!(
+                            bytemuck::cast_slice::<_, u8>(opaque),
+                            bytemuck::cast_slice::<_, u8>(&cpu_opaque),
+                            "opaque face {} of a {size:?} model",
+                            face as usize
+                        );
+                        assert_eq!(
+                            bytemuck::cast_slice::<_, u8>(transparent),
+                            bytemuck::cast_slice::<_, u8>(&cpu_transparent),
+                            "transparent face {} of a {size:?} model",
+                            face as usize
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,337 @@
+use crate::vxm_biome::BiomePalette;
+use bevy::math::Vec2;
+use serde::{Deserialize, Serialize};
+
+// Distance in voxels between the points a path's spline is sampled at
+const PATH_SAMPLE_SPACING: f32 = 4.0;
+
+// Samples either side averaged into the height of a road, so it rolls over hills rather than
+// following every bump
+const ROAD_SMOOTHING_SAMPLES: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum TerrainPathKind {
+    /// Cuts through hills and fills dips to keep the ground level across its width
+    Road,
+    /// Only ever carves down, running downhill from its first point and filled with water
+    River,
+}
+
+/// A road, river or track the terrain is carved along, following a smooth spline through its
+/// points
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TerrainPath {
+    pub kind: TerrainPathKind,
+    /// World x and z positions in voxels the path passes through, at least two
+    pub points: Vec<[f32; 2]>,
+    /// Width in voxels of the bed of the path
+    pub width: f32,
+    /// Distance in voxels beyond the edge of the bed over which the ground blends back to its
+    /// own height
+    pub falloff: f32,
+    /// How far in voxels the bed is sunk below the surface of the path, which rivers fill with
+    /// water
+    #[serde(default)]
+    pub depth: f32,
+    /// Colour of the ground of the bed
+    pub palette: BiomePalette,
+}
+
+/// How a path changes a column of ground
+pub struct PathColumn<'a> {
+    pub path: &'a TerrainPath,
+    /// Height of the ground in voxels once carved
+    pub height: f32,
+    /// Whether the column is on the bed of the path rather than the slopes either side
+    pub is_bed: bool,
+    /// How much the path shapes the column, from 1 on the bed to 0 where the falloff ends
+    pub influence: f32,
+    /// Height in voxels of the water surface of rivers
+    pub water_level: Option<f32>,
+}
+
+/// A path sampled along its spline, with the height of its surface at each sample
+pub struct SampledPath<'a> {
+    path: &'a TerrainPath,
+    /// World x, z and the surface height in voxels of each sample
+    samples: Vec<[f32; 3]>,
+}
+
+// Point on a Catmull-Rom spline segment between p1 and p2
+fn catmull_rom(p0: Vec2, p1: Vec2, p2: Vec2, p3: Vec2, t: f32) -> Vec2 {
+    let t2 = t * t;
+    let t3 = t2 * t;
+    0.5 * (2.0 * p1
+        + (p2 - p0) * t
+        + (2.0 * p0 - 5.0 * p1 + 4.0 * p2 - p3) * t2
+        + (3.0 * p1 - p0 - 3.0 * p2 + p3) * t3)
+}
+
+impl TerrainPath {
+    // Distance from the centre line at which the path stops shaping the ground
+    fn reach(&self) -> f32 {
+        self.width * 0.5 + self.falloff
+    }
+
+    /// Samples the spline of the path from its first point to its last if it reaches a
+    /// rectangle of world columns, with `ground_height` giving the height in voxels of the
+    /// ground at a world position.
+    ///
+    /// The whole path is sampled the same way for every chunk, so the height of its surface
+    /// comes out the same on both sides of chunk borders.
+    pub fn sample_over(
+        &self,
+        min: Vec2,
+        max: Vec2,
+        ground_height: impl Fn(f32, f32) -> f32,
+    ) -> Option<SampledPath<'_>> {
+        let points = self
+            .points
+            .iter()
+            .copied()
+            .map(Vec2::from)
+            .collect::<Vec<_>>();
+        let mut positions = Vec::new();
+        for index in 0..points.len().saturating_sub(1) {
+            let p0 = points[index.saturating_sub(1)];
+            let (p1, p2) = (points[index], points[index + 1]);
+            let p3 = points[(index + 2).min(points.len() - 1)];
+            let steps = ((p1.distance(p2) / PATH_SAMPLE_SPACING).ceil() as usize).max(1);
+            for step in 0..steps {
+                positions.push(catmull_rom(p0, p1, p2, p3, step as f32 / steps as f32));
+            }
+        }
+        if let Some(&last) = points.last() {
+            positions.push(last);
+        }
+        // Samples are close enough together that any column in reach is near one of them
+        let margin = self.reach() + PATH_SAMPLE_SPACING;
+        if !positions.iter().any(|position| {
+            position.cmpge(min - margin).all() && position.cmple(max + margin).all()
+        }) {
+            return None;
+        }
+
+        let ground = positions
+            .iter()
+            .map(|position| ground_height(position.x, position.y))
+            .collect::<Vec<_>>();
+        let surface = match self.kind {
+            TerrainPathKind::Road => (0..ground.len())
+                .map(|index| {
+                    let window = &ground[index.saturating_sub(ROAD_SMOOTHING_SAMPLES)
+                        ..(index + ROAD_SMOOTHING_SAMPLES + 1).min(ground.len())];
+                    window.iter().sum::<f32>() / window.len() as f32
+                })
+                .collect::<Vec<_>>(),
+            // Water never runs uphill, so the river surface only ever drops along it
+            TerrainPathKind::River => ground
+                .iter()
+                .scan(f32::INFINITY, |lowest, &height| {
+                    *lowest = lowest.min(height);
+                    Some(*lowest)
+                })
+                .collect::<Vec<_>>(),
+        };
+
+        Some(SampledPath {
+            path: self,
+            samples: positions
+                .iter()
+                .zip(surface)
+                .map(|(position, surface)| [position.x, position.y, surface])
+                .collect(),
+        })
+    }
+}
+
+impl<'a> SampledPath<'a> {
+    // Distance from a point to the centre line, and the surface height of the path nearest it
+    fn nearest(&self, point: Vec2) -> Option<(f32, f32)> {
+        let reach = self.path.reach();
+        let mut nearest: Option<(f32, f32)> = None;
+        for segment in self.samples.windows(2) {
+            let [start_x, start_z, start_surface] = segment[0];
+            let [end_x, end_z, end_surface] = segment[1];
+            let (start, end) = (Vec2::new(start_x, start_z), Vec2::new(end_x, end_z));
+            // Cheap rejection of segments out of reach before projecting onto them
+            if point.x < start.x.min(end.x) - reach
+                || point.x > start.x.max(end.x) + reach
+                || point.y < start.y.min(end.y) - reach
+                || point.y > start.y.max(end.y) + reach
+            {
+                continue;
+            }
+            let along = end - start;
+            let t = if along.length_squared() > 0.0 {
+                ((point - start).dot(along) / along.length_squared()).clamp(0.0, 1.0)
+            } else {
+                0.0
+            };
+            let distance = point.distance(start + along * t);
+            if nearest.is_none_or(|(nearest_distance, _)| distance < nearest_distance) {
+                nearest = Some((distance, start_surface + (end_surface - start_surface) * t));
+            }
+        }
+        nearest.filter(|&(distance, _)| distance < reach)
+    }
+
+    /// Carves a world column of ground `ground_height` voxels high, or `None` if the path
+    /// doesn't reach it
+    pub fn carve(&self, world_x: i32, world_z: i32, ground_height: f32) -> Option<PathColumn<'a>> {
+        let (distance, surface) = self.nearest(Vec2::new(world_x as f32, world_z as f32))?;
+        let path = self.path;
+        let bed = surface - path.depth;
+        let beyond_bed = distance - path.width * 0.5;
+        let influence = if beyond_bed <= 0.0 {
+            1.0
+        } else {
+            // Smoothstep, so the slopes meet the bed and the ground either side without a crease
+            let t = 1.0 - beyond_bed / path.falloff.max(f32::EPSILON);
+            t * t * (3.0 - 2.0 * t)
+        };
+        let carved = ground_height + (bed - ground_height) * influence;
+
+        Some(match path.kind {
+            TerrainPathKind::Road => PathColumn {
+                path,
+                height: carved,
+                is_bed: beyond_bed <= 0.0,
+                influence,
+                water_level: None,
+            },
+            TerrainPathKind::River => PathColumn {
+                path,
+                height: ground_height.min(carved),
+                is_bed: beyond_bed <= 0.0,
+                influence,
+                water_level: Some(surface),
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_path(kind: TerrainPathKind) -> TerrainPath {
+        TerrainPath {
+            kind,
+            points: vec![[-40.0, -10.0], [0.0, 5.0], [40.0, 0.0], [80.0, 30.0]],
+            width: 6.0,
+            falloff: 4.0,
+            depth: 2.0,
+            palette: BiomePalette {
+                colour: [0.4, 0.35, 0.3],
+                variation: [0.0; 3],
+            },
+        }
+    }
+
+    fn ground_height(x: f32, z: f32) -> f32 {
+        30.0 + 10.0 * (x / 20.0).sin() + 5.0 * (z / 15.0).cos()
+    }
+
+    // Carves the columns of a 16 by 16 chunk with the path sampled over just that chunk
+    fn carve_chunk(path: &TerrainPath, origin: [i32; 2]) -> Vec<Option<(f32, Option<f32>)>> {
+        let min = Vec2::new(origin[0] as f32, origin[1] as f32);
+        let Some(sampled) = path.sample_over(min, min + 15.0, ground_height) else {
+            return vec![None; 16 * 16];
+        };
+        (0..16)
+            .flat_map(|x| (0..16).map(move |z| (origin[0] + x, origin[1] + z)))
+            .map(|(x, z)| {
+                sampled
+                    .carve(x, z, ground_height(x as f32, z as f32))
+                    .map(|column| (column.height, column.water_level))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn paths_carve_the_same_columns_from_any_chunk() {
+        for kind in [TerrainPathKind::Road, TerrainPathKind::River] {
+            let path = test_path(kind);
+            let whole = path
+                .sample_over(Vec2::splat(-64.0), Vec2::splat(96.0), ground_height)
+                .unwrap();
+            for chunk_x in -3..6 {
+                for chunk_z in -2..3 {
+                    let origin = [chunk_x * 16, chunk_z * 16];
+                    let columns = carve_chunk(&path, origin);
+                    for (i, column) in columns.into_iter().enumerate() {
+                        let x = origin[0] + i as i32 / 16;
+                        let z = origin[1] + i as i32 % 16;
+                        let expected = whole
+                            .carve(x, z, ground_height(x as f32, z as f32))
+                            .map(|column| (column.height, column.water_level));
+                        assert_eq!(column, expected, "{kind:?} column {x} {z}");
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn paths_out_of_reach_are_not_sampled() {
+        let path = test_path(TerrainPathKind::Road);
+        assert!(path
+            .sample_over(Vec2::new(0.0, 100.0), Vec2::new(15.0, 115.0), ground_height)
+            .is_none());
+        assert!(path
+            .sample_over(Vec2::new(0.0, 0.0), Vec2::new(15.0, 15.0), ground_height)
+            .is_some());
+    }
+
+    #[test]
+    fn rivers_only_run_downhill_and_only_carve_down() {
+        let path = test_path(TerrainPathKind::River);
+        let sampled = path
+            .sample_over(Vec2::splat(-64.0), Vec2::splat(96.0), ground_height)
+            .unwrap();
+        assert!(sampled
+            .samples
+            .windows(2)
+            .all(|pair| pair[1][2] <= pair[0][2]));
+
+        for x in -64..96 {
+            for z in -32..48 {
+                let ground = ground_height(x as f32, z as f32);
+                if let Some(column) = sampled.carve(x, z, ground) {
+                    assert!(column.height <= ground, "column {x} {z}");
+                    let water_level = column.water_level.unwrap();
+                    if column.is_bed {
+                        assert!(column.height < water_level, "column {x} {z}");
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn roads_are_level_across_their_bed_and_blend_into_the_ground() {
+        let path = test_path(TerrainPathKind::Road);
+        let sampled = path
+            .sample_over(Vec2::splat(-64.0), Vec2::splat(96.0), ground_height)
+            .unwrap();
+        for x in -64..96 {
+            for z in -32..48 {
+                let ground = ground_height(x as f32, z as f32);
+                let Some(column) = sampled.carve(x, z, ground) else {
+                    continue;
+                };
+                let (_, surface) = sampled.nearest(Vec2::new(x as f32, z as f32)).unwrap();
+                let bed = surface - path.depth;
+                assert!((0.0..=1.0).contains(&column.influence));
+                if column.is_bed {
+                    assert_eq!(column.influence, 1.0);
+                    assert!((column.height - bed).abs() < 1e-4, "column {x} {z}");
+                } else {
+                    let (low, high) = (ground.min(bed), ground.max(bed));
+                    assert!((low..=high).contains(&column.height), "column {x} {z}");
+                }
+            }
+        }
+    }
+}
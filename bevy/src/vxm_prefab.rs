@@ -0,0 +1,364 @@
+use crate::vxm::{VxmAsset, VxmVoxel};
+use crate::vxm_rng::hash;
+use bevy::math::IVec3;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+/// Voxels of a model stamped into terrain, laid out x, then y, then z
+#[derive(Debug, Default, PartialEq)]
+pub struct Prefab {
+    pub size: [i32; 3],
+    pub voxels: Vec<VxmVoxel>,
+}
+
+impl Prefab {
+    pub fn from_vxm(vxm: &VxmAsset) -> Self {
+        Self {
+            size: vxm.size.map(|size| size as i32),
+            voxels: vxm
+                .voxel_array
+                .iter()
+                .flat_map(|plane| plane.iter().flat_map(|row| row.iter().cloned()))
+                .collect(),
+        }
+    }
+
+    fn voxel_at(&self, x: i32, y: i32, z: i32) -> &VxmVoxel {
+        &self.voxels[((x * self.size[1] + y) * self.size[2] + z) as usize]
+    }
+}
+
+/// Where a prefab may be placed on the terrain, and how often
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PrefabPlacement {
+    /// Path of the .vxm model, relative to the assets folder
+    pub path: String,
+    /// Size in voxels of the grid cells the prefab is scattered over, one at most per cell
+    pub spacing: i32,
+    /// Chance of a cell holding the prefab, from 0 to 1
+    pub chance: f32,
+    /// Greatest difference in ground height in voxels under the corners of the prefab
+    pub max_slope: i32,
+    /// Names of the biomes the prefab is placed in, or any biome if empty
+    #[serde(default)]
+    pub biomes: Vec<String>,
+    /// How many voxels the prefab is sunk into the ground
+    #[serde(default)]
+    pub sink: i32,
+    /// Filled in when the terrain settings are loaded
+    #[serde(skip)]
+    pub prefab: Arc<Prefab>,
+}
+
+/// A prefab placed in the world, turned a quarter turn `rotation` times around y
+pub struct Placement<'a> {
+    pub prefab: &'a Prefab,
+    /// World position of the corner of the prefab with the lowest coordinates
+    pub origin: IVec3,
+    pub rotation: u32,
+}
+
+impl Placement<'_> {
+    /// Size of the prefab in the world once rotated
+    pub fn size(&self) -> IVec3 {
+        let [x, y, z] = self.prefab.size;
+        if self.rotation % 2 == 0 {
+            IVec3::new(x, y, z)
+        } else {
+            IVec3::new(z, y, x)
+        }
+    }
+
+    // Voxel of the unrotated prefab at a position within the rotated footprint
+    fn voxel_at(&self, x: i32, y: i32, z: i32) -> &VxmVoxel {
+        let [size_x, _, size_z] = self.prefab.size;
+        let (prefab_x, prefab_z) = match self.rotation % 4 {
+            0 => (x, z),
+            1 => (z, size_z - 1 - x),
+            2 => (size_x - 1 - x, size_z - 1 - z),
+            _ => (size_x - 1 - z, x),
+        };
+        self.prefab.voxel_at(prefab_x, y, prefab_z)
+    }
+
+    /// Copies the solid voxels of the prefab that fall inside a chunk into its voxels, returning
+    /// whether any were copied
+    pub fn stamp(
+        &self,
+        voxel_array: &mut [Vec<Vec<VxmVoxel>>],
+        chunk_origin: IVec3,
+        chunk_size: i32,
+    ) -> bool {
+        let min = (self.origin - chunk_origin).max(IVec3::ZERO);
+        let max = (self.origin + self.size() - chunk_origin).min(IVec3::splat(chunk_size));
+        let mut is_stamped = false;
+        for x in min.x..max.x {
+            for y in min.y..max.y {
+                for z in min.z..max.z {
+                    let local = IVec3::new(x, y, z) + chunk_origin - self.origin;
+                    let voxel = self.voxel_at(local.x, local.y, local.z);
+                    // Every colour encoding reserves 0 for air
+                    if voxel.hsl == 0 {
+                        continue;
+                    }
+                    voxel_array[x as usize][y as usize][z as usize] = voxel.clone();
+                    is_stamped = true;
+                }
+            }
+        }
+        is_stamped
+    }
+}
+
+// Mixes the seed, grid cell and placement rule into well distributed bits
+fn hash_cell(seed: i32, cell_x: i32, cell_z: i32, rule: usize) -> u64 {
+    hash(
+        ((seed as u32 as u64) << 32) ^ rule as u64,
+        [cell_x as u32 as u64, cell_z as u32 as u64],
+    )
+}
+
+/// Finds every prefab placed over a chunk.
+///
+/// Prefabs are scattered over a grid of cells in world space, jittered within their cell by a
+/// hash of the seed and the cell. Every chunk a prefab overlaps looks at the same cells and gets
+/// the same result, so prefabs crossing chunk borders come out whole whatever order chunks load.
+///
+/// `ground_at` gives the height of the highest solid voxel of a world column and the name of its
+/// biome, or `None` if the column is under water.
+pub fn placements_over_chunk<'a>(
+    placements: &'a [PrefabPlacement],
+    seed: i32,
+    chunk_origin: IVec3,
+    chunk_size: i32,
+    ground_at: impl Fn(i32, i32) -> Option<(i32, &'a str)>,
+) -> Vec<Placement<'a>> {
+    let chunk_end = chunk_origin + IVec3::splat(chunk_size);
+    let mut placed = Vec::new();
+
+    for (rule, placement) in placements.iter().enumerate() {
+        let prefab = placement.prefab.as_ref();
+        let spacing = placement.spacing.max(1);
+        // Prefabs anchored in cells around the chunk can still reach into it
+        let reach = prefab.size[0].max(prefab.size[2]);
+        let min_cell_x = (chunk_origin.x - reach).div_euclid(spacing);
+        let max_cell_x = (chunk_end.x + reach).div_euclid(spacing);
+        let min_cell_z = (chunk_origin.z - reach).div_euclid(spacing);
+        let max_cell_z = (chunk_end.z + reach).div_euclid(spacing);
+
+        for cell_x in min_cell_x..=max_cell_x {
+            for cell_z in min_cell_z..=max_cell_z {
+                let hash = hash_cell(seed, cell_x, cell_z, rule);
+                if (hash & 0xFFFF) as f32 / 65536.0 >= placement.chance {
+                    continue;
+                }
+                let x = cell_x * spacing + ((hash >> 16) & 0xFFFF) as i32 % spacing;
+                let z = cell_z * spacing + ((hash >> 32) & 0xFFFF) as i32 % spacing;
+                let rotation = ((hash >> 48) % 4) as u32;
+
+                let mut candidate = Placement {
+                    prefab,
+                    origin: IVec3::new(x, 0, z),
+                    rotation,
+                };
+                let size = candidate.size();
+                if x >= chunk_end.x
+                    || z >= chunk_end.z
+                    || x + size.x <= chunk_origin.x
+                    || z + size.z <= chunk_origin.z
+                {
+                    continue;
+                }
+
+                let Some((ground, biome)) = ground_at(x + size.x / 2, z + size.z / 2) else {
+                    continue;
+                };
+                if !placement.biomes.is_empty()
+                    && !placement.biomes.iter().any(|name| name == biome)
+                {
+                    continue;
+                }
+
+                let mut lowest = ground;
+                let mut highest = ground;
+                for (corner_x, corner_z) in [
+                    (x, z),
+                    (x + size.x - 1, z),
+                    (x, z + size.z - 1),
+                    (x + size.x - 1, z + size.z - 1),
+                ] {
+                    let Some((corner_ground, _)) = ground_at(corner_x, corner_z) else {
+                        lowest = i32::MIN;
+                        break;
+                    };
+                    lowest = lowest.min(corner_ground);
+                    highest = highest.max(corner_ground);
+                }
+                if lowest == i32::MIN || highest - lowest > placement.max_slope {
+                    continue;
+                }
+
+                candidate.origin.y = ground + 1 - placement.sink;
+                if candidate.origin.y >= chunk_end.y
+                    || candidate.origin.y + size.y <= chunk_origin.y
+                {
+                    continue;
+                }
+                placed.push(candidate);
+            }
+        }
+    }
+    placed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHUNK_SIZE: i32 = 16;
+
+    // Places a prefab across the corner of four chunks
+    const SEED: i32 = 4;
+
+    // A prefab with a different colour in every voxel, so misplaced voxels show
+    fn test_prefab(size: [i32; 3]) -> Prefab {
+        Prefab {
+            size,
+            voxels: (1..=size[0] * size[1] * size[2])
+                .map(|hsl| VxmVoxel {
+                    hsl: hsl as u16,
+                    emissive: false,
+                    opacity: u8::MAX,
+                })
+                .collect(),
+        }
+    }
+
+    fn test_placements() -> Vec<PrefabPlacement> {
+        vec![
+            PrefabPlacement {
+                path: "tower.vxm".to_string(),
+                spacing: 16,
+                chance: 0.5,
+                max_slope: 8,
+                biomes: Vec::new(),
+                sink: 1,
+                prefab: Arc::new(test_prefab([6, 5, 9])),
+            },
+            PrefabPlacement {
+                path: "rock.vxm".to_string(),
+                spacing: 7,
+                chance: 0.3,
+                max_slope: 8,
+                biomes: vec!["desert".to_string()],
+                sink: 0,
+                prefab: Arc::new(test_prefab([3, 2, 3])),
+            },
+        ]
+    }
+
+    // Gently rolling ground, desert to the east of x 20
+    fn ground_at<'a>(x: i32, z: i32) -> Option<(i32, &'a str)> {
+        let height = 20 + (x + 2 * z).rem_euclid(5);
+        Some((height, if x > 20 { "desert" } else { "plains" }))
+    }
+
+    fn placed_over(
+        placements: &[PrefabPlacement],
+        seed: i32,
+        chunk_origin: IVec3,
+        chunk_size: i32,
+    ) -> Vec<(IVec3, u32, [i32; 3])> {
+        placements_over_chunk(placements, seed, chunk_origin, chunk_size, ground_at)
+            .iter()
+            .map(|placement| (placement.origin, placement.rotation, placement.prefab.size))
+            .collect()
+    }
+
+    fn stamped(
+        placements: &[PrefabPlacement],
+        chunk_origin: IVec3,
+        chunk_size: i32,
+    ) -> Vec<Vec<Vec<VxmVoxel>>> {
+        let size = chunk_size as usize;
+        let mut voxel_array = vec![vec![vec![VxmVoxel::default(); size]; size]; size];
+        for placement in
+            placements_over_chunk(placements, SEED, chunk_origin, chunk_size, ground_at)
+        {
+            placement.stamp(&mut voxel_array, chunk_origin, chunk_size);
+        }
+        voxel_array
+    }
+
+    #[test]
+    fn the_same_seed_places_the_same_prefabs() {
+        let placements = test_placements();
+        let chunk_origin = IVec3::new(-16, 16, 32);
+        assert_eq!(
+            placed_over(&placements, SEED, chunk_origin, CHUNK_SIZE),
+            placed_over(&placements, SEED, chunk_origin, CHUNK_SIZE)
+        );
+        assert_ne!(
+            placed_over(&placements, SEED, chunk_origin, 64),
+            placed_over(&placements, SEED + 1, chunk_origin, 64)
+        );
+    }
+
+    #[test]
+    fn prefabs_are_only_placed_in_their_biomes() {
+        let placements = test_placements();
+        let placed = placed_over(&placements, SEED, IVec3::new(-64, 0, -64), 128);
+        let rocks = placed
+            .iter()
+            .filter(|(_, _, size)| *size == [3, 2, 3])
+            .collect::<Vec<_>>();
+        assert!(!rocks.is_empty());
+        for (origin, _, _) in rocks {
+            assert_eq!(ground_at(origin.x + 1, origin.z + 1).unwrap().1, "desert");
+        }
+    }
+
+    #[test]
+    fn prefabs_on_chunk_borders_agree_from_both_sides() {
+        let placements = test_placements();
+        let region_origin = IVec3::new(0, 16, 0);
+        let region_size = 2 * CHUNK_SIZE;
+        let region = stamped(&placements, region_origin, region_size);
+
+        // Some prefab must cross a chunk border for the chunks to have anything to agree on
+        let placed = placed_over(&placements, SEED, region_origin, region_size);
+        assert!(placed.iter().any(|&(origin, rotation, size)| {
+            let (width, depth) = if rotation % 2 == 0 {
+                (size[0], size[2])
+            } else {
+                (size[2], size[0])
+            };
+            origin.x < CHUNK_SIZE
+                && origin.x + width > CHUNK_SIZE
+                && origin.z < CHUNK_SIZE
+                && origin.z + depth > CHUNK_SIZE
+        }));
+
+        for chunk_x in 0..2 {
+            for chunk_y in 0..2 {
+                for chunk_z in 0..2 {
+                    let offset = IVec3::new(chunk_x, chunk_y, chunk_z) * CHUNK_SIZE;
+                    let chunk = stamped(&placements, region_origin + offset, CHUNK_SIZE);
+                    for x in 0..CHUNK_SIZE {
+                        for y in 0..CHUNK_SIZE {
+                            for z in 0..CHUNK_SIZE {
+                                let in_region = IVec3::new(x, y, z) + offset;
+                                assert_eq!(
+                                    chunk[x as usize][y as usize][z as usize],
+                                    region[in_region.x as usize][in_region.y as usize]
+                                        [in_region.z as usize],
+                                    "voxel {x} {y} {z} of chunk {offset}"
+                                );
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
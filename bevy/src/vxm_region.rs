@@ -0,0 +1,621 @@
+use crate::color_conversion::ColourEncoding;
+use crate::vxm::{VxmAsset, VxmLight, VxmVoxel};
+use crate::vxm_terrain_generator::ActiveTerrainGenerator;
+use bevy::log::{error, info};
+use bevy::prelude::*;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use thiserror::Error;
+
+/// Number of chunks along the x and z sides of a single region file
+const REGION_SIZE: i32 = 32;
+
+const REGION_MAGIC: &[u8; 4] = b"SFRG";
+
+const REGION_VERSION: u8 = 1;
+
+// Offset, length and crc32 of a chunk record, each a u32
+const INDEX_ENTRY_SIZE: usize = 12;
+
+const INDEX_ENTRY_COUNT: usize = (REGION_SIZE * REGION_SIZE) as usize;
+
+// Magic, version, index and the crc32 of the index
+const HEADER_SIZE: usize = 4 + 1 + INDEX_ENTRY_COUNT * INDEX_ENTRY_SIZE + 4;
+
+/// Possible errors that can be produced by [`RegionStore`]
+#[non_exhaustive]
+#[derive(Debug, Error)]
+pub enum RegionError {
+    /// An [IO](std::io) Error
+    #[error("Could not access region file: {0}")]
+    Io(#[from] std::io::Error),
+    /// The region file header or index does not match its checksum
+    #[error("Region file {0} has a corrupt header")]
+    CorruptHeader(PathBuf),
+    /// The region file was written in a format version this build can't read
+    #[error("Region file {1} has unsupported version {0}")]
+    UnsupportedVersion(u8, PathBuf),
+    /// A chunk record does not match its checksum
+    #[error("Chunk {0:?} in region file {1} is corrupt")]
+    CorruptChunk((i32, i32, i32), PathBuf),
+}
+
+/// Metadata stored alongside the voxels of every saved chunk
+#[derive(Debug, Clone)]
+pub struct ChunkMetadata {
+    pub position: (i32, i32, i32),
+    pub size: [u8; 3],
+    /// Seconds since the unix epoch when the chunk was last written
+    pub saved_at: u64,
+}
+
+/// Marks an entity as a terrain chunk, keeping its voxels alive after meshing. Empty chunks have
+/// a default handle that no voxels are stored under.
+#[derive(Component)]
+pub struct TerrainChunk {
+    pub position: (i32, i32, i32),
+    pub handle: Handle<VxmAsset>,
+}
+
+/// Stores edited terrain chunks in region files of [`REGION_SIZE`]x[`REGION_SIZE`] chunks.
+///
+/// Each file starts with an index of (offset, length, crc32) per chunk slot, followed by a crc32
+/// of the index itself, then the chunk records.
+#[derive(Resource, Clone)]
+pub struct RegionStore {
+    pub root: PathBuf,
+}
+
+impl Default for RegionStore {
+    fn default() -> Self {
+        Self {
+            root: PathBuf::from("saves/terrain"),
+        }
+    }
+}
+
+// Returns the region a chunk belongs to and the index slot within that region
+fn region_slot(position: (i32, i32, i32)) -> ((i32, i32, i32), usize) {
+    let (x, y, z) = position;
+    let region = (x.div_euclid(REGION_SIZE), y, z.div_euclid(REGION_SIZE));
+    let local_x = x.rem_euclid(REGION_SIZE);
+    let local_z = z.rem_euclid(REGION_SIZE);
+    (region, (local_x * REGION_SIZE + local_z) as usize)
+}
+
+/// Standard crc32 (IEEE 802.3) checksum
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (!(crc & 1)).wrapping_add(1);
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+fn read_u32(bytes: &[u8], index: usize) -> Option<u32> {
+    let slice = bytes.get(index..index + 4)?;
+    Some(u32::from_le_bytes(slice.try_into().unwrap()))
+}
+
+// Checks the magic, version and index checksum, returning the index
+fn validate_header<'a>(bytes: &'a [u8], path: &PathBuf) -> Result<&'a [u8], RegionError> {
+    if bytes.len() < HEADER_SIZE || &bytes[0..4] != REGION_MAGIC {
+        return Err(RegionError::CorruptHeader(path.clone()));
+    }
+    if bytes[4] != REGION_VERSION {
+        return Err(RegionError::UnsupportedVersion(bytes[4], path.clone()));
+    }
+
+    let index = &bytes[5..5 + INDEX_ENTRY_COUNT * INDEX_ENTRY_SIZE];
+    if read_u32(bytes, HEADER_SIZE - 4) != Some(crc32(index)) {
+        return Err(RegionError::CorruptHeader(path.clone()));
+    }
+    Ok(index)
+}
+
+struct RecordReader<'a> {
+    bytes: &'a [u8],
+    index: usize,
+}
+
+impl<'a> RecordReader<'a> {
+    fn take(&mut self, amount: usize) -> Option<&'a [u8]> {
+        let slice = self.bytes.get(self.index..self.index + amount)?;
+        self.index += amount;
+        Some(slice)
+    }
+
+    fn read_u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn read_u16(&mut self) -> Option<u16> {
+        Some(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn read_u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn read_i32(&mut self) -> Option<i32> {
+        Some(i32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn read_u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn read_f32(&mut self) -> Option<f32> {
+        Some(f32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+}
+
+/// Serialises a chunk into a record, run length encoding voxels in x, y, z order
+fn encode_chunk(metadata: &ChunkMetadata, vxm: &VxmAsset) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(&metadata.position.0.to_le_bytes());
+    bytes.extend_from_slice(&metadata.position.1.to_le_bytes());
+    bytes.extend_from_slice(&metadata.position.2.to_le_bytes());
+    bytes.extend_from_slice(&vxm.size);
+    bytes.extend_from_slice(&metadata.saved_at.to_le_bytes());
+
+    bytes.extend_from_slice(&(vxm.lights.len() as u32).to_le_bytes());
+    for light in &vxm.lights {
+        for value in light.min_pos.iter().chain(light.max_pos.iter()) {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        for value in light.color.iter() {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        bytes.extend_from_slice(&light.intensity.to_le_bytes());
+    }
+
+    bytes.push(vxm.colour_encoding as u8);
+    bytes.extend_from_slice(&(vxm.palette.len() as u32).to_le_bytes());
+    for colour in &vxm.palette {
+        bytes.extend_from_slice(colour);
+    }
+
+    let mut runs: Vec<(u16, u16, bool, u8)> = Vec::new();
+    for plane in &vxm.voxel_array {
+        for column in plane {
+            for voxel in column {
+                match runs.last_mut() {
+                    Some((length, hsl, emissive, opacity))
+                        if *hsl == voxel.hsl
+                            && *emissive == voxel.emissive
+                            && *opacity == voxel.opacity
+                            && *length < u16::MAX =>
+                    {
+                        *length += 1;
+                    }
+                    _ => runs.push((1, voxel.hsl, voxel.emissive, voxel.opacity)),
+                }
+            }
+        }
+    }
+
+    bytes.extend_from_slice(&(runs.len() as u32).to_le_bytes());
+    for (length, hsl, emissive, opacity) in runs {
+        bytes.extend_from_slice(&length.to_le_bytes());
+        bytes.extend_from_slice(&hsl.to_le_bytes());
+        bytes.push(emissive as u8);
+        bytes.push(opacity);
+    }
+    bytes
+}
+
+fn decode_chunk(bytes: &[u8]) -> Option<(ChunkMetadata, VxmAsset)> {
+    let mut reader = RecordReader { bytes, index: 0 };
+    let position = (reader.read_i32()?, reader.read_i32()?, reader.read_i32()?);
+    let size = [reader.read_u8()?, reader.read_u8()?, reader.read_u8()?];
+    let saved_at = reader.read_u64()?;
+
+    let light_count = reader.read_u32()?;
+    let mut lights = Vec::new();
+    for _ in 0..light_count {
+        lights.push(VxmLight {
+            min_pos: [reader.read_u32()?, reader.read_u32()?, reader.read_u32()?],
+            max_pos: [reader.read_u32()?, reader.read_u32()?, reader.read_u32()?],
+            color: [reader.read_f32()?, reader.read_f32()?, reader.read_f32()?],
+            intensity: reader.read_f32()?,
+        });
+    }
+
+    let colour_encoding = match reader.read_u8()? {
+        0 => ColourEncoding::Hsl,
+        1 => ColourEncoding::Rgb565,
+        2 => ColourEncoding::Palette,
+        _ => return None,
+    };
+    let palette_length = reader.read_u32()?;
+    let mut palette = Vec::new();
+    for _ in 0..palette_length {
+        palette.push([reader.read_u8()?, reader.read_u8()?, reader.read_u8()?]);
+    }
+
+    let (x_dim, y_dim, z_dim) = (size[0] as usize, size[1] as usize, size[2] as usize);
+    let mut voxel_array = vec![vec![vec![VxmVoxel::default(); z_dim]; y_dim]; x_dim];
+    let voxel_count = x_dim * y_dim * z_dim;
+    let run_count = reader.read_u32()?;
+    let mut i = 0;
+    for _ in 0..run_count {
+        let length = reader.read_u16()? as usize;
+        let hsl = reader.read_u16()?;
+        let emissive = reader.read_u8()? > 0;
+        let opacity = reader.read_u8()?;
+        if i + length > voxel_count {
+            return None;
+        }
+        for j in i..i + length {
+            let x = j / (y_dim * z_dim);
+            let y = (j / z_dim) % y_dim;
+            let z = j % z_dim;
+            voxel_array[x][y][z] = VxmVoxel {
+                hsl,
+                emissive,
+                opacity,
+            };
+        }
+        i += length;
+    }
+
+    if i != voxel_count {
+        return None;
+    }
+
+    Some((
+        ChunkMetadata {
+            position,
+            size,
+            saved_at,
+        },
+        VxmAsset {
+            size,
+            voxel_array,
+            lights,
+            colour_encoding,
+            palette,
+        },
+    ))
+}
+
+impl RegionStore {
+    /// The store of one terrain generator's chunks, in a folder of their own so chunks saved
+    /// from one world are never loaded into another
+    pub fn for_generator(&self, save_name: &str) -> Self {
+        Self {
+            root: self.root.join(save_name),
+        }
+    }
+
+    fn region_path(&self, region: (i32, i32, i32)) -> PathBuf {
+        self.root
+            .join(format!("r.{}.{}.{}.sfr", region.0, region.1, region.2))
+    }
+
+    /// Reads every valid record of a region file, keyed by index slot
+    fn read_region(&self, path: &PathBuf) -> Result<HashMap<usize, Vec<u8>>, RegionError> {
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashMap::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let index = validate_header(&bytes, path)?;
+
+        let mut records = HashMap::new();
+        for slot in 0..INDEX_ENTRY_COUNT {
+            let entry = slot * INDEX_ENTRY_SIZE;
+            let offset = read_u32(index, entry).unwrap() as usize;
+            let length = read_u32(index, entry + 4).unwrap() as usize;
+            let checksum = read_u32(index, entry + 8).unwrap();
+            if length == 0 {
+                continue;
+            }
+            match bytes.get(offset..offset + length) {
+                Some(record) if crc32(record) == checksum => {
+                    records.insert(slot, record.to_vec());
+                }
+                _ => error!("Dropping corrupt chunk slot {} in {:?}", slot, path),
+            }
+        }
+        Ok(records)
+    }
+
+    /// Loads a saved chunk, returning `None` if it has never been saved
+    pub fn load_chunk(&self, position: (i32, i32, i32)) -> Result<Option<VxmAsset>, RegionError> {
+        let (region, slot) = region_slot(position);
+        let path = self.region_path(region);
+        let bytes = match fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        let index = validate_header(&bytes, &path)?;
+
+        let entry = slot * INDEX_ENTRY_SIZE;
+        let offset = read_u32(index, entry).unwrap() as usize;
+        let length = read_u32(index, entry + 4).unwrap() as usize;
+        let checksum = read_u32(index, entry + 8).unwrap();
+        if length == 0 {
+            return Ok(None);
+        }
+
+        let record = match bytes.get(offset..offset + length) {
+            Some(record) if crc32(record) == checksum => record,
+            _ => return Err(RegionError::CorruptChunk(position, path)),
+        };
+
+        match decode_chunk(record) {
+            Some((metadata, vxm)) if metadata.position == position => Ok(Some(vxm)),
+            _ => Err(RegionError::CorruptChunk(position, path)),
+        }
+    }
+
+    /// Writes a chunk into its region file, rewriting the file atomically via a temporary file
+    pub fn save_chunk(&self, position: (i32, i32, i32), vxm: &VxmAsset) -> Result<(), RegionError> {
+        let (region, slot) = region_slot(position);
+        let path = self.region_path(region);
+        fs::create_dir_all(&self.root)?;
+
+        // A corrupt region is replaced rather than blocking saves forever
+        let mut records = match self.read_region(&path) {
+            Ok(records) => records,
+            Err(RegionError::CorruptHeader(path)) => {
+                error!("Replacing region file with corrupt header {:?}", path);
+                HashMap::new()
+            }
+            Err(e) => return Err(e),
+        };
+
+        let metadata = ChunkMetadata {
+            position,
+            size: vxm.size,
+            saved_at: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|duration| duration.as_secs())
+                .unwrap_or(0),
+        };
+        records.insert(slot, encode_chunk(&metadata, vxm));
+
+        let mut index = vec![0u8; INDEX_ENTRY_COUNT * INDEX_ENTRY_SIZE];
+        let mut body = Vec::new();
+        let mut slots = records.keys().copied().collect::<Vec<_>>();
+        slots.sort();
+        for slot in slots {
+            let record = &records[&slot];
+            let entry = slot * INDEX_ENTRY_SIZE;
+            let offset = (HEADER_SIZE + body.len()) as u32;
+            index[entry..entry + 4].copy_from_slice(&offset.to_le_bytes());
+            index[entry + 4..entry + 8].copy_from_slice(&(record.len() as u32).to_le_bytes());
+            index[entry + 8..entry + 12].copy_from_slice(&crc32(record).to_le_bytes());
+            body.extend_from_slice(record);
+        }
+
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + body.len());
+        bytes.extend_from_slice(REGION_MAGIC);
+        bytes.push(REGION_VERSION);
+        bytes.extend_from_slice(&index);
+        bytes.extend_from_slice(&crc32(&index).to_le_bytes());
+        bytes.extend_from_slice(&body);
+
+        let temp_path = path.with_extension("sfr.tmp");
+        fs::write(&temp_path, &bytes)?;
+        fs::rename(&temp_path, &path)?;
+        Ok(())
+    }
+}
+
+/// Saves terrain chunks whose voxels have been edited since they were loaded, unless the
+/// generator they came from doesn't keep edits
+pub fn save_modified_chunks_system(
+    mut events: EventReader<AssetEvent<VxmAsset>>,
+    chunks: Query<&TerrainChunk>,
+    vxm_assets: Res<Assets<VxmAsset>>,
+    region_store: Res<RegionStore>,
+    generator: Res<ActiveTerrainGenerator>,
+) {
+    let Some(region_store) = generator.get().and_then(|generator| {
+        generator
+            .save_name()
+            .map(|name| region_store.for_generator(name))
+    }) else {
+        events.clear();
+        return;
+    };
+    for event in events.read() {
+        let AssetEvent::Modified { id } = event else {
+            continue;
+        };
+        let Some(chunk) = chunks.iter().find(|chunk| chunk.handle.id() == *id) else {
+            continue;
+        };
+        let Some(vxm) = vxm_assets.get(*id) else {
+            continue;
+        };
+        match region_store.save_chunk(chunk.position, vxm) {
+            Ok(()) => info!("Saved terrain chunk {:?}", chunk.position),
+            Err(e) => error!("Failed to save terrain chunk {:?}: {}", chunk.position, e),
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// A region store in its own temporary directory, removed when dropped
+    pub(crate) struct TempRegionStore(pub(crate) RegionStore);
+
+    impl TempRegionStore {
+        pub(crate) fn new(name: &str) -> Self {
+            let root = std::env::temp_dir().join(format!(
+                "soulflame-region-{}-{}",
+                name,
+                std::process::id()
+            ));
+            let _ = fs::remove_dir_all(&root);
+            Self(RegionStore { root })
+        }
+    }
+
+    impl Drop for TempRegionStore {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0.root);
+        }
+    }
+
+    fn test_chunk() -> VxmAsset {
+        let size = [4, 5, 6];
+        let mut voxel_array =
+            vec![
+                vec![vec![VxmVoxel::default(); size[2] as usize]; size[1] as usize];
+                size[0] as usize
+            ];
+        for x in 0..size[0] as usize {
+            for y in 0..2 {
+                for z in 0..size[2] as usize {
+                    voxel_array[x][y][z] = VxmVoxel {
+                        hsl: 0x8000 | (x * 7 + z) as u16,
+                        emissive: x == 1 && z == 2,
+                        opacity: if y == 1 { 128 } else { 255 },
+                    };
+                }
+            }
+        }
+        VxmAsset {
+            size,
+            voxel_array,
+            lights: vec![VxmLight {
+                min_pos: [1, 0, 2],
+                max_pos: [1, 1, 2],
+                color: [1.0, 0.5, 0.25],
+                intensity: 3.0,
+            }],
+            colour_encoding: ColourEncoding::Palette,
+            palette: vec![[255, 0, 0], [0, 128, 255]],
+        }
+    }
+
+    fn assert_same_chunk(a: &VxmAsset, b: &VxmAsset) {
+        assert_eq!(a.size, b.size);
+        assert_eq!(a.voxel_array, b.voxel_array);
+        assert_eq!(a.colour_encoding, b.colour_encoding);
+        assert_eq!(a.palette, b.palette);
+        assert_eq!(a.lights.len(), b.lights.len());
+        for (a, b) in a.lights.iter().zip(&b.lights) {
+            assert_eq!(a.min_pos, b.min_pos);
+            assert_eq!(a.max_pos, b.max_pos);
+            assert_eq!(a.color, b.color);
+            assert_eq!(a.intensity, b.intensity);
+        }
+    }
+
+    #[test]
+    fn crc32_matches_the_standard_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(&[]), 0);
+    }
+
+    #[test]
+    fn chunk_records_round_trip() {
+        let vxm = test_chunk();
+        let metadata = ChunkMetadata {
+            position: (-3, 1, 40),
+            size: vxm.size,
+            saved_at: 1_700_000_000,
+        };
+        let (decoded_metadata, decoded) = decode_chunk(&encode_chunk(&metadata, &vxm)).unwrap();
+        assert_eq!(decoded_metadata.position, metadata.position);
+        assert_eq!(decoded_metadata.size, metadata.size);
+        assert_eq!(decoded_metadata.saved_at, metadata.saved_at);
+        assert_same_chunk(&decoded, &vxm);
+    }
+
+    #[test]
+    fn truncated_chunk_records_are_rejected() {
+        let vxm = test_chunk();
+        let metadata = ChunkMetadata {
+            position: (0, 0, 0),
+            size: vxm.size,
+            saved_at: 0,
+        };
+        let bytes = encode_chunk(&metadata, &vxm);
+        assert!(decode_chunk(&bytes[..bytes.len() - 1]).is_none());
+    }
+
+    #[test]
+    fn saved_chunks_load_back() {
+        let store = TempRegionStore::new("round-trip");
+        let vxm = test_chunk();
+        store.0.save_chunk((-1, 2, 33), &vxm).unwrap();
+        store.0.save_chunk((-2, 2, 33), &test_chunk()).unwrap();
+
+        assert_same_chunk(&store.0.load_chunk((-1, 2, 33)).unwrap().unwrap(), &vxm);
+        assert!(store.0.load_chunk((-1, 2, 34)).unwrap().is_none());
+    }
+
+    #[test]
+    fn flipped_byte_in_a_chunk_record_is_rejected() {
+        let store = TempRegionStore::new("flipped-record");
+        let position = (5, 0, 7);
+        store.0.save_chunk(position, &test_chunk()).unwrap();
+
+        let (region, _) = region_slot(position);
+        let path = store.0.region_path(region);
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[HEADER_SIZE + 20] ^= 0x01;
+        fs::write(&path, &bytes).unwrap();
+
+        assert!(matches!(
+            store.0.load_chunk(position),
+            Err(RegionError::CorruptChunk(..))
+        ));
+    }
+
+    #[test]
+    fn flipped_byte_in_the_index_is_rejected() {
+        let store = TempRegionStore::new("flipped-index");
+        let position = (5, 0, 7);
+        store.0.save_chunk(position, &test_chunk()).unwrap();
+
+        let (region, _) = region_slot(position);
+        let path = store.0.region_path(region);
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[5] ^= 0x80;
+        fs::write(&path, &bytes).unwrap();
+
+        assert!(matches!(
+            store.0.load_chunk(position),
+            Err(RegionError::CorruptHeader(_))
+        ));
+    }
+
+    #[test]
+    fn other_region_versions_are_rejected() {
+        let store = TempRegionStore::new("version");
+        let position = (0, -1, 0);
+        store.0.save_chunk(position, &test_chunk()).unwrap();
+
+        let (region, _) = region_slot(position);
+        let path = store.0.region_path(region);
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[4] = REGION_VERSION + 1;
+        fs::write(&path, &bytes).unwrap();
+
+        assert!(matches!(
+            store.0.load_chunk(position),
+            Err(RegionError::UnsupportedVersion(version, _)) if version == REGION_VERSION + 1
+        ));
+        // Saving must not overwrite a file this build can't read
+        assert!(store.0.save_chunk(position, &test_chunk()).is_err());
+    }
+}
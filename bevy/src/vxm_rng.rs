@@ -0,0 +1,69 @@
+// splitmix64's increment, the golden ratio as a 64 bit fraction
+const GOLDEN_GAMMA: u64 = 0x9E37_79B9_7F4A_7C15;
+
+// splitmix64's finaliser, spreading every input bit over the output
+fn mix(mut value: u64) -> u64 {
+    value = (value ^ (value >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    value = (value ^ (value >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    value ^ (value >> 31)
+}
+
+/// Mixes `values` into `start` one after another, giving well distributed bits that are always
+/// the same for the same inputs
+pub fn hash(start: u64, values: impl IntoIterator<Item = u64>) -> u64 {
+    values.into_iter().fold(start, |hash, value| {
+        mix(hash.wrapping_add(GOLDEN_GAMMA) ^ value)
+    })
+}
+
+/// Small deterministic random numbers, so the same seed always builds the same model
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    // splitmix64
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(GOLDEN_GAMMA);
+        mix(self.0)
+    }
+
+    /// Between 0 and 1
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    pub fn range(&mut self, min: f32, max: f32) -> f32 {
+        min + (max - min) * self.next_f32()
+    }
+
+    /// Between `min` and `max`, both included
+    pub fn range_inclusive(&mut self, min: i32, max: i32) -> i32 {
+        if max <= min {
+            return min;
+        }
+        min + (self.next_u64() % (max - min + 1) as u64) as i32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_splitmix64() {
+        // The first outputs of the reference splitmix64 seeded with 0
+        let mut rng = Rng::new(0);
+        assert_eq!(rng.next_u64(), 0xE220_A839_7B1D_CDAF);
+        assert_eq!(rng.next_u64(), 0x6E78_9E6A_A1B9_65F4);
+        assert_eq!(rng.next_u64(), 0x06C4_5D18_8009_454F);
+    }
+
+    #[test]
+    fn hashing_a_value_matches_drawing_from_its_seed() {
+        assert_eq!(hash(0, [0]), Rng::new(0).next_u64());
+        assert_ne!(hash(1, [2]), hash(2, [1]));
+    }
+}
@@ -0,0 +1,373 @@
+use crate::color_conversion::decode_voxel_colour;
+use crate::render::main::{SmoothMeshData, SmoothVertex};
+use crate::vxm_mesh::{is_opaque_voxel, VoxelNeighbourhood};
+use bevy::math::Vec3;
+use bevy::prelude::Component;
+use std::sync::Arc;
+
+/// Meshes the opaque voxels of a model as a smooth surface using surface nets instead of greedy
+/// quads, drawn by the smooth render pass. Transparent voxels are still meshed as faces.
+#[derive(Component, Default)]
+pub struct SmoothVoxelMesh;
+
+// Fraction of opaque voxels around a point at which the surface is placed
+const ISO_LEVEL: f32 = 0.5;
+
+// Pairs of cell corners joined by each of the 12 cell edges, corners indexed by their x, y and z
+// offsets as bits 0, 1 and 2
+const CELL_EDGES: [(usize, usize); 12] = [
+    (0, 1),
+    (2, 3),
+    (4, 5),
+    (6, 7),
+    (0, 2),
+    (1, 3),
+    (4, 6),
+    (5, 7),
+    (0, 4),
+    (1, 5),
+    (2, 6),
+    (3, 7),
+];
+
+fn corner_offset(corner: usize) -> [usize; 3] {
+    [corner & 1, (corner >> 1) & 1, (corner >> 2) & 1]
+}
+
+struct Grid {
+    dims: [usize; 3],
+    values: Vec<f32>,
+}
+
+impl Grid {
+    fn new(dims: [usize; 3]) -> Self {
+        Self {
+            dims,
+            values: vec![0.0; dims[0] * dims[1] * dims[2]],
+        }
+    }
+
+    fn index(&self, position: [usize; 3]) -> usize {
+        (position[0] * self.dims[1] + position[1]) * self.dims[2] + position[2]
+    }
+
+    fn get(&self, position: [usize; 3]) -> f32 {
+        self.values[self.index(position)]
+    }
+}
+
+/// Density at each voxel centre from -1 to the model size on every axis, stored offset by 1.
+///
+/// Density is the fraction of opaque voxels in the 3x3x3 block around a voxel, blurring the
+/// occupancy so terrain steps become slopes. Voxels of unloaded neighbouring chunks count as air.
+fn density_field(neighbourhood: &VoxelNeighbourhood) -> Grid {
+    let size = neighbourhood.centre.size.map(|s| s as usize);
+
+    // Occupancy from -2 to size + 1, stored offset by 2
+    let mut field = Grid::new(size.map(|s| s + 4));
+    for x in 0..field.dims[0] {
+        for y in 0..field.dims[1] {
+            for z in 0..field.dims[2] {
+                let is_opaque = neighbourhood
+                    .voxel_at(x as i32 - 2, y as i32 - 2, z as i32 - 2)
+                    .is_some_and(is_opaque_voxel);
+                if is_opaque {
+                    let index = field.index([x, y, z]);
+                    field.values[index] = 1.0;
+                }
+            }
+        }
+    }
+
+    // Box blur one axis at a time, each pass trimming a voxel from both ends of that axis
+    for axis in 0..3 {
+        let mut dims = field.dims;
+        dims[axis] -= 2;
+        let mut blurred = Grid::new(dims);
+        for x in 0..dims[0] {
+            for y in 0..dims[1] {
+                for z in 0..dims[2] {
+                    let mut sum = 0.0;
+                    for k in 0..3 {
+                        let mut position = [x, y, z];
+                        position[axis] += k;
+                        sum += field.get(position);
+                    }
+                    let index = blurred.index([x, y, z]);
+                    blurred.values[index] = sum / 3.0;
+                }
+            }
+        }
+        field = blurred;
+    }
+
+    field
+}
+
+// Average colour of the opaque voxels at the corners of a cell, looking one voxel further out
+// when the surface passes through a cell of air
+fn cell_colour(neighbourhood: &VoxelNeighbourhood, min_corner: [i32; 3]) -> [f32; 3] {
+    let vxm = neighbourhood.centre;
+    for radius in 0..2 {
+        let mut sum = [0.0; 3];
+        let mut count = 0;
+        for x in min_corner[0] - radius..=min_corner[0] + 1 + radius {
+            for y in min_corner[1] - radius..=min_corner[1] + 1 + radius {
+                for z in min_corner[2] - radius..=min_corner[2] + 1 + radius {
+                    let Some(voxel) = neighbourhood.voxel_at(x, y, z) else {
+                        continue;
+                    };
+                    if !is_opaque_voxel(voxel) {
+                        continue;
+                    }
+                    let (r, g, b) =
+                        decode_voxel_colour(voxel.hsl, vxm.colour_encoding, &vxm.palette);
+                    sum[0] += r;
+                    sum[1] += g;
+                    sum[2] += b;
+                    count += 1;
+                }
+            }
+        }
+        if count > 0 {
+            return sum.map(|channel| channel / count as f32);
+        }
+    }
+    [0.5; 3]
+}
+
+fn pack_colour(colour: [f32; 3]) -> u32 {
+    let [r, g, b] = colour.map(|channel| (channel.clamp(0.0, 1.0) * 255.0).round() as u32);
+    r | g << 8 | b << 16 | 0xFF << 24
+}
+
+/// Meshes the opaque voxels of a model, and the chunks loaded around it, as a smooth surface.
+///
+/// Every cell between 8 voxel centres that the surface passes through gets one vertex, placed
+/// at the average of where the surface crosses the cell's edges. Each voxel edge the surface
+/// crosses then joins the vertices of the 4 cells around it into a quad. A chunk meshes the
+/// edges starting inside it, so neighbouring chunks share vertices along their border without
+/// overlapping.
+pub fn surface_nets(neighbourhood: &VoxelNeighbourhood) -> SmoothMeshData {
+    let size = neighbourhood.centre.size.map(|s| s as usize);
+    let density = density_field(neighbourhood);
+
+    // Cells are indexed by their minimum corner plus 1, starting with the cells reaching into
+    // the neighbouring chunks below
+    let cell_dims = size.map(|s| s + 1);
+    let cell_index = |cell: [usize; 3]| (cell[0] * cell_dims[1] + cell[1]) * cell_dims[2] + cell[2];
+    let mut cell_vertices = vec![u32::MAX; cell_dims[0] * cell_dims[1] * cell_dims[2]];
+    let mut vertices = Vec::new();
+
+    for x in 0..cell_dims[0] {
+        for y in 0..cell_dims[1] {
+            for z in 0..cell_dims[2] {
+                let corners: [f32; 8] = std::array::from_fn(|corner| {
+                    let offset = corner_offset(corner);
+                    density.get([x + offset[0], y + offset[1], z + offset[2]]) - ISO_LEVEL
+                });
+                let inside_count = corners.iter().filter(|&&d| d > 0.0).count();
+                if inside_count == 0 || inside_count == 8 {
+                    continue;
+                }
+
+                let mut crossing_sum = Vec3::ZERO;
+                let mut crossing_count = 0;
+                for (a, b) in CELL_EDGES {
+                    if (corners[a] > 0.0) == (corners[b] > 0.0) {
+                        continue;
+                    }
+                    let t = corners[a] / (corners[a] - corners[b]);
+                    let from = Vec3::from_array(corner_offset(a).map(|o| o as f32));
+                    let to = Vec3::from_array(corner_offset(b).map(|o| o as f32));
+                    crossing_sum += from.lerp(to, t);
+                    crossing_count += 1;
+                }
+
+                // Density increases into the voxels, so the surface faces down its gradient
+                let mut gradient = Vec3::ZERO;
+                for (corner, density) in corners.iter().enumerate() {
+                    let offset = corner_offset(corner);
+                    gradient += Vec3::new(
+                        if offset[0] == 1 { *density } else { -density },
+                        if offset[1] == 1 { *density } else { -density },
+                        if offset[2] == 1 { *density } else { -density },
+                    );
+                }
+                let normal = (-gradient).normalize_or(Vec3::Y);
+
+                // The cell starts at the centre of the voxel before it, half a voxel back
+                let position = Vec3::new(x as f32, y as f32, z as f32) - Vec3::splat(0.5)
+                    + crossing_sum / crossing_count as f32;
+                let min_corner = [x as i32 - 1, y as i32 - 1, z as i32 - 1];
+
+                cell_vertices[cell_index([x, y, z])] = vertices.len() as u32;
+                vertices.push(SmoothVertex {
+                    position: position.to_array(),
+                    normal: normal.to_array(),
+                    colour: pack_colour(cell_colour(neighbourhood, min_corner)),
+                });
+            }
+        }
+    }
+
+    let mut indices = Vec::new();
+    for axis in 0..3 {
+        let (axis_b, axis_c) = ((axis + 1) % 3, (axis + 2) % 3);
+        for x in 0..size[0] {
+            for y in 0..size[1] {
+                for z in 0..size[2] {
+                    let voxel = [x, y, z];
+                    let mut next = voxel.map(|p| p + 1);
+                    next[axis] += 1;
+                    let is_inside = density.get(voxel.map(|p| p + 1)) > ISO_LEVEL;
+                    if is_inside == (density.get(next) > ISO_LEVEL) {
+                        continue;
+                    }
+
+                    // The 4 cells around the edge, counter-clockwise when looking down the axis
+                    let cell = |step_b: usize, step_c: usize| {
+                        let mut cell = voxel.map(|p| p + 1);
+                        cell[axis_b] -= 1 - step_b;
+                        cell[axis_c] -= 1 - step_c;
+                        cell_vertices[cell_index(cell)]
+                    };
+                    let quad = [cell(0, 0), cell(1, 0), cell(1, 1), cell(0, 1)];
+
+                    // Face out of the voxels, towards whichever end of the edge is air
+                    if is_inside {
+                        indices.extend([quad[0], quad[1], quad[2], quad[0], quad[2], quad[3]]);
+                    } else {
+                        indices.extend([quad[0], quad[2], quad[1], quad[0], quad[3], quad[2]]);
+                    }
+                }
+            }
+        }
+    }
+
+    SmoothMeshData {
+        vertices: Arc::new(vertices),
+        indices: Arc::new(indices),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color_conversion::create_hsl_voxel;
+    use crate::vxm::{VxmAsset, VxmVoxel};
+    use std::collections::HashMap;
+
+    const SPHERE_CENTRE: f32 = 6.0;
+
+    // A ball of voxels in the middle of a 12x12x12 model, red above its middle and blue below
+    fn sphere_model() -> VxmAsset {
+        let ground = |r, g, b| VxmVoxel {
+            hsl: create_hsl_voxel(r, g, b),
+            emissive: false,
+            opacity: u8::MAX,
+        };
+        let (red, blue) = (ground(0.8, 0.2, 0.2), ground(0.2, 0.2, 0.8));
+        let voxel_array = (0..12)
+            .map(|x| {
+                (0..12)
+                    .map(|y| {
+                        (0..12)
+                            .map(|z| {
+                                let centre = Vec3::new(x as f32, y as f32, z as f32) + 0.5;
+                                if centre.distance(Vec3::splat(SPHERE_CENTRE)) > 4.0 {
+                                    VxmVoxel::default()
+                                } else if y >= 6 {
+                                    red.clone()
+                                } else {
+                                    blue.clone()
+                                }
+                            })
+                            .collect()
+                    })
+                    .collect()
+            })
+            .collect();
+        VxmAsset {
+            size: [12; 3],
+            voxel_array,
+            lights: Vec::new(),
+            colour_encoding: Default::default(),
+            palette: Vec::new(),
+        }
+    }
+
+    fn unpack_colour(colour: u32) -> [u32; 3] {
+        [colour & 0xFF, (colour >> 8) & 0xFF, (colour >> 16) & 0xFF]
+    }
+
+    #[test]
+    fn a_sphere_is_meshed_as_a_closed_surface_facing_out() {
+        let vxm = sphere_model();
+        let mesh = surface_nets(&VoxelNeighbourhood::isolated(&vxm));
+        assert!(!mesh.indices.is_empty());
+        assert!(mesh
+            .indices
+            .iter()
+            .all(|&index| (index as usize) < mesh.vertices.len()));
+
+        // Each edge is shared by exactly two triangles, running opposite ways around them
+        let mut edges = HashMap::new();
+        for triangle in mesh.indices.chunks(3) {
+            for corner in 0..3 {
+                let edge = (triangle[corner], triangle[(corner + 1) % 3]);
+                *edges.entry(edge).or_insert(0) += 1;
+            }
+
+            let [a, b, c] = [0, 1, 2]
+                .map(|corner| Vec3::from_array(mesh.vertices[triangle[corner] as usize].position));
+            let outwards = (a + b + c) / 3.0 - Vec3::splat(SPHERE_CENTRE);
+            assert!(
+                (b - a).cross(c - a).dot(outwards) > 0.0,
+                "triangle faces in"
+            );
+        }
+        for (&(from, to), &count) in &edges {
+            assert_eq!(count, 1, "edge {from} {to} is repeated");
+            assert_eq!(edges.get(&(to, from)), Some(&1), "edge {from} {to} is open");
+        }
+
+        // A closed surface without holes through it
+        let vertex_count = mesh.vertices.len() as i32;
+        let edge_count = edges.len() as i32 / 2;
+        let triangle_count = mesh.indices.len() as i32 / 3;
+        assert_eq!(vertex_count - edge_count + triangle_count, 2);
+    }
+
+    #[test]
+    fn smooth_vertex_colours_blend_the_voxels_around_them() {
+        let vxm = sphere_model();
+        let mesh = surface_nets(&VoxelNeighbourhood::isolated(&vxm));
+        let [red, blue] = [vxm.voxel_array[6][9][6].hsl, vxm.voxel_array[6][2][6].hsl].map(|hsl| {
+            unpack_colour(pack_colour(
+                decode_voxel_colour(hsl, vxm.colour_encoding, &vxm.palette).into(),
+            ))
+        });
+
+        let mut is_blended = false;
+        for vertex in mesh.vertices.iter() {
+            let colour = unpack_colour(vertex.colour);
+            for channel in 0..3 {
+                let (low, high) = (
+                    red[channel].min(blue[channel]),
+                    red[channel].max(blue[channel]),
+                );
+                assert!((low..=high).contains(&colour[channel]), "{colour:?}");
+            }
+            is_blended |= colour != red && colour != blue;
+
+            // Vertices well above or below the middle only touch voxels of one colour
+            if vertex.position[1] > 7.5 {
+                assert_eq!(colour, red);
+            } else if vertex.position[1] < 4.5 {
+                assert_eq!(colour, blue);
+            }
+        }
+        assert!(is_blended, "no vertex blends the two colours");
+    }
+}
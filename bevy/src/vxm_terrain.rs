@@ -1,181 +1,1054 @@
 use crate::camera::CameraTarget;
-use crate::color_conversion::create_hsl_voxel;
+use crate::color_conversion::{create_hsl_voxel, ColourEncoding};
 use crate::vxm::{PendingVxm, VxmAsset, VxmVoxel};
-use bevy::app::{App, Plugin, Update};
-use bevy::asset::Assets;
-use bevy::math::Vec3;
-use bevy::prelude::{Commands, Name, ResMut, Resource, Transform};
+use crate::vxm_biome::{BlendedBiome, SHORE_HEIGHT, SNOW};
+use crate::vxm_erosion::{erode, ErodedColumn, ErosionCache, ErosionSettings};
+use crate::vxm_light::{LightVolume, VoxelLightStore};
+use crate::vxm_lod::{TerrainChunkLevel, VoxelLod, VoxelLodLevel};
+use crate::vxm_mesh::{
+    is_opaque_voxel, ColourVolumeMesh, NeighbourRemeshQueue, VoxelNeighbourhood, FACE_NORMALS,
+};
+use crate::vxm_path::PathColumn;
+use crate::vxm_prefab::placements_over_chunk;
+use crate::vxm_region::{save_modified_chunks_system, RegionStore, TerrainChunk};
+use crate::vxm_surface_nets::SmoothVoxelMesh;
+use crate::vxm_terrain_generator::{
+    ActiveTerrainGenerator, NoiseTerrainGenerator, TerrainGenerator,
+};
+use crate::vxm_terrain_settings::{HeightNoise, TerrainSettings, TerrainSettingsLoader};
+use bevy::app::{App, Plugin, Startup, Update};
+use bevy::asset::{AssetApp, AssetEvent, AssetServer, Assets, Handle};
+use bevy::log::{info, warn};
+use bevy::math::{IVec3, Vec2, Vec3, Vec3Swizzles};
+use bevy::prelude::{
+    resource_exists, Camera, Commands, Component, DetectChanges, DetectChangesMut, Entity,
+    EventReader, GlobalTransform, Has, IntoScheduleConfigs, Name, Query, Res, ResMut, Resource,
+    Transform, With,
+};
+use bevy::tasks::{block_on, futures_lite::future, AsyncComputeTaskPool, Task};
 use fastnoise2::{generator::prelude::*, SafeNode};
-use std::collections::VecDeque;
+use std::collections::{HashMap, HashSet};
+use std::f32::consts::TAU;
+use std::sync::Arc;
 
-#[derive(Resource)]
-pub struct ChunkQueue(VecDeque<(i32, i32, i32)>);
+/// How far around the camera terrain chunks are streamed in and out, in chunks
+#[derive(Resource, Clone, Copy)]
+pub struct TerrainStreaming {
+    pub load_radius: f32,
+    /// Kept larger than the load radius so chunks on the edge aren't unloaded and loaded again
+    /// every time the camera moves back and forth across a chunk border
+    pub unload_radius: f32,
+    /// Chunks loaded or generated in the background at once
+    pub max_generating_chunks: usize,
+}
 
-impl Default for ChunkQueue {
+impl Default for TerrainStreaming {
     fn default() -> Self {
-        let mut queue = VecDeque::new();
-        for r in 0..8 {
-            for x in (-r)..(r + 1) {
-                for z in (-r)..(r + 1) {
-                    if !queue.contains(&(x, 0, z)) {
-                        queue.push_back((x, 0, z));
-                    }
-                }
-            }
+        Self {
+            load_radius: 8.0,
+            unload_radius: 10.0,
+            max_generating_chunks: 4,
         }
-        Self(queue)
     }
 }
-const TERRAIN_SIZE: i32 = 64;
 
-const SCALE_FACTOR: i32 = 2048;
+/// Chunks waiting to be loaded, with the next chunk to load at the end
+#[derive(Resource, Default)]
+pub struct ChunkQueue {
+    chunks: Vec<(i32, i32, i32)>,
+    // Camera chunk and view direction the queue was last ordered for
+    ordered_for: Option<((i32, i32, i32), i32)>,
+}
+
+/// Chunks being loaded or generated in the background, finishing with `None` for chunks with
+/// nothing in them. Dropping a task cancels it.
+#[derive(Resource, Default)]
+pub struct GeneratingChunks(HashMap<(i32, i32, i32), Task<Option<VxmAsset>>>);
 
-fn create_node() -> GeneratorWrapper<SafeNode> {
-    opensimplex2().fbm(0.65, 0.5, 6, 2.5).build()
+/// Marks terrain chunks whose every voxel is opaque. They are lit, so chunks below them are
+/// shaded from the sky, but only meshed once a chunk that isn't solid loads beside them or
+/// their voxels are edited, as until then none of their faces can be seen.
+#[derive(Component)]
+pub struct SolidChunk;
+
+/// How newly generated terrain chunks are meshed. Individual chunks can be switched by adding or
+/// leaving out [`SmoothVoxelMesh`] or [`ColourVolumeMesh`] before they are meshed.
+#[derive(Resource, Default, Clone, Copy, PartialEq)]
+pub enum TerrainMesher {
+    #[default]
+    Blocky,
+    /// Blocky, with faces merged across voxels of different colours
+    ColourVolume,
+    Smooth,
+}
+
+const WATER_OPACITY: u8 = 160;
+
+// Open ground below this fraction of the world height fills with water
+const WATER_LEVEL: f32 = 0.35;
+
+const TERRAIN_SETTINGS_PATH: &str = "world.terrain.ron";
+
+// Downsampled levels distant chunks are drawn from, each halving the resolution
+const TERRAIN_LOD_LEVELS: u32 = 2;
+
+// View directions are bucketed so turning the camera slightly doesn't reorder the queue
+const VIEW_DIRECTION_SECTORS: f32 = 16.0;
+
+// Density noise is sampled every few voxels and interpolated between, as it varies slowly
+const DENSITY_STEP: i32 = 4;
+
+// Voxels above a chunk scanned to find how deep under the surface its top voxels are, deeper
+// than any biome's subsurface
+const SURFACE_LOOKAHEAD: i32 = 16;
+
+// How far overhang noise at full strength moves the surface, as a fraction of the chunk height
+const OVERHANG_AMPLITUDE: f32 = 0.08;
+
+const OVERHANG_SCALE: f32 = 96.0;
+
+const CAVE_SCALE: f32 = 48.0;
+
+// Size of the temperature and moisture features that biomes are picked from, in voxels
+const CLIMATE_SCALE: f32 = 1024.0;
+
+fn create_node(height_noise: &HeightNoise) -> GeneratorWrapper<SafeNode> {
+    opensimplex2()
+        .fbm(
+            height_noise.gain,
+            height_noise.weighted_strength,
+            height_noise.octaves,
+            height_noise.lacunarity,
+        )
+        .build()
+}
+
+fn create_climate_node() -> GeneratorWrapper<SafeNode> {
+    opensimplex2().fbm(0.5, 0.0, 3, 2.0).build()
+}
+
+fn create_overhang_node() -> GeneratorWrapper<SafeNode> {
+    opensimplex2().fbm(0.65, 0.5, 3, 2.0).build()
+}
+
+fn create_cave_node() -> GeneratorWrapper<SafeNode> {
+    opensimplex2().fbm(0.5, 0.0, 2, 2.0).build()
 }
 
 fn lerp(from: f32, to: f32, t: f32) -> f32 {
     from + (to - from) * t
 }
 
-pub fn create_vxm_from_noise(x_pos: i32, y_pos: i32, z_pos: i32) -> VxmAsset {
+/// Overhang offset and cave carving of a chunk, sampled every [`DENSITY_STEP`] voxels
+struct DensityGrid {
+    size: [usize; 3],
+    overhang: Vec<f32>,
+    cave: Vec<f32>,
+}
+
+/// Reorders a 2D grid generated by FastNoise2, which varies x fastest, into columns laid out x,
+/// then z like the column arrays of a chunk
+fn columns_from_grid(grid: &[f32], x_size: i32, z_size: i32) -> Vec<f32> {
+    let mut columns = Vec::with_capacity(grid.len());
+    for x in 0..x_size {
+        for z in 0..z_size {
+            columns.push(grid[(z * x_size + x) as usize]);
+        }
+    }
+    columns
+}
+
+// Where noise sampled every DENSITY_STEP voxels is sampled for a voxel position. FastNoise2
+// multiplies a grid index by the frequency, rather than dividing the position by the scale, and
+// the two round differently, so single samples do the same to match the grids exactly.
+fn density_sample_position(position: i32, scale: f32) -> f32 {
+    position as f32 / DENSITY_STEP as f32 * (DENSITY_STEP as f32 / scale)
+}
+
+// Samples a 3D noise field every DENSITY_STEP voxels from a world position, laid out x, then y,
+// then z
+fn sample_density_field(
+    node: &GeneratorWrapper<SafeNode>,
+    origin: [i32; 3],
+    size: [usize; 3],
+    scale: f32,
+    seed: i32,
+) -> Vec<f32> {
+    let [x_size, y_size, z_size] = size;
+    let mut field = Vec::with_capacity(x_size * y_size * z_size);
+
+    // A grid starting on a multiple of the step lines up with FastNoise2's uniform grids, which
+    // sample whole chunks at once with SIMD
+    if origin.iter().all(|&origin| origin % DENSITY_STEP == 0) {
+        let mut grid = vec![0.0; x_size * y_size * z_size];
+        node.gen_uniform_grid_3d(
+            &mut grid,
+            origin[0] / DENSITY_STEP,
+            origin[1] / DENSITY_STEP,
+            origin[2] / DENSITY_STEP,
+            x_size as i32,
+            y_size as i32,
+            z_size as i32,
+            DENSITY_STEP as f32 / scale,
+            seed,
+        );
+        for x in 0..x_size {
+            for y in 0..y_size {
+                for z in 0..z_size {
+                    field.push(grid[(z * y_size + y) * x_size + x]);
+                }
+            }
+        }
+        return field;
+    }
+
+    for x in 0..x_size as i32 {
+        for y in 0..y_size as i32 {
+            for z in 0..z_size as i32 {
+                field.push(node.gen_single_3d(
+                    density_sample_position(origin[0] + x * DENSITY_STEP, scale),
+                    density_sample_position(origin[1] + y * DENSITY_STEP, scale),
+                    density_sample_position(origin[2] + z * DENSITY_STEP, scale),
+                    seed,
+                ));
+            }
+        }
+    }
+    field
+}
+
+impl DensityGrid {
+    fn new(origin: [i32; 3], voxel_size: [i32; 3], settings: &TerrainSettings) -> Self {
+        // Enough samples to cover the far edge of the chunk
+        let size = voxel_size.map(|size| ((size + DENSITY_STEP - 1) / DENSITY_STEP + 1) as usize);
+        let sample_count = size[0] * size[1] * size[2];
+
+        // Sampled in world space so the grids of neighbouring chunks line up
+        let overhang = if settings.overhang_strength == 0.0 {
+            vec![0.0; sample_count]
+        } else {
+            let mut overhang = sample_density_field(
+                &create_overhang_node(),
+                origin,
+                size,
+                OVERHANG_SCALE,
+                settings.seed,
+            );
+            for value in &mut overhang {
+                *value *= OVERHANG_AMPLITUDE * settings.overhang_strength;
+            }
+            overhang
+        };
+
+        // Tunnels run where two noise fields both cross zero
+        let cave = if settings.cave_width == 0.0 {
+            vec![1.0; sample_count]
+        } else {
+            let cave_node = create_cave_node();
+            let tunnel_1 = sample_density_field(
+                &cave_node,
+                origin,
+                size,
+                CAVE_SCALE,
+                settings.seed.wrapping_add(1),
+            );
+            let tunnel_2 = sample_density_field(
+                &cave_node,
+                origin,
+                size,
+                CAVE_SCALE,
+                settings.seed.wrapping_add(2),
+            );
+            tunnel_1
+                .iter()
+                .zip(&tunnel_2)
+                .map(|(tunnel_1, tunnel_2)| tunnel_1.abs() + tunnel_2.abs())
+                .collect()
+        };
+
+        Self {
+            size,
+            overhang,
+            cave,
+        }
+    }
+
+    // Trilinearly interpolates a sampled field at a voxel position within the chunk
+    fn sample(&self, field: &[f32], x: i32, y: i32, z: i32) -> f32 {
+        let (cell_x, t_x) = (
+            (x / DENSITY_STEP) as usize,
+            (x % DENSITY_STEP) as f32 / DENSITY_STEP as f32,
+        );
+        let (cell_y, t_y) = (
+            (y / DENSITY_STEP) as usize,
+            (y % DENSITY_STEP) as f32 / DENSITY_STEP as f32,
+        );
+        let (cell_z, t_z) = (
+            (z / DENSITY_STEP) as usize,
+            (z % DENSITY_STEP) as f32 / DENSITY_STEP as f32,
+        );
+        let at = |dx: usize, dy: usize, dz: usize| {
+            field[((cell_x + dx) * self.size[1] + cell_y + dy) * self.size[2] + cell_z + dz]
+        };
+        lerp(
+            lerp(
+                lerp(at(0, 0, 0), at(0, 0, 1), t_z),
+                lerp(at(0, 1, 0), at(0, 1, 1), t_z),
+                t_y,
+            ),
+            lerp(
+                lerp(at(1, 0, 0), at(1, 0, 1), t_z),
+                lerp(at(1, 1, 0), at(1, 1, 1), t_z),
+                t_y,
+            ),
+            t_x,
+        )
+    }
+}
+
+/// Temperature and moisture of a square of world columns, which biomes are picked by, laid out
+/// x, then z
+fn climate_columns(
+    climate_node: &GeneratorWrapper<SafeNode>,
+    seed: i32,
+    start: [i32; 2],
+    size: i32,
+) -> (Vec<f32>, Vec<f32>) {
+    let mut grid = vec![0.0; (size * size) as usize];
+    climate_node.gen_uniform_grid_2d(
+        &mut grid,
+        start[0],
+        start[1],
+        size,
+        size,
+        1.0 / CLIMATE_SCALE,
+        seed.wrapping_add(3),
+    );
+    let temperature = columns_from_grid(&grid, size, size);
+    climate_node.gen_uniform_grid_2d(
+        &mut grid,
+        start[0],
+        start[1],
+        size,
+        size,
+        1.0 / CLIMATE_SCALE,
+        seed.wrapping_add(4),
+    );
+    let moisture = columns_from_grid(&grid, size, size);
+    (temperature, moisture)
+}
+
+/// Height of the heightfield at a world column as a fraction of the world height, before
+/// overhangs and caves, from the height noise there
+fn ground_height(height_noise: f32, biome: &BlendedBiome) -> f32 {
+    (height_noise * 0.5) * biome.height_scale + 0.5 + biome.height_offset
+}
+
+/// Generates a chunk from 3D density, solid wherever the heightfield plus overhang noise is above
+/// the voxel and no cave runs through it. Returns `None` for chunks with nothing in them.
+///
+/// The eroded heightfield is shared with the other chunks of the column through
+/// `erosion_cache`, which must only ever be used with the same settings.
+pub fn create_vxm_from_noise(
+    x_pos: i32,
+    y_pos: i32,
+    z_pos: i32,
+    settings: &TerrainSettings,
+    erosion_cache: &ErosionCache,
+) -> Option<VxmAsset> {
     let start_time = std::time::Instant::now();
 
-    let (x_size, y_size, z_size) = (TERRAIN_SIZE, 255, TERRAIN_SIZE);
-    let node = create_node();
-    let terrain_colour_node =
-        SafeNode::from_encoded_node_tree("DwAEAAAAAAAAQAcAAAAAAD8AAAAAAA==").unwrap();
+    let chunk_size = settings.chunk_size;
+    let (x_size, y_size, z_size) = (chunk_size, chunk_size, chunk_size);
+    let node = create_node(&settings.height_noise);
+    let climate_node = create_climate_node();
+    // Checked when the settings are loaded
+    let terrain_colour_node = SafeNode::from_encoded_node_tree(&settings.colour_node_tree).unwrap();
+
+    let mut height_out = vec![0.0; (x_size * z_size) as usize];
 
-    let mut noise_out = vec![0.0; (x_size * z_size) as usize];
+    let mut sediment_out = vec![0.0; (x_size * z_size) as usize];
 
-    let mut colour_noise_out = vec![0.0; (x_size * z_size) as usize];
+    let mut biome_out = Vec::with_capacity((x_size * z_size) as usize);
 
     let mut voxel_array =
         vec![vec![vec![VxmVoxel::default(); z_size as usize]; y_size as usize]; x_size as usize];
 
+    // The heightfield is built over the chunk and a margin around it, wide enough for erosion to
+    // come out the same on both sides of chunk borders and for prefabs reaching into the chunk
+    // to find the ground under them
+    let erosion_reach = settings.erosion.as_ref().map_or(0, ErosionSettings::reach);
+    let prefab_reach = settings
+        .prefabs
+        .iter()
+        .map(|placement| placement.prefab.size[0].max(placement.prefab.size[2]))
+        .max()
+        .unwrap_or(0);
+    let padding = erosion_reach + prefab_reach;
+    let padded_size = chunk_size + 2 * padding;
+    let padded_origin_x = x_pos * x_size - padding;
+    let padded_origin_z = z_pos * z_size - padding;
+    let mut height_grid = vec![0.0; (padded_size * padded_size) as usize];
+    node.gen_uniform_grid_2d(
+        &mut height_grid,
+        padded_origin_x,
+        padded_origin_z,
+        padded_size,
+        padded_size,
+        1.0 / settings.scale_factor,
+        settings.seed,
+    );
+    let height_noise = columns_from_grid(&height_grid, padded_size, padded_size);
+    let (temperatures, moistures) = climate_columns(
+        &climate_node,
+        settings.seed,
+        [padded_origin_x, padded_origin_z],
+        padded_size,
+    );
+    let mut padded_heights = Vec::with_capacity((padded_size * padded_size) as usize);
+    let mut padded_biome_names = Vec::with_capacity((padded_size * padded_size) as usize);
+    for x in 0..padded_size {
+        for z in 0..padded_size {
+            let padded_i = (x * padded_size + z) as usize;
+            let (temperature, moisture) = (temperatures[padded_i], moistures[padded_i]);
+            let biome = settings.biomes.blend(temperature, moisture);
+            // In voxels while eroding, so the erosion settings don't depend on the world height
+            padded_heights
+                .push(ground_height(height_noise[padded_i], &biome) * settings.world_height);
+            padded_biome_names.push(settings.biomes.nearest(temperature, moisture).name.as_str());
+            if (padding..padding + chunk_size).contains(&x)
+                && (padding..padding + chunk_size).contains(&z)
+            {
+                biome_out.push(biome);
+            }
+        }
+    }
+    let padded_sediment = match &settings.erosion {
+        Some(erosion) => {
+            let eroded = erosion_cache.get_or_erode((x_pos, z_pos), || {
+                let mut heights = padded_heights.clone();
+                let sediment = erode(&mut heights, padded_size, erosion);
+                ErodedColumn { heights, sediment }
+            });
+            padded_heights.clone_from(&eroded.heights);
+            eroded.sediment.clone()
+        }
+        None => vec![0.0; padded_heights.len()],
+    };
+
+    // Paths are carved after erosion so rivers aren't silted back up. Their surface follows the
+    // ground sampled along the whole path, the same for every chunk they cross.
+    let natural_height = |world_x: f32, world_z: f32| {
+        let height_noise = node.gen_single_2d(
+            world_x / settings.scale_factor,
+            world_z / settings.scale_factor,
+            settings.seed,
+        );
+        let temperature = climate_node.gen_single_2d(
+            world_x / CLIMATE_SCALE,
+            world_z / CLIMATE_SCALE,
+            settings.seed.wrapping_add(3),
+        );
+        let moisture = climate_node.gen_single_2d(
+            world_x / CLIMATE_SCALE,
+            world_z / CLIMATE_SCALE,
+            settings.seed.wrapping_add(4),
+        );
+        let biome = settings.biomes.blend(temperature, moisture);
+        ground_height(height_noise, &biome) * settings.world_height
+    };
+    let padded_min = Vec2::new(padded_origin_x as f32, padded_origin_z as f32);
+    let padded_max = padded_min + (padded_size - 1) as f32;
+    let sampled_paths = settings
+        .paths
+        .iter()
+        .filter_map(|path| path.sample_over(padded_min, padded_max, natural_height))
+        .collect::<Vec<_>>();
+    let mut padded_paths = Vec::<Option<PathColumn>>::with_capacity(padded_heights.len());
+    for x in 0..padded_size {
+        for z in 0..padded_size {
+            let padded_i = (x * padded_size + z) as usize;
+            let mut path_column = None;
+            for sampled_path in &sampled_paths {
+                if let Some(column) = sampled_path.carve(
+                    x + padded_origin_x,
+                    z + padded_origin_z,
+                    padded_heights[padded_i],
+                ) {
+                    padded_heights[padded_i] = column.height;
+                    path_column = Some(column);
+                }
+            }
+            padded_paths.push(path_column);
+        }
+    }
+
+    let mut colour_grid = vec![0.0; (x_size * z_size) as usize];
+    terrain_colour_node.gen_uniform_grid_2d(
+        &mut colour_grid,
+        x_pos * x_size,
+        z_pos * z_size,
+        x_size,
+        z_size,
+        0.25,
+        settings.seed,
+    );
+    let colour_noise_out = columns_from_grid(&colour_grid, x_size, z_size);
+
     for x in 0..x_size {
         for z in 0..z_size {
             let i = (x * z_size + z) as usize;
-            let offset_x = x + x_pos * x_size;
-            let offset_z = z + z_pos * z_size;
-            noise_out[i] = node.gen_single_2d(
-                (offset_x as f32 / SCALE_FACTOR as f32),
-                (offset_z as f32 / SCALE_FACTOR as f32),
-                1337,
-            );
-            colour_noise_out[i] = terrain_colour_node.gen_single_2d(
-                offset_x as f32 / 4.0,
-                offset_z as f32 / 4.0,
-                1337,
-            );
+            let padded_i = ((x + padding) * padded_size + z + padding) as usize;
+            height_out[i] = padded_heights[padded_i] / settings.world_height;
+            sediment_out[i] = padded_sediment[padded_i];
         }
     }
 
-    for x in 0..x_size {
-        for y in 0..y_size {
-            for z in 0..z_size {
-                let i = (x * z_size + z) as usize;
-                let value = noise_out[i];
-                let normalized_value = (value * 0.5) + 0.5;
-                let normalized_y = y as f32 / y_size as f32;
-                if normalized_value > normalized_y {
-                    let colour_noise = (colour_noise_out[i] * 0.5) + 0.5;
-                    let grass_r = 0.1 - colour_noise * (1.0 / 32.0);
-                    let grass_g = 0.5 - colour_noise * (4.0 / 32.0);
-                    let grass_b = 0.1 - colour_noise * (2.0 / 32.0);
-
-                    let dirt_r = 0.3 - colour_noise * (1.0 / 32.0);
-                    let dirt_g = 0.2 - colour_noise * (1.0 / 32.0);
-                    let dirt_b = 0.1 - colour_noise * (1.0 / 32.0);
-
-                    let stone_r = 0.2f32 - colour_noise * (1.0 / 32.0);
-                    let stone_g = 0.2f32 - colour_noise * (1.0 / 32.0);
-                    let stone_b = 0.2f32 - colour_noise * (1.0 / 32.0);
-
-                    let sand_r = 0.9f32 - colour_noise * (4.0 / 32.0);
-                    let sand_g = 0.8f32 - colour_noise * (6.0 / 32.0);
-                    let sand_b = 0.5f32 - colour_noise * (3.0 / 32.0);
-
-                    let snow_threshold = 0.75 - colour_noise * (2.0 / 32.0);
-                    let sand_threshold = 0.4;
-                    let water_threshold = 0.35;
-                    let is_top_block = normalized_value < normalized_y + (1.0 / y_size as f32);
-
-                    let is_snow = normalized_value > snow_threshold && is_top_block;
-                    let is_sand = normalized_value < sand_threshold;
-                    let is_stone = !is_top_block;
-                    let is_water = normalized_value < water_threshold;
-
-                    let snow = 0.9 - colour_noise * 0.1;
-
-                    let t = normalized_y;
-
-                    let (r, g, b) = match (is_snow, is_sand, is_water, is_stone) {
-                        (true, _, _, _) => (snow, snow, snow),
-                        (_, _, true, _) => (0.0, 0.05, 0.5),
-                        (_, true, _, _) => (sand_r, sand_g, sand_b),
-                        (_, _, _, true) => (stone_r, stone_g, stone_b),
-                        _ => (
-                            lerp(grass_r, dirt_r, t),
-                            lerp(grass_g, dirt_g, t),
-                            lerp(grass_b, dirt_b, t),
-                        ),
-                    };
+    let density_grid = DensityGrid::new(
+        [x_pos * x_size, y_pos * y_size, z_pos * z_size],
+        [x_size, y_size + SURFACE_LOOKAHEAD, z_size],
+        settings,
+    );
+    // Heights are fractions of the world height, so the same settings shape the same hills
+    // whatever the chunk size
+    let normalized_height = |y: i32| (y_pos * y_size + y) as f32 / settings.world_height;
+    let is_solid = |x: i32, y: i32, z: i32, normalized_value: f32, overhang_scale: f32| {
+        let normalized_y = normalized_height(y);
+        let overhang = density_grid.sample(&density_grid.overhang, x, y, z) * overhang_scale;
+        normalized_value + overhang > normalized_y
+            && density_grid.sample(&density_grid.cave, x, y, z) >= settings.cave_width
+    };
 
-                    voxel_array[x as usize][y as usize][z as usize] = VxmVoxel {
-                        hsl: create_hsl_voxel(r, g, b),
-                        emissive: false,
+    let mut is_empty = true;
+    for x in 0..x_size {
+        for z in 0..z_size {
+            let i = (x * z_size + z) as usize;
+            let path_column =
+                padded_paths[((x + padding) * padded_size + z + padding) as usize].as_ref();
+            let biome = &biome_out[i];
+            let normalized_value = height_out[i];
+            let sediment_depth = sediment_out[i].round();
+            // Paths keep overhangs off their bed and out of the way of their slopes
+            let overhang_scale = path_column.map_or(1.0, |column| 1.0 - column.influence);
+            let water_line = path_column
+                .and_then(|column| column.water_level)
+                .map_or(WATER_LEVEL, |water_level| {
+                    (water_level / settings.world_height).max(WATER_LEVEL)
+                });
+            let colour_noise = (colour_noise_out[i] * 0.5) + 0.5;
+            let snow_line = biome.snow_line - colour_noise * (2.0 / 32.0);
+            // Scanned from the top down counting how deep under the surface each voxel is, so cave
+            // floors and the ground under overhangs take the colour of the depth they lie at. The
+            // scan starts in the chunk above, so the top of a buried chunk isn't taken as surface.
+            let mut depth = 0;
+            for y in (0..y_size + SURFACE_LOOKAHEAD).rev() {
+                let normalized_y = normalized_height(y);
+                let is_in_chunk = y < y_size;
+                if !is_solid(x, y, z, normalized_value, overhang_scale) {
+                    // Fill open ground below the water line with see-through water, leaving caves
+                    // under the surface dry
+                    if is_in_chunk && normalized_y < water_line && normalized_value <= normalized_y
+                    {
+                        is_empty = false;
+                        voxel_array[x as usize][y as usize][z as usize] = VxmVoxel {
+                            hsl: create_hsl_voxel(0.0, 0.05, 0.5),
+                            emissive: false,
+                            opacity: WATER_OPACITY,
+                        }
                     }
+                    if depth > 0 {
+                        depth += 1;
+                    }
+                    continue;
+                }
+
+                let palette = if let Some(column) =
+                    path_column.filter(|column| depth == 0 && column.is_bed)
+                {
+                    &column.path.palette
+                } else if depth == 0 && normalized_value > snow_line {
+                    &SNOW
+                } else if (depth as f32) < sediment_depth {
+                    // Sediment is only left where erosion is enabled
+                    &settings.erosion.as_ref().unwrap().sediment
+                } else if depth == 0 && normalized_value < SHORE_HEIGHT {
+                    &biome.shore
+                } else if depth == 0 {
+                    &biome.surface
+                } else if (depth as f32) < biome.subsurface_depth {
+                    &biome.subsurface
+                } else {
+                    &biome.stone
+                };
+                depth += 1;
+                if !is_in_chunk {
+                    continue;
+                }
+
+                is_empty = false;
+                let (r, g, b) = palette.colour_at(colour_noise);
+                voxel_array[x as usize][y as usize][z as usize] = VxmVoxel {
+                    hsl: create_hsl_voxel(r, g, b),
+                    emissive: false,
+                    opacity: 255,
                 }
             }
         }
     }
 
+    // Prefabs stand on the eroded heightfield, as overhangs and caves are only known within a
+    // chunk
+    let chunk_origin = IVec3::new(x_pos, y_pos, z_pos) * chunk_size;
+    let ground_at = |world_x: i32, world_z: i32| {
+        let (x, z) = (world_x - padded_origin_x, world_z - padded_origin_z);
+        if !(0..padded_size).contains(&x) || !(0..padded_size).contains(&z) {
+            return None;
+        }
+        let padded_i = (x * padded_size + z) as usize;
+        let height = padded_heights[padded_i];
+        if height <= WATER_LEVEL * settings.world_height {
+            return None;
+        }
+        // Roads and rivers are kept clear
+        if padded_paths[padded_i]
+            .as_ref()
+            .is_some_and(|column| column.is_bed || column.water_level.is_some())
+        {
+            return None;
+        }
+        Some((height.ceil() as i32 - 1, padded_biome_names[padded_i]))
+    };
+    for placement in placements_over_chunk(
+        &settings.prefabs,
+        settings.seed,
+        chunk_origin,
+        chunk_size,
+        ground_at,
+    ) {
+        is_empty &= !placement.stamp(&mut voxel_array, chunk_origin, chunk_size);
+    }
+
     println!("Terrain creation took {:?}", start_time.elapsed());
 
-    VxmAsset {
+    if is_empty {
+        return None;
+    }
+    Some(VxmAsset {
         size: [x_size as u8, y_size as u8, z_size as u8],
         voxel_array,
         lights: Vec::new(),
+        colour_encoding: ColourEncoding::Hsl,
+        palette: Vec::new(),
+    })
+}
+
+/// Distance from the camera to the centre of a chunk in chunks, and how far the chunk is in
+/// front of the camera from -1 behind it to 1 straight ahead, ignoring height
+fn chunk_distance_and_facing(
+    chunk: (i32, i32, i32),
+    chunk_size: i32,
+    camera: Vec3,
+    forward: Vec2,
+) -> (f32, f32) {
+    let chunk_size = chunk_size as f32;
+    let centre = (Vec3::new(chunk.0 as f32, chunk.1 as f32, chunk.2 as f32) + 0.5) * chunk_size;
+    let to_chunk = (centre - camera) / chunk_size;
+    (
+        to_chunk.length(),
+        to_chunk.xz().normalize_or_zero().dot(forward),
+    )
+}
+
+/// Queues the chunks within [`TerrainStreaming::load_radius`] of the camera that aren't loaded
+/// yet, nearest first, and chunks in view ahead of chunks behind the camera.
+///
+/// The queue is only rebuilt when the camera moves into another chunk or turns, which also drops
+/// requests for chunks that have fallen out of range before they were loaded.
+fn queue_chunks_around_camera_system(
+    mut chunk_queue: ResMut<ChunkQueue>,
+    streaming: Res<TerrainStreaming>,
+    settings: Res<TerrainSettings>,
+    camera_query: Query<&GlobalTransform, With<Camera>>,
+    generating_chunks: Res<GeneratingChunks>,
+    terrain_chunks: Query<&TerrainChunk>,
+) {
+    let Ok(camera_transform) = camera_query.single() else {
+        return;
+    };
+    let camera = camera_transform.translation();
+    // Looking straight up or down leaves no direction to favour, so chunks are ordered by distance
+    let forward = camera_transform
+        .forward()
+        .as_vec3()
+        .xz()
+        .normalize_or_zero();
+
+    let camera_chunk = (camera / settings.chunk_size as f32).floor().as_ivec3();
+    let view_sector = if forward == Vec2::ZERO {
+        -1
+    } else {
+        (forward.to_angle() / TAU * VIEW_DIRECTION_SECTORS).round() as i32
+    };
+    let ordered_for = Some((camera_chunk.into(), view_sector));
+    if chunk_queue.ordered_for == ordered_for {
+        return;
     }
+    chunk_queue.ordered_for = ordered_for;
+
+    let loaded_chunks = terrain_chunks
+        .iter()
+        .map(|chunk| chunk.position)
+        .collect::<HashSet<_>>();
+
+    let radius = streaming.load_radius.ceil() as i32;
+    let min_y = (camera_chunk.y - radius).max(settings.min_chunk_y);
+    let max_y = (camera_chunk.y + radius).min(settings.max_chunk_y);
+    let mut chunks = Vec::new();
+    for x in (camera_chunk.x - radius)..=(camera_chunk.x + radius) {
+        for y in min_y..=max_y {
+            for z in (camera_chunk.z - radius)..=(camera_chunk.z + radius) {
+                let position = (x, y, z);
+                if loaded_chunks.contains(&position) || generating_chunks.0.contains_key(&position)
+                {
+                    continue;
+                }
+                let (distance, facing) =
+                    chunk_distance_and_facing(position, settings.chunk_size, camera, forward);
+                if distance > streaming.load_radius {
+                    continue;
+                }
+                // Chunks behind the camera load as if they were up to twice as far away
+                chunks.push((distance * (1.5 - 0.5 * facing), position));
+            }
+        }
+    }
+    chunks.sort_by(|a, b| b.0.total_cmp(&a.0));
+    chunk_queue.chunks = chunks.into_iter().map(|(_, position)| position).collect();
 }
 
-fn terrain_system(
+/// Despawns chunks beyond [`TerrainStreaming::unload_radius`] of the camera and frees their voxels,
+/// and cancels any still generating that far away.
+///
+/// Neighbours that stay loaded, and the same levels of detail of them, are queued on the
+/// [`NeighbourRemeshQueue`] to have their borders re-meshed, so the faces they culled against an
+/// unloaded chunk are drawn again.
+fn unload_distant_chunks_system(
     mut commands: Commands,
-    mut chunk_queue: ResMut<ChunkQueue>,
     mut vxm_assets: ResMut<Assets<VxmAsset>>,
+    mut generating_chunks: ResMut<GeneratingChunks>,
+    mut neighbour_remeshes: ResMut<NeighbourRemeshQueue>,
+    streaming: Res<TerrainStreaming>,
+    settings: Res<TerrainSettings>,
+    camera_query: Query<&GlobalTransform, With<Camera>>,
+    terrain_chunks: Query<(Entity, &TerrainChunk)>,
+    terrain_levels: Query<(Entity, &TerrainChunkLevel, &VoxelLodLevel)>,
 ) {
-    if chunk_queue.0.len() == 0 {
+    let Ok(camera_transform) = camera_query.single() else {
         return;
+    };
+    let camera = camera_transform.translation();
+
+    // The camera has moved away before these finished, so their results are no longer needed
+    generating_chunks.0.retain(|&position, _| {
+        let (distance, _) =
+            chunk_distance_and_facing(position, settings.chunk_size, camera, Vec2::ZERO);
+        distance <= streaming.unload_radius
+    });
+
+    let is_distant = |position| {
+        let (distance, _) =
+            chunk_distance_and_facing(position, settings.chunk_size, camera, Vec2::ZERO);
+        distance > streaming.unload_radius
+    };
+    let mut unloaded_positions = Vec::new();
+    let mut loaded_chunks = HashMap::new();
+    for (entity, chunk) in terrain_chunks.iter() {
+        if !is_distant(chunk.position) {
+            loaded_chunks.insert(chunk.position, (entity, chunk.handle.id()));
+            continue;
+        }
+        commands.entity(entity).despawn();
+        vxm_assets.remove(&chunk.handle);
+        unloaded_positions.push(chunk.position);
     }
-    let (x_pos, y_pos, z_pos) = chunk_queue.0.pop_front().unwrap();
-    let vxm = create_vxm_from_noise(x_pos, y_pos, z_pos);
-    let vxm_handle = vxm_assets.add(vxm);
-    if x_pos == 0 && z_pos == 0 {
-        commands.spawn((
-            Name::new(format!("Terrain {} {}", x_pos, z_pos)),
-            PendingVxm(vxm_handle),
-            Transform::from_translation(Vec3::new(
-                (TERRAIN_SIZE * x_pos) as f32,
-                0.0,
-                (TERRAIN_SIZE * z_pos) as f32,
-            )),
-            CameraTarget(Vec3::new(
-                TERRAIN_SIZE as f32 * 0.5,
-                200.0,
-                TERRAIN_SIZE as f32 * 0.5,
-            )),
+    if unloaded_positions.is_empty() {
+        return;
+    }
+
+    // Levels are despawned along with their chunk, but their voxels are freed now so the levels
+    // around them are re-meshed without them
+    let mut loaded_levels: HashMap<u32, HashMap<_, _>> = HashMap::new();
+    for (entity, chunk_level, level) in terrain_levels.iter() {
+        if is_distant(chunk_level.position) {
+            vxm_assets.remove(&chunk_level.handle);
+        } else {
+            loaded_levels
+                .entry(level.0)
+                .or_default()
+                .insert(chunk_level.position, (entity, chunk_level.handle.id()));
+        }
+    }
+
+    for position in unloaded_positions {
+        neighbour_remeshes.queue_around(position, None, &loaded_chunks);
+        for (&level, loaded_levels) in &loaded_levels {
+            neighbour_remeshes.queue_around(position, Some(level), loaded_levels);
+        }
+    }
+}
+
+/// Loads the chunk at a chunk position from the generator's saves, or generates it if it hasn't
+/// been saved. Saved chunks contain edits, so they take priority over regenerating them.
+fn load_or_generate_chunk(
+    generator: &dyn TerrainGenerator,
+    region_store: &RegionStore,
+    position: (i32, i32, i32),
+    chunk_size: i32,
+) -> Option<VxmAsset> {
+    let Some(save_name) = generator.save_name() else {
+        return generator.generate_chunk(position, chunk_size);
+    };
+    match region_store.for_generator(save_name).load_chunk(position) {
+        Ok(Some(vxm)) if vxm.size.iter().all(|&size| size as i32 == chunk_size) => Some(vxm),
+        Ok(Some(_)) => {
+            warn!(
+                "Saved chunk {:?} is a different size, regenerating it",
+                position
+            );
+            generator.generate_chunk(position, chunk_size)
+        }
+        Ok(None) => generator.generate_chunk(position, chunk_size),
+        Err(e) => {
+            warn!("{}, regenerating it", e);
+            generator.generate_chunk(position, chunk_size)
+        }
+    }
+}
+
+/// Starts loading or generating queued chunks on the [`AsyncComputeTaskPool`], keeping at most
+/// [`TerrainStreaming::max_generating_chunks`] in flight
+fn terrain_system(
+    mut chunk_queue: ResMut<ChunkQueue>,
+    mut generating_chunks: ResMut<GeneratingChunks>,
+    streaming: Res<TerrainStreaming>,
+    region_store: Res<RegionStore>,
+    settings: Res<TerrainSettings>,
+    generator: Res<ActiveTerrainGenerator>,
+) {
+    let Some(generator) = generator.get() else {
+        return;
+    };
+    let task_pool = AsyncComputeTaskPool::get();
+    while generating_chunks.0.len() < streaming.max_generating_chunks {
+        let Some(position) = chunk_queue.chunks.pop() else {
+            return;
+        };
+        let region_store = region_store.clone();
+        let chunk_size = settings.chunk_size;
+        let generator = generator.clone();
+        let task = task_pool.spawn(async move {
+            load_or_generate_chunk(generator.as_ref(), &region_store, position, chunk_size)
+        });
+        generating_chunks.0.insert(position, task);
+    }
+}
+
+/// Adds the voxels of chunks that have finished generating and spawns their entities to be meshed.
+///
+/// Empty chunks get an entity without voxels, so they count as loaded but are never meshed, and
+/// their neighbours see them as air. Solid chunks are lit and wait as a [`SolidChunk`] to be
+/// meshed.
+fn spawn_generated_chunks_system(
+    mut commands: Commands,
+    mut generating_chunks: ResMut<GeneratingChunks>,
+    mut vxm_assets: ResMut<Assets<VxmAsset>>,
+    mut light_store: ResMut<VoxelLightStore>,
+    terrain_mesher: Res<TerrainMesher>,
+    settings: Res<TerrainSettings>,
+) {
+    let chunk_size = settings.chunk_size;
+    generating_chunks.0.retain(|&(x_pos, y_pos, z_pos), task| {
+        let Some(vxm) = block_on(future::poll_once(task)) else {
+            return true;
+        };
+        let name = Name::new(format!("Terrain {} {} {}", x_pos, y_pos, z_pos));
+        let transform = Transform::from_translation(Vec3::new(
+            (chunk_size * x_pos) as f32,
+            (chunk_size * y_pos) as f32,
+            (chunk_size * z_pos) as f32,
         ));
-    } else {
-        commands.spawn((
-            Name::new(format!("Terrain {} {}", x_pos, z_pos)),
-            PendingVxm(vxm_handle),
-            Transform::from_translation(Vec3::new(
-                (TERRAIN_SIZE * x_pos) as f32,
-                0.0,
-                (TERRAIN_SIZE * z_pos) as f32,
-            )),
+        let Some(vxm) = vxm else {
+            commands.spawn((
+                name,
+                TerrainChunk {
+                    position: (x_pos, y_pos, z_pos),
+                    handle: Handle::default(),
+                },
+                transform,
+            ));
+            return false;
+        };
+        let solid_light =
+            is_solid_chunk(&vxm).then(|| LightVolume::compute(&VoxelNeighbourhood::isolated(&vxm)));
+        let vxm_handle = vxm_assets.add(vxm);
+        let terrain_chunk = TerrainChunk {
+            position: (x_pos, y_pos, z_pos),
+            handle: vxm_handle.clone(),
+        };
+        let mut chunk_entity = commands.spawn((
+            name,
+            terrain_chunk,
+            VoxelLod::new(TERRAIN_LOD_LEVELS),
+            transform,
         ));
+        if (x_pos, y_pos, z_pos) == (0, 0, 0) {
+            chunk_entity.insert(CameraTarget(Vec3::new(
+                chunk_size as f32 * 0.5,
+                200.0,
+                chunk_size as f32 * 0.5,
+            )));
+        }
+        match solid_light {
+            // Nothing beyond its own voxels reaches inside, so it's lit the same wherever it is
+            Some(light) => {
+                light_store.0.insert(vxm_handle.id(), light);
+                chunk_entity.insert(SolidChunk);
+            }
+            None => {
+                chunk_entity.insert(PendingVxm(vxm_handle));
+            }
+        }
+        match *terrain_mesher {
+            TerrainMesher::Blocky => {}
+            TerrainMesher::ColourVolume => {
+                chunk_entity.insert(ColourVolumeMesh);
+            }
+            TerrainMesher::Smooth => {
+                chunk_entity.insert(SmoothVoxelMesh);
+            }
+        }
+        false
+    });
+}
+
+/// Whether every voxel of a chunk is opaque, hiding every voxel behind its outermost ones
+fn is_solid_chunk(vxm: &VxmAsset) -> bool {
+    vxm.voxel_array
+        .iter()
+        .flatten()
+        .flatten()
+        .all(is_opaque_voxel)
+}
+
+/// Whether any face of a solid chunk can be seen, from a loaded chunk beside it that isn't solid
+/// or from the sky above the top of the world. Chunks that haven't loaded yet are taken to hide
+/// it, as are the chunks below the bottom of the world, which are never loaded.
+fn is_solid_chunk_exposed(
+    (x_pos, y_pos, z_pos): (i32, i32, i32),
+    loaded_chunks: &HashMap<(i32, i32, i32), bool>,
+    max_chunk_y: i32,
+) -> bool {
+    FACE_NORMALS.iter().any(|&[dx, dy, dz]| {
+        let neighbour = (x_pos + dx, y_pos + dy, z_pos + dz);
+        if neighbour.1 > max_chunk_y {
+            return true;
+        }
+        loaded_chunks
+            .get(&neighbour)
+            .is_some_and(|&is_solid| !is_solid)
+    })
+}
+
+/// Queues solid chunks to be meshed once any of their faces can be seen or their voxels are
+/// edited
+fn mesh_exposed_solid_chunks_system(
+    mut commands: Commands,
+    mut events: EventReader<AssetEvent<VxmAsset>>,
+    settings: Res<TerrainSettings>,
+    terrain_chunks: Query<(Entity, &TerrainChunk, Has<SolidChunk>)>,
+) {
+    let edited = events
+        .read()
+        .filter_map(|event| match event {
+            AssetEvent::Modified { id } => Some(*id),
+            _ => None,
+        })
+        .collect::<HashSet<_>>();
+    let loaded_chunks = terrain_chunks
+        .iter()
+        .map(|(_, chunk, is_solid)| (chunk.position, is_solid))
+        .collect::<HashMap<_, _>>();
+
+    for (entity, chunk, is_solid) in terrain_chunks.iter() {
+        if !is_solid {
+            continue;
+        }
+        if edited.contains(&chunk.handle.id())
+            || is_solid_chunk_exposed(chunk.position, &loaded_chunks, settings.max_chunk_y)
+        {
+            commands
+                .entity(entity)
+                .remove::<SolidChunk>()
+                .insert(PendingVxm(chunk.handle.clone()));
+        }
+    }
+}
+
+/// Handle of the settings file terrain is generated from
+#[derive(Resource)]
+pub struct TerrainSettingsHandle(pub Handle<TerrainSettings>);
+
+fn load_terrain_settings_system(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands.insert_resource(TerrainSettingsHandle(
+        asset_server.load(TERRAIN_SETTINGS_PATH),
+    ));
+}
+
+/// Makes the loaded terrain settings current. When the settings file is edited, every loaded
+/// chunk is unloaded so the world regenerates around the camera with the new settings.
+fn apply_terrain_settings_system(
+    mut commands: Commands,
+    mut events: EventReader<AssetEvent<TerrainSettings>>,
+    settings_handle: Res<TerrainSettingsHandle>,
+    settings_assets: Res<Assets<TerrainSettings>>,
+    current_settings: Option<Res<TerrainSettings>>,
+    mut generator: ResMut<ActiveTerrainGenerator>,
+    mut vxm_assets: ResMut<Assets<VxmAsset>>,
+    mut chunk_queue: ResMut<ChunkQueue>,
+    mut generating_chunks: ResMut<GeneratingChunks>,
+    terrain_chunks: Query<(Entity, &TerrainChunk)>,
+) {
+    let mut is_settings_changed = false;
+    for event in events.read() {
+        is_settings_changed |= event.is_loaded_with_dependencies(&settings_handle.0)
+            || event.is_modified(&settings_handle.0);
+    }
+    let new_settings = settings_assets.get(&settings_handle.0).filter(|settings| {
+        is_settings_changed
+            && current_settings
+                .as_ref()
+                .is_none_or(|current_settings| **current_settings != **settings)
+    });
+    let is_generator_changed = generator.is_changed() && !generator.is_added();
+    if new_settings.is_none() && !is_generator_changed {
+        return;
+    }
+
+    for (entity, chunk) in terrain_chunks.iter() {
+        commands.entity(entity).despawn();
+        vxm_assets.remove(&chunk.handle);
+    }
+    generating_chunks.0.clear();
+    chunk_queue.chunks.clear();
+    chunk_queue.ordered_for = None;
+    if let Some(settings) = new_settings {
+        info!("Generating terrain with seed {}", settings.seed);
+        // Not a change of generator, which would regenerate the terrain again next frame
+        generator.bypass_change_detection().noise =
+            Some(Arc::new(NoiseTerrainGenerator::new(settings.clone())));
+        commands.insert_resource(settings.clone());
+    } else {
+        info!("Terrain generator changed, regenerating terrain");
     }
 }
 
@@ -184,6 +1057,521 @@ pub struct VoxelTerrainPlugin;
 impl Plugin for VoxelTerrainPlugin {
     fn build(&self, app: &mut App) {
         app.init_resource::<ChunkQueue>();
-        app.add_systems(Update, terrain_system);
+        app.init_resource::<GeneratingChunks>();
+        app.init_resource::<TerrainStreaming>();
+        app.init_resource::<TerrainMesher>();
+        app.init_resource::<ActiveTerrainGenerator>();
+        app.init_resource::<RegionStore>();
+        app.init_asset::<TerrainSettings>();
+        app.init_asset_loader::<TerrainSettingsLoader>();
+        app.add_systems(Startup, load_terrain_settings_system);
+        // Edits are saved before their chunk can be unloaded
+        app.add_systems(
+            Update,
+            (
+                save_modified_chunks_system,
+                apply_terrain_settings_system,
+                (
+                    unload_distant_chunks_system,
+                    queue_chunks_around_camera_system,
+                    spawn_generated_chunks_system,
+                    mesh_exposed_solid_chunks_system,
+                    terrain_system,
+                )
+                    .chain()
+                    .run_if(resource_exists::<TerrainSettings>),
+            )
+                .chain(),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::main::InstanceMaterialData;
+    use crate::vxm_mesh::{
+        create_mesh_on_vxm_import_system, MeshedVoxelsFace, MeshingBackend, MeshingBudget,
+    };
+    use crate::vxm_region::tests::TempRegionStore;
+    use crate::vxm_terrain_generator::{
+        FlatTerrainGenerator, GroundLayers, HeightmapTerrainGenerator,
+    };
+    use bevy::ecs::system::RunSystemOnce;
+    use bevy::prelude::{ChildOf, Mesh, World};
+    use std::time::Duration;
+
+    // The shipped world, in smaller chunks and without the prefabs that need loading as assets
+    fn test_settings() -> TerrainSettings {
+        let mut settings: TerrainSettings =
+            ron::de::from_str(include_str!("../assets/world.terrain.ron")).unwrap();
+        settings.chunk_size = 32;
+        settings.prefabs.clear();
+        settings
+    }
+
+    // Chunks from the bottom of the world up to the surface of the hills
+    fn test_chunk_positions() -> impl Iterator<Item = (i32, i32, i32)> {
+        (0..2).flat_map(|x| (0..2).flat_map(move |z| (0..5).map(move |y| (x, y, z))))
+    }
+
+    // Whether any column of a chunk has air under ground, which a plain heightfield can't make
+    fn has_air_under_ground(vxm: &VxmAsset) -> bool {
+        vxm.voxel_array.iter().any(|column| {
+            (0..vxm.size[2] as usize).any(|z| {
+                let mut is_air_below = false;
+                column.iter().any(|row| {
+                    let voxel = &row[z];
+                    let is_ground = voxel.opacity == u8::MAX && voxel.hsl != 0;
+                    is_air_below |= voxel.hsl == 0;
+                    is_air_below && is_ground
+                })
+            })
+        })
+    }
+
+    #[test]
+    fn the_same_seed_generates_the_same_chunks() {
+        let settings = test_settings();
+        for (x, y, z) in test_chunk_positions() {
+            let first = create_vxm_from_noise(x, y, z, &settings, &ErosionCache::default());
+            let second = create_vxm_from_noise(x, y, z, &settings, &ErosionCache::default());
+            assert_eq!(
+                first.map(|vxm| vxm.voxel_array),
+                second.map(|vxm| vxm.voxel_array),
+                "chunk {x} {y} {z}"
+            );
+        }
+    }
+
+    #[test]
+    fn chunks_sharing_eroded_columns_generate_as_if_eroded_alone() {
+        let settings = test_settings();
+        assert!(settings.erosion.is_some());
+        let erosion_cache = ErosionCache::default();
+        for (x, y, z) in test_chunk_positions() {
+            let shared = create_vxm_from_noise(x, y, z, &settings, &erosion_cache);
+            let alone = create_vxm_from_noise(x, y, z, &settings, &ErosionCache::default());
+            assert_eq!(
+                shared.map(|vxm| vxm.voxel_array),
+                alone.map(|vxm| vxm.voxel_array),
+                "chunk {x} {y} {z}"
+            );
+        }
+    }
+
+    // Noise from uniform grids differs from single samples by float rounding at most
+    const GRID_TOLERANCE: f32 = 1e-4;
+
+    #[test]
+    fn uniform_density_grids_match_single_samples() {
+        let node = create_cave_node();
+        let size = [9, 13, 9];
+        // Both on a multiple of the step, sampled as a uniform grid, and off it, sampled singly
+        for origin in [[-32, 64, 96], [-30, 65, 99]] {
+            let field = sample_density_field(&node, origin, size, CAVE_SCALE, 7);
+            let mut samples = field.iter();
+            for x in 0..size[0] as i32 {
+                for y in 0..size[1] as i32 {
+                    for z in 0..size[2] as i32 {
+                        let expected = node.gen_single_3d(
+                            density_sample_position(origin[0] + x * DENSITY_STEP, CAVE_SCALE),
+                            density_sample_position(origin[1] + y * DENSITY_STEP, CAVE_SCALE),
+                            density_sample_position(origin[2] + z * DENSITY_STEP, CAVE_SCALE),
+                            7,
+                        );
+                        let sample = *samples.next().unwrap();
+                        assert!(
+                            (sample - expected).abs() < GRID_TOLERANCE,
+                            "{sample} at {x} {y} {z} from {origin:?}, expected {expected}"
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn uniform_column_grids_match_single_samples() {
+        let settings = test_settings();
+        let node = create_node(&settings.height_noise);
+        let climate_node = create_climate_node();
+        let (start, size) = ([-40, 72], 24);
+
+        let mut grid = vec![0.0; (size * size) as usize];
+        node.gen_uniform_grid_2d(
+            &mut grid,
+            start[0],
+            start[1],
+            size,
+            size,
+            1.0 / settings.scale_factor,
+            settings.seed,
+        );
+        let heights = columns_from_grid(&grid, size, size);
+        let (temperatures, moistures) = climate_columns(&climate_node, settings.seed, start, size);
+
+        for x in 0..size {
+            for z in 0..size {
+                let i = (x * size + z) as usize;
+                let (world_x, world_z) = ((start[0] + x) as f32, (start[1] + z) as f32);
+                let expected_height = node.gen_single_2d(
+                    world_x / settings.scale_factor,
+                    world_z / settings.scale_factor,
+                    settings.seed,
+                );
+                let expected_temperature = climate_node.gen_single_2d(
+                    world_x / CLIMATE_SCALE,
+                    world_z / CLIMATE_SCALE,
+                    settings.seed.wrapping_add(3),
+                );
+                let expected_moisture = climate_node.gen_single_2d(
+                    world_x / CLIMATE_SCALE,
+                    world_z / CLIMATE_SCALE,
+                    settings.seed.wrapping_add(4),
+                );
+                assert!((heights[i] - expected_height).abs() < GRID_TOLERANCE);
+                assert!((temperatures[i] - expected_temperature).abs() < GRID_TOLERANCE);
+                assert!((moistures[i] - expected_moisture).abs() < GRID_TOLERANCE);
+            }
+        }
+    }
+
+    fn is_ground(vxm: &VxmAsset, [x, y, z]: [usize; 3]) -> bool {
+        let voxel = &vxm.voxel_array[x][y][z];
+        voxel.opacity == u8::MAX && voxel.hsl != 0
+    }
+
+    fn is_air(vxm: &VxmAsset, [x, y, z]: [usize; 3]) -> bool {
+        vxm.voxel_array[x][y][z].hsl == 0
+    }
+
+    // The air voxels of a chunk reachable from a voxel of air through the faces of others
+    fn air_around(vxm: &VxmAsset, start: [usize; 3]) -> Vec<[usize; 3]> {
+        let size = vxm.size.map(|s| s as usize);
+        let mut reached = vec![start];
+        let mut visited = HashSet::from([start]);
+        let mut next = 0;
+        while let Some(&voxel) = reached.get(next) {
+            next += 1;
+            for axis in 0..3 {
+                for neighbour in [voxel[axis].checked_sub(1), Some(voxel[axis] + 1)] {
+                    let Some(neighbour) = neighbour.filter(|&n| n < size[axis]) else {
+                        continue;
+                    };
+                    let mut position = voxel;
+                    position[axis] = neighbour;
+                    if is_air(vxm, position) && visited.insert(position) {
+                        reached.push(position);
+                    }
+                }
+            }
+        }
+        reached
+    }
+
+    #[test]
+    fn a_known_seed_carves_caves_and_overhangs() {
+        let settings = test_settings();
+
+        // A cave pocket under the hills, sealed in ground on every side
+        let vxm = create_vxm_from_noise(1, 1, 0, &settings, &ErosionCache::default()).unwrap();
+        let size = vxm.size.map(|s| s as usize);
+        let cave = [12, 8, 19];
+        assert!(is_air(&vxm, cave));
+        assert!(is_ground(&vxm, [12, 9, 19]));
+        assert!(is_ground(&vxm, [12, 7, 19]));
+        let pocket = air_around(&vxm, cave);
+        assert!(pocket.len() > 1);
+        assert!(pocket
+            .iter()
+            .all(|voxel| (0..3).all(|axis| voxel[axis] > 0 && voxel[axis] < size[axis] - 1)));
+
+        // A ledge on the top chunks of the world, with ground over air that's open to the sky
+        let vxm = create_vxm_from_noise(-1, 4, 1, &settings, &ErosionCache::default()).unwrap();
+        assert_eq!(settings.max_chunk_y, 4);
+        let size = vxm.size.map(|s| s as usize);
+        let overhang = [7, 12, 17];
+        assert!(is_air(&vxm, overhang));
+        assert!((13..25).all(|y| is_ground(&vxm, [7, y, 17])));
+        assert!(is_air(&vxm, [7, 25, 17]));
+        assert!(air_around(&vxm, overhang)
+            .iter()
+            .any(|voxel| voxel[1] == size[1] - 1));
+    }
+
+    #[test]
+    fn a_plain_heightfield_has_no_air_under_ground() {
+        let settings = TerrainSettings {
+            overhang_strength: 0.0,
+            cave_width: 0.0,
+            ..test_settings()
+        };
+        for (x, y, z) in test_chunk_positions() {
+            if let Some(vxm) = create_vxm_from_noise(x, y, z, &settings, &ErosionCache::default()) {
+                assert!(!has_air_under_ground(&vxm), "chunk {x} {y} {z}");
+            }
+        }
+    }
+
+    fn surface_voxel(layers: &GroundLayers) -> u16 {
+        let (r, g, b) = layers.surface.colour_at(0.0);
+        create_hsl_voxel(r, g, b)
+    }
+
+    #[test]
+    fn a_flat_generator_drives_chunk_generation() {
+        let region_store = TempRegionStore::new("flat-generation");
+        let generator = FlatTerrainGenerator {
+            height: 40,
+            layers: GroundLayers::default(),
+        };
+
+        let below = load_or_generate_chunk(&generator, &region_store.0, (0, 1, 0), 16).unwrap();
+        assert_eq!(below.size, [16; 3]);
+        assert!(below
+            .voxel_array
+            .iter()
+            .flatten()
+            .flatten()
+            .all(|voxel| voxel.opacity == u8::MAX && voxel.hsl != 0));
+
+        // The ground ends 8 voxels into the third chunk, with the surface on top
+        let surface = load_or_generate_chunk(&generator, &region_store.0, (-3, 2, 5), 16).unwrap();
+        for column in &surface.voxel_array {
+            for z in 0..16 {
+                assert_eq!(column[7][z].hsl, surface_voxel(&generator.layers));
+                assert!(column[8..].iter().all(|row| row[z].hsl == 0));
+            }
+        }
+
+        assert!(load_or_generate_chunk(&generator, &region_store.0, (0, 3, 0), 16).is_none());
+    }
+
+    // Opaque faces of a meshed chunk on its border with the next chunk along x
+    fn right_border_faces(world: &mut World, chunk: Entity, chunk_size: u8) -> usize {
+        world
+            .query::<(&ChildOf, &MeshedVoxelsFace, &InstanceMaterialData)>()
+            .iter(world)
+            .filter(|(child_of, face, _)| {
+                child_of.parent() == chunk && matches!(face, MeshedVoxelsFace::Right)
+            })
+            .flat_map(|(_, _, instances)| instances.0.iter())
+            .filter(|instance| instance.position[0] == chunk_size - 1)
+            .count()
+    }
+
+    #[test]
+    fn chunks_beside_an_unloaded_chunk_mesh_the_faces_it_hid() {
+        let generator = FlatTerrainGenerator {
+            height: 8,
+            layers: GroundLayers::default(),
+        };
+        let mut world = World::new();
+        world.insert_resource(TerrainSettings {
+            chunk_size: 16,
+            ..test_settings()
+        });
+        // Only the chunk the camera is in stays loaded
+        world.insert_resource(TerrainStreaming {
+            unload_radius: 0.5,
+            ..TerrainStreaming::default()
+        });
+        world.init_resource::<GeneratingChunks>();
+        world.init_resource::<NeighbourRemeshQueue>();
+        world.init_resource::<Assets<VxmAsset>>();
+        world.init_resource::<Assets<Mesh>>();
+        world.init_resource::<VoxelLightStore>();
+        world.init_resource::<MeshingBackend>();
+        // Everything pending is meshed in one run, leaving nothing queued for the next
+        world.insert_resource(MeshingBudget {
+            max_time_per_frame: Duration::MAX,
+        });
+        world.spawn((Camera::default(), GlobalTransform::from_xyz(8.0, 8.0, 8.0)));
+
+        let chunks = [(0, 0, 0), (1, 0, 0)].map(|position| {
+            let vxm = generator.generate_chunk(position, 16).unwrap();
+            let handle = world.resource_mut::<Assets<VxmAsset>>().add(vxm);
+            world
+                .spawn((
+                    TerrainChunk {
+                        position,
+                        handle: handle.clone(),
+                    },
+                    PendingVxm(handle),
+                    Transform::default(),
+                ))
+                .id()
+        });
+        world
+            .run_system_once(create_mesh_on_vxm_import_system)
+            .unwrap();
+        assert_eq!(right_border_faces(&mut world, chunks[0], 16), 0);
+
+        world.run_system_once(unload_distant_chunks_system).unwrap();
+        assert!(world.get_entity(chunks[1]).is_err());
+        world
+            .run_system_once(create_mesh_on_vxm_import_system)
+            .unwrap();
+        assert!(right_border_faces(&mut world, chunks[0], 16) > 0);
+    }
+
+    #[test]
+    fn generators_that_dont_keep_edits_ignore_saved_chunks() {
+        let region_store = TempRegionStore::new("flat-saves");
+        let saved = FlatTerrainGenerator {
+            height: 10,
+            layers: GroundLayers::default(),
+        }
+        .generate_chunk((0, 0, 0), 16)
+        .unwrap();
+        region_store
+            .0
+            .for_generator("noise")
+            .save_chunk((0, 0, 0), &saved)
+            .unwrap();
+
+        let generator = FlatTerrainGenerator {
+            height: 40,
+            layers: GroundLayers::default(),
+        };
+        let vxm = load_or_generate_chunk(&generator, &region_store.0, (0, 0, 0), 16).unwrap();
+        assert_eq!(
+            vxm.voxel_array,
+            generator.generate_chunk((0, 0, 0), 16).unwrap().voxel_array
+        );
+    }
+
+    #[test]
+    fn saved_chunks_load_in_place_of_generating_them() {
+        let region_store = TempRegionStore::new("noise-saves");
+        let saved = FlatTerrainGenerator {
+            height: 10,
+            layers: GroundLayers::default(),
+        }
+        .generate_chunk((0, 0, 0), 32)
+        .unwrap();
+        let generator = NoiseTerrainGenerator::new(test_settings());
+        region_store
+            .0
+            .for_generator(generator.save_name().unwrap())
+            .save_chunk((0, 0, 0), &saved)
+            .unwrap();
+
+        let vxm = load_or_generate_chunk(&generator, &region_store.0, (0, 0, 0), 32).unwrap();
+        assert_eq!(vxm.voxel_array, saved.voxel_array);
+    }
+
+    #[test]
+    fn chunks_saved_with_another_seed_are_not_loaded() {
+        let region_store = TempRegionStore::new("noise-seeds");
+        let saved = FlatTerrainGenerator {
+            height: 10,
+            layers: GroundLayers::default(),
+        }
+        .generate_chunk((0, 0, 0), 32)
+        .unwrap();
+        let generator = NoiseTerrainGenerator::new(test_settings());
+        region_store
+            .0
+            .for_generator(generator.save_name().unwrap())
+            .save_chunk((0, 0, 0), &saved)
+            .unwrap();
+
+        let mut settings = test_settings();
+        settings.seed += 1;
+        let reseeded = NoiseTerrainGenerator::new(settings.clone());
+        assert_ne!(reseeded.save_name(), generator.save_name());
+        let vxm = load_or_generate_chunk(&reseeded, &region_store.0, (0, 0, 0), 32);
+        assert_eq!(
+            vxm.map(|vxm| vxm.voxel_array),
+            create_vxm_from_noise(0, 0, 0, &settings, &ErosionCache::default())
+                .map(|vxm| vxm.voxel_array)
+        );
+    }
+
+    #[test]
+    fn a_heightmap_generator_has_air_beyond_its_edges() {
+        let region_store = TempRegionStore::new("heightmap");
+        // A ramp rising 1 voxel along each step in x
+        let heights = (0..8)
+            .flat_map(|x| (0..4).map(move |_| x as f32 + 1.0))
+            .collect();
+        let generator = HeightmapTerrainGenerator::new(8, 4, heights, GroundLayers::default());
+
+        let vxm = load_or_generate_chunk(&generator, &region_store.0, (0, 0, 0), 16).unwrap();
+        for (x, column) in vxm.voxel_array.iter().enumerate() {
+            for z in 0..16 {
+                let height = if x < 8 && z < 4 { x + 1 } else { 0 };
+                assert!(column[..height].iter().all(|row| row[z].hsl != 0));
+                assert!(column[height..].iter().all(|row| row[z].hsl == 0));
+            }
+        }
+
+        assert!(load_or_generate_chunk(&generator, &region_store.0, (1, 0, 0), 16).is_none());
+        assert!(load_or_generate_chunk(&generator, &region_store.0, (0, 0, -1), 16).is_none());
+    }
+
+    #[test]
+    fn only_chunks_under_the_ground_are_solid() {
+        let generator = FlatTerrainGenerator {
+            height: 40,
+            layers: GroundLayers::default(),
+        };
+        assert!(is_solid_chunk(
+            &generator.generate_chunk((0, 1, 0), 16).unwrap()
+        ));
+        assert!(!is_solid_chunk(
+            &generator.generate_chunk((0, 2, 0), 16).unwrap()
+        ));
+
+        let mut holed = generator.generate_chunk((0, 0, 0), 16).unwrap();
+        holed.voxel_array[3][5][7] = VxmVoxel::default();
+        assert!(!is_solid_chunk(&holed));
+    }
+
+    #[test]
+    fn solid_chunks_are_exposed_by_chunks_beside_them_that_arent_solid() {
+        let position = (2, 1, -3);
+        let max_chunk_y = 4;
+        let mut loaded_chunks = FACE_NORMALS
+            .iter()
+            .map(|&[dx, dy, dz]| ((position.0 + dx, position.1 + dy, position.2 + dz), true))
+            .collect::<HashMap<_, _>>();
+        assert!(!is_solid_chunk_exposed(
+            position,
+            &loaded_chunks,
+            max_chunk_y
+        ));
+
+        // Chunks only touching an edge or a corner don't show any of its faces
+        loaded_chunks.insert((3, 2, -3), false);
+        loaded_chunks.insert((3, 2, -2), false);
+        assert!(!is_solid_chunk_exposed(
+            position,
+            &loaded_chunks,
+            max_chunk_y
+        ));
+
+        loaded_chunks.remove(&(2, 1, -2));
+        assert!(!is_solid_chunk_exposed(
+            position,
+            &loaded_chunks,
+            max_chunk_y
+        ));
+
+        loaded_chunks.insert((2, 1, -2), false);
+        assert!(is_solid_chunk_exposed(
+            position,
+            &loaded_chunks,
+            max_chunk_y
+        ));
+    }
+
+    #[test]
+    fn solid_chunks_are_exposed_to_the_sky_but_not_the_bottom_of_the_world() {
+        let loaded_chunks = HashMap::new();
+        assert!(is_solid_chunk_exposed((0, 4, 0), &loaded_chunks, 4));
+        assert!(!is_solid_chunk_exposed((0, -2, 0), &loaded_chunks, 4));
     }
 }
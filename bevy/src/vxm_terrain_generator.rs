@@ -0,0 +1,228 @@
+use crate::color_conversion::{create_hsl_voxel, ColourEncoding};
+use crate::vxm::{VxmAsset, VxmVoxel};
+use crate::vxm_biome::BiomePalette;
+use crate::vxm_erosion::ErosionCache;
+use crate::vxm_rng::hash;
+use crate::vxm_terrain::create_vxm_from_noise;
+use crate::vxm_terrain_settings::TerrainSettings;
+use bevy::prelude::Resource;
+use std::sync::Arc;
+
+/// Generates the voxels of terrain chunks, one chunk at a time on background threads
+pub trait TerrainGenerator: Send + Sync + 'static {
+    /// Voxels of the chunk at a chunk position, `chunk_size` voxels along each side, or `None`
+    /// for chunks with nothing in them
+    fn generate_chunk(&self, position: (i32, i32, i32), chunk_size: i32) -> Option<VxmAsset>;
+
+    /// Folder of the [`RegionStore`](crate::vxm_region::RegionStore) that edited chunks are saved
+    /// in and loaded back from in place of generating them, or `None` for worlds that are
+    /// generated afresh every time
+    fn save_name(&self) -> Option<&str> {
+        None
+    }
+}
+
+/// The generator terrain chunks are generated with. Game modes set `custom` to supply their own
+/// worlds, otherwise chunks are generated from the noise in the loaded [`TerrainSettings`].
+/// Changing it regenerates every loaded chunk.
+#[derive(Resource, Clone, Default)]
+pub struct ActiveTerrainGenerator {
+    pub custom: Option<Arc<dyn TerrainGenerator>>,
+    /// Rebuilt whenever the terrain settings change
+    pub(crate) noise: Option<Arc<NoiseTerrainGenerator>>,
+}
+
+impl ActiveTerrainGenerator {
+    /// The generator to use, or `None` while there's no custom generator and the terrain
+    /// settings haven't loaded
+    pub fn get(&self) -> Option<Arc<dyn TerrainGenerator>> {
+        match (&self.custom, &self.noise) {
+            (Some(custom), _) => Some(custom.clone()),
+            (None, Some(noise)) => Some(noise.clone()),
+            (None, None) => None,
+        }
+    }
+}
+
+/// Hills, caves, biomes and prefabs shaped by the noise in terrain settings
+pub struct NoiseTerrainGenerator {
+    settings: TerrainSettings,
+    erosion_cache: ErosionCache,
+    save_name: String,
+}
+
+impl NoiseTerrainGenerator {
+    pub fn new(settings: TerrainSettings) -> Self {
+        // Edits only make sense on the terrain they were made to, so each seed and every other
+        // change to the settings saves to a folder of its own
+        let settings_ron = ron::to_string(&settings).expect("Terrain settings always serialise");
+        let settings_hash = hash(
+            settings_ron.len() as u64,
+            settings_ron.as_bytes().chunks(8).map(|bytes| {
+                let mut word = [0; 8];
+                word[..bytes.len()].copy_from_slice(bytes);
+                u64::from_le_bytes(word)
+            }),
+        );
+        Self {
+            settings,
+            erosion_cache: ErosionCache::default(),
+            save_name: format!("noise-{settings_hash:016x}"),
+        }
+    }
+}
+
+impl TerrainGenerator for NoiseTerrainGenerator {
+    // Chunks are streamed by the chunk size of the same settings
+    fn generate_chunk(
+        &self,
+        (x_pos, y_pos, z_pos): (i32, i32, i32),
+        _chunk_size: i32,
+    ) -> Option<VxmAsset> {
+        create_vxm_from_noise(x_pos, y_pos, z_pos, &self.settings, &self.erosion_cache)
+    }
+
+    fn save_name(&self) -> Option<&str> {
+        Some(&self.save_name)
+    }
+}
+
+/// Colours of the layers of ground from the surface down
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GroundLayers {
+    /// Topmost voxel of the ground
+    pub surface: BiomePalette,
+    /// Voxels under the surface, down to `subsurface_depth`
+    pub subsurface: BiomePalette,
+    pub subsurface_depth: i32,
+    /// Everything deeper than the subsurface
+    pub stone: BiomePalette,
+}
+
+impl Default for GroundLayers {
+    fn default() -> Self {
+        Self {
+            surface: BiomePalette {
+                colour: [0.2, 0.4, 0.1],
+                variation: [0.0; 3],
+            },
+            subsurface: BiomePalette {
+                colour: [0.3, 0.2, 0.1],
+                variation: [0.0; 3],
+            },
+            subsurface_depth: 4,
+            stone: BiomePalette {
+                colour: [0.35, 0.35, 0.35],
+                variation: [0.0; 3],
+            },
+        }
+    }
+}
+
+impl GroundLayers {
+    fn voxel_at_depth(&self, depth: i32) -> VxmVoxel {
+        let palette = if depth == 0 {
+            &self.surface
+        } else if depth < self.subsurface_depth {
+            &self.subsurface
+        } else {
+            &self.stone
+        };
+        let (r, g, b) = palette.colour_at(0.0);
+        VxmVoxel {
+            hsl: create_hsl_voxel(r, g, b),
+            emissive: false,
+            opacity: 255,
+        }
+    }
+}
+
+/// Fills a chunk with ground below the height in voxels `ground_height` gives for each world
+/// column, or `None` where a column has no ground. Returns `None` if the chunk is all air.
+fn chunk_from_heights(
+    (x_pos, y_pos, z_pos): (i32, i32, i32),
+    chunk_size: i32,
+    layers: &GroundLayers,
+    ground_height: impl Fn(i32, i32) -> Option<i32>,
+) -> Option<VxmAsset> {
+    let size = chunk_size as usize;
+    let mut voxel_array = vec![vec![vec![VxmVoxel::default(); size]; size]; size];
+
+    let mut is_empty = true;
+    for x in 0..chunk_size {
+        for z in 0..chunk_size {
+            let Some(height) = ground_height(x_pos * chunk_size + x, z_pos * chunk_size + z) else {
+                continue;
+            };
+            let top = (height - y_pos * chunk_size).min(chunk_size);
+            for y in 0..top {
+                is_empty = false;
+                let depth = height - 1 - (y_pos * chunk_size + y);
+                voxel_array[x as usize][y as usize][z as usize] = layers.voxel_at_depth(depth);
+            }
+        }
+    }
+
+    if is_empty {
+        return None;
+    }
+    Some(VxmAsset {
+        size: [chunk_size as u8; 3],
+        voxel_array,
+        lights: Vec::new(),
+        colour_encoding: ColourEncoding::Hsl,
+        palette: Vec::new(),
+    })
+}
+
+/// Level ground reaching in every direction, such as a test arena for gameplay. Edits aren't
+/// saved, so every run starts on the same ground.
+pub struct FlatTerrainGenerator {
+    /// World height in voxels of the top of the ground
+    pub height: i32,
+    pub layers: GroundLayers,
+}
+
+impl TerrainGenerator for FlatTerrainGenerator {
+    fn generate_chunk(&self, position: (i32, i32, i32), chunk_size: i32) -> Option<VxmAsset> {
+        chunk_from_heights(position, chunk_size, &self.layers, |_, _| Some(self.height))
+    }
+}
+
+/// Ground shaped by a grid of heights, with its corner at the world origin and air beyond its
+/// edges. Edits aren't saved, as they would be lost whenever the heights change.
+pub struct HeightmapTerrainGenerator {
+    width: i32,
+    depth: i32,
+    /// Heights in voxels, laid out x, then z
+    heights: Vec<f32>,
+    pub layers: GroundLayers,
+}
+
+impl HeightmapTerrainGenerator {
+    /// Heights in voxels of `width` by `depth` columns, laid out x, then z
+    pub fn new(width: i32, depth: i32, heights: Vec<f32>, layers: GroundLayers) -> Self {
+        assert_eq!(
+            heights.len(),
+            (width * depth) as usize,
+            "Heightmap doesn't have width * depth heights"
+        );
+        Self {
+            width,
+            depth,
+            heights,
+            layers,
+        }
+    }
+}
+
+impl TerrainGenerator for HeightmapTerrainGenerator {
+    fn generate_chunk(&self, position: (i32, i32, i32), chunk_size: i32) -> Option<VxmAsset> {
+        chunk_from_heights(position, chunk_size, &self.layers, |world_x, world_z| {
+            if !(0..self.width).contains(&world_x) || !(0..self.depth).contains(&world_z) {
+                return None;
+            }
+            Some(self.heights[(world_x * self.depth + world_z) as usize].round() as i32)
+        })
+    }
+}
@@ -0,0 +1,212 @@
+use crate::vxm::VxmAsset;
+use crate::vxm_biome::Biomes;
+use crate::vxm_erosion::ErosionSettings;
+use crate::vxm_path::TerrainPath;
+use crate::vxm_prefab::{Prefab, PrefabPlacement};
+use bevy::asset::{io::Reader, Asset, AssetLoader, LoadContext, LoadDirectError};
+use bevy::prelude::Resource;
+use bevy::reflect::TypePath;
+use fastnoise2::SafeNode;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use thiserror::Error;
+
+/// Parameters of the fractal noise the terrain heightfield is built from
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct HeightNoise {
+    pub gain: f32,
+    pub weighted_strength: f32,
+    pub octaves: i32,
+    pub lacunarity: f32,
+}
+
+/// Everything that shapes generated terrain, loaded from a `.terrain.ron` file.
+///
+/// The same settings always generate the same terrain. Editing the file while the game runs
+/// regenerates every loaded chunk. Edits to the terrain are saved for each set of settings, so
+/// they're left behind when the settings change and come back if the change is undone.
+#[derive(Asset, Resource, TypePath, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TerrainSettings {
+    pub seed: i32,
+    /// Width, height and depth of a chunk in voxels, at most 255
+    pub chunk_size: i32,
+    /// Height in voxels that the heightfield and the biome heights are fractions of
+    pub world_height: f32,
+    /// Lowest and highest layers of chunks generated, with chunk 0 starting at height 0.
+    /// Ground continues below the heightfield, so lower layers are solid apart from caves.
+    pub min_chunk_y: i32,
+    pub max_chunk_y: i32,
+    /// Size of the hills of the heightfield in voxels
+    pub scale_factor: f32,
+    pub height_noise: HeightNoise,
+    /// FastNoise2 node tree for the colour variation of the ground, as exported by its node editor
+    pub colour_node_tree: String,
+    /// 0 generates a plain heightfield, higher values let 3D noise push cliffs out over the
+    /// ground below and carve arches through hills
+    pub overhang_strength: f32,
+    /// Width of the winding cave tunnels carved through solid ground, 0 for no caves
+    pub cave_width: f32,
+    pub biomes: Biomes,
+    /// Wears the heightfield down before it is turned into voxels, or `None` to leave it as the
+    /// noise shaped it
+    #[serde(default)]
+    pub erosion: Option<ErosionSettings>,
+    /// Roads and rivers carved into the terrain
+    #[serde(default)]
+    pub paths: Vec<TerrainPath>,
+    /// Models scattered over the terrain, stamped into its voxels
+    #[serde(default)]
+    pub prefabs: Vec<PrefabPlacement>,
+}
+
+#[derive(Default)]
+pub struct TerrainSettingsLoader;
+
+/// Possible errors that can be produced by [`TerrainSettingsLoader`]
+#[non_exhaustive]
+#[derive(Debug, Error)]
+pub enum TerrainSettingsLoaderError {
+    /// An [IO](std::io) Error
+    #[error("Could not load asset: {0}")]
+    Io(#[from] std::io::Error),
+    /// A [RON](ron) Error
+    #[error("Could not parse terrain settings: {0}")]
+    Ron(#[from] ron::error::SpannedError),
+    /// The chunk size does not fit in a [`VxmAsset`](crate::vxm::VxmAsset)
+    #[error("Terrain chunk size {0} is not between 1 and 255")]
+    InvalidChunkSize(i32),
+    /// The colour node tree could not be decoded by FastNoise2
+    #[error("Could not decode terrain colour node tree {0:?}")]
+    InvalidColourNodeTree(String),
+    /// Terrain needs at least one biome to pick from
+    #[error("Terrain settings have no biomes")]
+    NoBiomes,
+    /// A path needs two points to run between
+    #[error("Terrain path {0} has fewer than two points")]
+    PathTooShort(usize),
+    /// A prefab model could not be loaded
+    #[error("Could not load terrain prefab: {0}")]
+    Prefab(#[from] LoadDirectError),
+}
+
+/// Parses terrain settings, without their prefabs loaded.
+///
+/// Settings that can't generate terrain are rejected here, so a bad edit keeps the current
+/// settings rather than failing generation.
+fn parse_terrain_settings(bytes: &[u8]) -> Result<TerrainSettings, TerrainSettingsLoaderError> {
+    let settings = ron::de::from_bytes::<TerrainSettings>(bytes)?;
+    if !(1..=255).contains(&settings.chunk_size) {
+        return Err(TerrainSettingsLoaderError::InvalidChunkSize(
+            settings.chunk_size,
+        ));
+    }
+    if SafeNode::from_encoded_node_tree(&settings.colour_node_tree).is_err() {
+        return Err(TerrainSettingsLoaderError::InvalidColourNodeTree(
+            settings.colour_node_tree,
+        ));
+    }
+    if settings.biomes.0.is_empty() {
+        return Err(TerrainSettingsLoaderError::NoBiomes);
+    }
+    if let Some(index) = settings.paths.iter().position(|path| path.points.len() < 2) {
+        return Err(TerrainSettingsLoaderError::PathTooShort(index));
+    }
+    Ok(settings)
+}
+
+impl AssetLoader for TerrainSettingsLoader {
+    type Asset = TerrainSettings;
+    type Settings = ();
+    type Error = TerrainSettingsLoaderError;
+    async fn load(
+        &self,
+        reader: &mut dyn Reader,
+        _settings: &(),
+        load_context: &mut LoadContext<'_>,
+    ) -> Result<Self::Asset, Self::Error> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await?;
+        let mut settings = parse_terrain_settings(&bytes)?;
+
+        // Loaded as dependencies, so editing a prefab regenerates the terrain too
+        for placement in &mut settings.prefabs {
+            let vxm = load_context
+                .loader()
+                .immediate()
+                .load::<VxmAsset>(&placement.path)
+                .await?;
+            placement.prefab = Arc::new(Prefab::from_vxm(vxm.get()));
+        }
+        Ok(settings)
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["terrain.ron"]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WORLD_SETTINGS: &str = include_str!("../assets/world.terrain.ron");
+
+    // The shipped settings with one change, written back out as a settings file
+    fn parse_edited(
+        edit: impl FnOnce(&mut TerrainSettings),
+    ) -> Result<TerrainSettings, TerrainSettingsLoaderError> {
+        let mut settings: TerrainSettings = ron::de::from_str(WORLD_SETTINGS).unwrap();
+        edit(&mut settings);
+        parse_terrain_settings(ron::to_string(&settings).unwrap().as_bytes())
+    }
+
+    #[test]
+    fn the_shipped_settings_load() {
+        let settings = parse_terrain_settings(WORLD_SETTINGS.as_bytes()).unwrap();
+        assert_eq!(settings, ron::de::from_str(WORLD_SETTINGS).unwrap());
+        assert!(parse_edited(|settings| settings.chunk_size = 255).is_ok());
+    }
+
+    #[test]
+    fn malformed_settings_are_rejected() {
+        assert!(matches!(
+            parse_terrain_settings(b"(seed: 1"),
+            Err(TerrainSettingsLoaderError::Ron(_))
+        ));
+    }
+
+    #[test]
+    fn chunk_sizes_a_model_cant_hold_are_rejected() {
+        for chunk_size in [-1, 0, 256] {
+            assert!(matches!(
+                parse_edited(|settings| settings.chunk_size = chunk_size),
+                Err(TerrainSettingsLoaderError::InvalidChunkSize(size)) if size == chunk_size
+            ));
+        }
+    }
+
+    #[test]
+    fn undecodable_colour_node_trees_are_rejected() {
+        assert!(matches!(
+            parse_edited(|settings| settings.colour_node_tree = "not a node tree".into()),
+            Err(TerrainSettingsLoaderError::InvalidColourNodeTree(_))
+        ));
+    }
+
+    #[test]
+    fn settings_without_biomes_are_rejected() {
+        assert!(matches!(
+            parse_edited(|settings| settings.biomes.0.clear()),
+            Err(TerrainSettingsLoaderError::NoBiomes)
+        ));
+    }
+
+    #[test]
+    fn paths_without_two_points_are_rejected() {
+        let result = parse_edited(|settings| settings.paths[1].points.truncate(1));
+        assert!(matches!(
+            result,
+            Err(TerrainSettingsLoaderError::PathTooShort(index)) if index == 1
+        ));
+    }
+}
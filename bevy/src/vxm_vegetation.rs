@@ -0,0 +1,532 @@
+use crate::color_conversion::{create_hsl_voxel, ColourEncoding};
+use crate::vxm::{VxmAsset, VxmVoxel};
+use crate::vxm_biome::BiomePalette;
+use crate::vxm_rng::Rng;
+use bevy::math::{IVec3, Vec3};
+use fastnoise2::generator::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+// Growth steps after which a tree stops growing even if it hasn't reached every attractor
+const MAX_GROWTH_STEPS: usize = 128;
+
+// Radius in voxels of the tips of branches, which thicken towards the trunk
+const TIP_RADIUS: f32 = 0.5;
+
+// Share of the voxels of a leaf cluster filled, leaving gaps light shows through
+const LEAF_DENSITY: f32 = 0.8;
+
+// Size in voxels of the bumps on a rock's surface
+const ROCK_BUMP_SCALE: f32 = 6.0;
+
+/// Shape of the crown a tree's branches grow to fill
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum CanopyShape {
+    /// Round or squashed crown, with its radii along x, y and z
+    Ellipsoid([f32; 3]),
+    /// Pointed crown of conifers, widest at the bottom
+    Cone { radius: f32, height: f32 },
+}
+
+impl CanopyShape {
+    // Half the size of the box around the crown, centred on the crown
+    fn half_extents(&self) -> Vec3 {
+        match *self {
+            CanopyShape::Ellipsoid(radii) => Vec3::from(radii),
+            CanopyShape::Cone { radius, height } => Vec3::new(radius, height * 0.5, radius),
+        }
+    }
+
+    fn contains(&self, offset: Vec3) -> bool {
+        match *self {
+            CanopyShape::Ellipsoid(radii) => (offset / Vec3::from(radii)).length_squared() <= 1.0,
+            CanopyShape::Cone { radius, height } => {
+                let from_base = offset.y + height * 0.5;
+                (0.0..=height).contains(&from_base)
+                    && offset.x.hypot(offset.z) <= radius * (1.0 - from_base / height)
+            }
+        }
+    }
+
+    // Random point inside the crown relative to its centre, by rejection from the box around it
+    fn random_point(&self, rng: &mut Rng) -> Vec3 {
+        let half_extents = self.half_extents();
+        loop {
+            let offset = Vec3::new(
+                rng.range(-half_extents.x, half_extents.x),
+                rng.range(-half_extents.y, half_extents.y),
+                rng.range(-half_extents.z, half_extents.z),
+            );
+            if self.contains(offset) {
+                return offset;
+            }
+        }
+    }
+}
+
+/// How a tree or bush grows, by space colonisation: branches grow from the top of the trunk
+/// towards points scattered through the crown until they have reached them
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TreeSettings {
+    pub seed: u64,
+    /// Height in voxels of the trunk before it starts branching, 0 for bushes
+    pub trunk_height: f32,
+    /// Radius in voxels of the trunk at its base, with branches thinning towards their tips
+    pub trunk_radius: f32,
+    pub canopy: CanopyShape,
+    /// Height of the centre of the crown above the top of the trunk
+    pub canopy_offset: f32,
+    /// Points scattered through the crown for branches to grow to, more giving denser branches
+    pub attractors: usize,
+    /// Voxels a branch grows each step
+    pub segment_length: f32,
+    /// Distance within which the points pull on branches
+    pub influence_radius: f32,
+    /// Radius in voxels of the clusters of leaves at the tips of the branches, 0 for bare trees
+    pub leaf_radius: f32,
+    pub wood: BiomePalette,
+    pub leaves: BiomePalette,
+}
+
+impl TreeSettings {
+    /// Broad tree with a round crown
+    pub fn oak(seed: u64) -> Self {
+        Self {
+            seed,
+            trunk_height: 14.0,
+            trunk_radius: 2.5,
+            canopy: CanopyShape::Ellipsoid([14.0, 10.0, 14.0]),
+            canopy_offset: 8.0,
+            attractors: 200,
+            segment_length: 2.0,
+            influence_radius: 12.0,
+            leaf_radius: 3.0,
+            wood: BiomePalette {
+                colour: [0.35, 0.25, 0.15],
+                variation: [0.0625, 0.0625, 0.03125],
+            },
+            leaves: BiomePalette {
+                colour: [0.2, 0.45, 0.15],
+                variation: [0.09375, 0.125, 0.0625],
+            },
+        }
+    }
+
+    /// Tall conifer with a pointed crown
+    pub fn pine(seed: u64) -> Self {
+        Self {
+            seed,
+            trunk_height: 8.0,
+            trunk_radius: 1.5,
+            canopy: CanopyShape::Cone {
+                radius: 8.0,
+                height: 30.0,
+            },
+            canopy_offset: 14.0,
+            attractors: 160,
+            segment_length: 2.0,
+            influence_radius: 8.0,
+            leaf_radius: 2.0,
+            wood: BiomePalette {
+                colour: [0.3, 0.2, 0.12],
+                variation: [0.0625, 0.0625, 0.03125],
+            },
+            leaves: BiomePalette {
+                colour: [0.1, 0.3, 0.15],
+                variation: [0.0625, 0.09375, 0.0625],
+            },
+        }
+    }
+
+    /// Low, dense shrub branching from the ground
+    pub fn bush(seed: u64) -> Self {
+        Self {
+            seed,
+            trunk_height: 0.0,
+            trunk_radius: 1.0,
+            canopy: CanopyShape::Ellipsoid([5.0, 3.0, 5.0]),
+            canopy_offset: 3.0,
+            attractors: 60,
+            segment_length: 1.0,
+            influence_radius: 5.0,
+            leaf_radius: 1.5,
+            wood: BiomePalette {
+                colour: [0.3, 0.22, 0.12],
+                variation: [0.0625, 0.0625, 0.03125],
+            },
+            leaves: BiomePalette {
+                colour: [0.25, 0.45, 0.12],
+                variation: [0.09375, 0.125, 0.0625],
+            },
+        }
+    }
+}
+
+/// How a rock is shaped, an ellipsoid with a bumpy surface half sunk into the ground
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RockSettings {
+    pub seed: u64,
+    /// Radii in voxels along x, y and z
+    pub radii: [f32; 3],
+    /// How far bumps push the surface in and out, as a fraction of the radii
+    pub roughness: f32,
+    pub palette: BiomePalette,
+}
+
+impl RockSettings {
+    pub fn boulder(seed: u64) -> Self {
+        Self {
+            seed,
+            radii: [6.0, 4.0, 5.0],
+            roughness: 0.3,
+            palette: BiomePalette {
+                colour: [0.45, 0.45, 0.42],
+                variation: [0.125, 0.125, 0.125],
+            },
+        }
+    }
+}
+
+/// Voxels of a model being built, growing to fit whatever is drawn into it
+struct VoxelCanvas {
+    voxels: Vec<(IVec3, VxmVoxel)>,
+    filled: HashSet<IVec3>,
+}
+
+impl VoxelCanvas {
+    fn new() -> Self {
+        Self {
+            voxels: Vec::new(),
+            filled: HashSet::new(),
+        }
+    }
+
+    /// Sets a voxel unless one is already there
+    fn fill(&mut self, position: IVec3, palette: &BiomePalette, colour_noise: f32) {
+        if !self.filled.insert(position) {
+            return;
+        }
+        let (r, g, b) = palette.colour_at(colour_noise);
+        self.voxels.push((
+            position,
+            VxmVoxel {
+                hsl: create_hsl_voxel(r, g, b),
+                emissive: false,
+                opacity: 255,
+            },
+        ));
+    }
+
+    /// Packs the voxels into a model just big enough to hold them, with its lowest voxels at
+    /// y 0. Anything beyond the 255 voxels a model can hold along each axis is cut off.
+    fn into_vxm(self) -> VxmAsset {
+        let min = self
+            .voxels
+            .iter()
+            .fold(IVec3::MAX, |min, (position, _)| min.min(*position));
+        let max = self
+            .voxels
+            .iter()
+            .fold(IVec3::MIN, |max, (position, _)| max.max(*position));
+        let size = if self.voxels.is_empty() {
+            IVec3::ONE
+        } else {
+            (max - min + 1).min(IVec3::splat(u8::MAX as i32))
+        };
+
+        let mut voxel_array = vec![
+            vec![vec![VxmVoxel::default(); size.z as usize]; size.y as usize];
+            size.x as usize
+        ];
+        for (position, voxel) in self.voxels {
+            let local = position - min;
+            if local.cmplt(size).all() {
+                voxel_array[local.x as usize][local.y as usize][local.z as usize] = voxel;
+            }
+        }
+
+        VxmAsset {
+            size: [size.x as u8, size.y as u8, size.z as u8],
+            voxel_array,
+            lights: Vec::new(),
+            colour_encoding: ColourEncoding::Hsl,
+            palette: Vec::new(),
+        }
+    }
+}
+
+struct Branch {
+    position: Vec3,
+    parent: Option<usize>,
+}
+
+/// Grows a tree or bush, the same every time for the same settings
+pub fn generate_tree(settings: &TreeSettings) -> VxmAsset {
+    let mut rng = Rng::new(settings.seed);
+    let segment_length = settings.segment_length.max(0.5);
+
+    let mut branches = vec![Branch {
+        position: Vec3::ZERO,
+        parent: None,
+    }];
+    while branches.last().unwrap().position.y < settings.trunk_height {
+        branches.push(Branch {
+            position: branches.last().unwrap().position + Vec3::Y * segment_length,
+            parent: Some(branches.len() - 1),
+        });
+    }
+
+    let canopy_centre = Vec3::Y * (settings.trunk_height + settings.canopy_offset);
+    let mut attractors = (0..settings.attractors)
+        .map(|_| canopy_centre + settings.canopy.random_point(&mut rng))
+        .collect::<Vec<_>>();
+    let kill_distance = segment_length * 2.0;
+    // Branches are only grown once from each position, or two points pulling equally in
+    // opposite directions would grow the same branch over and over
+    let mut grown = branches
+        .iter()
+        .map(|branch| (branch.position * 4.0).round().as_ivec3())
+        .collect::<HashSet<_>>();
+
+    for _ in 0..MAX_GROWTH_STEPS {
+        if attractors.is_empty() {
+            break;
+        }
+
+        let mut pulls = vec![Vec3::ZERO; branches.len()];
+        for attractor in &attractors {
+            let nearest = branches
+                .iter()
+                .enumerate()
+                .map(|(index, branch)| (index, branch.position.distance(*attractor)))
+                .filter(|&(_, distance)| distance < settings.influence_radius)
+                .min_by(|a, b| a.1.total_cmp(&b.1));
+            if let Some((index, _)) = nearest {
+                pulls[index] += (*attractor - branches[index].position).normalize_or_zero();
+            }
+        }
+
+        let first_new = branches.len();
+        if pulls.iter().all(|pull| *pull == Vec3::ZERO) {
+            // Nothing in reach yet, so the trunk keeps growing up into the crown
+            let top = branches
+                .iter()
+                .enumerate()
+                .max_by(|a, b| a.1.position.y.total_cmp(&b.1.position.y))
+                .map(|(index, _)| index)
+                .unwrap();
+            branches.push(Branch {
+                position: branches[top].position + Vec3::Y * segment_length,
+                parent: Some(top),
+            });
+        } else {
+            for (index, pull) in pulls.into_iter().enumerate() {
+                if pull == Vec3::ZERO {
+                    continue;
+                }
+                // A little jitter keeps branches from growing dead straight
+                let jitter = Vec3::new(
+                    rng.range(-0.2, 0.2),
+                    rng.range(-0.2, 0.2),
+                    rng.range(-0.2, 0.2),
+                );
+                let direction = (pull.normalize_or_zero() + jitter).normalize_or_zero();
+                let position = branches[index].position + direction * segment_length;
+                if grown.insert((position * 4.0).round().as_ivec3()) {
+                    branches.push(Branch {
+                        position,
+                        parent: Some(index),
+                    });
+                }
+            }
+        }
+
+        let new_branches = &branches[first_new..];
+        attractors.retain(|attractor| {
+            new_branches
+                .iter()
+                .all(|branch| branch.position.distance(*attractor) > kill_distance)
+        });
+    }
+
+    // Each branch is as thick as the branches it carries put together, so the tree thickens
+    // from its tips down to the trunk
+    let mut areas = vec![0.0f32; branches.len()];
+    let mut has_children = vec![false; branches.len()];
+    for index in (0..branches.len()).rev() {
+        if !has_children[index] {
+            areas[index] = TIP_RADIUS * TIP_RADIUS;
+        }
+        if let Some(parent) = branches[index].parent {
+            areas[parent] += areas[index];
+            has_children[parent] = true;
+        }
+    }
+    let radius_scale = settings.trunk_radius / areas[0].sqrt();
+    let radii = areas
+        .iter()
+        .map(|area| (area.sqrt() * radius_scale).max(TIP_RADIUS))
+        .collect::<Vec<_>>();
+
+    let mut canvas = VoxelCanvas::new();
+    for (index, branch) in branches.iter().enumerate() {
+        let Some(parent) = branch.parent else {
+            continue;
+        };
+        let (start, end) = (branches[parent].position, branch.position);
+        let (start_radius, end_radius) = (radii[parent], radii[index]);
+        let reach = start_radius.max(end_radius);
+        let min = (start.min(end) - reach).floor().as_ivec3();
+        let max = (start.max(end) + reach).ceil().as_ivec3();
+        let along = end - start;
+        for x in min.x..=max.x {
+            for y in min.y..=max.y {
+                for z in min.z..=max.z {
+                    let centre = IVec3::new(x, y, z).as_vec3() + 0.5;
+                    let t = ((centre - start).dot(along) / along.length_squared()).clamp(0.0, 1.0);
+                    let radius = start_radius + (end_radius - start_radius) * t;
+                    if centre.distance(start + along * t) <= radius {
+                        canvas.fill(IVec3::new(x, y, z), &settings.wood, rng.next_f32());
+                    }
+                }
+            }
+        }
+    }
+
+    // Wood is drawn first so leaves never cover it up
+    if settings.leaf_radius > 0.0 {
+        let leaf_reach = settings.leaf_radius.ceil() as i32;
+        for (index, branch) in branches.iter().enumerate() {
+            if has_children[index] {
+                continue;
+            }
+            let tip = branch.position.floor().as_ivec3();
+            for x in -leaf_reach..=leaf_reach {
+                for y in -leaf_reach..=leaf_reach {
+                    for z in -leaf_reach..=leaf_reach {
+                        let offset = IVec3::new(x, y, z);
+                        if offset.as_vec3().length() <= settings.leaf_radius
+                            && rng.next_f32() < LEAF_DENSITY
+                        {
+                            canvas.fill(tip + offset, &settings.leaves, rng.next_f32());
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    canvas.into_vxm()
+}
+
+/// Shapes a rock, the same every time for the same settings
+pub fn generate_rock(settings: &RockSettings) -> VxmAsset {
+    let bump_node = opensimplex2().build();
+    let seed = settings.seed as i32;
+    let radii = Vec3::from(settings.radii).max(Vec3::ONE);
+    let reach = (radii * (1.0 + settings.roughness)).ceil().as_ivec3();
+
+    let mut canvas = VoxelCanvas::new();
+    // The bottom third is left off, as if sunk into the ground
+    for x in -reach.x..=reach.x {
+        for y in (-radii.y / 3.0) as i32..=reach.y {
+            for z in -reach.z..=reach.z {
+                let position = IVec3::new(x, y, z).as_vec3() + 0.5;
+                let bump = bump_node.gen_single_3d(
+                    position.x / ROCK_BUMP_SCALE,
+                    position.y / ROCK_BUMP_SCALE,
+                    position.z / ROCK_BUMP_SCALE,
+                    seed,
+                );
+                if (position / radii).length() <= 1.0 + bump * settings.roughness {
+                    canvas.fill(IVec3::new(x, y, z), &settings.palette, bump * 0.5 + 0.5);
+                }
+            }
+        }
+    }
+
+    canvas.into_vxm()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn solid_positions(vxm: &VxmAsset) -> Vec<[usize; 3]> {
+        let mut positions = Vec::new();
+        for (x, column) in vxm.voxel_array.iter().enumerate() {
+            for (y, row) in column.iter().enumerate() {
+                for (z, voxel) in row.iter().enumerate() {
+                    if voxel.hsl != 0 {
+                        positions.push([x, y, z]);
+                    }
+                }
+            }
+        }
+        positions
+    }
+
+    #[test]
+    fn the_same_seed_grows_the_same_tree() {
+        for tree in [TreeSettings::oak, TreeSettings::pine, TreeSettings::bush] {
+            let first = generate_tree(&tree(12));
+            let second = generate_tree(&tree(12));
+            assert_eq!(first.size, second.size);
+            assert_eq!(first.voxel_array, second.voxel_array);
+            assert_ne!(first.voxel_array, generate_tree(&tree(13)).voxel_array);
+        }
+    }
+
+    #[test]
+    fn the_same_seed_shapes_the_same_rock() {
+        let first = generate_rock(&RockSettings::boulder(12));
+        let second = generate_rock(&RockSettings::boulder(12));
+        assert_eq!(first.size, second.size);
+        assert_eq!(first.voxel_array, second.voxel_array);
+        assert_ne!(
+            first.voxel_array,
+            generate_rock(&RockSettings::boulder(13)).voxel_array
+        );
+    }
+
+    #[test]
+    fn trees_stand_on_their_trunk_under_their_crown() {
+        for settings in [TreeSettings::oak(3), TreeSettings::pine(3)] {
+            let vxm = generate_tree(&settings);
+            assert!(vxm.size[1] as f32 >= settings.trunk_height + settings.canopy_offset);
+
+            // Only the trunk touches the ground
+            let base = solid_positions(&vxm)
+                .into_iter()
+                .filter(|&[_, y, _]| y == 0)
+                .collect::<Vec<_>>();
+            assert!(!base.is_empty());
+            let centre = base.iter().fold([0.0; 2], |sum, &[x, _, z]| {
+                [sum[0] + x as f32, sum[1] + z as f32]
+            });
+            let centre = centre.map(|sum| sum / base.len() as f32);
+            for &[x, _, z] in &base {
+                let distance = (x as f32 - centre[0]).hypot(z as f32 - centre[1]);
+                assert!(distance <= settings.trunk_radius + 1.0);
+            }
+        }
+    }
+
+    #[test]
+    fn rocks_are_sunk_into_the_ground() {
+        let settings = RockSettings::boulder(5);
+        let vxm = generate_rock(&settings);
+        assert!(
+            vxm.size[1] as f32
+                <= settings.radii[1] * (4.0 / 3.0) * (1.0 + settings.roughness) + 2.0
+        );
+
+        // Cut off below the middle, so the rock sits on a flat base nearly as wide as it is
+        let positions = solid_positions(&vxm);
+        let layer_sizes = (0..vxm.size[1] as usize)
+            .map(|layer| positions.iter().filter(|&&[_, y, _]| y == layer).count())
+            .collect::<Vec<_>>();
+        let widest = *layer_sizes.iter().max().unwrap();
+        assert!(layer_sizes[0] * 2 >= widest);
+    }
+}